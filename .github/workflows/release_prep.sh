@@ -19,7 +19,7 @@ tools/create-release-archive/target/release/create-release-archive \
     "$TAG" \
     "dist/hermetic_launcher-${TAG}.tar.gz"
 
-echo "Archive created at dist/hermetic_launcher-${TAG}.tar.gz" >&2
+echo "Archive created at dist/hermetic_launcher-${TAG}.tar.gz (checksum in .sha256)" >&2
 
 echo "Packaging Starlark docs..." 1>&2
 # Add generated API docs to the release, see https://github.com/bazelbuild/bazel-central-registry/issues/5593
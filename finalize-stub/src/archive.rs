@@ -0,0 +1,129 @@
+//! Runfiles archive appended to self-extracting stubs
+//!
+//! The archive is a flat list of regular files followed by a fixed-size
+//! trailer at the very end of the stub. All integers are little-endian:
+//!
+//! ```text
+//! entry:   u32 path_len | path (relative, '/'-separated) | u32 mode | u64 data_len | data
+//! trailer: u64 archive_offset | u64 entry_count | u64 archive_hash | "RFSTUBSX"
+//! ```
+//!
+//! The stub locates the trailer by seeking to the end of its own executable,
+//! so the archive must be appended after any post-processing (e.g. Mach-O
+//! signing) that rewrites the binary. Files are stored uncompressed.
+
+use std::fs;
+use std::path::Path;
+
+/// Magic bytes closing the trailer
+pub const MAGIC: &[u8; 8] = b"RFSTUBSX";
+
+/// Maximum length of an entry path, matching the stub's path buffers
+pub const MAX_ENTRY_PATH_LEN: usize = 255;
+
+struct Entry {
+    path: String,
+    mode: u32,
+    data: Vec<u8>,
+}
+
+/// Collects all regular files below `dir` (following symlinks), sorted by path
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<Entry>) -> Result<(), String> {
+    let read_dir = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+
+    let mut children: Vec<_> = read_dir
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Failed to read directory {}: {}", dir.display(), e))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let path = child.path();
+        let metadata = fs::metadata(&path)
+            .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+
+        if metadata.is_dir() {
+            collect_entries(root, &path, entries)?;
+            continue;
+        }
+
+        let relative = path.strip_prefix(root).expect("walked path is below root");
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("Path is not valid UTF-8: {}", path.display()))?
+            .join("/");
+        if relative.len() > MAX_ENTRY_PATH_LEN {
+            return Err(format!(
+                "Runfiles path too long for self-extracting stub: {} ({} bytes > {} bytes max)",
+                relative,
+                relative.len(),
+                MAX_ENTRY_PATH_LEN
+            ));
+        }
+
+        let data = fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        entries.push(Entry {
+            path: relative,
+            mode: file_mode(&metadata),
+            data,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    // Keep only the executable bits; everything is extracted as read/write for the owner
+    if metadata.permissions().mode() & 0o111 != 0 {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    // No executable bit to preserve; extract everything as executable
+    0o755
+}
+
+/// FNV-1a, used to name the extraction directory after the archive contents
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Builds an archive of `dir` to be appended to a stub of `stub_len` bytes.
+///
+/// Returns the archive including its trailer, and the number of files.
+pub fn build(dir: &Path, stub_len: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut entries = Vec::new();
+    collect_entries(dir, dir, &mut entries)?;
+
+    let mut archive = Vec::new();
+    for entry in &entries {
+        archive.extend_from_slice(&(entry.path.len() as u32).to_le_bytes());
+        archive.extend_from_slice(entry.path.as_bytes());
+        archive.extend_from_slice(&entry.mode.to_le_bytes());
+        archive.extend_from_slice(&(entry.data.len() as u64).to_le_bytes());
+        archive.extend_from_slice(&entry.data);
+    }
+
+    let hash = fnv1a(&archive);
+    archive.extend_from_slice(&(stub_len as u64).to_le_bytes());
+    archive.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    archive.extend_from_slice(&hash.to_le_bytes());
+    archive.extend_from_slice(MAGIC);
+
+    Ok((archive, entries.len()))
+}
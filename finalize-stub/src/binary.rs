@@ -0,0 +1,372 @@
+//! Minimal executable header parsing (ELF, PE, Mach-O)
+//!
+//! The finalizer only needs a handful of facts about a template, so instead of
+//! pulling in a full object-file parser we read the few header fields we need
+//! by hand. Every accessor is bounds-checked and returns `None` on malformed
+//! input rather than panicking.
+
+use std::ops::Range;
+
+// The names below must match the `placeholder_section!` macro of each stub
+// platform (runfiles-stub/src/{linux,macos,windows}.rs)
+
+/// Section holding the placeholders in ELF templates (Linux)
+pub const ELF_PLACEHOLDER_SECTION: &str = ".runfiles_stubs";
+
+/// Section holding the placeholders in PE templates (Windows)
+pub const PE_PLACEHOLDER_SECTION: &str = ".runfiles";
+
+/// Segment and section holding the placeholders in Mach-O templates (macOS)
+pub const MACHO_PLACEHOLDER_SEGMENT: &str = "__DATA";
+pub const MACHO_PLACEHOLDER_SECTION: &str = "__runfiles";
+
+pub fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(8)?)?;
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(buf))
+}
+
+/// Reads a NUL-terminated (or NUL-padded) name from a byte slice
+fn read_name(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Converts a file offset and size into a range, rejecting ranges past the end of the file
+fn file_range(data: &[u8], offset: u64, size: u64) -> Option<Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(size).ok()?)?;
+    if end > data.len() {
+        return None;
+    }
+    Some(start..end)
+}
+
+fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+pub fn is_pe(data: &[u8]) -> bool {
+    data.starts_with(b"MZ")
+}
+
+fn is_macho64(data: &[u8]) -> bool {
+    read_u32(data, 0) == Some(0xfeedfacf)
+}
+
+fn is_macho32(data: &[u8]) -> bool {
+    read_u32(data, 0) == Some(0xfeedface)
+}
+
+/// Whether `data` starts like an executable of one of the template platforms
+pub fn is_executable(data: &[u8]) -> bool {
+    is_elf(data) || is_pe(data) || is_macho64(data) || is_macho32(data)
+}
+
+/// Returns the file range of the section that holds the stub placeholders.
+///
+/// Returns `None` if the binary format is not recognized, the section headers
+/// were stripped, or the section is missing.
+pub fn placeholder_section(data: &[u8]) -> Option<Range<usize>> {
+    if is_elf(data) {
+        elf_section(data, ELF_PLACEHOLDER_SECTION)
+    } else if is_macho64(data) || is_macho32(data) {
+        macho_section(data, MACHO_PLACEHOLDER_SEGMENT, MACHO_PLACEHOLDER_SECTION)
+    } else if is_pe(data) {
+        pe_section(data, PE_PLACEHOLDER_SECTION)
+    } else {
+        None
+    }
+}
+
+/// Returns the name of the placeholder section for the binary's format, as
+/// spelled in the stub's `#[link_section]` (`__DATA,__runfiles` for Mach-O).
+///
+/// Returns `None` if the binary format is not recognized.
+pub fn placeholder_section_name(data: &[u8]) -> Option<String> {
+    if is_elf(data) {
+        Some(ELF_PLACEHOLDER_SECTION.to_string())
+    } else if is_macho64(data) || is_macho32(data) {
+        Some(format!("{},{}", MACHO_PLACEHOLDER_SEGMENT, MACHO_PLACEHOLDER_SECTION))
+    } else if is_pe(data) {
+        Some(PE_PLACEHOLDER_SECTION.to_string())
+    } else {
+        None
+    }
+}
+
+/// Returns the dynamic interpreter (`PT_INTERP`) of an ELF binary.
+///
+/// Returns `None` for non-ELF input and for static binaries, which have no
+/// interpreter.
+pub fn elf_interpreter(data: &[u8]) -> Option<String> {
+    const PT_INTERP: u32 = 3;
+
+    if !is_elf(data) || data.get(5) != Some(&1) {
+        return None;
+    }
+    let is_64 = match data.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return None,
+    };
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        (
+            read_u64(data, 0x20)?,
+            read_u16(data, 0x36)? as usize,
+            read_u16(data, 0x38)? as usize,
+        )
+    } else {
+        (
+            read_u32(data, 0x1C)? as u64,
+            read_u16(data, 0x2A)? as usize,
+            read_u16(data, 0x2C)? as usize,
+        )
+    };
+    let phoff = usize::try_from(phoff).ok()?;
+
+    for index in 0..phnum {
+        let base = phoff.checked_add(index.checked_mul(phentsize)?)?;
+        if read_u32(data, base)? != PT_INTERP {
+            continue;
+        }
+        let (offset, size) = if is_64 {
+            (read_u64(data, base + 8)?, read_u64(data, base + 32)?)
+        } else {
+            (read_u32(data, base + 4)? as u64, read_u32(data, base + 16)? as u64)
+        };
+        let interp = read_name(data.get(file_range(data, offset, size)?)?);
+        return Some(String::from_utf8_lossy(interp).into_owned());
+    }
+    None
+}
+
+/// Returns the platform a binary was built for as (arch, os), named like
+/// `std::env::consts::{ARCH, OS}`, from the machine field of its header.
+///
+/// Returns `None` if the format or machine is not one a template is built for.
+pub fn platform(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    if is_elf(data) {
+        let arch = match read_u16(data, 0x12)? {
+            0x03 => "x86",
+            0x28 => "arm",
+            0x3E => "x86_64",
+            0xB7 => "aarch64",
+            0xF3 if data.get(4) == Some(&2) => "riscv64",
+            0xF3 => "riscv32",
+            _ => return None,
+        };
+        Some((arch, "linux"))
+    } else if is_macho64(data) || is_macho32(data) {
+        let arch = match read_u32(data, 4)? {
+            0x0000_0007 => "x86",
+            0x0000_000C => "arm",
+            0x0100_0007 => "x86_64",
+            0x0100_000C => "aarch64",
+            _ => return None,
+        };
+        Some((arch, "macos"))
+    } else if is_pe(data) {
+        let pe_offset = read_u32(data, 0x3C)? as usize;
+        if data.get(pe_offset..pe_offset.checked_add(4)?)? != b"PE\0\0" {
+            return None;
+        }
+        let arch = match read_u16(data, pe_offset + 4)? {
+            0x014C => "x86",
+            0x01C4 => "arm",
+            0x8664 => "x86_64",
+            0xAA64 => "aarch64",
+            _ => return None,
+        };
+        Some((arch, "windows"))
+    } else {
+        None
+    }
+}
+
+/// Returns the platform a Rust target triple (e.g. `aarch64-apple-darwin`) runs on
+/// as (arch, os), named like [`platform`].
+///
+/// Returns `None` for architectures and operating systems without a template.
+pub fn triple_platform(triple: &str) -> Option<(&'static str, &'static str)> {
+    let (arch, rest) = triple.split_once('-')?;
+    let arch = match arch {
+        "x86_64" => "x86_64",
+        "aarch64" | "arm64" => "aarch64",
+        "i386" | "i586" | "i686" => "x86",
+        _ if arch.starts_with("riscv64") => "riscv64",
+        _ if arch.starts_with("riscv32") => "riscv32",
+        _ if arch.starts_with("arm") || arch.starts_with("thumb") => "arm",
+        _ => return None,
+    };
+    let os = rest.split('-').find_map(|part| match part {
+        "linux" => Some("linux"),
+        "darwin" | "macos" => Some("macos"),
+        "windows" => Some("windows"),
+        _ => None,
+    })?;
+    Some((arch, os))
+}
+
+/// Whether a binary built for `platform` runs on `host` (both `(arch, os)`):
+/// the same platform, or one the host runs natively alongside its own, namely
+/// 32-bit x86 on x86_64 Linux and Windows and x86_64 on Apple silicon (Rosetta 2).
+pub fn runs_on(platform: (&str, &str), host: (&str, &str)) -> bool {
+    platform == host
+        || matches!(
+            (platform, host),
+            (("x86", "linux"), ("x86_64", "linux"))
+                | (("x86", "windows"), ("x86_64", "windows"))
+                | (("x86_64", "macos"), ("aarch64", "macos"))
+        )
+}
+
+fn elf_section(data: &[u8], name: &str) -> Option<Range<usize>> {
+    // Only little-endian ELF is produced by our targets
+    if data.get(5) != Some(&1) {
+        return None;
+    }
+    let is_64 = match data.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return None,
+    };
+
+    let (shoff, shentsize, shnum, shstrndx) = if is_64 {
+        (
+            read_u64(data, 0x28)?,
+            read_u16(data, 0x3A)? as usize,
+            read_u16(data, 0x3C)? as usize,
+            read_u16(data, 0x3E)? as usize,
+        )
+    } else {
+        (
+            read_u32(data, 0x20)? as u64,
+            read_u16(data, 0x2E)? as usize,
+            read_u16(data, 0x30)? as usize,
+            read_u16(data, 0x32)? as usize,
+        )
+    };
+    let shoff = usize::try_from(shoff).ok()?;
+
+    // Returns (name offset, file offset, size) of the section header at `index`
+    let section_header = |index: usize| -> Option<(usize, u64, u64)> {
+        let base = shoff.checked_add(index.checked_mul(shentsize)?)?;
+        if is_64 {
+            Some((
+                read_u32(data, base)? as usize,
+                read_u64(data, base + 24)?,
+                read_u64(data, base + 32)?,
+            ))
+        } else {
+            Some((
+                read_u32(data, base)? as usize,
+                read_u32(data, base + 16)? as u64,
+                read_u32(data, base + 20)? as u64,
+            ))
+        }
+    };
+
+    let (_, strtab_offset, strtab_size) = section_header(shstrndx)?;
+    let strtab = data.get(file_range(data, strtab_offset, strtab_size)?)?;
+
+    for index in 0..shnum {
+        let (name_offset, offset, size) = section_header(index)?;
+        if read_name(strtab.get(name_offset..)?) == name.as_bytes() {
+            return file_range(data, offset, size);
+        }
+    }
+    None
+}
+
+fn macho_section(data: &[u8], segment: &str, section: &str) -> Option<Range<usize>> {
+    let is_64 = is_macho64(data);
+    let ncmds = read_u32(data, 16)? as usize;
+    let mut cmd_offset = if is_64 { 32 } else { 28 };
+
+    const LC_SEGMENT: u32 = 0x1;
+    const LC_SEGMENT_64: u32 = 0x19;
+
+    for _ in 0..ncmds {
+        let cmd = read_u32(data, cmd_offset)?;
+        let cmdsize = read_u32(data, cmd_offset + 4)? as usize;
+
+        if cmd == LC_SEGMENT_64 || cmd == LC_SEGMENT {
+            let (nsects_offset, sections_offset, section_size) = if cmd == LC_SEGMENT_64 {
+                (64, 72, 80)
+            } else {
+                (48, 56, 68)
+            };
+            let nsects = read_u32(data, cmd_offset + nsects_offset)? as usize;
+
+            for i in 0..nsects {
+                let base = cmd_offset + sections_offset + i * section_size;
+                let sectname = read_name(data.get(base..base + 16)?);
+                let segname = read_name(data.get(base + 16..base + 32)?);
+                if sectname == section.as_bytes() && segname == segment.as_bytes() {
+                    let (size, offset) = if cmd == LC_SEGMENT_64 {
+                        (read_u64(data, base + 40)?, read_u32(data, base + 48)? as u64)
+                    } else {
+                        (read_u32(data, base + 36)? as u64, read_u32(data, base + 40)? as u64)
+                    };
+                    return file_range(data, offset, size);
+                }
+            }
+        }
+
+        if cmdsize == 0 {
+            return None;
+        }
+        cmd_offset = cmd_offset.checked_add(cmdsize)?;
+    }
+    None
+}
+
+fn pe_section(data: &[u8], name: &str) -> Option<Range<usize>> {
+    let pe_offset = read_u32(data, 0x3C)? as usize;
+    if data.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    let coff = pe_offset + 4;
+    let nsections = read_u16(data, coff + 2)? as usize;
+    let symtab_offset = read_u32(data, coff + 8)? as usize;
+    let nsymbols = read_u32(data, coff + 12)? as usize;
+    let optional_header_size = read_u16(data, coff + 16)? as usize;
+    let section_table = coff + 20 + optional_header_size;
+
+    for i in 0..nsections {
+        let base = section_table + i * 40;
+        let raw_name = read_name(data.get(base..base + 8)?);
+
+        // Names longer than 8 bytes are either truncated (MSVC) or stored in
+        // the COFF string table as "/<offset>" (GNU ld)
+        let matches = if let Some(offset) = raw_name.strip_prefix(b"/") {
+            let offset: usize = std::str::from_utf8(offset).ok()?.parse().ok()?;
+            let strtab = symtab_offset.checked_add(nsymbols.checked_mul(18)?)?;
+            read_name(data.get(strtab.checked_add(offset)?..)?) == name.as_bytes()
+        } else {
+            raw_name == name.as_bytes()
+                || (raw_name.len() == 8 && name.as_bytes().starts_with(raw_name))
+        };
+
+        if matches {
+            let raw_size = read_u32(data, base + 16)? as u64;
+            let raw_offset = read_u32(data, base + 20)? as u64;
+            return file_range(data, raw_offset, raw_size);
+        }
+    }
+    None
+}
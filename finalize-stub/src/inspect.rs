@@ -0,0 +1,110 @@
+//! Reading the settings back out of a finalized stub (`--inspect`)
+//!
+//! A finalized stub keeps its metadata block, now marked with the finalized
+//! magic, and the block's `args_offset` points at the embedded arguments.
+//! Other regions (exec path, default arguments, ...) lose their markers when
+//! patched and aren't reported.
+
+use std::fs;
+use std::path::Path;
+
+use crate::binary;
+use crate::metadata::{self, Metadata, StubConfig};
+
+/// Settings embedded in a finalized stub
+struct Report {
+    /// Section the metadata block was found in, if the binary has one
+    section: Option<String>,
+    metadata: Metadata,
+    args: Vec<String>,
+}
+
+fn read_report(data: &[u8]) -> Result<Report, String> {
+    // Without the section (stripped headers, or renamed by a linker script) the
+    // whole file is searched
+    let section = binary::placeholder_section(data);
+    let config = StubConfig::read(data, section.clone().unwrap_or(0..data.len()))?;
+    let section = section.and(binary::placeholder_section_name(data));
+    let args = config.args.iter().map(|arg| String::from_utf8_lossy(arg).into_owned()).collect();
+    Ok(Report { section, metadata: config.metadata, args })
+}
+
+fn options(flags: u32) -> Vec<&'static str> {
+    metadata::FLAG_OPTIONS
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn print_text(report: &Report) {
+    let m = &report.metadata;
+    println!("section:         {}", report.section.as_deref().unwrap_or("(none, searched the whole file)"));
+    println!("version:         {}", m.version);
+    println!("arg_size:        {}", m.arg_size);
+    println!("argc:            {}", m.argc);
+    println!("transform_flags: 0b{:b}", m.transform_flags);
+    println!("value_flags:     0b{:b}", m.value_flags);
+    println!("flags:           0b{:b} {}", m.flags, options(m.flags).join(" "));
+    for (i, arg) in report.args.iter().enumerate() {
+        let transformed = if m.value_flags & (1 << i) != 0 {
+            " (value transformed)"
+        } else if m.transform_flags & (1 << i) != 0 {
+            " (transformed)"
+        } else {
+            ""
+        };
+        println!("argv[{}]:         {:?}{}", i, arg, transformed);
+    }
+}
+
+fn print_json(report: &Report) {
+    let m = &report.metadata;
+    let options: Vec<String> = options(m.flags).into_iter().map(json_string).collect();
+    let args: Vec<String> = report
+        .args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            format!("{{\"value\": {}, \"transform\": {}}}", json_string(arg), m.transform_flags & (1 << i) != 0)
+        })
+        .collect();
+    let section = report.section.as_deref().map_or("null".to_string(), json_string);
+    println!("{{");
+    println!("  \"section\": {},", section);
+    println!("  \"version\": {},", m.version);
+    println!("  \"arg_size\": {},", m.arg_size);
+    println!("  \"argc\": {},", m.argc);
+    println!("  \"transform_flags\": {},", m.transform_flags);
+    println!("  \"value_flags\": {},", m.value_flags);
+    println!("  \"flags\": {},", m.flags);
+    println!("  \"options\": [{}],", options.join(", "));
+    println!("  \"args\": [{}]", args.join(", "));
+    println!("}}");
+}
+
+/// Prints the metadata and embedded arguments of the finalized stub at `path`
+pub fn run(path: &Path, json: bool) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read stub {}: {}", path.display(), e))?;
+    let report = read_report(&data)?;
+    if json {
+        print_json(&report);
+    } else {
+        print_text(&report);
+    }
+    Ok(())
+}
@@ -1,17 +1,56 @@
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
 use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use std::process;
 
-const ARG_SIZE: usize = 256;
+mod archive;
+mod binary;
+mod inspect;
+mod metadata;
+mod version_info;
+
+use metadata::{Metadata, MAX_ARG_SIZE};
+
+/// Argument placeholder size of templates without a metadata block
+const LEGACY_ARG_SIZE: usize = 256;
+/// Region holding the default runtime arguments (NUL-separated, ending with an empty string)
+const DEFAULT_ARGS_SIZE: usize = 1024;
+/// Region holding the NUL-terminated exec path
+const EXEC_PATH_SIZE: usize = 256;
+/// Region holding the NUL-terminated --chdir directory
+const CHDIR_SIZE: usize = 256;
+/// Region holding the NUL-terminated --argv0 name
+const ARGV0_SIZE: usize = 256;
+/// Region holding the NUL-terminated canonical name of the stub's repository
+const SOURCE_REPO_SIZE: usize = 256;
+/// Region holding the NUL-terminated sandbox root
+const SANDBOX_ROOT_SIZE: usize = 256;
+/// Region holding the environment allowlist (NUL-separated, ending with an empty string)
+const ENV_ALLOWLIST_SIZE: usize = 1024;
+/// Region holding the allowed rlocation prefixes (NUL-separated, ending with an empty string)
+const RLOCATION_PREFIXES_SIZE: usize = 1024;
+/// Region holding the --set-env variables (NUL-separated NAME=value, ending with an empty string)
+const SET_ENV_SIZE: usize = 1024;
+/// Region holding the NUL-terminated --umask digits (octal)
+const UMASK_SIZE: usize = 32;
+/// Region holding the NUL-terminated --runtime-args-position index (decimal)
+const RUNTIME_POS_SIZE: usize = 32;
+/// Region holding the --rlimit entries (NUL-separated RESOURCE=VALUE, ending with an empty string)
+const RLIMITS_SIZE: usize = 512;
+/// Resources the stub can limit with --rlimit
+const RLIMIT_RESOURCES: [&str; 9] = ["cpu", "fsize", "data", "stack", "core", "nproc", "nofile", "memlock", "as"];
 const ARGC_SIZE: usize = 32;
+/// Token in untransformed arguments that the stub replaces with the runfiles directory
+const RUNFILES_ROOT_TOKEN: &str = "{RUNFILES_ROOT}";
 
 /// Finalize a runfiles stub template with actual arguments
 #[derive(Parser)]
 #[command(name = "finalize-stub")]
 #[command(version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 #[command(after_help = "EXAMPLES:\n  \
     # Transform only arg0:\n  \
     finalize-stub --template template --transform 0 --output finalized -- arg0 --flag value\n\n  \
@@ -19,33 +58,241 @@ const ARGC_SIZE: usize = 32;
     finalize-stub --template template --transform 0 --transform 2 --output output -- arg0 arg1 arg2\n\n  \
     # Transform arg0 and arg2 (comma-separated):\n  \
     finalize-stub --template template --transform 0,2 --output output -- arg0 arg1 arg2\n\n  \
+    # Transform arg0 and the value of --data=... in arg1:\n  \
+    finalize-stub --template template --transform 0 --transform-value 1 --output output -- arg0 --data=_main/data/x.txt\n\n  \
     # No transforms (all arguments are literals):\n  \
-    finalize-stub --template template --output output -- /absolute/path --flag")]
+    finalize-stub --template template --output output -- /absolute/path --flag\n\n  \
+    # Bundle a runfiles tree into the stub (self-extracting):\n  \
+    finalize-stub --template template --transform 0 --self-extracting bin.runfiles --output output -- _main/bin/tool")]
 struct Cli {
     /// Path to template runfiles-stub binary
-    #[arg(short, long, required = true)]
-    template: String,
+    #[arg(short, long, required_unless_present = "inspect")]
+    template: Option<String>,
 
     /// Write output to file (default: stdout)
     #[arg(short, long)]
     output: Option<String>,
 
+    /// Permissions of the output file, in octal (e.g. `644` for a stub that must not be run
+    /// directly). Ignored on Windows.
+    #[arg(long, value_name = "OCTAL", default_value = "755", value_parser = parse_mode, requires = "output")]
+    mode: u32,
+
+    /// Create the directory of --output, including missing parents, if it doesn't exist
+    #[arg(long, requires = "output")]
+    create_dirs: bool,
+
     /// Argument indices to transform (0-9). Can be specified multiple times or comma-separated.
     /// If not specified, no arguments are transformed by default.
     #[arg(long, action = ArgAction::Append, value_delimiter = ',', value_parser = clap::value_parser!(u32).range(0..10))]
     transform: Vec<u32>,
 
+    /// Transform the arguments that start with this prefix instead of those given with
+    /// --transform, e.g. `@` for `@_main/bin/tool`. The prefix is stripped before embedding.
+    #[arg(long, value_name = "PREFIX", conflicts_with = "transform", value_parser = clap::builder::NonEmptyStringValueParser::new())]
+    inline_transform_marker: Option<String>,
+
+    /// Indices of `--flag=value` arguments (0-9) of which only the value, after the first `=`,
+    /// is resolved through runfiles; the `--flag=` part is kept. Implies --transform for them.
+    #[arg(long, action = ArgAction::Append, value_delimiter = ',', value_parser = clap::value_parser!(u32).range(0..10))]
+    transform_value: Vec<u32>,
+
     /// Export runfiles environment variables (RUNFILES_DIR, RUNFILES_MANIFEST_FILE, JAVA_RUNFILES) to the executed process
     #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
     export_runfiles_env: bool,
 
+    /// With --export-runfiles-env, export RUNFILES_MANIFEST_FILE. When false, the variable is
+    /// passed on as the parent set it.
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    export_runfiles_manifest: bool,
+
+    /// With --export-runfiles-env, export RUNFILES_DIR. When false, the variable is passed on
+    /// as the parent set it.
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    export_runfiles_dir: bool,
+
+    /// With --export-runfiles-env, export JAVA_RUNFILES. When false, the variable is passed on
+    /// as the parent set it.
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    export_java_runfiles: bool,
+
+    /// Bundle the given runfiles directory into the stub. At runtime the stub extracts it to a
+    /// temporary directory and uses that as its runfiles directory, so no external runfiles are needed.
+    #[arg(long, value_name = "RUNFILES_DIR")]
+    self_extracting: Option<String>,
+
+    /// Treat relative runfiles manifest values as references to other manifest entries and
+    /// resolve them once more (a single level of indirection; self-references are not followed)
+    #[arg(long)]
+    resolve_indirection: bool,
+
+    /// Percent-decode transformed arguments (e.g. `%20` to a space) before looking them up
+    /// in the runfiles, for rlocation paths produced by tools that URL-encode them
+    #[arg(long)]
+    percent_decode: bool,
+
+    /// Expand environment variable references in RUNFILES_MANIFEST_FILE and RUNFILES_DIR
+    /// (`$VAR` and `${VAR}`, `%VAR%` on Windows) for environments that pass them unexpanded
+    #[arg(long)]
+    expand_runfiles_env_vars: bool,
+
+    /// Pass on RUNFILES_DIR, RUNFILES_MANIFEST_FILE and JAVA_RUNFILES from the parent instead of
+    /// replacing them, and export the stub's own values only for those it doesn't set. Runfiles
+    /// that can't be found are then not an error unless an argument or --exec needs them.
+    #[arg(long, conflicts_with = "sandbox_root")]
+    keep_inherited_runfiles_env: bool,
+
+    /// Program to run instead of argv[0], which then only sets the name the program sees.
+    /// A relative path is resolved through the runfiles, an absolute one is used as-is.
+    #[arg(long, value_name = "PATH")]
+    exec: Option<String>,
+
+    /// Name the program sees as argv[0], e.g. its rlocation path for tools that locate their
+    /// runfiles from it. The program run is still the (resolved) first argument. Linux and macOS only.
+    #[arg(long, value_name = "NAME", conflicts_with = "exec")]
+    argv0: Option<String>,
+
+    /// Directory to run the program in, e.g. `_main` for tools that expect the main repository
+    /// of the runfiles tree as their working directory. Resolved like --exec; a directory a
+    /// manifest doesn't list is taken relative to the runfiles directory.
+    #[arg(long, value_name = "RLOCATION", conflicts_with = "sandbox_root")]
+    chdir: Option<String>,
+
+    /// Canonical name of the repository the stub belongs to, which selects the entries of the
+    /// runfiles repository mapping (`_repo_mapping`) used to translate apparent repository
+    /// names in transformed arguments. Default: the main repository.
+    #[arg(long, value_name = "CANONICAL_NAME")]
+    source_repo: Option<String>,
+
+    /// Runtime argument to append when the stub is run without any (e.g. a default subcommand).
+    /// Can be specified multiple times; any runtime argument suppresses all defaults.
+    #[arg(long = "default-runtime-args", value_name = "ARG", action = ArgAction::Append, allow_hyphen_values = true)]
+    default_runtime_args: Vec<String>,
+
+    /// Insert the runtime arguments before the embedded argument at this index instead of after
+    /// the last one (e.g. 1 to place them between the program and a trailing `--`).
+    #[arg(long, value_name = "INDEX")]
+    runtime_args_position: Option<usize>,
+
+    /// Run the target hermetically below this absolute directory: the stub changes into it,
+    /// uses it as the runfiles directory (RUNFILES_DIR) and clears the inherited environment
+    /// except for variables named with --allow-env (Linux and macOS only)
+    #[arg(long, value_name = "DIR", conflicts_with = "self_extracting")]
+    sandbox_root: Option<String>,
+
+    /// Environment variable to pass through to the target with --sandbox-root.
+    /// Can be specified multiple times.
+    #[arg(long = "allow-env", value_name = "NAME", action = ArgAction::Append, requires = "sandbox_root")]
+    allow_env: Vec<String>,
+
+    /// Set an environment variable for the target, replacing any inherited value (e.g.
+    /// `RUST_BACKTRACE=1`). Can be specified multiple times.
+    #[arg(long, value_name = "NAME=VALUE", action = ArgAction::Append)]
+    set_env: Vec<String>,
+
+    /// File-creation mask for the target, in octal (e.g. `022`) (Linux and macOS only)
+    #[arg(long, value_name = "OCTAL")]
+    umask: Option<String>,
+
+    /// Resource limit for the target as `RESOURCE=VALUE`, setting both its soft and hard limit.
+    /// RESOURCE is one of cpu, fsize, data, stack, core, nproc, nofile, memlock and as; VALUE is
+    /// a number or `unlimited`. Can be specified multiple times (Linux and macOS only).
+    #[arg(long, value_name = "RESOURCE=VALUE", action = ArgAction::Append)]
+    rlimit: Vec<String>,
+
+    /// Only resolve transformed arguments whose rlocation path starts with this prefix
+    /// (e.g. `_main/bin/`); others are passed through unresolved. Can be specified multiple times.
+    #[arg(long = "allow-rlocation-prefix", value_name = "PREFIX", action = ArgAction::Append)]
+    allow_rlocation_prefix: Vec<String>,
+
+    /// Exit with an error instead of passing through a transformed argument outside the
+    /// --allow-rlocation-prefix prefixes
+    #[arg(long, requires = "allow_rlocation_prefix")]
+    strict_rlocation_prefixes: bool,
+
+    /// Run the target as a child process instead of replacing the stub (Linux only).
+    /// The stub forwards SIGTERM, SIGINT, SIGHUP, SIGQUIT, SIGUSR1 and SIGUSR2 to the child,
+    /// exits with its status, and reaps orphaned processes when running as PID 1.
+    #[arg(long)]
+    spawn: bool,
+
+    /// Add a version resource (VERSIONINFO) with this product name to a Windows stub, so file
+    /// properties and security tools can tell what it is. The stub is still not code-signed.
+    #[arg(long, value_name = "NAME")]
+    product_name: Option<String>,
+
+    /// File and product version in the version resource: up to four dot-separated numbers.
+    /// Default: 0.0.0.0
+    #[arg(long, value_name = "VERSION", requires = "product_name")]
+    product_version: Option<String>,
+
+    /// Fail if the template is a dynamically linked ELF binary (has a PT_INTERP program header).
+    /// Without this flag a dynamically linked template only produces a warning.
+    #[arg(long)]
+    require_static: bool,
+
+    /// Target triple the stub is meant to run on (e.g. `aarch64-apple-darwin`), checked against
+    /// the machine in the template's header. Default: the platform the finalizer runs on.
+    #[arg(long, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Fail if the template is built for another platform than --target (or the host).
+    /// Without this flag a mismatch only produces a warning.
+    #[arg(long)]
+    strict: bool,
+
+    /// Strip leading and trailing ASCII whitespace from each embedded argument.
+    /// Without this flag arguments are embedded verbatim, with a warning for trailing whitespace.
+    #[arg(long)]
+    trim_args: bool,
+
+    /// Zero the argument placeholders past the embedded arguments, so the output holds no
+    /// leftover runs of '@' bytes
+    #[arg(long)]
+    zero_unused: bool,
+
+    /// Print the offset, placeholder and value of every replacement instead of writing the
+    /// stub. All checks still run, so errors (e.g. too long values) show up as usual.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the metadata and embedded arguments of a finalized stub instead of finalizing one
+    #[arg(long, value_name = "STUB", conflicts_with_all = ["template", "output", "args", "args_file", "dry_run"])]
+    inspect: Option<String>,
+
+    /// Print the --inspect report as JSON
+    #[arg(long, requires = "inspect")]
+    json: bool,
+
     /// Enable verbose output
     #[arg(short, long)]
     verbose: bool,
 
+    /// Suppress all output except errors, including warnings
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Read arguments to embed from a file, one per line, placed before any given on the
+    /// command line. Empty lines and lines starting with '#' are skipped.
+    #[arg(long, value_name = "PATH")]
+    args_file: Option<String>,
+
     /// Arguments to embed in the stub (argv[0], argv[1], ...)
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = ["args_file", "inspect"])]
     args: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Tasks other than finalizing a stub
+#[derive(Subcommand)]
+enum Command {
+    /// Print a completion script for SHELL to stdout
+    #[command(hide = true)]
+    Completions {
+        shell: clap_complete::Shell,
+    },
 }
 
 fn find_pattern(data: &[u8], pattern: &[u8]) -> Option<usize> {
@@ -92,37 +339,226 @@ fn replace_at(data: &mut [u8], offset: usize, new_value: &[u8], fixed_size: usiz
     Ok(())
 }
 
-fn finalize_stub(template_path: &str, output_path: Option<&str>, argv: &[String], transform_flags: u32, export_runfiles_env: bool, verbose: bool) -> Result<(), String> {
-    if argv.is_empty() {
-        return Err("At least one argument (argv[0]) is required".to_string());
+/// Replaces an optional feature flag placeholder (`@@RUNFILES_<name>@@`) with "1" or "0".
+///
+/// Templates built before the feature existed don't have the placeholder, which is
+/// only an error if the feature is requested.
+fn replace_feature_flag(data: &mut [u8], name: &str, enabled: bool, flag: &str, verbose: bool) -> Result<(), String> {
+    let pattern = format!("@@RUNFILES_{}@@", name);
+    match find_pattern(data, pattern.as_bytes()) {
+        Some(pos) => {
+            let value = if enabled { "1" } else { "0" };
+            replace_at(data, pos, value.as_bytes(), 32)?;
+
+            if verbose {
+                eprintln!("Replaced {} with: {}", name, value);
+            }
+            Ok(())
+        }
+        None if enabled => Err(format!(
+            "{} placeholder not found in template (the template is too old or its platform does not support {})",
+            name, flag
+        )),
+        None => Ok(()),
     }
+}
 
-    if argv.len() > 10 {
-        return Err("Maximum 10 arguments supported (argv[0] to argv[9])".to_string());
+/// Checks of the template itself, which warn or fail but embed nothing
+struct TemplateChecks<'a> {
+    /// Fail for a dynamically linked template instead of warning
+    require_static: bool,
+    /// Target triple the stub is meant to run on; None for the host
+    target: Option<&'a str>,
+    /// Fail for a template built for another platform instead of warning
+    strict: bool,
+}
+
+/// Runtime behavior embedded into the finalized stub
+struct StubOptions<'a> {
+    /// Bitmask of argument indices to resolve through runfiles
+    transform_flags: u32,
+    /// Transformed `--flag=value` arguments of which only the value is resolved
+    value_flags: u32,
+    /// Export RUNFILES_DIR, RUNFILES_MANIFEST_FILE and JAVA_RUNFILES to the child
+    export_runfiles_env: bool,
+    /// Which of them to export: RUNFILES_MANIFEST_FILE, RUNFILES_DIR, JAVA_RUNFILES
+    export_vars: [bool; 3],
+    /// Runfiles directory to bundle into the stub
+    self_extracting: Option<&'a str>,
+    /// Follow one level of manifest indirection (relative values are keys)
+    resolve_indirection: bool,
+    /// Run the target as a child and wait for it instead of exec'ing it
+    spawn: bool,
+    /// Percent-decode rlocation paths before lookup
+    percent_decode: bool,
+    /// Expand variable references in the runfiles environment variables
+    expand_runfiles_env_vars: bool,
+    /// Pass on runfiles variables the parent set instead of replacing them
+    keep_inherited_runfiles_env: bool,
+    /// Program to run instead of argv[0]
+    exec: Option<&'a str>,
+    /// Name passed as argv[0] instead of the first argument
+    argv0: Option<&'a str>,
+    /// Directory the program runs in
+    chdir: Option<&'a str>,
+    /// Some untransformed argument contains RUNFILES_ROOT_TOKEN
+    expand_runfiles_root: bool,
+    /// Canonical name of the stub's repository for the repository mapping
+    source_repo: Option<&'a str>,
+    /// Runtime arguments used when the stub is run without any
+    default_runtime_args: &'a [String],
+    /// Index of the embedded argument the runtime arguments go before
+    runtime_args_position: Option<usize>,
+    /// Directory to run the target in, as its runfiles tree and with a scrubbed environment
+    sandbox_root: Option<&'a str>,
+    /// Environment variables passed through with a sandbox root
+    allow_env: &'a [String],
+    /// NAME=value variables set for the target
+    set_env: &'a [String],
+    /// Octal file-creation mask for the target
+    umask: Option<&'a str>,
+    /// RESOURCE=VALUE limits for the target
+    rlimits: &'a [String],
+    /// Rlocation prefixes transformed arguments must start with to be resolved
+    rlocation_prefixes: &'a [String],
+    /// Reject, rather than pass through, arguments outside `rlocation_prefixes`
+    strict_rlocation_prefixes: bool,
+    /// Zero the ARG placeholders past argc (the stub never reads them)
+    zero_unused: bool,
+    /// Product name and version for a Windows version resource
+    product_name: Option<&'a str>,
+    product_version: Option<&'a str>,
+    /// Report the replacements instead of writing the stub
+    dry_run: bool,
+    /// Permissions of the output file (Unix only)
+    output_mode: u32,
+    /// Create a missing output directory instead of failing
+    create_dirs: bool,
+}
+
+/// Writes `values` as NUL-terminated strings, followed by an empty one, into the
+/// template's `name` region of `size` bytes
+fn replace_string_list(data: &mut [u8], name: &str, values: &[String], size: usize, verbose: bool) -> Result<(), String> {
+    let magic = format!("@@RUNFILES_{}@@", name);
+    let pos = find_pattern(data, magic.as_bytes())
+        .ok_or(format!("{} placeholder not found in template", name))?;
+
+    let mut encoded = Vec::new();
+    for value in values {
+        if value.is_empty() || value.contains('\0') {
+            return Err(format!("{} entry {:?} must be non-empty and must not contain NUL", name, value));
+        }
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.push(0);
+    }
+    // Keep room for the terminating empty string
+    if encoded.len() >= size {
+        return Err(format!(
+            "{} too long: {} bytes > {} bytes max",
+            name,
+            encoded.len() + 1,
+            size
+        ));
     }
+    replace_at(data, pos, &encoded, size)?;
 
-    // Prevent overwriting the input file
-    if let Some(output) = output_path {
-        let template_canon = fs::canonicalize(template_path)
-            .map_err(|e| format!("Failed to resolve template path: {}", e))?;
-        let output_canon = fs::canonicalize(output).ok();
+    if verbose {
+        eprintln!("Replaced {} with: {:?}", name, values);
+    }
+    Ok(())
+}
 
-        if output_canon.as_ref() == Some(&template_canon) {
-            return Err("Output path cannot be the same as template path (would overwrite input)".to_string());
+/// Writes the sandbox root and environment allowlist into the template
+fn replace_sandbox(data: &mut [u8], root: &str, allow_env: &[String], verbose: bool) -> Result<(), String> {
+    if !root.starts_with('/') || root.contains('\0') {
+        return Err(format!("Sandbox root must be an absolute path: {:?}", root));
+    }
+    if let Some(name) = allow_env.iter().find(|name| name.contains('=')) {
+        return Err(format!("Invalid environment variable name for --allow-env: {:?}", name));
+    }
+
+    let pos = find_pattern(data, b"@@RUNFILES_SANDBOX_ROOT@@")
+        .ok_or("SANDBOX_ROOT placeholder not found in template")?;
+    // The stub relies on the root being NUL-terminated
+    replace_at(data, pos, root.as_bytes(), SANDBOX_ROOT_SIZE - 1)?;
+    if verbose {
+        eprintln!("Replaced SANDBOX_ROOT with: {}", root);
+    }
+
+    replace_string_list(data, "ENV_ALLOWLIST", allow_env, ENV_ALLOWLIST_SIZE, verbose)
+}
+
+/// Writes the --set-env variables into the template
+fn replace_set_env(data: &mut [u8], vars: &[String], verbose: bool) -> Result<(), String> {
+    for var in vars {
+        let name = var.split_once('=').map(|(name, _)| name).unwrap_or("");
+        if name.is_empty() || var.contains('\0') {
+            return Err(format!("Invalid --set-env value {:?}: expected NAME=VALUE", var));
         }
+        if ["RUNFILES_MANIFEST_FILE", "RUNFILES_DIR", "JAVA_RUNFILES"].contains(&name) {
+            return Err(format!("--set-env can't set {}; the stub exports it (see --export-runfiles-env)", name));
+        }
+    }
+    // Each variable takes its length plus a NUL, and the list ends with an empty string
+    let size = vars.iter().map(|var| var.len() + 1).sum::<usize>() + 1;
+    if size > SET_ENV_SIZE {
+        return Err(format!(
+            "--set-env variables too long: {} bytes > {} bytes max (each takes its length plus one)",
+            size, SET_ENV_SIZE
+        ));
     }
+    replace_string_list(data, "SET_ENV", vars, SET_ENV_SIZE, verbose)
+}
 
-    // Read template
-    let mut data = fs::read(template_path)
-        .map_err(|e| format!("Failed to read template {}: {}", template_path, e))?;
+/// Writes the --umask value into the template as octal digits
+fn replace_umask(data: &mut [u8], umask: &str, verbose: bool) -> Result<(), String> {
+    let mask = Some(umask)
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| (b'0'..=b'7').contains(&b)))
+        .and_then(|digits| u32::from_str_radix(digits, 8).ok())
+        .filter(|&mask| mask <= 0o777)
+        .ok_or_else(|| format!("Invalid --umask value {:?}: expected an octal mask such as 022", umask))?;
+    let digits = format!("{:03o}", mask);
+    let pos = find_pattern(data, b"@@RUNFILES_UMASK@@")
+        .ok_or("UMASK placeholder not found in template")?;
+    // The stub relies on the digits being NUL-terminated
+    replace_at(data, pos, digits.as_bytes(), UMASK_SIZE - 1)?;
+    if verbose {
+        eprintln!("Replaced UMASK with: {}", digits);
+    }
+    Ok(())
+}
+
+/// Writes the --rlimit entries into the template
+fn replace_rlimits(data: &mut [u8], limits: &[String], verbose: bool) -> Result<(), String> {
+    for limit in limits {
+        let (resource, value) = limit
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid --rlimit value {:?}: expected RESOURCE=VALUE", limit))?;
+        if !RLIMIT_RESOURCES.contains(&resource) {
+            return Err(format!(
+                "Unknown resource {:?} in --rlimit (expected one of {})",
+                resource,
+                RLIMIT_RESOURCES.join(", ")
+            ));
+        }
+        // Limits must fit the signed rlim_t range of macOS
+        let valid = value == "unlimited" || value.bytes().all(|b| b.is_ascii_digit()) && value.parse::<i64>().is_ok();
+        if !valid {
+            return Err(format!("Invalid --rlimit value {:?}: expected a number or \"unlimited\"", limit));
+        }
+    }
+    replace_string_list(data, "RLIMITS", limits, RLIMITS_SIZE, verbose)
+}
 
+/// Patches the per-setting placeholders of templates without a metadata block (format version 0)
+fn patch_legacy_placeholders(data: &mut [u8], argc: usize, options: &StubOptions, verbose: bool) -> Result<(), String> {
     // Find and replace ARGC
     let argc_pattern = b"@@RUNFILES_ARGC@@";
-    let argc_pos = find_pattern(&data, argc_pattern)
+    let argc_pos = find_pattern(data, argc_pattern)
         .ok_or("ARGC placeholder not found in template")?;
 
-    let argc_str = argv.len().to_string();
-    replace_at(&mut data, argc_pos, argc_str.as_bytes(), ARGC_SIZE)?;
+    let argc_str = argc.to_string();
+    replace_at(data, argc_pos, argc_str.as_bytes(), ARGC_SIZE)?;
 
     if verbose {
         eprintln!("Replaced ARGC with: {}", argc_str);
@@ -130,66 +566,518 @@ fn finalize_stub(template_path: &str, output_path: Option<&str>, argv: &[String]
 
     // Find and replace TRANSFORM_FLAGS
     let flags_pattern = b"@@RUNFILES_TRANSFORM_FLAGS@@";
-    let flags_pos = find_pattern(&data, flags_pattern)
+    let flags_pos = find_pattern(data, flags_pattern)
         .ok_or("TRANSFORM_FLAGS placeholder not found in template")?;
 
-    let flags_str = transform_flags.to_string();
-    replace_at(&mut data, flags_pos, flags_str.as_bytes(), 32)?;
+    let flags_str = options.transform_flags.to_string();
+    replace_at(data, flags_pos, flags_str.as_bytes(), 32)?;
 
     if verbose {
-        eprintln!("Replaced TRANSFORM_FLAGS with: {} (0b{:b})", flags_str, transform_flags);
+        eprintln!("Replaced TRANSFORM_FLAGS with: {} (0b{:b})", flags_str, options.transform_flags);
     }
 
     // Find and replace EXPORT_RUNFILES_ENV
     let export_pattern = b"@@RUNFILES_EXPORT_ENV@@";
-    let export_pos = find_pattern(&data, export_pattern)
+    let export_pos = find_pattern(data, export_pattern)
         .ok_or("EXPORT_RUNFILES_ENV placeholder not found in template")?;
 
-    let export_str = if export_runfiles_env { "1" } else { "0" };
-    replace_at(&mut data, export_pos, export_str.as_bytes(), 32)?;
+    let export_str = if options.export_runfiles_env { "1" } else { "0" };
+    replace_at(data, export_pos, export_str.as_bytes(), 32)?;
 
     if verbose {
         eprintln!("Replaced EXPORT_RUNFILES_ENV with: {}", export_str);
     }
 
+    // Optional feature flags
+    replace_feature_flag(data, "SELF_EXTRACT", options.self_extracting.is_some(), "--self-extracting", verbose)?;
+    replace_feature_flag(data, "INDIRECTION", options.resolve_indirection, "--resolve-indirection", verbose)?;
+    replace_feature_flag(data, "SPAWN", options.spawn, "--spawn", verbose)?;
+    replace_feature_flag(data, "PERCENT_DECODE", options.percent_decode, "--percent-decode", verbose)?;
+    replace_feature_flag(data, "DEFAULT_ARGS", !options.default_runtime_args.is_empty(), "--default-runtime-args", verbose)?;
+    replace_feature_flag(data, "SANDBOX", options.sandbox_root.is_some(), "--sandbox-root", verbose)?;
+    replace_feature_flag(data, "EXEC", options.exec.is_some(), "--exec", verbose)?;
+    replace_feature_flag(data, "EXPAND_ROOT", options.expand_runfiles_root, RUNFILES_ROOT_TOKEN, verbose)?;
+    replace_feature_flag(data, "RLOCATION_PREFIXES", !options.rlocation_prefixes.is_empty(), "--allow-rlocation-prefix", verbose)?;
+    replace_feature_flag(data, "STRICT_PREFIXES", options.strict_rlocation_prefixes, "--strict-rlocation-prefixes", verbose)?;
+    replace_feature_flag(data, "SOURCE_REPO", options.source_repo.is_some(), "--source-repo", verbose)?;
+    replace_feature_flag(data, "EXPAND_ENV", options.expand_runfiles_env_vars, "--expand-runfiles-env-vars", verbose)?;
+    replace_feature_flag(data, "KEEP_ENV", options.keep_inherited_runfiles_env, "--keep-inherited-runfiles-env", verbose)?;
+    replace_feature_flag(data, "NO_EXPORT_MANIFEST", !options.export_vars[0], "--export-runfiles-manifest=false", verbose)?;
+    replace_feature_flag(data, "NO_EXPORT_DIR", !options.export_vars[1], "--export-runfiles-dir=false", verbose)?;
+    replace_feature_flag(data, "NO_EXPORT_JAVA", !options.export_vars[2], "--export-java-runfiles=false", verbose)?;
+    replace_feature_flag(data, "SET_ENV", !options.set_env.is_empty(), "--set-env", verbose)?;
+    replace_feature_flag(data, "UMASK", options.umask.is_some(), "--umask", verbose)?;
+    replace_feature_flag(data, "RLIMIT", !options.rlimits.is_empty(), "--rlimit", verbose)?;
+    replace_feature_flag(data, "TRANSFORM_VALUE", options.value_flags != 0, "--transform-value", verbose)?;
+    replace_feature_flag(data, "ARGV0", options.argv0.is_some(), "--argv0", verbose)?;
+    replace_feature_flag(data, "RUNTIME_POS", options.runtime_args_position.is_some(), "--runtime-args-position", verbose)?;
+    replace_feature_flag(data, "CHDIR", options.chdir.is_some(), "--chdir", verbose)?;
+
+    Ok(())
+}
+
+/// Patches the template's metadata block at `pos` with argc and the requested options.
+///
+/// Returns the size of the template's argument placeholders.
+fn patch_metadata(data: &mut [u8], pos: usize, argc: usize, options: &StubOptions, verbose: bool) -> Result<usize, String> {
+    let template = Metadata::decode(&data[pos..])
+        .ok_or("Metadata block in template is truncated")?;
+
+    if template.version != metadata::VERSION {
+        return Err(format!(
+            "Template metadata format version {} is not supported (this finalizer supports version {})",
+            template.version,
+            metadata::VERSION
+        ));
+    }
+    let arg_size = template.arg_size as usize;
+    if arg_size == 0 || arg_size > MAX_ARG_SIZE {
+        return Err(format!(
+            "Template advertises an invalid argument size of {} bytes (expected 1..={})",
+            arg_size, MAX_ARG_SIZE
+        ));
+    }
+
+    let requested = [
+        (options.export_runfiles_env, metadata::FLAG_EXPORT_ENV, "--export-runfiles-env"),
+        (options.self_extracting.is_some(), metadata::FLAG_SELF_EXTRACT, "--self-extracting"),
+        (options.resolve_indirection, metadata::FLAG_INDIRECTION, "--resolve-indirection"),
+        (options.spawn, metadata::FLAG_SPAWN, "--spawn"),
+        (options.percent_decode, metadata::FLAG_PERCENT_DECODE, "--percent-decode"),
+        (!options.default_runtime_args.is_empty(), metadata::FLAG_DEFAULT_ARGS, "--default-runtime-args"),
+        (options.sandbox_root.is_some(), metadata::FLAG_SANDBOX, "--sandbox-root"),
+        (options.exec.is_some(), metadata::FLAG_EXEC, "--exec"),
+        (options.expand_runfiles_root, metadata::FLAG_EXPAND_ROOT, RUNFILES_ROOT_TOKEN),
+        (!options.rlocation_prefixes.is_empty(), metadata::FLAG_RLOCATION_PREFIXES, "--allow-rlocation-prefix"),
+        (options.strict_rlocation_prefixes, metadata::FLAG_STRICT_PREFIXES, "--strict-rlocation-prefixes"),
+        (options.source_repo.is_some(), metadata::FLAG_SOURCE_REPO, "--source-repo"),
+        (options.expand_runfiles_env_vars, metadata::FLAG_EXPAND_ENV, "--expand-runfiles-env-vars"),
+        (options.keep_inherited_runfiles_env, metadata::FLAG_KEEP_ENV, "--keep-inherited-runfiles-env"),
+        (!options.export_vars[0], metadata::FLAG_NO_EXPORT_MANIFEST, "--export-runfiles-manifest=false"),
+        (!options.export_vars[1], metadata::FLAG_NO_EXPORT_DIR, "--export-runfiles-dir=false"),
+        (!options.export_vars[2], metadata::FLAG_NO_EXPORT_JAVA, "--export-java-runfiles=false"),
+        (!options.set_env.is_empty(), metadata::FLAG_SET_ENV, "--set-env"),
+        (options.umask.is_some(), metadata::FLAG_UMASK, "--umask"),
+        (!options.rlimits.is_empty(), metadata::FLAG_RLIMIT, "--rlimit"),
+        (options.value_flags != 0, metadata::FLAG_TRANSFORM_VALUE, "--transform-value"),
+        (options.argv0.is_some(), metadata::FLAG_ARGV0, "--argv0"),
+        (options.runtime_args_position.is_some(), metadata::FLAG_RUNTIME_POS, "--runtime-args-position"),
+        (options.chdir.is_some(), metadata::FLAG_CHDIR, "--chdir"),
+    ];
+    let mut flags = 0;
+    for (enabled, flag, name) in requested {
+        if !enabled {
+            continue;
+        }
+        if template.features & flag == 0 {
+            return Err(format!(
+                "Template does not support {} (its platform or build configuration lacks the feature)",
+                name
+            ));
+        }
+        flags |= flag;
+    }
+
+    let finalized = Metadata {
+        argc: argc as u32,
+        transform_flags: options.transform_flags,
+        flags,
+        value_flags: options.value_flags,
+        ..template
+    };
+    data[pos..pos + metadata::SIZE].copy_from_slice(&finalized.encode());
+
+    if verbose {
+        eprintln!(
+            "Replaced metadata (version {}): argc={}, transform_flags=0b{:b}, value_flags=0b{:b}, flags=0b{:b}, arg_size={}",
+            finalized.version, finalized.argc, finalized.transform_flags, finalized.value_flags, finalized.flags, arg_size
+        );
+    }
+
+    Ok(arg_size)
+}
+
+fn finalize_stub(template_path: &str, output_path: Option<&str>, argv: &[String], options: &StubOptions, checks: &TemplateChecks, verbose: bool, quiet: bool) -> Result<(), String> {
+
+    if argv.is_empty() {
+        return Err("At least one argument (argv[0]) is required".to_string());
+    }
+
+    if argv.len() > 10 {
+        return Err("Maximum 10 arguments supported (argv[0] to argv[9])".to_string());
+    }
+
+    if options.keep_inherited_runfiles_env && !options.export_runfiles_env {
+        return Err("--keep-inherited-runfiles-env has no effect with --export-runfiles-env=false".to_string());
+    }
+
+    if options.export_vars.contains(&false) && !options.export_runfiles_env {
+        return Err("--export-runfiles-manifest, --export-runfiles-dir and --export-java-runfiles have no effect with --export-runfiles-env=false".to_string());
+    }
+
+    // A bit past the last argument never applies, usually because the arguments
+    // were reordered without updating --transform
+    for (mask, option) in [(options.value_flags, "--transform-value"), (options.transform_flags, "--transform")] {
+        check_transform_range(mask, option, argv.len())?;
+    }
+
+    // Runtime arguments can't come before the program itself
+    if let Some(position) = options.runtime_args_position {
+        if position == 0 || position > argv.len() {
+            return Err(format!(
+                "--runtime-args-position {} is out of range: {} argument{} given (valid positions: 1-{})",
+                position,
+                argv.len(),
+                if argv.len() == 1 { "" } else { "s" },
+                argv.len()
+            ));
+        }
+    }
+
+    for (i, arg) in argv.iter().enumerate() {
+        if options.transform_flags & (1 << i) != 0 && arg.contains(RUNFILES_ROOT_TOKEN) {
+            return Err(format!(
+                "Argument {} is resolved through runfiles and can't contain {}",
+                i, RUNFILES_ROOT_TOKEN
+            ));
+        }
+        if options.value_flags & (1 << i) != 0 && !arg.contains('=') {
+            return Err(format!("Argument {} has no `=` to split for --transform-value: {:?}", i, arg));
+        }
+    }
+
+    let expected_platform = match checks.target {
+        Some(triple) => binary::triple_platform(triple)
+            .ok_or_else(|| format!("--target {} is not a platform the stub supports", triple))?,
+        None => (std::env::consts::ARCH, std::env::consts::OS),
+    };
+
+    // Prevent overwriting the input file
+    let mut missing_output_dir = None;
+    if let Some(output) = output_path {
+        let template_canon = fs::canonicalize(template_path)
+            .map_err(|e| format!("Failed to resolve template path: {}", e))?;
+        let output_canon = fs::canonicalize(output).ok();
+
+        if output_canon.as_ref() == Some(&template_canon) {
+            return Err("Output path cannot be the same as template path (would overwrite input)".to_string());
+        }
+
+        // Check before doing any work, rather than failing on the temporary file at the end.
+        // With --create-dirs the directory is only created once the stub is ready to write,
+        // so a template that fails validation leaves nothing behind.
+        let dir = match Path::new(output).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if !dir.is_dir() {
+            if !options.create_dirs {
+                return Err(format!(
+                    "Output directory does not exist: {} (pass --create-dirs to create it)",
+                    dir.display()
+                ));
+            }
+            missing_output_dir = Some(dir);
+        }
+    }
+
+    // Read template
+    let mut data = fs::read(template_path)
+        .map_err(|e| format!("Failed to read template {}: {}", template_path, e))?;
+    // A text file or a wrong build artifact would otherwise only fail with a
+    // missing placeholder
+    if !binary::is_executable(&data) {
+        return Err(format!(
+            "{} is not a recognized executable template (expected an ELF, PE or Mach-O binary)",
+            template_path
+        ));
+    }
+    let template = if options.dry_run { data.clone() } else { Vec::new() };
+
+    // The Linux template is meant to be fully static. A dynamically linked one
+    // (e.g. built with the wrong linker flags) only fails at runtime, in
+    // environments that lack its interpreter.
+    if let Some(interpreter) = binary::elf_interpreter(&data) {
+        let message = format!(
+            "template is dynamically linked (PT_INTERP {}); the stub will fail wherever that interpreter is missing",
+            interpreter
+        );
+        if checks.require_static {
+            return Err(message);
+        }
+        if !quiet {
+            eprintln!("Warning: {}", message);
+        }
+    }
+
+    // A template for another platform finalizes fine, and the stub then crashes
+    // or fails to load where it runs
+    if let Some((arch, os)) = binary::platform(&data) {
+        // Without --target the stub runs on this host, which may also run templates
+        // of a compatible platform
+        let runs = match checks.target {
+            Some(_) => (arch, os) == expected_platform,
+            None => binary::runs_on((arch, os), expected_platform),
+        };
+        if !runs {
+            let message = format!(
+                "template is built for {}-{}, but the stub is meant to run on {}-{} ({}); it will not run there",
+                arch,
+                os,
+                expected_platform.0,
+                expected_platform.1,
+                if checks.target.is_some() { "--target" } else { "the host; pass --target to finalize for another platform" }
+            );
+            if checks.strict {
+                return Err(message);
+            }
+            if !quiet {
+                eprintln!("Warning: {}", message);
+            }
+        }
+    }
+
+    // Scalar settings: a packed metadata block in current templates, one
+    // placeholder per setting in templates that predate it
+    let metadata_pos = find_pattern(&data, metadata::TEMPLATE_MAGIC);
+    let arg_size = match metadata_pos {
+        Some(pos) => patch_metadata(&mut data, pos, argv.len(), options, verbose)?,
+        None => {
+            if !quiet {
+                eprintln!(
+                    "Warning: template has no metadata block, assuming {}-byte argument placeholders",
+                    LEGACY_ARG_SIZE
+                );
+            }
+            patch_legacy_placeholders(&mut data, argv.len(), options, verbose)?;
+            LEGACY_ARG_SIZE
+        }
+    };
+
+    if !options.default_runtime_args.is_empty() {
+        replace_string_list(&mut data, "DEFAULT_ARGS", options.default_runtime_args, DEFAULT_ARGS_SIZE, verbose)?;
+    }
+
+    if let Some(exec) = options.exec {
+        if exec.is_empty() || exec.contains('\0') {
+            return Err("Exec path must be non-empty and must not contain NUL".to_string());
+        }
+        let pos = find_pattern(&data, b"@@RUNFILES_EXEC_PATH@@")
+            .ok_or("EXEC_PATH placeholder not found in template")?;
+        // The stub relies on the path being NUL-terminated
+        replace_at(&mut data, pos, exec.as_bytes(), EXEC_PATH_SIZE - 1)?;
+        if verbose {
+            eprintln!("Replaced EXEC_PATH with: {}", exec);
+        }
+    }
+
+    if let Some(dir) = options.chdir {
+        if dir.is_empty() || dir.contains('\0') {
+            return Err("--chdir must be non-empty and must not contain NUL".to_string());
+        }
+        let pos = find_pattern(&data, b"@@RUNFILES_CHDIR@@")
+            .ok_or("CHDIR placeholder not found in template")?;
+        // The stub relies on the path being NUL-terminated
+        replace_at(&mut data, pos, dir.as_bytes(), CHDIR_SIZE - 1)?;
+        if verbose {
+            eprintln!("Replaced CHDIR with: {}", dir);
+        }
+    }
+
+    if let Some(argv0) = options.argv0 {
+        if argv0.contains('\0') {
+            return Err("--argv0 must not contain NUL".to_string());
+        }
+        let pos = find_pattern(&data, b"@@RUNFILES_ARGV0@@")
+            .ok_or("ARGV0 placeholder not found in template")?;
+        replace_at(&mut data, pos, argv0.as_bytes(), ARGV0_SIZE - 1)?;
+        if verbose {
+            eprintln!("Replaced ARGV0 with: {}", argv0);
+        }
+    }
+
+    if let Some(position) = options.runtime_args_position {
+        let digits = position.to_string();
+        let pos = find_pattern(&data, b"@@RUNFILES_RUNTIME_POS@@")
+            .ok_or("RUNTIME_POS placeholder not found in template")?;
+        // The stub relies on the digits being NUL-terminated
+        replace_at(&mut data, pos, digits.as_bytes(), RUNTIME_POS_SIZE - 1)?;
+        if verbose {
+            eprintln!("Replaced RUNTIME_POS with: {}", digits);
+        }
+    }
+
+    if let Some(repo) = options.source_repo {
+        if repo.contains(['/', ',', '\0']) {
+            return Err(format!("Invalid canonical repository name for --source-repo: {:?}", repo));
+        }
+        let pos = find_pattern(&data, b"@@RUNFILES_SOURCE_REPO@@")
+            .ok_or("SOURCE_REPO placeholder not found in template")?;
+        // The stub relies on the name being NUL-terminated
+        replace_at(&mut data, pos, repo.as_bytes(), SOURCE_REPO_SIZE - 1)?;
+        if verbose {
+            eprintln!("Replaced SOURCE_REPO with: {}", repo);
+        }
+    }
+
+    if let Some(root) = options.sandbox_root {
+        replace_sandbox(&mut data, root, options.allow_env, verbose)?;
+    }
+
+    if !options.rlocation_prefixes.is_empty() {
+        replace_string_list(&mut data, "RLOCATION_PREFIXES", options.rlocation_prefixes, RLOCATION_PREFIXES_SIZE, verbose)?;
+    }
+
+    if !options.set_env.is_empty() {
+        replace_set_env(&mut data, options.set_env, verbose)?;
+    }
+
+    if let Some(umask) = options.umask {
+        replace_umask(&mut data, umask, verbose)?;
+    }
+
+    if !options.rlimits.is_empty() {
+        replace_rlimits(&mut data, options.rlimits, verbose)?;
+    }
+
     // Find and replace ARG placeholders
-    let arg_pattern = &[b'@'; ARG_SIZE];
+    let arg_pattern = vec![b'@'; arg_size];
 
     // Find all placeholder positions FIRST (before any replacements modify the data)
     let mut arg_positions: Vec<usize> = Vec::new();
     for i in 0..argv.len() {
-        let arg_pos = find_nth_pattern(&data, arg_pattern, i)
+        let arg_pos = find_nth_pattern(&data, &arg_pattern, i)
             .ok_or(format!("ARG{} placeholder not found in template", i))?;
         arg_positions.push(arg_pos);
     }
 
+    // The template's remaining ARG placeholders, located before any of them is replaced
+    let mut unused_positions: Vec<usize> = Vec::new();
+    if options.zero_unused {
+        while let Some(pos) = find_nth_pattern(&data, &arg_pattern, argv.len() + unused_positions.len()) {
+            unused_positions.push(pos);
+        }
+    }
+
+    // Sanity check: every ARG placeholder must lie inside the template's placeholder
+    // section. A stray run of '@' elsewhere in the binary (e.g. in a string literal)
+    // would otherwise be matched and patched instead of the real placeholder.
+    match binary::placeholder_section(&data) {
+        Some(section) => {
+            for (i, &arg_pos) in arg_positions.iter().enumerate() {
+                if arg_pos < section.start || arg_pos + arg_size > section.end {
+                    return Err(format!(
+                        "ARG{} placeholder match at offset {:#x} is outside the placeholder section ({:#x}..{:#x}); \
+                         the template contains a stray run of '@' bytes",
+                        i, arg_pos, section.start, section.end
+                    ));
+                }
+            }
+            if let Some(pos) = metadata_pos {
+                if pos < section.start || pos + metadata::SIZE > section.end {
+                    return Err(format!(
+                        "Metadata block at offset {:#x} is outside the placeholder section ({:#x}..{:#x})",
+                        pos, section.start, section.end
+                    ));
+                }
+            }
+            // Only the placeholders themselves are zeroed, not stray runs of '@'
+            unused_positions.retain(|&pos| pos >= section.start && pos + arg_size <= section.end);
+            if verbose {
+                eprintln!("Placeholder section: {:#x}..{:#x}", section.start, section.end);
+            }
+        }
+        None => {
+            if !quiet {
+                eprintln!("Warning: placeholder section not found in template, skipping placeholder location check");
+                if options.zero_unused {
+                    eprintln!("Warning: not zeroing unused argument placeholders");
+                }
+            }
+            unused_positions.clear();
+        }
+    }
+
     // Now do the replacements
     for (i, arg) in argv.iter().enumerate() {
+        // The stub reads arguments as C strings, so the last byte stays NUL
+        if arg.len() >= arg_size {
+            return Err(format!(
+                "Argument {} too long: {} bytes > {} bytes max (a {}-byte placeholder reserves one byte for NUL)",
+                i,
+                arg.len(),
+                arg_size - 1,
+                arg_size
+            ));
+        }
         let arg_pos = arg_positions[i];
-        replace_at(&mut data, arg_pos, arg.as_bytes(), ARG_SIZE)?;
+        replace_at(&mut data, arg_pos, arg.as_bytes(), arg_size)?;
         if verbose {
             eprintln!("Replaced ARG{} with: {}", i, arg);
         }
     }
+    for (i, &pos) in unused_positions.iter().enumerate() {
+        data[pos..pos + arg_size].fill(0);
+        if verbose {
+            eprintln!("Zeroed ARG{}", argv.len() + i);
+        }
+    }
+
+    // Record where the arguments start, for --inspect. The offset describes all of
+    // them only if they are back to back.
+    if let Some(pos) = metadata_pos {
+        if arg_positions.windows(2).all(|pair| pair[1] == pair[0] + arg_size) {
+            let mut block = Metadata::decode(&data[pos..]).ok_or("Metadata block in template is truncated")?;
+            block.args_offset = (arg_positions[0] as i64 - pos as i64) as i32;
+            data[pos..pos + metadata::SIZE].copy_from_slice(&block.encode());
+        } else if !quiet {
+            eprintln!("Warning: argument placeholders are not contiguous, --inspect won't find the arguments");
+        }
+    }
+
+    if let Some(product_name) = options.product_name {
+        let info = version_info::VersionInfo {
+            product_name,
+            version: options.product_version.unwrap_or("0.0.0.0"),
+            original_filename: output_path.and_then(|output| Path::new(output).file_name()?.to_str()),
+        };
+        version_info::add(&mut data, &info)?;
+        if verbose {
+            eprintln!("Added version resource: {} {}", info.product_name, info.version);
+        }
+    }
 
     // Post-process the finalized binary (e.g., re-signing)
     data = post_process_binary(data, verbose)?;
 
+    // Append the runfiles archive last: the stub finds it through a trailer at the
+    // end of the file, and re-signing above would otherwise discard it
+    if let Some(runfiles_dir) = options.self_extracting {
+        let (archive, file_count) = archive::build(Path::new(runfiles_dir), data.len())?;
+        if verbose {
+            eprintln!("Appending runfiles archive: {} files, {} bytes", file_count, archive.len());
+        }
+        data.extend_from_slice(&archive);
+    }
+
+    if options.dry_run {
+        if let Some(dir) = missing_output_dir {
+            println!("Would create output directory {}", dir.display());
+        }
+        print_replacements(&template, &data, &arg_positions, &unused_positions);
+        return Ok(());
+    }
+
     // Write output
     if let Some(output) = output_path {
-        fs::write(output, &data)
-            .map_err(|e| format!("Failed to write output {}: {}", output, e))?;
-
-        // Make executable (Unix only)
-        #[cfg(unix)]
-        {
-            let mut perms = fs::metadata(output)
-                .map_err(|e| format!("Failed to get metadata: {}", e))?
-                .permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(output, perms)
-                .map_err(|e| format!("Failed to set permissions: {}", e))?;
+        if let Some(dir) = missing_output_dir {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create output directory {}: {}", dir.display(), e))?;
+            if verbose {
+                eprintln!("Created output directory {}", dir.display());
+            }
         }
+        write_output_atomically(Path::new(output), &data, options.output_mode)?;
 
         if verbose {
             eprintln!("\nFinalized stub written to: {}", output);
@@ -204,6 +1092,156 @@ fn finalize_stub(template_path: &str, output_path: Option<&str>, argv: &[String]
     Ok(())
 }
 
+/// Prints the placeholder section, then every placeholder that differs between
+/// `template` and the finalized `data`, ordered by offset, followed by the size
+/// of anything appended
+fn print_replacements(template: &[u8], data: &[u8], arg_positions: &[usize], unused_positions: &[usize]) {
+    let section_name = binary::placeholder_section_name(template).unwrap_or_else(|| "placeholder section".to_string());
+    let section = binary::placeholder_section(template);
+    match &section {
+        Some(range) => println!("Section {} at {:#010x}-{:#010x}", section_name, range.start, range.end),
+        // Stripped section headers, or a linker script that renamed the section
+        None => println!("Section {} not found; placeholders were located by their markers", section_name),
+    }
+
+    let mut replacements: Vec<(usize, String, String)> = Vec::new();
+
+    // Named placeholders: the value is a NUL-terminated string, or a list of them
+    // ending with an empty one
+    let prefix = b"@@RUNFILES_";
+    let mut pos = 0;
+    while let Some(offset) = find_pattern(&template[pos..], prefix) {
+        let start = pos + offset;
+        pos = start + prefix.len();
+        let name_len = find_pattern(&template[pos..], b"@@").unwrap_or(0);
+        let name = String::from_utf8_lossy(&template[pos..pos + name_len]).into_owned();
+        if data[start..pos] == template[start..pos] {
+            continue;
+        }
+
+        let value = if name == "METADATA" {
+            match Metadata::decode(&data[start..]) {
+                Some(m) => format!(
+                    "argc={} transform_flags=0b{:b} value_flags=0b{:b} flags=0b{:b} arg_size={}",
+                    m.argc, m.transform_flags, m.value_flags, m.flags, m.arg_size
+                ),
+                None => String::new(),
+            }
+        } else {
+            data[start..]
+                .split(|&b| b == 0)
+                .take_while(|value| !value.is_empty())
+                .map(|value| format!("{:?}", String::from_utf8_lossy(value)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        replacements.push((start, name, value));
+    }
+
+    for (i, &pos) in arg_positions.iter().enumerate() {
+        let len = data[pos..].iter().position(|&b| b == 0).unwrap_or(0);
+        replacements.push((pos, format!("ARG{}", i), format!("{:?}", String::from_utf8_lossy(&data[pos..pos + len]))));
+    }
+    for (i, &pos) in unused_positions.iter().enumerate() {
+        replacements.push((pos, format!("ARG{}", arg_positions.len() + i), "(zeroed)".to_string()));
+    }
+
+    replacements.sort();
+    for (offset, name, value) in replacements {
+        let outside = match &section {
+            Some(range) if !range.contains(&offset) => format!(" (outside {})", section_name),
+            _ => String::new(),
+        };
+        println!("{:#010x}  {:<20} {}{}", offset, name, value, outside);
+    }
+    if data.len() > template.len() {
+        println!("{:#010x}  {:<20} {} bytes", template.len(), "(appended)", data.len() - template.len());
+    }
+}
+
+/// Parses the octal --mode value. Setuid, setgid and sticky are refused: the stub
+/// execs whatever the runfiles environment variables point at, so a setuid stub
+/// would run arbitrary programs with its owner's privileges.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let mode = Some(mode)
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| (b'0'..=b'7').contains(&b)))
+        .and_then(|digits| u32::from_str_radix(digits, 8).ok())
+        .ok_or_else(|| "expected octal permissions such as 755".to_string())?;
+    if mode > 0o777 {
+        return Err(format!(
+            "{:o} sets setuid, setgid or sticky bits, which the stub must not have (at most 777)",
+            mode
+        ));
+    }
+    Ok(mode)
+}
+
+/// Checks that the argument indices in a transform `mask` for `option` are below
+/// `argc` (at least 1), reporting the first one that isn't
+fn check_transform_range(mask: u32, option: &str, argc: usize) -> Result<(), String> {
+    let unused = mask >> argc;
+    if unused == 0 {
+        return Ok(());
+    }
+    Err(format!(
+        "{} {} is out of range: {} argument{} given (valid indices: 0-{})",
+        option,
+        argc + unused.trailing_zeros() as usize,
+        argc,
+        if argc == 1 { "" } else { "s" },
+        argc - 1
+    ))
+}
+
+/// Writes `data` to `output` through a temporary file in the same directory that is
+/// renamed into place, so readers and concurrent finalizers never see a partial stub.
+/// `mode` sets the permissions of the output on Unix.
+fn write_output_atomically(output: &Path, data: &[u8], mode: u32) -> Result<(), String> {
+    let file_name = output
+        .file_name()
+        .ok_or_else(|| format!("Output path {} has no file name", output.display()))?;
+    let dir = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    // Temporary names are unique per process; O_EXCL creation skips names left
+    // behind by an earlier process with the same PID
+    let (temp_path, mut file) = (0..100u32)
+        .find_map(|attempt| {
+            let mut temp_name = std::ffi::OsString::from(".");
+            temp_name.push(file_name);
+            temp_name.push(format!(".{}.{}.tmp", process::id(), attempt));
+            let temp_path = dir.join(temp_name);
+            match fs::OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+                Ok(file) => Some(Ok((temp_path, file))),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => None,
+                Err(e) => Some(Err(format!("Failed to create temporary file in {}: {}", dir.display(), e))),
+            }
+        })
+        .unwrap_or_else(|| Err(format!("Failed to find a free temporary file name in {}", dir.display())))?;
+
+    let result = (|| {
+        file.write_all(data)
+            .map_err(|e| format!("Failed to write output {}: {}", output.display(), e))?;
+        drop(file);
+
+        // Make executable, unless --mode says otherwise (Unix only)
+        #[cfg(unix)]
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        fs::rename(&temp_path, output)
+            .map_err(|e| format!("Failed to move output into place at {}: {}", output.display(), e))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
 /// Post-processes a finalized binary based on its format
 fn post_process_binary(data: Vec<u8>, verbose: bool) -> Result<Vec<u8>, String> {
     // Try Mach-O signing first
@@ -272,9 +1310,49 @@ fn resign_macho(data: Vec<u8>, verbose: bool) -> Result<Vec<u8>, String> {
     Ok(signed_data)
 }
 
+/// Strips `marker` from the arguments starting with it and returns the
+/// transform flags selecting them
+fn strip_transform_markers(args: &mut [String], marker: &str) -> u32 {
+    let mut flags = 0u32;
+    for (i, arg) in args.iter_mut().enumerate() {
+        if let Some(stripped) = arg.strip_prefix(marker) {
+            *arg = stripped.to_string();
+            // More than 10 arguments are rejected later
+            flags |= 1u32.checked_shl(i as u32).unwrap_or(0);
+        }
+    }
+    flags
+}
+
+/// Reads the arguments of an --args-file: one per line (LF or CRLF), skipping
+/// blank lines and `#` comments
+fn read_args_file(path: &str) -> Result<Vec<String>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read args file {}: {}", path, e))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim_ascii().is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
 fn main() {
     let cli = Cli::parse();
 
+    if let Some(Command::Completions { shell }) = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), "finalize-stub", &mut io::stdout());
+        return;
+    }
+
+    if let Some(stub) = cli.inspect {
+        if let Err(e) = inspect::run(Path::new(&stub), cli.json) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let template = cli.template.expect("clap requires --template without --inspect");
+
     // Calculate transform flags bitmask
     let transform_flags = if cli.transform.is_empty() {
         // Default: transform none
@@ -288,7 +1366,78 @@ fn main() {
         flags
     };
 
-    match finalize_stub(&cli.template, cli.output.as_deref(), &cli.args, transform_flags, cli.export_runfiles_env, cli.verbose) {
+    let mut args = match cli.args_file.as_deref().map(read_args_file).transpose() {
+        Ok(file_args) => file_args.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    args.extend(cli.args);
+
+    // Trailing whitespace (e.g. from heredocs or generated scripts) turns a
+    // runfiles path into one that doesn't exist, so it is almost always a mistake
+    let mut args: Vec<String> = if cli.trim_args {
+        args.iter().map(|arg| arg.trim_ascii().to_string()).collect()
+    } else {
+        for (i, arg) in args.iter().enumerate() {
+            if arg.trim_ascii_end().len() != arg.len() && !cli.quiet {
+                eprintln!("Warning: argument {} has trailing whitespace: {:?} (use --trim-args to strip it)", i, arg);
+            }
+        }
+        args
+    };
+
+    // Inline markers select the arguments to transform instead of --transform
+    let transform_flags = match cli.inline_transform_marker.as_deref() {
+        Some(marker) => strip_transform_markers(&mut args, marker),
+        None => transform_flags,
+    };
+
+    // `--flag=value` arguments are transformed too, just not as a whole
+    let value_flags = cli.transform_value.iter().fold(0u32, |flags, idx| flags | 1 << idx);
+    let transform_flags = transform_flags | value_flags;
+
+    let options = StubOptions {
+        transform_flags,
+        value_flags,
+        export_runfiles_env: cli.export_runfiles_env,
+        export_vars: [cli.export_runfiles_manifest, cli.export_runfiles_dir, cli.export_java_runfiles],
+        self_extracting: cli.self_extracting.as_deref(),
+        resolve_indirection: cli.resolve_indirection,
+        spawn: cli.spawn,
+        percent_decode: cli.percent_decode,
+        expand_runfiles_env_vars: cli.expand_runfiles_env_vars,
+        keep_inherited_runfiles_env: cli.keep_inherited_runfiles_env,
+        exec: cli.exec.as_deref(),
+        argv0: cli.argv0.as_deref(),
+        chdir: cli.chdir.as_deref(),
+        expand_runfiles_root: args.iter().any(|arg| arg.contains(RUNFILES_ROOT_TOKEN)),
+        source_repo: cli.source_repo.as_deref(),
+        default_runtime_args: &cli.default_runtime_args,
+        runtime_args_position: cli.runtime_args_position,
+        sandbox_root: cli.sandbox_root.as_deref(),
+        allow_env: &cli.allow_env,
+        set_env: &cli.set_env,
+        umask: cli.umask.as_deref(),
+        rlimits: &cli.rlimit,
+        rlocation_prefixes: &cli.allow_rlocation_prefix,
+        strict_rlocation_prefixes: cli.strict_rlocation_prefixes,
+        zero_unused: cli.zero_unused,
+        product_name: cli.product_name.as_deref(),
+        product_version: cli.product_version.as_deref(),
+        dry_run: cli.dry_run,
+        output_mode: cli.mode,
+        create_dirs: cli.create_dirs,
+    };
+
+    let checks = TemplateChecks {
+        require_static: cli.require_static,
+        target: cli.target.as_deref(),
+        strict: cli.strict,
+    };
+
+    match finalize_stub(&template, cli.output.as_deref(), &args, &options, &checks, cli.verbose, cli.quiet) {
         Ok(()) => {
             if cli.verbose {
                 if let Some(output) = cli.output {
@@ -306,3 +1455,66 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transform_range_accepts_indices_below_argc() {
+        assert_eq!(check_transform_range(0, "--transform", 1), Ok(()));
+        assert_eq!(check_transform_range(0b1, "--transform", 1), Ok(()));
+        assert_eq!(check_transform_range(0b101, "--transform", 3), Ok(()));
+        assert_eq!(check_transform_range(1 << 9, "--transform", 10), Ok(()));
+    }
+
+    #[test]
+    fn transform_range_rejects_first_index_past_argc() {
+        assert_eq!(
+            check_transform_range(0b110, "--transform", 2),
+            Err("--transform 2 is out of range: 2 arguments given (valid indices: 0-1)".to_string())
+        );
+        assert_eq!(
+            check_transform_range(0b1_1001, "--transform-value", 3),
+            Err("--transform-value 3 is out of range: 3 arguments given (valid indices: 0-2)".to_string())
+        );
+    }
+
+    #[test]
+    fn transform_range_single_argument() {
+        assert_eq!(
+            check_transform_range(1 << 9, "--transform", 1),
+            Err("--transform 9 is out of range: 1 argument given (valid indices: 0-0)".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_mode_accepts_octal_permissions() {
+        assert_eq!(parse_mode("755"), Ok(0o755));
+        assert_eq!(parse_mode("0750"), Ok(0o750));
+        assert_eq!(parse_mode("0"), Ok(0));
+        assert_eq!(parse_mode("777"), Ok(0o777));
+    }
+
+    #[test]
+    fn parse_mode_rejects_non_octal() {
+        for mode in ["", "rwx", "8", "-755", "0o755", " 755"] {
+            assert_eq!(
+                parse_mode(mode),
+                Err("expected octal permissions such as 755".to_string()),
+                "{:?}",
+                mode
+            );
+        }
+        // Too large for u32
+        assert!(parse_mode("77777777777777").is_err());
+    }
+
+    #[test]
+    fn parse_mode_rejects_special_bits() {
+        for mode in ["4755", "2755", "1777", "7777", "1000"] {
+            let err = parse_mode(mode).unwrap_err();
+            assert!(err.contains("setuid, setgid or sticky"), "{}: {}", mode, err);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Packed metadata block holding a stub's scalar settings
+//!
+//! Templates carry one fixed-size block with argc, the transform mask and the
+//! feature flags, which the finalizer patches as a unit. All integers are
+//! little-endian:
+//!
+//! ```text
+//! 0   magic            [u8; 24]  "@@RUNFILES_METADATA@@" (template) / "RUNFILES_METADATA" (finalized)
+//! 24  version          u32       layout version, see VERSION
+//! 28  arg_size         u32       size of each ARG placeholder
+//! 32  argc             u32
+//! 36  transform_flags  u32       bitmask of arguments resolved through runfiles
+//! 40  flags            u32       FLAG_* bits
+//! 44  features         u32       FLAG_* bits the template supports (set by the template)
+//! 48  args_offset      i32       offset of ARG0 from the block, 0 if unknown (set by the finalizer)
+//! 52  value_flags      u32       bitmask of transformed `--flag=value` arguments whose value alone is resolved
+//! 56  reserved         [u8; 8]
+//! ```
+//!
+//! `args_offset` lets tools find the embedded arguments of a finalized stub,
+//! whose placeholders no longer carry a marker. The finalizer only sets it when
+//! the used ARG placeholders are contiguous, ARGn at `args_offset + n * arg_size`.
+//! The stub itself doesn't read it.
+//!
+//! Templates predating the block (format version 0) use one decimal string
+//! placeholder per setting instead; the finalizer still patches those.
+//!
+//! [`StubConfig::read`] decodes the block and the arguments of a finalized stub;
+//! `--inspect` and the round-trip test in integration-tests both use it.
+//!
+//! tools/create-release-archive and integration-tests include this file, so it
+//! must only depend on `std`.
+
+use std::ops::Range;
+
+/// Magic at the start of an unpatched block
+pub const TEMPLATE_MAGIC: &[u8] = b"@@RUNFILES_METADATA@@";
+
+/// Magic at the start of a finalized block
+pub const FINALIZED_MAGIC: &[u8] = b"RUNFILES_METADATA";
+
+/// Layout version understood by this finalizer
+pub const VERSION: u32 = 1;
+
+/// Total size of the block
+pub const SIZE: usize = 64;
+
+/// Size of the magic field; the magic is NUL-padded to it
+pub const MAGIC_SIZE: usize = 24;
+
+/// Upper bound for the argument size a template may advertise
+pub const MAX_ARG_SIZE: usize = 65536;
+
+/// Number of argument placeholders
+pub const MAX_ARGS: u32 = 10;
+
+pub const FLAG_EXPORT_ENV: u32 = 1 << 0;
+pub const FLAG_SELF_EXTRACT: u32 = 1 << 1;
+pub const FLAG_INDIRECTION: u32 = 1 << 2;
+pub const FLAG_SPAWN: u32 = 1 << 3;
+pub const FLAG_PERCENT_DECODE: u32 = 1 << 4;
+pub const FLAG_DEFAULT_ARGS: u32 = 1 << 5;
+pub const FLAG_SANDBOX: u32 = 1 << 6;
+pub const FLAG_EXEC: u32 = 1 << 7;
+pub const FLAG_EXPAND_ROOT: u32 = 1 << 8;
+pub const FLAG_RLOCATION_PREFIXES: u32 = 1 << 9;
+pub const FLAG_STRICT_PREFIXES: u32 = 1 << 10;
+pub const FLAG_SOURCE_REPO: u32 = 1 << 11;
+pub const FLAG_EXPAND_ENV: u32 = 1 << 12;
+pub const FLAG_KEEP_ENV: u32 = 1 << 13;
+pub const FLAG_NO_EXPORT_MANIFEST: u32 = 1 << 14;
+pub const FLAG_NO_EXPORT_DIR: u32 = 1 << 15;
+pub const FLAG_NO_EXPORT_JAVA: u32 = 1 << 16;
+pub const FLAG_SET_ENV: u32 = 1 << 17;
+pub const FLAG_UMASK: u32 = 1 << 18;
+pub const FLAG_RLIMIT: u32 = 1 << 19;
+pub const FLAG_TRANSFORM_VALUE: u32 = 1 << 20;
+pub const FLAG_ARGV0: u32 = 1 << 21;
+pub const FLAG_RUNTIME_POS: u32 = 1 << 22;
+pub const FLAG_CHDIR: u32 = 1 << 23;
+
+/// Finalizer option behind each FLAG_* bit
+pub const FLAG_OPTIONS: [(u32, &str); 24] = [
+    (FLAG_EXPORT_ENV, "--export-runfiles-env"),
+    (FLAG_SELF_EXTRACT, "--self-extracting"),
+    (FLAG_INDIRECTION, "--resolve-indirection"),
+    (FLAG_SPAWN, "--spawn"),
+    (FLAG_PERCENT_DECODE, "--percent-decode"),
+    (FLAG_DEFAULT_ARGS, "--default-runtime-args"),
+    (FLAG_SANDBOX, "--sandbox-root"),
+    (FLAG_EXEC, "--exec"),
+    (FLAG_EXPAND_ROOT, "{RUNFILES_ROOT}"),
+    (FLAG_RLOCATION_PREFIXES, "--allow-rlocation-prefix"),
+    (FLAG_STRICT_PREFIXES, "--strict-rlocation-prefixes"),
+    (FLAG_SOURCE_REPO, "--source-repo"),
+    (FLAG_EXPAND_ENV, "--expand-runfiles-env-vars"),
+    (FLAG_KEEP_ENV, "--keep-inherited-runfiles-env"),
+    (FLAG_NO_EXPORT_MANIFEST, "--export-runfiles-manifest=false"),
+    (FLAG_NO_EXPORT_DIR, "--export-runfiles-dir=false"),
+    (FLAG_NO_EXPORT_JAVA, "--export-java-runfiles=false"),
+    (FLAG_SET_ENV, "--set-env"),
+    (FLAG_UMASK, "--umask"),
+    (FLAG_RLIMIT, "--rlimit"),
+    (FLAG_TRANSFORM_VALUE, "--transform-value"),
+    (FLAG_ARGV0, "--argv0"),
+    (FLAG_RUNTIME_POS, "--runtime-args-position"),
+    (FLAG_CHDIR, "--chdir"),
+];
+
+/// Decoded contents of a metadata block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub version: u32,
+    pub arg_size: u32,
+    pub argc: u32,
+    pub transform_flags: u32,
+    pub flags: u32,
+    pub features: u32,
+    pub args_offset: i32,
+    pub value_flags: u32,
+}
+
+fn read_u32(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([block[offset], block[offset + 1], block[offset + 2], block[offset + 3]])
+}
+
+impl Metadata {
+    /// Decodes a block (template or finalized). Returns `None` if `block` is too short.
+    pub fn decode(block: &[u8]) -> Option<Metadata> {
+        if block.len() < SIZE {
+            return None;
+        }
+        Some(Metadata {
+            version: read_u32(block, 24),
+            arg_size: read_u32(block, 28),
+            argc: read_u32(block, 32),
+            transform_flags: read_u32(block, 36),
+            flags: read_u32(block, 40),
+            features: read_u32(block, 44),
+            args_offset: read_u32(block, 48) as i32,
+            value_flags: read_u32(block, 52),
+        })
+    }
+
+    /// Encodes a finalized block
+    pub fn encode(&self) -> [u8; SIZE] {
+        let mut block = [0u8; SIZE];
+        block[..FINALIZED_MAGIC.len()].copy_from_slice(FINALIZED_MAGIC);
+        let fields = [
+            self.version,
+            self.arg_size,
+            self.argc,
+            self.transform_flags,
+            self.flags,
+            self.features,
+            self.args_offset as u32,
+            self.value_flags,
+        ];
+        for (i, value) in fields.iter().enumerate() {
+            let offset = MAGIC_SIZE + i * 4;
+            block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        block
+    }
+}
+
+/// Settings embedded in a finalized stub, as the stub reads them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StubConfig {
+    pub metadata: Metadata,
+    /// ARG0 to ARG(argc - 1), without their NUL padding
+    pub args: Vec<Vec<u8>>,
+}
+
+impl StubConfig {
+    /// Reads the configuration of the finalized stub in `data`. The metadata block
+    /// is searched for in `search` (the placeholder section, or the whole file),
+    /// so a copy of the magic elsewhere (e.g. in the stub's code) isn't mistaken
+    /// for it.
+    pub fn read(data: &[u8], search: Range<usize>) -> Result<StubConfig, String> {
+        if find(data, TEMPLATE_MAGIC).is_some() {
+            return Err("This is a template, not a finalized stub".to_string());
+        }
+        let (pos, metadata) = find_finalized(data, search)
+            .ok_or("No finalized metadata block found (not a stub, or finalized from a template without one)")?;
+        if metadata.argc > MAX_ARGS {
+            return Err(format!("Invalid argc {} in metadata", metadata.argc));
+        }
+        if metadata.args_offset == 0 {
+            return Err("The stub doesn't record where its arguments are (finalized by an older finalize-stub)".to_string());
+        }
+
+        let arg_size = metadata.arg_size as usize;
+        let args_start = pos as i64 + metadata.args_offset as i64;
+        let mut args = Vec::new();
+        for i in 0..metadata.argc as usize {
+            let start = usize::try_from(args_start + (i * arg_size) as i64)
+                .map_err(|_| "Argument offset points before the start of the file".to_string())?;
+            let placeholder = data
+                .get(start..start + arg_size)
+                .ok_or_else(|| format!("ARG{} lies past the end of the file", i))?;
+            let len = placeholder.iter().position(|&b| b == 0).unwrap_or(arg_size);
+            args.push(placeholder[..len].to_vec());
+        }
+        Ok(StubConfig { metadata, args })
+    }
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|window| window == pattern)
+}
+
+/// Finds the first plausible finalized block in `data[search]`
+fn find_finalized(data: &[u8], search: Range<usize>) -> Option<(usize, Metadata)> {
+    let mut magic = [0u8; MAGIC_SIZE];
+    magic[..FINALIZED_MAGIC.len()].copy_from_slice(FINALIZED_MAGIC);
+
+    let mut pos = search.start;
+    while let Some(offset) = find(data.get(pos..search.end)?, &magic) {
+        let start = pos + offset;
+        if let Some(block) = Metadata::decode(&data[start..]) {
+            if block.version == VERSION && block.arg_size != 0 && block.arg_size as usize <= MAX_ARG_SIZE {
+                return Some((start, block));
+            }
+        }
+        pos = start + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake stub: padding, a finalized block, then `args` in `arg_size` slots
+    fn stub(metadata: &Metadata, args: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0xAAu8; 100];
+        data.extend_from_slice(&metadata.encode());
+        for arg in args {
+            let mut slot = vec![0u8; metadata.arg_size as usize];
+            slot[..arg.len()].copy_from_slice(arg);
+            data.extend_from_slice(&slot);
+        }
+        data
+    }
+
+    fn metadata(argc: u32) -> Metadata {
+        Metadata {
+            version: VERSION,
+            arg_size: 16,
+            argc,
+            transform_flags: 0b101,
+            flags: FLAG_EXPORT_ENV,
+            features: 0,
+            args_offset: SIZE as i32,
+            value_flags: 0,
+        }
+    }
+
+    #[test]
+    fn read_returns_metadata_and_args() {
+        let args: [&[u8]; 3] = [b"bin/tool", b"", b"full-slot-arg-15"];
+        let data = stub(&metadata(3), &args);
+        let config = StubConfig::read(&data, 0..data.len()).unwrap();
+        assert_eq!(config.metadata, metadata(3));
+        assert_eq!(config.args, args.map(|arg| arg.to_vec()));
+    }
+
+    #[test]
+    fn read_only_searches_the_given_range() {
+        let data = stub(&metadata(1), &[b"x"]);
+        assert!(StubConfig::read(&data, 0..100).is_err());
+        assert!(StubConfig::read(&data, 100..data.len()).is_ok());
+    }
+
+    #[test]
+    fn read_rejects_templates_and_bad_blocks() {
+        let mut template = stub(&metadata(1), &[b"x"]);
+        template[..TEMPLATE_MAGIC.len()].copy_from_slice(TEMPLATE_MAGIC);
+        assert_eq!(
+            StubConfig::read(&template, 0..template.len()),
+            Err("This is a template, not a finalized stub".to_string())
+        );
+
+        let data = stub(&metadata(11), &[]);
+        assert_eq!(StubConfig::read(&data, 0..data.len()), Err("Invalid argc 11 in metadata".to_string()));
+
+        // Arguments claimed past the end of the file
+        let data = stub(&metadata(2), &[b"x"]);
+        assert_eq!(StubConfig::read(&data, 0..data.len()), Err("ARG1 lies past the end of the file".to_string()));
+
+        let data = stub(&Metadata { args_offset: 0, ..metadata(1) }, &[b"x"]);
+        assert!(StubConfig::read(&data, 0..data.len()).unwrap_err().contains("older finalize-stub"));
+    }
+}
@@ -0,0 +1,255 @@
+//! Windows version resource (VERSIONINFO) for finalized PE stubs
+//!
+//! Explorer's file properties, inventory tools and anti-virus heuristics read
+//! a program's product name and version from its `RT_VERSION` resource. The
+//! templates are linked without resources, so the finalizer appends a new
+//! `.rsrc` section holding a single `VS_VERSIONINFO` and points the resource
+//! data directory at it:
+//!
+//! ```text
+//! 0   root directory      1 entry: type RT_VERSION (16)
+//! 24  name directory      1 entry: id 1
+//! 48  language directory  1 entry: en-US (0x0409)
+//! 72  data entry          RVA and size of the VS_VERSIONINFO below
+//! 88  VS_VERSIONINFO      fixed file info, StringFileInfo, VarFileInfo
+//! ```
+//!
+//! This only adds metadata; the stub is still not code-signed. A template that
+//! already has resources or an Authenticode signature is rejected.
+
+use crate::binary::{is_pe, read_u16, read_u32};
+
+/// Name of the added section
+const SECTION_NAME: &[u8; 8] = b".rsrc\0\0\0";
+
+/// IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ
+const SECTION_CHARACTERISTICS: u32 = 0x4000_0040;
+
+const RT_VERSION: u32 = 16;
+const LANG_EN_US: u16 = 0x0409;
+/// UTF-16LE, the code page of the string table
+const CODEPAGE_UNICODE: u16 = 1200;
+
+/// Offset of the VS_VERSIONINFO in the section, after the directory tree
+const VERSION_INFO_OFFSET: usize = 88;
+
+/// Contents of the version resource
+pub struct VersionInfo<'a> {
+    pub product_name: &'a str,
+    /// Dot-separated version as given, shown as the file and product version
+    pub version: &'a str,
+    /// File name of the finalized stub, if known
+    pub original_filename: Option<&'a str>,
+}
+
+/// Parses up to four dot-separated 16-bit numbers ("1.2" is 1.2.0.0)
+fn parse_version(version: &str) -> Result<[u16; 4], String> {
+    let invalid = || format!("Invalid version {:?}: expected up to four dot-separated numbers (0-65535)", version);
+    let mut parts = [0u16; 4];
+    for (i, part) in version.split('.').enumerate() {
+        *parts.get_mut(i).ok_or_else(invalid)? = part.parse().map_err(|_| invalid())?;
+    }
+    Ok(parts)
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+fn pad(out: &mut Vec<u8>) {
+    out.resize(align(out.len(), 4), 0);
+}
+
+fn push_utf16(out: &mut Vec<u8>, s: &str) {
+    for unit in s.encode_utf16().chain([0]) {
+        out.extend_from_slice(&unit.to_le_bytes());
+    }
+}
+
+/// Encodes one node of the VS_VERSIONINFO tree:
+/// `u16 length | u16 value_length | u16 type | key | pad | value | pad | children`
+fn node(key: &str, value: &[u8], text: bool, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0u8; 6];
+    push_utf16(&mut out, key);
+    pad(&mut out);
+    out.extend_from_slice(value);
+    for child in children {
+        pad(&mut out);
+        out.extend_from_slice(child);
+    }
+
+    // Text values are counted in UTF-16 units, binary ones in bytes
+    let value_length = if text { value.len() / 2 } else { value.len() };
+    let length = out.len() as u16;
+    out[0..2].copy_from_slice(&length.to_le_bytes());
+    out[2..4].copy_from_slice(&(value_length as u16).to_le_bytes());
+    out[4..6].copy_from_slice(&(text as u16).to_le_bytes());
+    out
+}
+
+fn string_node(key: &str, value: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    push_utf16(&mut encoded, value);
+    node(key, &encoded, true, &[])
+}
+
+/// Builds the VS_VERSIONINFO blob
+fn build_version_info(info: &VersionInfo) -> Result<Vec<u8>, String> {
+    let [major, minor, patch, build] = parse_version(info.version)?;
+    let version_ms = (major as u32) << 16 | minor as u32;
+    let version_ls = (patch as u32) << 16 | build as u32;
+
+    // VS_FIXEDFILEINFO
+    let fixed: [u32; 13] = [
+        0xFEEF_04BD, // signature
+        0x0001_0000, // structure version
+        version_ms,  // file version
+        version_ls,
+        version_ms, // product version
+        version_ls,
+        0x3F,        // file flags mask
+        0,           // file flags
+        0x0004_0004, // VOS_NT_WINDOWS32
+        1,           // VFT_APP
+        0,           // subtype
+        0,           // file date
+        0,
+    ];
+    let fixed: Vec<u8> = fixed.iter().flat_map(|v| v.to_le_bytes()).collect();
+
+    let mut strings = vec![
+        string_node("FileDescription", info.product_name),
+        string_node("FileVersion", info.version),
+        string_node("ProductName", info.product_name),
+        string_node("ProductVersion", info.version),
+    ];
+    if let Some(filename) = info.original_filename {
+        strings.push(string_node("OriginalFilename", filename));
+    }
+    let table_key = format!("{:04X}{:04X}", LANG_EN_US, CODEPAGE_UNICODE);
+    let string_file_info = node("StringFileInfo", &[], true, &[node(&table_key, &[], true, &strings)]);
+
+    let translation = [LANG_EN_US.to_le_bytes(), CODEPAGE_UNICODE.to_le_bytes()].concat();
+    let var_file_info = node("VarFileInfo", &[], true, &[node("Translation", &translation, false, &[])]);
+
+    let blob = node("VS_VERSION_INFO", &fixed, false, &[string_file_info, var_file_info]);
+    if blob.len() > u16::MAX as usize {
+        return Err("Version resource too large (product name or file name too long)".to_string());
+    }
+    Ok(blob)
+}
+
+/// Builds the resource section placed at relative virtual address `rva`
+fn build_section(version_info: &[u8], rva: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    // IMAGE_RESOURCE_DIRECTORY with a single id entry pointing at `target`
+    let directory = |out: &mut Vec<u8>, id: u32, target: u32| {
+        out.extend_from_slice(&[0u8; 14]);
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&id.to_le_bytes());
+        out.extend_from_slice(&target.to_le_bytes());
+    };
+    // The high bit marks a subdirectory, as opposed to a data entry
+    directory(&mut out, RT_VERSION, 0x8000_0000 | 24);
+    directory(&mut out, 1, 0x8000_0000 | 48);
+    directory(&mut out, LANG_EN_US as u32, 72);
+
+    // IMAGE_RESOURCE_DATA_ENTRY: RVA, size, code page, reserved
+    out.extend_from_slice(&(rva + VERSION_INFO_OFFSET as u32).to_le_bytes());
+    out.extend_from_slice(&(version_info.len() as u32).to_le_bytes());
+    out.extend_from_slice(&[0u8; 8]);
+
+    debug_assert_eq!(out.len(), VERSION_INFO_OFFSET);
+    out.extend_from_slice(version_info);
+    out
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Adds a `.rsrc` section with the version resource to the PE image in `data`
+pub fn add(data: &mut Vec<u8>, info: &VersionInfo) -> Result<(), String> {
+    let malformed = || "Malformed PE headers in template".to_string();
+
+    if !is_pe(data) {
+        return Err("--product-name requires a Windows (PE) template".to_string());
+    }
+    let version_info = build_version_info(info)?;
+
+    let pe_offset = read_u32(data, 0x3C).ok_or_else(malformed)? as usize;
+    if data.get(pe_offset..pe_offset + 4) != Some(b"PE\0\0") {
+        return Err(malformed());
+    }
+    let coff = pe_offset + 4;
+    let nsections = read_u16(data, coff + 2).ok_or_else(malformed)? as usize;
+    let optional_header_size = read_u16(data, coff + 16).ok_or_else(malformed)? as usize;
+    let optional = coff + 20;
+    let data_directories = match read_u16(data, optional).ok_or_else(malformed)? {
+        0x10b => optional + 96,  // PE32
+        0x20b => optional + 112, // PE32+
+        magic => return Err(format!("Unknown PE optional header magic {:#x}", magic)),
+    };
+    let section_alignment = read_u32(data, optional + 32).ok_or_else(malformed)? as usize;
+    let file_alignment = read_u32(data, optional + 36).ok_or_else(malformed)? as usize;
+    let headers_size = read_u32(data, optional + 60).ok_or_else(malformed)? as usize;
+    let directory_count = read_u32(data, data_directories - 4).ok_or_else(malformed)?;
+    if section_alignment == 0 || file_alignment == 0 || directory_count < 5 {
+        return Err(malformed());
+    }
+
+    let resource_directory = data_directories + 2 * 8;
+    let security_directory = data_directories + 4 * 8;
+    if read_u32(data, resource_directory + 4).ok_or_else(malformed)? != 0 {
+        return Err("Template already has resources; not adding a version resource".to_string());
+    }
+    if read_u32(data, security_directory + 4).ok_or_else(malformed)? != 0 {
+        return Err("Template is code-signed; adding a version resource would invalidate the signature".to_string());
+    }
+
+    // The new section goes after the last one, in memory and in the file
+    let section_table = optional + optional_header_size;
+    let mut first_raw_data = headers_size;
+    let mut image_end = 0;
+    for i in 0..nsections {
+        let base = section_table + i * 40;
+        let virtual_size = read_u32(data, base + 8).ok_or_else(malformed)? as usize;
+        let virtual_address = read_u32(data, base + 12).ok_or_else(malformed)? as usize;
+        let raw_size = read_u32(data, base + 16).ok_or_else(malformed)? as usize;
+        let raw_offset = read_u32(data, base + 20).ok_or_else(malformed)? as usize;
+        if raw_size != 0 {
+            first_raw_data = first_raw_data.min(raw_offset);
+        }
+        image_end = image_end.max(virtual_address + virtual_size.max(raw_size));
+    }
+
+    // The header must fit between the section table and the first section's data
+    let header = section_table + nsections * 40;
+    if header + 40 > first_raw_data || data.get(header..header + 40).is_none_or(|h| h.iter().any(|&b| b != 0)) {
+        return Err("No room for another section header in the template".to_string());
+    }
+
+    let rva = align(image_end, section_alignment);
+    let section = build_section(&version_info, rva as u32);
+    let raw_offset = align(data.len(), file_alignment);
+    let raw_size = align(section.len(), file_alignment);
+    data.resize(raw_offset, 0);
+    data.extend_from_slice(&section);
+    data.resize(raw_offset + raw_size, 0);
+
+    data[header..header + 8].copy_from_slice(SECTION_NAME);
+    write_u32(data, header + 8, section.len() as u32);
+    write_u32(data, header + 12, rva as u32);
+    write_u32(data, header + 16, raw_size as u32);
+    write_u32(data, header + 20, raw_offset as u32);
+    write_u32(data, header + 36, SECTION_CHARACTERISTICS);
+    data[coff + 2..coff + 4].copy_from_slice(&(nsections as u16 + 1).to_le_bytes());
+
+    let initialized_data = read_u32(data, optional + 8).ok_or_else(malformed)?;
+    write_u32(data, optional + 8, initialized_data + raw_size as u32);
+    write_u32(data, optional + 56, align(rva + section.len(), section_alignment) as u32);
+    write_u32(data, resource_directory, rva as u32);
+    write_u32(data, resource_directory + 4, section.len() as u32);
+
+    Ok(())
+}
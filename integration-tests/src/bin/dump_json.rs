@@ -0,0 +1,53 @@
+//! Demo program: Dump command line arguments and environment variables as JSON
+//!
+//! Usage: dump-json [args...]
+//! Outputs a single JSON object:
+//!   {"argv": [...], "cwd": "...", "env": {"<name>": "<value>" or null, ...}, "cmdline": ...}
+//!
+//! `cmdline` is the raw command line on Windows, where the launcher assembles
+//! it itself, and null elsewhere.
+//!
+//! The runfiles variables are always included; DUMP_JSON_ENV names more
+//! (comma-separated). Unlike print-env's line format, arguments containing
+//! separators or newlines come out unambiguous, so tests can compare exactly.
+
+use serde_json::{json, Map, Value};
+use std::env;
+
+#[cfg(windows)]
+fn command_line() -> Option<String> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCommandLineW() -> *const u16;
+    }
+    unsafe {
+        let line = GetCommandLineW();
+        let mut len = 0;
+        while *line.add(len) != 0 {
+            len += 1;
+        }
+        Some(String::from_utf16_lossy(std::slice::from_raw_parts(line, len)))
+    }
+}
+
+#[cfg(not(windows))]
+fn command_line() -> Option<String> {
+    None
+}
+
+fn main() {
+    let argv: Vec<String> = env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    let cwd = env::current_dir().map(|cwd| cwd.display().to_string()).ok();
+
+    let extra = env::var("DUMP_JSON_ENV").unwrap_or_default();
+    let names = ["RUNFILES_DIR", "RUNFILES_MANIFEST_FILE", "JAVA_RUNFILES"]
+        .into_iter()
+        .chain(extra.split(',').filter(|name| !name.is_empty()));
+    let mut vars = Map::new();
+    for name in names {
+        let value = env::var_os(name).map(|value| value.to_string_lossy().into_owned());
+        vars.insert(name.to_string(), value.map_or(Value::Null, Value::String));
+    }
+
+    println!("{}", json!({ "argv": argv, "cwd": cwd, "env": vars, "cmdline": command_line() }));
+}
@@ -0,0 +1,34 @@
+//! Test helper: runs a program with an empty argv (argc == 0)
+//!
+//! Usage: exec-empty-argv <program>
+//!
+//! The environment is passed on unchanged. Linux 5.18 and later substitute a
+//! single empty argument for an empty argv; the stub has to cope with both.
+
+#[cfg(unix)]
+fn main() {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+    use std::os::unix::ffi::OsStrExt;
+
+    extern "C" {
+        static environ: *const *const c_char;
+        fn execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int;
+    }
+
+    let Some(program) = std::env::args_os().nth(1) else {
+        eprintln!("Usage: exec-empty-argv <program>");
+        std::process::exit(2);
+    };
+    let program = CString::new(program.as_bytes()).expect("program path contains NUL");
+    let argv: [*const c_char; 1] = [std::ptr::null()];
+    unsafe { execve(program.as_ptr(), argv.as_ptr(), environ) };
+    eprintln!("execve failed: {}", std::io::Error::last_os_error());
+    std::process::exit(1);
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("exec-empty-argv is only supported on Unix");
+    std::process::exit(1);
+}
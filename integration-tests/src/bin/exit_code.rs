@@ -0,0 +1,25 @@
+//! Demo program: Exit with a given code
+//!
+//! Usage: exit-code <code>
+//! Prints nothing and exits with <code>, so tests can check that the stub
+//! relays a nonzero status instead of reporting its own.
+
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 2 {
+        eprintln!("Usage: {} <code>", args[0]);
+        process::exit(1);
+    }
+
+    match args[1].parse::<i32>() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("Error parsing '{}' as exit code: {}", args[1], e);
+            process::exit(1);
+        }
+    }
+}
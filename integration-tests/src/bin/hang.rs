@@ -0,0 +1,68 @@
+//! Demo program: Hang instead of exiting
+//!
+//! Usage: hang [--exit-on-ctrl-break <code>]
+//! Sleeps for two minutes without output, far longer than any test should
+//! take, so the test runner's timeout has something to kill. It still exits
+//! eventually, in case the kill only reaches the stub and not its child.
+//!
+//! With --exit-on-ctrl-break (Windows only), it prints READY, and on Ctrl+C or
+//! Ctrl+Break prints CTRL_BREAK and exits with <code> half a second later, like
+//! a tool shutting down cleanly. A stub that exited on the event itself would
+//! have ended with the console's default exit code before that.
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(windows)]
+mod ctrl_break {
+    use std::io::Write;
+    use std::process;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<unsafe extern "system" fn(u32) -> i32>, add: i32) -> i32;
+    }
+
+    unsafe extern "system" fn handler(_ctrl_type: u32) -> i32 {
+        println!("CTRL_BREAK");
+        let _ = std::io::stdout().flush();
+        thread::sleep(Duration::from_millis(500));
+        process::exit(EXIT_CODE.load(Ordering::SeqCst));
+    }
+
+    pub fn exit_on_ctrl_break(code: i32) {
+        EXIT_CODE.store(code, Ordering::SeqCst);
+        unsafe {
+            SetConsoleCtrlHandler(Some(handler), 1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        None => {}
+        #[cfg(windows)]
+        Some("--exit-on-ctrl-break") if args.len() == 3 => {
+            let code = args[2].parse().unwrap_or_else(|e| {
+                eprintln!("Error parsing '{}' as exit code: {}", args[2], e);
+                process::exit(1);
+            });
+            ctrl_break::exit_on_ctrl_break(code);
+            println!("READY");
+        }
+        Some(_) => {
+            eprintln!("Usage: {} [--exit-on-ctrl-break <code>] (the option is Windows only)", args[0]);
+            process::exit(1);
+        }
+    }
+
+    thread::sleep(Duration::from_secs(120));
+}
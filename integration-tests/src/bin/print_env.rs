@@ -3,8 +3,11 @@
 //! Usage: print-env [args...]
 //! Outputs:
 //!   ARGS:<arg0>|<arg1>|...
+//!   CWD:<current directory>
 //!   ENV:<key>=<value> (for each env var)
 //!
+//! Exits with PRINT_ENV_EXIT_CODE if set, so callers can check exit code propagation.
+//!
 //! This is useful for debugging and validating what the stub passes to child processes.
 
 use std::env;
@@ -16,6 +19,9 @@ fn main() -> ExitCode {
     let args_str = args.join("|");
     println!("ARGS:{}", args_str);
     println!("ARGC:{}", args.len());
+    if let Ok(cwd) = env::current_dir() {
+        println!("CWD:{}", cwd.display());
+    }
 
     // Print selected environment variables (runfiles-related and common ones)
     let interesting_vars = [
@@ -49,5 +55,8 @@ fn main() -> ExitCode {
     }
     println!("---ALL_ENV_END---");
 
-    ExitCode::SUCCESS
+    match env::var("PRINT_ENV_EXIT_CODE").ok().and_then(|code| code.parse::<u8>().ok()) {
+        Some(code) => ExitCode::from(code),
+        None => ExitCode::SUCCESS,
+    }
 }
@@ -7,16 +7,39 @@
 //! 4. Running the stubs and validating their behavior
 //!
 //! Usage: test-runner --template <path> --finalizer <path> --test-binaries <dir>
+//!        test-runner --auto-template <dir> --test-binaries <dir>
+//!
+//! `--filter <substring>` runs only the tests whose name contains it, like
+//! `cargo test <pattern>`, and `--junit <path>` also writes the results as
+//! JUnit XML. `--trace-syscalls` runs stubs under strace/dtruss and fails any
+//! that exceed a syscall budget before running their target.
+//!
+//! Every stub and finalizer run is killed after `--timeout` seconds (default 30)
+//! and reported as a TIMEOUT failure, so a hung target can't wedge the run.
+//!
+//! Tests run on `--jobs` threads (default: one per CPU). Each test's output is
+//! collected and printed in list order; `--jobs 1` runs them one by one and
+//! prints as they go.
 //!
 //! The test runner automatically detects the current platform and creates
 //! appropriate paths (Windows vs Unix style).
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitCode};
+use std::process::{Command, ExitCode, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The finalizer's reader for the settings embedded in a stub
+#[allow(dead_code)]
+#[path = "../../finalize-stub/src/metadata.rs"]
+mod metadata;
 
 /// Platform-specific path separator for manifest values
 #[cfg(windows)]
@@ -33,7 +56,48 @@ const EXE_EXT: &str = "";
 /// Workspace name used in runfiles paths
 const WORKSPACE_NAME: &str = "_main";
 
+/// Signature shared by all test functions
+type TestFn = fn(&TestConfig) -> Result<(), String>;
+
+thread_local! {
+    /// Output of the test running on this thread, if it is being collected
+    static TEST_OUTPUT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Like `println!`, but adds the line to the current test's collected output
+/// when tests run in parallel, so their reports don't interleave
+macro_rules! report {
+    () => {
+        report!("")
+    };
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        TEST_OUTPUT.with(|output| match output.borrow_mut().as_mut() {
+            Some(output) => {
+                output.push_str(&line);
+                output.push('\n');
+            }
+            None => println!("{}", line),
+        })
+    }};
+}
+
+/// Default for --timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// With --trace-syscalls, the most syscalls a stub may make before it runs its
+/// target (or exits), by runfiles mode. On Linux x86_64 a stub makes 6 in manifest
+/// mode and 10-12 in directory mode, where each transformed argument costs an
+/// access() probe; the budgets leave room for large manifests and error paths.
+const SYSCALL_BUDGET_MANIFEST: usize = 16;
+const SYSCALL_BUDGET_DIRECTORY: usize = 20;
+
+/// Added to the budgets on macOS, where dyld runs before the stub's main. This is
+/// a rough allowance rather than a measurement.
+const SYSCALL_BUDGET_DYLD: usize = if cfg!(target_os = "macos") { 100 } else { 0 };
+
 /// Test configuration
+#[derive(Clone)]
 struct TestConfig {
     /// Path to the runfiles-stub template binary
     template_path: PathBuf,
@@ -43,6 +107,23 @@ struct TestConfig {
     test_binaries_dir: PathBuf,
     /// Working directory for test artifacts
     work_dir: PathBuf,
+    /// How long a single stub or finalizer run may take
+    timeout: Duration,
+    /// Number of tests to run at once
+    jobs: usize,
+    /// Only run tests whose name contains this
+    filter: Option<String>,
+    /// Where to write a JUnit XML report of the results
+    junit: Option<PathBuf>,
+    /// Run stubs under strace/dtruss and check their syscall count
+    trace_syscalls: bool,
+    /// Overrides the per-mode syscall budgets
+    syscall_budget: Option<usize>,
+}
+
+/// Host platform as used in release binary names, e.g. `x86_64-linux` or `aarch64-macos`
+fn host_platform() -> String {
+    format!("{}-{}", env::consts::ARCH, env::consts::OS)
 }
 
 /// Runfiles setup for a test
@@ -61,8 +142,15 @@ impl TestConfig {
 
         let mut template_path = None;
         let mut finalizer_path = None;
+        let mut auto_template_dir = None;
         let mut test_binaries_dir = None;
         let mut work_dir = None;
+        let mut timeout = DEFAULT_TIMEOUT;
+        let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut filter = None;
+        let mut junit = None;
+        let mut trace_syscalls = false;
+        let mut syscall_budget = None;
 
         let mut i = 1;
         while i < args.len() {
@@ -75,6 +163,10 @@ impl TestConfig {
                     i += 1;
                     finalizer_path = Some(PathBuf::from(&args[i]));
                 }
+                "--auto-template" => {
+                    i += 1;
+                    auto_template_dir = Some(PathBuf::from(&args[i]));
+                }
                 "--test-binaries" => {
                     i += 1;
                     test_binaries_dir = Some(PathBuf::from(&args[i]));
@@ -83,14 +175,62 @@ impl TestConfig {
                     i += 1;
                     work_dir = Some(PathBuf::from(&args[i]));
                 }
+                "--timeout" => {
+                    i += 1;
+                    let seconds: u64 = args[i]
+                        .parse()
+                        .ok()
+                        .filter(|&seconds| seconds > 0)
+                        .ok_or_else(|| format!("Invalid --timeout {:?}: expected a number of seconds", args[i]))?;
+                    timeout = Duration::from_secs(seconds);
+                }
+                "--jobs" => {
+                    i += 1;
+                    jobs = args[i]
+                        .parse()
+                        .ok()
+                        .filter(|&jobs| jobs > 0)
+                        .ok_or_else(|| format!("Invalid --jobs {:?}: expected a positive number", args[i]))?;
+                }
+                "--filter" => {
+                    i += 1;
+                    filter = Some(args[i].clone());
+                }
+                "--junit" => {
+                    i += 1;
+                    junit = Some(PathBuf::from(&args[i]));
+                }
+                "--trace-syscalls" => {
+                    if cfg!(windows) {
+                        return Err("--trace-syscalls needs strace (Linux) or dtruss (macOS)".to_string());
+                    }
+                    trace_syscalls = true;
+                }
+                "--syscall-budget" => {
+                    i += 1;
+                    syscall_budget = Some(args[i]
+                        .parse()
+                        .map_err(|_| format!("Invalid --syscall-budget {:?}: expected a number", args[i]))?);
+                }
                 "--help" | "-h" => {
                     println!("Usage: test-runner --template <path> --finalizer <path> --test-binaries <dir> [--work-dir <dir>]");
+                    println!("       test-runner --auto-template <dir> --test-binaries <dir> [--work-dir <dir>]");
                     println!();
                     println!("Options:");
                     println!("  --template       Path to runfiles-stub template binary");
                     println!("  --finalizer      Path to finalize-stub binary");
+                    println!("  --auto-template  Directory of release binaries; picks runfiles-stub-{0}{1}", host_platform(), EXE_EXT);
+                    println!("                   and finalize-stub-{0}{1} (--template/--finalizer override)", host_platform(), EXE_EXT);
                     println!("  --test-binaries  Directory containing test binaries");
                     println!("  --work-dir       Working directory for test artifacts (default: temp dir)");
+                    println!("  --timeout        Seconds a stub or finalizer run may take before it is killed (default: {})", DEFAULT_TIMEOUT.as_secs());
+                    println!("  --jobs           Number of tests to run in parallel (default: number of CPUs; 1 runs them serially)");
+                    println!("  --filter         Only run tests whose name contains this substring");
+                    println!("  --junit          Also write the results as JUnit XML to this path");
+                    println!("  --trace-syscalls Run stubs under strace (Linux) or dtruss (macOS) and fail any that make more");
+                    println!("                   syscalls before running their target than the budget (needs tracing privileges)");
+                    println!("  --syscall-budget Budget for --trace-syscalls in both runfiles modes (default: {} manifest, {} directory)",
+                        SYSCALL_BUDGET_MANIFEST + SYSCALL_BUDGET_DYLD, SYSCALL_BUDGET_DIRECTORY + SYSCALL_BUDGET_DYLD);
                     std::process::exit(0);
                 }
                 _ => {
@@ -100,8 +240,18 @@ impl TestConfig {
             i += 1;
         }
 
-        let template_path = template_path.ok_or("--template is required")?;
-        let finalizer_path = finalizer_path.ok_or("--finalizer is required")?;
+        // Release binaries are named <tool>-<arch>-<os>[.exe]
+        let auto_path = |tool: &str| {
+            auto_template_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}-{}{}", tool, host_platform(), EXE_EXT)))
+        };
+        let template_path = template_path
+            .or_else(|| auto_path("runfiles-stub"))
+            .ok_or("--template or --auto-template is required")?;
+        let finalizer_path = finalizer_path
+            .or_else(|| auto_path("finalize-stub"))
+            .ok_or("--finalizer or --auto-template is required")?;
         let test_binaries_dir = test_binaries_dir.ok_or("--test-binaries is required")?;
         let work_dir = work_dir.unwrap_or_else(|| env::temp_dir().join("runfiles-stub-tests"));
 
@@ -121,6 +271,12 @@ impl TestConfig {
             finalizer_path,
             test_binaries_dir,
             work_dir,
+            timeout,
+            jobs,
+            filter,
+            junit,
+            trace_syscalls,
+            syscall_budget,
         })
     }
 }
@@ -183,6 +339,11 @@ impl RunfilesSetup {
         Ok(())
     }
 
+    /// Add a manifest entry whose value is another rlocation path (a redirect)
+    fn add_redirect(&mut self, rlocation_path: &str, target_rlocation_path: &str) {
+        self.entries.insert(rlocation_path.to_string(), PathBuf::from(target_rlocation_path));
+    }
+
     /// Write the manifest file
     fn write_manifest(&self) -> std::io::Result<()> {
         let mut file = File::create(&self.manifest_path)?;
@@ -200,7 +361,17 @@ impl RunfilesSetup {
             #[cfg(windows)]
             let abs_path_str = abs_path_str.replace('\\', "/");
 
-            writeln!(file, "{} {}", rlocation_path, abs_path_str)?;
+            // Like Bazel, escape entries whose paths contain spaces or newlines and
+            // mark them with a leading space
+            if format!("{}{}", rlocation_path, abs_path_str).contains([' ', '\n']) {
+                let escape = |path: &str, escape_space: bool| {
+                    let path = path.replace('\\', "\\b").replace('\n', "\\n");
+                    if escape_space { path.replace(' ', "\\s") } else { path }
+                };
+                writeln!(file, " {} {}", escape(rlocation_path, true), escape(&abs_path_str, false))?;
+            } else {
+                writeln!(file, "{} {}", rlocation_path, abs_path_str)?;
+            }
         }
 
         Ok(())
@@ -213,6 +384,55 @@ impl RunfilesSetup {
 }
 
 /// Finalize a stub binary
+/// Like `Command::output`, but kills the process once `timeout` has passed. `what`
+/// names the process in errors; a timeout is reported as a TIMEOUT error.
+fn output_with_timeout(cmd: &mut Command, what: &str, timeout: Duration) -> Result<Output, String> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", what, e))?;
+
+    // Drain both pipes while waiting, so a chatty process can't block on a full pipe
+    fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let mut poll = Duration::from_millis(1);
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => {
+                thread::sleep(poll);
+                poll = (poll * 2).min(Duration::from_millis(20));
+            }
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                // The readers are left behind: a surviving grandchild may hold the pipes open
+                return Err(format!("TIMEOUT: {} still running after {}s, killed", what, timeout.as_secs_f32()));
+            }
+            Err(e) => return Err(format!("Failed to wait for {}: {}", what, e)),
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
 fn finalize_stub(
     config: &TestConfig,
     output_path: &Path,
@@ -236,7 +456,7 @@ fn finalize_stub(
         cmd.arg(arg);
     }
 
-    let output = cmd.output().map_err(|e| format!("Failed to run finalizer: {}", e))?;
+    let output = output_with_timeout(&mut cmd, "finalizer", config.timeout)?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
@@ -258,14 +478,34 @@ fn finalize_stub(
     Ok(())
 }
 
+/// Run the finalizer with raw arguments and capture its output
+///
+/// Unlike `finalize_stub`, this does not treat a nonzero exit as an error, so
+/// tests can assert on the finalizer's own error reporting.
+fn run_finalizer(config: &TestConfig, args: &[&str]) -> Result<(String, String, i32), String> {
+    let output = output_with_timeout(Command::new(&config.finalizer_path).args(args), "finalizer", config.timeout)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let exit_code = output.status.code().unwrap_or(-1);
+
+    Ok((stdout, stderr, exit_code))
+}
+
 /// Run a stub and capture its output
 fn run_stub(
+    config: &TestConfig,
     stub_path: &Path,
     runfiles_setup: &RunfilesSetup,
     extra_args: &[&str],
     use_manifest: bool,
 ) -> Result<(String, String, i32), String> {
-    let mut cmd = Command::new(stub_path);
+    let trace_path = stub_path.with_extension("strace");
+    let mut cmd = if config.trace_syscalls {
+        traced_command(stub_path, &trace_path)
+    } else {
+        Command::new(stub_path)
+    };
 
     // Set runfiles environment
     if use_manifest {
@@ -281,18 +521,93 @@ fn run_stub(
         cmd.arg(arg);
     }
 
-    let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
+    let output = output_with_timeout(&mut cmd, "stub", config.timeout)?;
 
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let mut stderr = String::from_utf8_lossy(&output.stderr).to_string();
     let exit_code = output.status.code().unwrap_or(-1);
 
+    if config.trace_syscalls {
+        let (count, rest) = count_stub_syscalls(&trace_path, &stderr)?;
+        stderr = rest;
+        let (mode, default_budget) = if use_manifest {
+            ("manifest", SYSCALL_BUDGET_MANIFEST)
+        } else {
+            ("directory", SYSCALL_BUDGET_DIRECTORY)
+        };
+        let budget = config.syscall_budget.unwrap_or(default_budget + SYSCALL_BUDGET_DYLD);
+        if count > budget {
+            return Err(format!(
+                "SYSCALLS: {} made {} syscalls before running its target or exiting, over the {} mode budget of {} (trace: {})",
+                stub_path.display(), count, mode, budget, trace_path.display()
+            ));
+        }
+    }
+
     Ok((stdout, stderr, exit_code))
 }
 
+/// Command that runs the stub under the platform's syscall tracer. strace writes
+/// its log to `trace_path`; dtruss has no such option and writes to stderr.
+fn traced_command(stub_path: &Path, trace_path: &Path) -> Command {
+    if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("dtruss");
+        cmd.arg(stub_path);
+        cmd
+    } else {
+        let mut cmd = Command::new("strace");
+        cmd.arg("-o").arg(trace_path).arg(stub_path);
+        cmd
+    }
+}
+
+/// Counts the syscalls a traced stub made up to and including the execve of its
+/// target; the tracers follow the process through it, and the target's own calls
+/// don't count. Spawn mode forks instead, and the untraced child isn't counted
+/// either. Returns the count and the stub's stderr without dtruss' lines.
+fn count_stub_syscalls(trace_path: &Path, stderr: &str) -> Result<(usize, String), String> {
+    let count_until_execve = |calls: &mut dyn Iterator<Item = &str>| {
+        let mut count = 0;
+        for call in calls {
+            count += 1;
+            if call.starts_with("execve(") {
+                break;
+            }
+        }
+        count
+    };
+
+    if cfg!(target_os = "macos") {
+        // "name(args)\t\t = result errno", after a "SYSCALL(args)" header
+        let is_trace_line = |line: &str| {
+            line.split_once('(').is_some_and(|(name, rest)| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && rest.contains(" = ")
+            })
+        };
+        let count = count_until_execve(&mut stderr.lines().filter(|line| is_trace_line(line) && !line.starts_with("SYSCALL(")));
+        let rest: Vec<&str> = stderr.lines().filter(|line| !is_trace_line(line)).collect();
+        return Ok((count, rest.join("\n")));
+    }
+
+    // One call per line; "+++ exited +++" and "--- SIGNAL ---" lines aren't calls.
+    // The first line is strace starting the stub itself.
+    let log = fs::read_to_string(trace_path)
+        .map_err(|e| format!("Failed to read strace log {}: {}", trace_path.display(), e))?;
+    let mut calls = log
+        .lines()
+        .filter(|line| !line.starts_with("+++") && !line.starts_with("---"))
+        .skip(1);
+    Ok((count_until_execve(&mut calls), stderr.to_string()))
+}
+
+/// Parse the output of the dump-json demo program
+fn parse_dump_json(stdout: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(stdout.trim()).map_err(|e| format!("Invalid dump-json output ({}): {}", e, stdout))
+}
+
 /// Test: Basic hash-file invocation
 fn test_hash_file(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: hash_file");
+    report!("  Running test: hash_file");
 
     let test_dir = config.work_dir.join("test_hash_file");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -328,7 +643,7 @@ fn test_hash_file(config: &TestConfig) -> Result<(), String> {
     )?;
 
     // Test with manifest
-    let (stdout, stderr, exit_code) = run_stub(&stub_path, &runfiles, &[], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
 
     if exit_code != 0 {
         return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
@@ -342,21 +657,21 @@ fn test_hash_file(config: &TestConfig) -> Result<(), String> {
     }
 
     // Test with directory-based runfiles
-    let (_stdout2, stderr2, exit_code2) = run_stub(&stub_path, &runfiles, &[], false)?;
+    let (_stdout2, stderr2, exit_code2) = run_stub(config, &stub_path, &runfiles, &[], false)?;
 
     if exit_code2 != 0 {
         return Err(format!("Stub (dir mode) failed with exit code {}: {}", exit_code2, stderr2));
     }
 
-    println!("    PASS (manifest mode)");
-    println!("    PASS (directory mode)");
+    report!("    PASS (manifest mode)");
+    report!("    PASS (directory mode)");
 
     Ok(())
 }
 
 /// Test: add-numbers with runtime arguments
 fn test_add_numbers_runtime_args(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: add_numbers_runtime_args");
+    report!("  Running test: add_numbers_runtime_args");
 
     let test_dir = config.work_dir.join("test_add_numbers");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -384,7 +699,7 @@ fn test_add_numbers_runtime_args(config: &TestConfig) -> Result<(), String> {
     )?;
 
     // Run with runtime arguments
-    let (stdout, stderr, exit_code) = run_stub(&stub_path, &runfiles, &["10", "20", "30"], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &["10", "20", "30"], true)?;
 
     if exit_code != 0 {
         return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
@@ -394,14 +709,14 @@ fn test_add_numbers_runtime_args(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Unexpected output: {}. Expected 'SUM:60'", stdout));
     }
 
-    println!("    PASS");
+    report!("    PASS");
 
     Ok(())
 }
 
 /// Test: merge-json with two data files
 fn test_merge_json(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: merge_json");
+    report!("  Running test: merge_json");
 
     let test_dir = config.work_dir.join("test_merge_json");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -441,7 +756,7 @@ fn test_merge_json(config: &TestConfig) -> Result<(), String> {
         &[0, 1, 2], // Transform all arguments
     )?;
 
-    let (stdout, stderr, exit_code) = run_stub(&stub_path, &runfiles, &[], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
 
     if exit_code != 0 {
         return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
@@ -461,14 +776,384 @@ fn test_merge_json(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Merge lost 'extra' field: {}", stdout));
     }
 
-    println!("    PASS");
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: The stub exits with the target's exit code (Windows waits for the child)
+fn test_exit_code_propagation(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: exit_code_propagation");
+
+    let test_dir = config.work_dir.join("test_exit_code_propagation");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "exit_code_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("exit_code_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation], &[0])?;
+
+    // Codes other than 1, which the stub uses for its own errors
+    for code in [0, 3, 42] {
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .env("PRINT_ENV_EXIT_CODE", code.to_string())
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // The target must have finished, not just started
+        if !stdout.contains("---ALL_ENV_END---") {
+            return Err(format!("Target output incomplete for exit code {}: {}", code, stdout));
+        }
+        if output.status.code() != Some(code) {
+            return Err(format!("Expected exit code {}, got {:?}", code, output.status.code()));
+        }
+    }
+
+    // A code embedded at finalization, with manifest- and directory-based lookups
+    let exit_code_rlocation = format!("{}/bin/exit-code{}", WORKSPACE_NAME, EXE_EXT);
+    let exit_code_binary = config.test_binaries_dir.join(format!("exit-code{}", EXE_EXT));
+    runfiles.add_file(&exit_code_rlocation, &exit_code_binary)
+        .map_err(|e| format!("Failed to add exit-code: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("exit_code_42_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&exit_code_rlocation, "42"], &[0])?;
+
+    for use_manifest in [true, false] {
+        let (_stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+        if exit_code != 42 {
+            return Err(format!(
+                "Expected exit code 42 (manifest: {}), got {}: {}",
+                use_manifest, exit_code, stderr
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Windows stubs explain why the target couldn't be started (127: missing, 126: not runnable)
+fn test_windows_start_failures(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: windows_start_failures");
+
+    if !cfg!(windows) {
+        report!("    SKIP (Windows only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_windows_start_failures");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "start_failure_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let missing_rlocation = format!("{}/bin/missing.exe", WORKSPACE_NAME);
+    runfiles.add_file_content(&missing_rlocation, b"")
+        .map_err(|e| format!("Failed to add missing.exe: {}", e))?;
+    let text_rlocation = format!("{}/bin/not_a_program.exe", WORKSPACE_NAME);
+    runfiles.add_file_content(&text_rlocation, b"plain text\n")
+        .map_err(|e| format!("Failed to add not_a_program.exe: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    // Listed in the manifest, but gone from disk
+    fs::remove_file(&runfiles.entries[&missing_rlocation])
+        .map_err(|e| format!("Failed to remove missing.exe: {}", e))?;
+
+    let cases = [
+        (&missing_rlocation, "no such file", 127),
+        (&text_rlocation, "not a valid executable", 126),
+    ];
+    for (rlocation, reason, expected_code) in cases {
+        let stub_path = test_dir.join(format!("start_failure_stub_{}{}", expected_code, EXE_EXT));
+        finalize_stub(config, &stub_path, &[rlocation], &[0])?;
+
+        let (_stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+        let path = runfiles.entries[rlocation].to_string_lossy().to_string();
+        if exit_code != expected_code || !stderr.contains(reason) || !stderr.contains(&path) {
+            return Err(format!(
+                "Expected exit code {} and \"{}\" for {}, got {}: {}",
+                expected_code, reason, path, exit_code, stderr
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --product-name adds a version resource to Windows stubs, and is rejected for other templates
+fn test_windows_version_resource(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: windows_version_resource");
+
+    let test_dir = config.work_dir.join("test_windows_version_resource");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let stub_path = test_dir.join(format!("versioned_stub{}", EXE_EXT));
+
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--product-name", "Runfiles Stub Test",
+        "--product-version", "1.2.3",
+        "--", &print_env_rlocation,
+    ])?;
+
+    if !cfg!(windows) {
+        if exit_code == 0 || !stderr.contains("requires a Windows (PE) template") {
+            return Err(format!("Finalizer accepted --product-name for a non-PE template: {}", stderr));
+        }
+        report!("    PASS (rejected for non-PE template)");
+        return Ok(());
+    }
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let stub = fs::read(&stub_path).map_err(|e| format!("Failed to read stub: {}", e))?;
+    let utf16 = |s: &str| -> Vec<u8> { s.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect() };
+    for text in ["VS_VERSION_INFO", "Runfiles Stub Test", "1.2.3"] {
+        if find_bytes(&stub, &utf16(text)).is_none() {
+            return Err(format!("Version resource of the stub is missing {:?}", text));
+        }
+    }
+
+    // Windows itself must be able to read the resource
+    let script = format!("(Get-Item -LiteralPath '{}').VersionInfo.ProductName", stub_path.display());
+    if let Ok(output) = Command::new("powershell").args(["-NoProfile", "-Command", &script]).output() {
+        let product_name = String::from_utf8_lossy(&output.stdout);
+        if product_name.trim() != "Runfiles Stub Test" {
+            return Err(format!("Windows reports product name {:?}", product_name.trim()));
+        }
+    }
+
+    // ... and still load the stub
+    let mut runfiles = RunfilesSetup::new(&test_dir, "versioned_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_binary = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env_binary)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 || !stdout.contains("ARGC:1") {
+        return Err(format!("Stub with version resource failed ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A runfiles variable too long for the stub's path buffers is reported instead of
+/// being treated as unset (which would silently fall back to other runfiles)
+fn test_windows_long_env_var(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: windows_long_env_var");
+
+    if !cfg!(windows) {
+        report!("    SKIP (Windows only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_windows_long_env_var");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "long_env_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("long_env_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation], &[0])?;
+
+    // Far longer than the stub's path buffers
+    let long_value = format!("C:\\{}runfiles_manifest", "nested\\".repeat(600));
+    let output = Command::new(&stub_path)
+        .env("RUNFILES_MANIFEST_FILE", &long_value)
+        .env("RUNFILES_DIR", &runfiles.runfiles_dir)
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.code() != Some(1) || !stderr.contains("ERROR: RUNFILES_MANIFEST_FILE is too long") {
+        return Err(format!(
+            "Expected a length error for a {}-character RUNFILES_MANIFEST_FILE, got exit code {:?}: {}",
+            long_value.len(),
+            output.status.code(),
+            stderr
+        ));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Ctrl+Break reaches the target, and the stub outlasts it to pass on its exit code
+/// instead of exiting first and leaving it orphaned
+fn test_windows_ctrl_break(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: windows_ctrl_break");
+
+    if !cfg!(windows) {
+        report!("    SKIP (Windows only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_windows_ctrl_break");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "ctrl_break_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hang_rlocation = format!("{}/bin/hang{}", WORKSPACE_NAME, EXE_EXT);
+    let hang = config.test_binaries_dir.join(format!("hang{}", EXE_EXT));
+    runfiles.add_file(&hang_rlocation, &hang)
+        .map_err(|e| format!("Failed to add hang: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("ctrl_break_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&hang_rlocation, "--exit-on-ctrl-break", "42"], &[0])?;
+
+    #[cfg(windows)]
+    {
+        use std::io::{BufRead, BufReader, Read};
+        use std::os::windows::process::CommandExt;
+
+        extern "system" {
+            fn GenerateConsoleCtrlEvent(ctrl_event: u32, process_group_id: u32) -> i32;
+        }
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x200;
+        const CTRL_BREAK_EVENT: u32 = 1;
+
+        // A process group of its own keeps the event away from the test runner
+        let mut child = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .stdout(std::process::Stdio::piped())
+            .creation_flags(CREATE_NEW_PROCESS_GROUP)
+            .spawn()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+
+        let mut stdout = BufReader::new(child.stdout.take().ok_or("No stdout")?);
+        let mut first_line = String::new();
+        stdout.read_line(&mut first_line).map_err(|e| format!("Failed to read stub output: {}", e))?;
+        if first_line.trim() != "READY" {
+            let _ = child.kill();
+            return Err(format!("Target did not start: {:?}", first_line));
+        }
+
+        if unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, child.id()) } == 0 {
+            let _ = child.kill();
+            return Err(format!("Failed to send Ctrl+Break: {}", std::io::Error::last_os_error()));
+        }
+
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).map_err(|e| format!("Failed to read stub output: {}", e))?;
+        let exit_code = child.wait()
+            .map_err(|e| format!("Failed to wait for stub: {}", e))?
+            .code()
+            .unwrap_or(-1);
+        if !rest.contains("CTRL_BREAK") || exit_code != 42 {
+            return Err(format!("Expected the target to handle Ctrl+Break and exit 42, got exit code {:#x}: {}", exit_code, rest));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Runfiles below a directory deeper than MAX_PATH (260 characters) are found in
+/// every mode: the manifest is opened and the runfiles directory probed through \\?\ paths
+fn test_windows_long_paths(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: windows_long_paths");
+
+    if !cfg!(windows) {
+        report!("    SKIP (Windows only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_windows_long_paths");
+    let deep_dir = (0..12).fold(test_dir.clone(), |dir, level| dir.join(format!("nested-output-directory-{:02}", level)));
+    fs::create_dir_all(&deep_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&deep_dir, "long_path_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let data_rlocation = format!("{}/data/test.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add test.txt: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    let data_path = runfiles.entries[&data_rlocation].to_string_lossy().to_string();
+    if data_path.len() <= 260 {
+        return Err(format!("Test path is only {} characters long: {}", data_path.len(), data_path));
+    }
+
+    // The program stays at a short absolute path; only the runfiles are deep
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    let shallow_stub = test_dir.join(format!("long_path_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &shallow_stub.to_string_lossy(),
+        "--exec", &hash_binary.to_string_lossy(),
+        "--transform", "1",
+        "--", "hash-file", &data_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    // A copy next to the runfiles finds them without any environment variable
+    let deep_stub = deep_dir.join(format!("long_path_stub{}", EXE_EXT));
+    fs::copy(&shallow_stub, &deep_stub).map_err(|e| format!("Failed to copy stub: {}", e))?;
+
+    // Prefix of the SHA-256 of "Hello, World!\n"
+    let expected_hash = "c98c24b677eff44860afea6f493bb";
+    let check = |mode: &str, (stdout, stderr, exit_code): (String, String, i32)| {
+        if exit_code != 0 || !stdout.to_lowercase().contains(expected_hash) {
+            return Err(format!("Stub failed in {} (exit code {}): {}{}", mode, exit_code, stdout, stderr));
+        }
+        Ok(())
+    };
+    check("manifest mode", run_stub(config, &shallow_stub, &runfiles, &[], true)?)?;
+    check("directory mode", run_stub(config, &shallow_stub, &runfiles, &[], false)?)?;
+
+    fs::remove_file(&runfiles.manifest_path).map_err(|e| format!("Failed to remove manifest: {}", e))?;
+    let output = Command::new(&deep_stub)
+        .env_remove("RUNFILES_DIR")
+        .env_remove("RUNFILES_MANIFEST_FILE")
+        .env_remove("JAVA_RUNFILES")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    check("discovered directory mode", (
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+        output.status.code().unwrap_or(-1),
+    ))?;
+
+    report!("    PASS");
 
     Ok(())
 }
 
 /// Test: orchestrator calling hash-file (environment propagation)
 fn test_orchestrator_env_propagation(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: orchestrator_env_propagation");
+    report!("  Running test: orchestrator_env_propagation");
 
     let test_dir = config.work_dir.join("test_orchestrator");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -505,7 +1190,7 @@ fn test_orchestrator_env_propagation(config: &TestConfig) -> Result<(), String>
         &[0], // Only transform the binary path
     )?;
 
-    let (stdout, stderr, exit_code) = run_stub(&env_stub_path, &runfiles, &[], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &env_stub_path, &runfiles, &[], true)?;
 
     if exit_code != 0 {
         return Err(format!(
@@ -522,7 +1207,7 @@ fn test_orchestrator_env_propagation(config: &TestConfig) -> Result<(), String>
         ));
     }
 
-    println!("    PASS (env propagation)");
+    report!("    PASS (env propagation)");
 
     // Now test hash-and-report which calls hash-file binary
     let hash_stub_path = test_dir.join(format!("hash_and_report_stub{}", EXE_EXT));
@@ -545,7 +1230,7 @@ fn test_orchestrator_env_propagation(config: &TestConfig) -> Result<(), String>
         &[0], // Only transform the orchestrator path
     )?;
 
-    let (stdout, stderr, exit_code) = run_stub(&hash_stub_path, &runfiles, &[], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &hash_stub_path, &runfiles, &[], true)?;
 
     if exit_code != 0 {
         return Err(format!(
@@ -561,14 +1246,14 @@ fn test_orchestrator_env_propagation(config: &TestConfig) -> Result<(), String>
         ));
     }
 
-    println!("    PASS (hash-and-report)");
+    report!("    PASS (hash-and-report)");
 
     Ok(())
 }
 
 /// Test: Mixed transformed and literal arguments
 fn test_mixed_arguments(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: mixed_arguments");
+    report!("  Running test: mixed_arguments");
 
     let test_dir = config.work_dir.join("test_mixed_args");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -596,7 +1281,7 @@ fn test_mixed_arguments(config: &TestConfig) -> Result<(), String> {
         &[0], // Only transform the binary path, not the numbers
     )?;
 
-    let (stdout, stderr, exit_code) = run_stub(&stub_path, &runfiles, &[], true)?;
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
 
     if exit_code != 0 {
         return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
@@ -606,59 +1291,273 @@ fn test_mixed_arguments(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Unexpected output: {}. Expected 'SUM:300'", stdout));
     }
 
-    println!("    PASS");
+    report!("    PASS");
 
     Ok(())
 }
 
-/// Test: Fallback runfiles directory discovery
-fn test_fallback_runfiles_dir(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: fallback_runfiles_dir");
+/// Test: The target receives exactly the embedded and runtime arguments, and the exported variables
+fn test_exact_argv_and_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: exact_argv_and_env");
 
-    let test_dir = config.work_dir.join("test_fallback");
+    let test_dir = config.work_dir.join("test_exact_argv_and_env");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
 
-    // Create a stub with a .runfiles directory next to it
-    let stub_path = test_dir.join(format!("fallback_stub{}", EXE_EXT));
-    let runfiles_dir = test_dir.join(format!("fallback_stub{}.runfiles", EXE_EXT));
-
-    fs::create_dir_all(&runfiles_dir).map_err(|e| format!("Failed to create runfiles dir: {}", e))?;
-
-    // Add files directly to runfiles directory
-    let binary_dir = runfiles_dir.join(WORKSPACE_NAME).join("bin");
-    fs::create_dir_all(&binary_dir).map_err(|e| format!("Failed to create binary dir: {}", e))?;
+    let mut runfiles = RunfilesSetup::new(&test_dir, "exact_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
 
-    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
-    let dest_binary = binary_dir.join(format!("add-numbers{}", EXE_EXT));
-    fs::copy(&add_binary, &dest_binary).map_err(|e| format!("Failed to copy binary: {}", e))?;
+    // Separators print-env's format can't tell apart from argument boundaries
+    let embedded = ["a|b", "two words", "--key=value with = sign"];
+    let runtime = ["runtime arg", "x"];
+    let stub_path = test_dir.join(format!("exact_stub{}", EXE_EXT));
+    let mut args = vec![dump_json_rlocation.as_str()];
+    args.extend(embedded);
+    finalize_stub(config, &stub_path, &args, &[0])?;
+
+    let output = Command::new(&stub_path)
+        .args(runtime)
+        .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+        .env_remove("RUNFILES_DIR")
+        .env("DUMP_JSON_ENV", "STUB_TEST_VALUE")
+        .env("STUB_TEST_VALUE", "a=b;c d")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(format!("Stub failed: {}{}", stdout, String::from_utf8_lossy(&output.stderr)));
+    }
+    let dump = parse_dump_json(&stdout)?;
+
+    let argv: Vec<&str> = dump["argv"]
+        .as_array()
+        .ok_or_else(|| format!("No argv in {}", dump))?
+        .iter()
+        .filter_map(|arg| arg.as_str())
+        .collect();
+    let expected: Vec<&str> = embedded.iter().chain(&runtime).copied().collect();
+    if argv.get(1..) != Some(&expected[..]) {
+        return Err(format!("Expected arguments {:?}, got {:?}", expected, argv));
+    }
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&dest_binary)
-            .map_err(|e| format!("Failed to get permissions: {}", e))?
-            .permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&dest_binary, perms)
-            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    let env = &dump["env"];
+    let manifest = runfiles.manifest_path.to_string_lossy();
+    if env["RUNFILES_MANIFEST_FILE"] != *manifest || env["STUB_TEST_VALUE"] != "a=b;c d" {
+        return Err(format!("Unexpected environment: {}", env));
+    }
+    if env["JAVA_RUNFILES"] != env["RUNFILES_DIR"] {
+        return Err(format!("JAVA_RUNFILES differs from RUNFILES_DIR: {}", env));
     }
 
-    // Create the stub
-    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    report!("    PASS");
 
-    finalize_stub(
-        config,
-        &stub_path,
-        &[&add_rlocation, "5", "10"],
-        &[0],
-    )?;
+    Ok(())
+}
 
-    // Run WITHOUT setting any environment variables
-    let mut cmd = Command::new(&stub_path);
-    cmd.env_remove("RUNFILES_DIR");
-    cmd.env_remove("RUNFILES_MANIFEST_FILE");
+/// Test: Embedded arguments come before runtime arguments, each exactly once. On
+/// Windows the stub assembles the command line itself, so its spacing is checked too.
+fn test_command_line_order(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: command_line_order");
 
-    let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
+    let test_dir = config.work_dir.join("test_command_line_order");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "order_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // (name, embedded arguments after argv[0], runtime arguments, arguments after the program on the command line)
+    let cases: [(&str, &[&str], &[&str], &str); 4] = [
+        ("none", &[], &[], ""),
+        ("embedded_only", &["one", "two words"], &[], " one \"two words\""),
+        ("runtime_only", &[], &["one", "two words"], " one \"two words\""),
+        ("mixed", &["a", "b c"], &["d e", "f"], " a \"b c\" \"d e\" f"),
+    ];
+    for (name, embedded, runtime, expected_tail) in cases {
+        let stub_path = test_dir.join(format!("order_{}{}", name, EXE_EXT));
+        let mut args = vec![dump_json_rlocation.as_str()];
+        args.extend_from_slice(embedded);
+        finalize_stub(config, &stub_path, &args, &[0])?;
+
+        let output = Command::new(&stub_path)
+            .args(runtime)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(format!("Stub failed ({}): {}{}", name, stdout, String::from_utf8_lossy(&output.stderr)));
+        }
+        let dump = parse_dump_json(&stdout)?;
+
+        let argv: Vec<&str> = dump["argv"]
+            .as_array()
+            .ok_or_else(|| format!("No argv in {}", dump))?
+            .iter()
+            .filter_map(|arg| arg.as_str())
+            .collect();
+        let expected: Vec<&str> = embedded.iter().chain(runtime).copied().collect();
+        if argv.get(1..) != Some(&expected[..]) {
+            return Err(format!("Expected arguments {:?} ({}), got {:?}", expected, name, argv));
+        }
+
+        // The quoted program path, then each argument after exactly one space
+        if let Some(cmdline) = dump["cmdline"].as_str() {
+            let tail = cmdline
+                .strip_prefix('"')
+                .and_then(|rest| rest.split_once('"'))
+                .map(|(_, tail)| tail);
+            if tail != Some(expected_tail) {
+                return Err(format!("Expected command line \"<program>\"{} ({}), got {:?}", expected_tail, name, cmdline));
+            }
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A stub whose target hangs is killed after the runner's timeout and reported as TIMEOUT
+fn test_run_timeout(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: run_timeout");
+
+    let test_dir = config.work_dir.join("test_run_timeout");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "timeout_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hang_rlocation = format!("{}/bin/hang{}", WORKSPACE_NAME, EXE_EXT);
+    let hang = config.test_binaries_dir.join(format!("hang{}", EXE_EXT));
+    runfiles.add_file(&hang_rlocation, &hang)
+        .map_err(|e| format!("Failed to add hang: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("timeout_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&hang_rlocation], &[0])?;
+
+    let short = TestConfig { timeout: Duration::from_secs(1), ..config.clone() };
+    let start = Instant::now();
+    match run_stub(&short, &stub_path, &runfiles, &[], true) {
+        Err(e) if e.starts_with("TIMEOUT") => {}
+        other => return Err(format!("Expected a TIMEOUT error, got {:?}", other)),
+    }
+    if start.elapsed() > Duration::from_secs(10) {
+        return Err(format!("The hung stub was killed only after {:?}", start.elapsed()));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: On Windows, an executable's rlocation path resolves with or without its .exe
+fn test_executable_without_extension(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: executable_without_extension");
+
+    if !cfg!(windows) {
+        report!("    SKIP (Windows only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_executable_without_extension");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "exe_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let key_without_extension = format!("{}/bin/dump-json", WORKSPACE_NAME);
+    for (name, key) in [("with", &dump_json_rlocation), ("without", &key_without_extension)] {
+        let stub_path = test_dir.join(format!("exe_{}{}", name, EXE_EXT));
+        finalize_stub(config, &stub_path, &[key, "embedded"], &[0])?;
+
+        // Manifest-based and directory-based lookups
+        for use_manifest in [true, false] {
+            let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+            if exit_code != 0 {
+                return Err(format!(
+                    "Stub for {:?} failed (manifest: {}) with exit code {}: {}",
+                    key, use_manifest, exit_code, stderr
+                ));
+            }
+            let dump = parse_dump_json(&stdout)?;
+            if dump["argv"][1] != "embedded" {
+                return Err(format!("Unexpected arguments for {:?} (manifest: {}): {}", key, use_manifest, dump["argv"]));
+            }
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Fallback runfiles directory discovery
+fn test_fallback_runfiles_dir(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: fallback_runfiles_dir");
+
+    let test_dir = config.work_dir.join("test_fallback");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Create a stub with a .runfiles directory next to it
+    let stub_path = test_dir.join(format!("fallback_stub{}", EXE_EXT));
+    let runfiles_dir = test_dir.join(format!("fallback_stub{}.runfiles", EXE_EXT));
+
+    fs::create_dir_all(&runfiles_dir).map_err(|e| format!("Failed to create runfiles dir: {}", e))?;
+
+    // Add files directly to runfiles directory
+    let binary_dir = runfiles_dir.join(WORKSPACE_NAME).join("bin");
+    fs::create_dir_all(&binary_dir).map_err(|e| format!("Failed to create binary dir: {}", e))?;
+
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    let dest_binary = binary_dir.join(format!("add-numbers{}", EXE_EXT));
+    fs::copy(&add_binary, &dest_binary).map_err(|e| format!("Failed to copy binary: {}", e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(&dest_binary)
+            .map_err(|e| format!("Failed to get permissions: {}", e))?
+            .permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&dest_binary, perms)
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    // Create the stub
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+
+    finalize_stub(
+        config,
+        &stub_path,
+        &[&add_rlocation, "5", "10"],
+        &[0],
+    )?;
+
+    // Run WITHOUT setting any environment variables
+    let mut cmd = Command::new(&stub_path);
+    cmd.env_remove("RUNFILES_DIR");
+    cmd.env_remove("RUNFILES_MANIFEST_FILE");
+
+    let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
@@ -672,14 +1571,263 @@ fn test_fallback_runfiles_dir(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Unexpected output: {}. Expected 'SUM:15'", stdout));
     }
 
-    println!("    PASS");
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Fallback discovery works for a stub found through PATH (argv[0] is a bare name)
+fn test_path_lookup(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: path_lookup");
+
+    let test_dir = config.work_dir.join("test_path_lookup");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // The stub's .runfiles directory sits next to it on PATH
+    let mut runfiles = RunfilesSetup::new(&test_dir, &format!("path_stub{}", EXE_EXT))
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+
+    let stub_path = test_dir.join(format!("path_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&add_rlocation, "5", "10"], &[0])?;
+
+    let mut path_dirs = vec![test_dir.clone()];
+    if let Some(path) = env::var_os("PATH") {
+        path_dirs.extend(env::split_paths(&path));
+    }
+    let path = env::join_paths(path_dirs).map_err(|e| format!("Failed to build PATH: {}", e))?;
+
+    // Run by bare name, WITHOUT any runfiles environment variables
+    let mut cmd = Command::new("path_stub");
+    cmd.env("PATH", path);
+    cmd.env_remove("RUNFILES_DIR");
+    cmd.env_remove("RUNFILES_MANIFEST_FILE");
+
+    let output = cmd.output().map_err(|e| format!("Failed to run stub through PATH: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let exit_code = output.status.code().unwrap_or(-1);
+
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+
+    if !stdout.contains("SUM:15") {
+        return Err(format!("Unexpected output: {}. Expected 'SUM:15'", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: The stub reads its environment without /proc mounted (Linux)
+fn test_without_procfs(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: without_procfs");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (Linux only)");
+        return Ok(());
+    }
+
+    // Hiding /proc needs a private mount namespace
+    let hide_proc = "mount -t tmpfs none /proc && exec \"$0\"";
+    let probe = Command::new("unshare").args(["-m", "sh", "-c", hide_proc, "true"]).output();
+    if !probe.map(|output| output.status.success()).unwrap_or(false) {
+        report!("    SKIP (can't unshare the mount namespace)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_without_procfs");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Named apart from the stub so the fallback next to it doesn't find them
+    let mut runfiles = RunfilesSetup::new(&test_dir, "noproc_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers", WORKSPACE_NAME);
+    let add_binary = config.test_binaries_dir.join("add-numbers");
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join("noproc_stub");
+    finalize_stub(config, &stub_path, &[&add_rlocation, "5", "10"], &[0])?;
+
+    // Only RUNFILES_MANIFEST_FILE tells the stub where its runfiles are
+    let output = Command::new("unshare")
+        .args(["-m", "sh", "-c", hide_proc])
+        .arg(&stub_path)
+        .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+        .env_remove("RUNFILES_DIR")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stdout.contains("SUM:15") {
+        return Err(format!("Stub failed without /proc: {}{}", stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A stub exec'd with an empty argv finds <stub>.runfiles through /proc/self/exe
+fn test_empty_argv(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: empty_argv");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (Linux only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_empty_argv");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Only the fallback next to the stub can find these
+    let mut runfiles = RunfilesSetup::new(&test_dir, "empty_argv_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers", WORKSPACE_NAME);
+    let add_binary = config.test_binaries_dir.join("add-numbers");
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+
+    let stub_path = test_dir.join("empty_argv_stub");
+    finalize_stub(config, &stub_path, &[&add_rlocation, "5", "10"], &[0])?;
+
+    let output = Command::new(config.test_binaries_dir.join("exec-empty-argv"))
+        .arg(&stub_path)
+        .env_remove("RUNFILES_DIR")
+        .env_remove("RUNFILES_MANIFEST_FILE")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stdout.contains("SUM:15") {
+        return Err(format!("Stub failed with an empty argv ({}): {}{}", output.status, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: RUNFILES_MANIFEST_FILE is found after hundreds of kilobytes of other variables
+fn test_large_environment(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: large_environment");
+
+    let test_dir = config.work_dir.join("test_large_environment");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "large_env_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("large_env_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&add_rlocation, "5", "10"], &[0])?;
+
+    // Far more than a page (a single read of /proc/self/environ), sorted before RUNFILES_*
+    let mut cmd = Command::new(&stub_path);
+    for i in 0..200 {
+        cmd.env(format!("AAA_FILLER_{:03}", i), "x".repeat(1000));
+    }
+    let output = cmd
+        .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+        .env_remove("RUNFILES_DIR")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stdout.contains("SUM:15") {
+        return Err(format!("Stub failed with a large environment: {}{}", stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: One finalized stub works with every way of finding its runfiles
+fn test_one_stub_all_discovery_modes(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: one_stub_all_discovery_modes");
+
+    let test_dir = config.work_dir.join("test_one_stub_all_discovery_modes");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Named after the stub, so the runfiles are also its siblings
+    let mut runfiles = RunfilesSetup::new(&test_dir, &format!("unified_stub{}", EXE_EXT))
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    runfiles.add_file(&hash_rlocation, &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    let data_rlocation = format!("{}/data/test.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add test.txt: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Finalized once, then run in every mode
+    let stub_path = test_dir.join(format!("unified_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&hash_rlocation, &data_rlocation], &[0, 1])?;
+
+    let run_without_env = || -> Result<(String, String, i32), String> {
+        let output = Command::new(&stub_path)
+            .env_remove("RUNFILES_DIR")
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        Ok((
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            output.status.code().unwrap_or(-1),
+        ))
+    };
+
+    let mut outputs = Vec::new();
+    outputs.push(("RUNFILES_MANIFEST_FILE", run_stub(config, &stub_path, &runfiles, &[], true)?));
+    outputs.push(("RUNFILES_DIR", run_stub(config, &stub_path, &runfiles, &[], false)?));
+    outputs.push(("sibling .runfiles_manifest", run_without_env()?));
+    // Without the sibling manifest, the sibling directory is used
+    fs::remove_file(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to remove manifest: {}", e))?;
+    outputs.push(("sibling .runfiles directory", run_without_env()?));
+
+    let normalize = |output: &str| output.replace('\\', "/");
+    let (_, (expected, _, _)) = &outputs[0];
+    for (mode, (stdout, stderr, exit_code)) in &outputs {
+        if *exit_code != 0 {
+            return Err(format!("Stub ({}) failed with exit code {}: {}{}", mode, exit_code, stdout, stderr));
+        }
+        if !stdout.to_lowercase().contains("c98c24b677eff") {
+            return Err(format!("Unexpected output ({}): {}", mode, stdout));
+        }
+        if normalize(stdout) != normalize(expected) {
+            return Err(format!("Output ({}) differs from manifest mode:\n{}\nvs\n{}", mode, stdout, expected));
+        }
+    }
+
+    report!("    PASS");
 
     Ok(())
 }
 
 /// Test: Fallback runfiles_manifest file discovery
 fn test_fallback_runfiles_manifest(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: fallback_runfiles_manifest");
+    report!("  Running test: fallback_runfiles_manifest");
 
     let test_dir = config.work_dir.join("test_fallback_manifest");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -744,14 +1892,14 @@ fn test_fallback_runfiles_manifest(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Unexpected output: {}. Expected 'SUM:15'", stdout));
     }
 
-    println!("    PASS");
+    report!("    PASS");
 
     Ok(())
 }
 
 /// Test: print-env to verify environment and argument passing
 fn test_print_env(config: &TestConfig) -> Result<(), String> {
-    println!("  Running test: print_env");
+    report!("  Running test: print_env");
 
     let test_dir = config.work_dir.join("test_print_env");
     fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
@@ -780,6 +1928,7 @@ fn test_print_env(config: &TestConfig) -> Result<(), String> {
 
     // Test with manifest mode and runtime arguments
     let (stdout, stderr, exit_code) = run_stub(
+        config,
         &stub_path,
         &runfiles,
         &["--runtime-flag", "runtime-value"],
@@ -816,10 +1965,11 @@ fn test_print_env(config: &TestConfig) -> Result<(), String> {
         return Err(format!("Expected ARGC:5 but got: {}", stdout));
     }
 
-    println!("    PASS (manifest mode with embedded + runtime args)");
+    report!("    PASS (manifest mode with embedded + runtime args)");
 
     // Test with directory mode
     let (stdout2, stderr2, exit_code2) = run_stub(
+        config,
         &stub_path,
         &runfiles,
         &["dir-mode-arg"],
@@ -835,77 +1985,4578 @@ fn test_print_env(config: &TestConfig) -> Result<(), String> {
         return Err(format!("RUNFILES_DIR should be set in directory mode: {}", stdout2));
     }
 
-    println!("    PASS (directory mode)");
+    report!("    PASS (directory mode)");
 
     Ok(())
 }
 
-fn main() -> ExitCode {
-    println!("=== Runfiles Stub Test Suite ===");
-    println!();
+/// Test: Finalizer rejects a stray '@' run outside the placeholder section
+fn test_stray_placeholder_rejected(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: stray_placeholder_rejected");
 
-    let config = match TestConfig::from_args() {
-        Ok(c) => c,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            eprintln!("Use --help for usage information");
-            return ExitCode::from(1);
-        }
-    };
+    let test_dir = config.work_dir.join("test_stray_placeholder");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
 
-    // Clean and recreate work directory
-    if config.work_dir.exists() {
-        if let Err(e) = fs::remove_dir_all(&config.work_dir) {
-            eprintln!("Warning: Failed to clean work dir: {}", e);
-        }
+    let mut template = fs::read(&config.template_path)
+        .map_err(|e| format!("Failed to read template: {}", e))?;
+
+    // Plant an ARG-sized '@' run well before the placeholder section, so it is
+    // the first match the finalizer's placeholder search would find. The
+    // section's layout isn't fixed, so go below its earliest placeholder.
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let arg_placeholder = vec![b'@'; metadata_field(&template, metadata_pos, 28) as usize];
+    let region_magics: [&[u8]; 7] = [
+        &arg_placeholder,
+        b"@@RUNFILES_DEFAULT_ARGS@@",
+        b"@@RUNFILES_EXEC_PATH@@",
+        b"@@RUNFILES_SANDBOX_ROOT@@",
+        b"@@RUNFILES_ENV_ALLOWLIST@@",
+        b"@@RUNFILES_RLOCATION_PREFIXES@@",
+        b"@@RUNFILES_SOURCE_REPO@@",
+    ];
+    let section_pos = region_magics
+        .iter()
+        .filter_map(|magic| find_bytes(&template, magic))
+        .fold(metadata_pos, usize::min);
+    let stray_pos = section_pos
+        .checked_sub(4096)
+        .ok_or("Template too small to plant a stray placeholder")?;
+    template[stray_pos..stray_pos + arg_placeholder.len()].fill(b'@');
+
+    let bad_template = test_dir.join(format!("stray_template{}", EXE_EXT));
+    fs::write(&bad_template, &template)
+        .map_err(|e| format!("Failed to write template: {}", e))?;
+
+    let output = test_dir.join(format!("stray_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &bad_template.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--", "tool",
+    ])?;
+
+    if exit_code == 0 {
+        return Err("Finalizer accepted a template with a stray placeholder".to_string());
     }
-    if let Err(e) = fs::create_dir_all(&config.work_dir) {
-        eprintln!("Error: Failed to create work dir: {}", e);
-        return ExitCode::from(1);
+    if !stderr.contains("outside the placeholder section") {
+        return Err(format!("Unexpected finalizer error: {}", stderr));
     }
 
-    println!("Configuration:");
-    println!("  Template:      {}", config.template_path.display());
-    println!("  Finalizer:     {}", config.finalizer_path.display());
-    println!("  Test binaries: {}", config.test_binaries_dir.display());
-    println!("  Work dir:      {}", config.work_dir.display());
-    println!();
+    report!("    PASS");
 
-    let tests: Vec<(&str, fn(&TestConfig) -> Result<(), String>)> = vec![
-        ("hash_file", test_hash_file),
-        ("add_numbers_runtime_args", test_add_numbers_runtime_args),
-        ("merge_json", test_merge_json),
-        ("orchestrator_env_propagation", test_orchestrator_env_propagation),
-        ("mixed_arguments", test_mixed_arguments),
-        ("fallback_runfiles_dir", test_fallback_runfiles_dir),
-        ("fallback_runfiles_manifest", test_fallback_runfiles_manifest),
-        ("print_env", test_print_env),
-    ];
+    Ok(())
+}
 
-    let mut passed = 0;
-    let mut failed = 0;
+/// Test: Embedded arguments up to ARG_SIZE - 1 bytes work, longer ones are rejected
+fn test_arg_size_boundary(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: arg_size_boundary");
 
-    println!("Running {} tests...", tests.len());
-    println!();
+    let test_dir = config.work_dir.join("test_arg_size_boundary");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Placeholder size of the template (256 unless built with RUNFILES_ARG_SIZE)
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let arg_size = metadata_field(&template, metadata_pos, 28) as usize;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "boundary_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // The longest argument fills the placeholder up to its terminating NUL and is
+    // followed by another one, so a missing NUL would run into it
+    let longest = "x".repeat(arg_size - 1);
+    let stub_path = test_dir.join(format!("boundary_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, &longest, "next"], &[0])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<&str> = args_line.split('|').collect();
+    if args.get(1) != Some(&longest.as_str()) || args.get(2) != Some(&"next") {
+        return Err(format!("Expected a {}-byte argument followed by \"next\", got {:?}", longest.len(), args));
+    }
+
+    // One byte of the placeholder is reserved for the NUL
+    for len in [arg_size, arg_size * 4] {
+        let too_long = "x".repeat(len);
+        let output = test_dir.join(format!("too_long_stub_{}{}", len, EXE_EXT));
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &output.to_string_lossy(),
+            "--", &print_env_rlocation, &too_long,
+        ])?;
+        let expected = format!("Argument 1 too long: {} bytes > {} bytes max", len, arg_size - 1);
+        if exit_code == 0 || !stderr.contains(&expected) {
+            return Err(format!("Expected {:?} for a {}-byte argument, got: {}", expected, len, stderr));
+        }
+        if output.exists() {
+            return Err(format!("Finalizer wrote {} despite the error", output.display()));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A runfiles directory path that fills the path buffer is NUL-terminated, and one that
+/// doesn't fit is an error instead of being truncated (Linux and macOS)
+fn test_long_runfiles_dir_path(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: long_runfiles_dir_path");
+
+    if cfg!(windows) {
+        report!("    SKIP (Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_long_runfiles_dir_path");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Resolved paths hold at least a whole argument placeholder
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let max_path_len = (metadata_field(&template, metadata_pos, 28) as usize).max(256);
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "long_dir_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env", WORKSPACE_NAME);
+    let print_env = config.test_binaries_dir.join("print-env");
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+
+    let stub_path = test_dir.join("long_dir_stub");
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, "embedded"], &[0])?;
+
+    // Repeated slashes lengthen RUNFILES_DIR without changing the directory it names
+    let base = runfiles.runfiles_dir.to_string_lossy().to_string();
+    let runfiles_dir = |resolved_len: usize| format!("{}{}", base, "/".repeat(resolved_len - base.len() - print_env_rlocation.len()));
+    let run = |dir: &str| {
+        Command::new(&stub_path)
+            .env("RUNFILES_DIR", dir)
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))
+    };
+
+    // The longest path that fits leaves one byte for the NUL
+    let output = run(&runfiles_dir(max_path_len - 1))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !stdout.contains("|embedded") {
+        return Err(format!(
+            "Stub failed with a {}-byte path: {}{}",
+            max_path_len - 1,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let output = run(&runfiles_dir(max_path_len))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.code() != Some(1) || !stderr.contains("ERROR: Runfiles path too long") {
+        return Err(format!("Expected a path length error for a {}-byte path, got: {}", max_path_len, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --args-file embeds one argument per line, before those on the command line
+fn test_args_file(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: args_file");
+
+    let test_dir = config.work_dir.join("test_args_file");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "args_file_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Comments, blank lines and CRLF line endings as a generated params file may have
+    let args_file = test_dir.join("stub.params");
+    let content = format!(
+        "# generated\n{}\r\n\n--flag=with spaces\n  \nvalue # not a comment\n",
+        print_env_rlocation
+    );
+    fs::write(&args_file, content).map_err(|e| format!("Failed to write args file: {}", e))?;
+
+    let stub_path = test_dir.join(format!("args_file_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--args-file", &args_file.to_string_lossy(),
+        "--", "from-command-line",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&stub_path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<&str> = args_line.split('|').skip(1).collect();
+    if args != ["--flag=with spaces", "value # not a comment", "from-command-line"] {
+        return Err(format!("Unexpected embedded arguments: {:?}", args));
+    }
+
+    // A missing file is an error, not an empty argument list
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--args-file", &test_dir.join("missing.params").to_string_lossy(),
+    ])?;
+    if exit_code == 0 || !stderr.contains("Failed to read args file") {
+        return Err(format!("Finalizer accepted a missing args file: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer warns about or trims trailing whitespace in embedded arguments
+fn test_trim_args(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: trim_args");
+
+    let test_dir = config.work_dir.join("test_trim_args");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "trim_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    runfiles.add_file(&format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT), &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    runfiles.add_file_content(&format!("{}/data/test.txt", WORKSPACE_NAME), b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add test.txt: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("trim_stub{}", EXE_EXT));
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    // Trailing space, as left behind by a sloppy generator
+    let data_rlocation = format!("{}/data/test.txt ", WORKSPACE_NAME);
+
+    // Default: embedded verbatim, with a warning
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0,1",
+        "--", &hash_rlocation, &data_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    if !stderr.contains("Warning: argument 1 has trailing whitespace") {
+        return Err(format!("No trailing whitespace warning: {}", stderr));
+    }
+    let (_stdout, _stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code == 0 {
+        return Err("Stub resolved an argument with trailing whitespace".to_string());
+    }
+
+    // --trim-args: stripped, no warning, and the path resolves
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0,1",
+        "--trim-args",
+        "--", &hash_rlocation, &data_rlocation,
+    ])?;
+    if exit_code != 0 || stderr.contains("trailing whitespace") {
+        return Err(format!("Finalizer with --trim-args failed or warned: {}", stderr));
+    }
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+    if !stdout.to_lowercase().contains("c98c24b677eff") {
+        return Err(format!("Unexpected output: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Stub appends its configuration and argv to RUNFILES_STUB_DEBUG_FILE
+fn test_debug_file(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: debug_file");
+
+    let test_dir = config.work_dir.join("test_debug_file");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "debug_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_numbers = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT), &add_numbers)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("debug_stub{}", EXE_EXT));
+    let rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    finalize_stub(config, &stub_path, &[&rlocation, "1"], &[0])?;
+
+    let debug_file = test_dir.join("debug.log");
+    for _ in 0..2 {
+        let output = Command::new(&stub_path)
+            .arg("2")
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .env("RUNFILES_STUB_DEBUG_FILE", &debug_file)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("Stub failed: {}", String::from_utf8_lossy(&output.stdout)));
+        }
+    }
+
+    let log = fs::read_to_string(&debug_file)
+        .map_err(|e| format!("Debug file was not written: {}", e))?;
+    // argv[0] is the manifest entry, whose exact spelling depends on the platform
+    let resolved_suffix = format!("debug_stub.runfiles/{}", rlocation);
+    let expected_lines = [
+        "runfiles-stub: argc=2 transform_flags=1 flags=1 runfiles=manifest",
+        "argv[0]=",
+        "argv[1]=1",
+        "argv[2]=2",
+    ];
+    let lines: Vec<&str> = log.lines().collect();
+    // The file is appended to, so both runs are logged
+    if lines.len() != 2 * expected_lines.len() {
+        return Err(format!("Expected two runs in the debug file, got:\n{}", log));
+    }
+    for (line, expected) in lines.iter().zip(expected_lines.iter().cycle()) {
+        let matches = if *expected == "argv[0]=" {
+            line.starts_with(expected) && line.replace('\\', "/").ends_with(&resolved_suffix)
+        } else {
+            line == expected
+        };
+        if !matches {
+            return Err(format!("Unexpected debug line {:?}, expected {:?}", line, expected));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: No internal stub file descriptors leak into the target
+fn test_no_fd_leak(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: no_fd_leak");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (requires /proc/self/fd)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_no_fd_leak");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let runfiles = RunfilesSetup::new(&test_dir, "fd_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // `; true` keeps the shell from exec'ing ls, so $$ is the target process
+    let script = "ls /proc/$$/fd; true";
+
+    for extra_flags in [&[][..], &["--spawn"][..]] {
+        let stub_path = test_dir.join("fd_stub");
+        let stub_path_str = stub_path.to_string_lossy();
+        let mut args = vec!["--template", config.template_path.to_str().unwrap(), "--output", &stub_path_str];
+        args.extend_from_slice(extra_flags);
+        args.extend_from_slice(&["--", "/bin/sh", "-c", script]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        // Reading the manifest, the environment and the debug file all open fds
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .env("RUNFILES_STUB_DEBUG_FILE", test_dir.join("debug.log"))
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(format!("Stub failed: {}", stdout));
+        }
+
+        let fds: Vec<&str> = stdout.split_whitespace().collect();
+        if fds != ["0", "1", "2"] {
+            return Err(format!("Target {:?} has unexpected open fds: {:?}", extra_flags, fds));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Percent-encoded rlocation paths resolve with --percent-decode
+fn test_percent_decode(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: percent_decode");
+
+    let test_dir = config.work_dir.join("test_percent_decode");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "decode_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    runfiles.add_file(&format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT), &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    runfiles.add_file_content(&format!("{}/data/my file.txt", WORKSPACE_NAME), b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("decode_stub{}", EXE_EXT));
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    let encoded_rlocation = format!("{}/data/my%20file.txt", WORKSPACE_NAME);
+
+    for percent_decode in [false, true] {
+        let mut args = vec![
+            "--template".to_string(), config.template_path.to_string_lossy().to_string(),
+            "--output".to_string(), stub_path.to_string_lossy().to_string(),
+            "--transform".to_string(), "0,1".to_string(),
+        ];
+        if percent_decode {
+            args.push("--percent-decode".to_string());
+        }
+        args.extend(["--".to_string(), hash_rlocation.clone(), encoded_rlocation.clone()]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        for use_manifest in [false, true] {
+            let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+            let resolved = exit_code == 0 && stdout.to_lowercase().contains("c98c24b677eff");
+            if resolved != percent_decode {
+                return Err(format!(
+                    "percent_decode={}, use_manifest={}: expected the encoded path to {}resolve (exit code {}): {}{}",
+                    percent_decode, use_manifest, if percent_decode { "" } else { "not " }, exit_code, stdout, stderr
+                ));
+            }
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Escaped manifest entries (paths with spaces) resolve to the unescaped path
+fn test_escaped_manifest_entries(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: escaped_manifest_entries");
+
+    // The space in the directory ends up in every manifest value
+    let test_dir = config.work_dir.join("test_escaped manifest");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "escaped_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    let data_rlocation = format!("{}/data/file with spaces.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let manifest = fs::read_to_string(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    if !manifest.contains("file\\swith\\sspaces.txt") {
+        return Err(format!("Expected an escaped entry in the manifest: {}", manifest));
+    }
+
+    let stub_path = test_dir.join(format!("escaped_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, &data_rlocation], &[0, 1])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let expected_data = normalize(&runfiles.entries[&data_rlocation].to_string_lossy());
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<String> = args_line.split('|').map(normalize).collect();
+    if args.get(1) != Some(&expected_data) {
+        return Err(format!("Expected argument 1 to be {}, got {:?}", expected_data, args));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A manifest entry with a newline in its path is unescaped before the target opens it
+fn test_manifest_newline_entries(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: manifest_newline_entries");
+
+    // Windows file names can't contain newlines
+    if cfg!(windows) {
+        report!("    SKIP (Unix only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_manifest_newline");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "newline_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    runfiles.add_file(&hash_rlocation, &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    let data_rlocation = format!("{}/data/line\nbreak.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Both the key and the value are on one physical line, with the newline escaped
+    let manifest = fs::read_to_string(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    let escaped_line = manifest
+        .lines()
+        .find(|line| line.contains("line\\nbreak.txt"))
+        .ok_or_else(|| format!("Expected an escaped newline in the manifest: {:?}", manifest))?;
+    if !escaped_line.starts_with(' ') || escaped_line.matches("line\\nbreak.txt").count() != 2 {
+        return Err(format!("Unexpected escaped manifest line: {:?}", escaped_line));
+    }
+
+    let stub_path = test_dir.join(format!("newline_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&hash_rlocation, &data_rlocation], &[0, 1])?;
+
+    // hash-file only prints the hash if it could open the unescaped path
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+    let expected_hash = "SHA256:c98c24b677eff44860afea6f493bbaec5bb1c4cbb209c6fc2bbb47f66ff2ad31";
+    if stdout.trim() != expected_hash {
+        return Err(format!("Expected {}, got {:?}", expected_hash, stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Non-ASCII (UTF-8) runfiles paths and stub locations launch the right binary
+fn test_non_ascii_paths(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: non_ascii_paths");
+
+    // The non-ASCII directory ends up in argv[0], the environment and every manifest value
+    let test_dir = config.work_dir.join("test_café");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Named after the stub, so the runfiles are also its siblings
+    let stub_name = format!("café_stub{}", EXE_EXT);
+    let mut runfiles = RunfilesSetup::new(&test_dir, &stub_name)
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/café/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    // U+1D11E needs a surrogate pair in UTF-16
+    let data_rlocation = format!("{}/data/naïve_\u{1D11E}.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(&stub_name);
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, &data_rlocation], &[0, 1])?;
+
+    let run_without_env = || -> Result<(String, String, i32), String> {
+        let output = Command::new(&stub_path)
+            .env_remove("RUNFILES_DIR")
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        Ok((
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            output.status.code().unwrap_or(-1),
+        ))
+    };
+
+    let outputs = [
+        ("RUNFILES_MANIFEST_FILE", run_stub(config, &stub_path, &runfiles, &[], true)?),
+        ("RUNFILES_DIR", run_stub(config, &stub_path, &runfiles, &[], false)?),
+        ("sibling .runfiles_manifest", run_without_env()?),
+    ];
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    for (mode, (stdout, stderr, exit_code)) in &outputs {
+        if *exit_code != 0 {
+            return Err(format!("Stub ({}) failed with exit code {}: {}{}", mode, exit_code, stdout, stderr));
+        }
+        let args_line = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output ({}): {}", mode, stdout))?;
+        let args: Vec<String> = args_line.split('|').map(normalize).collect();
+        let data = args.get(1).ok_or_else(|| format!("Missing argument 1 ({}): {:?}", mode, args))?;
+        // Directory mode joins the runfiles root, manifest mode returns the target itself
+        if !data.contains("test_café") || !data.ends_with("naïve_\u{1D11E}.txt") {
+            return Err(format!("Unexpected argument 1 ({}): {:?}", mode, args));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --expand-runfiles-env-vars expands variable references in RUNFILES_MANIFEST_FILE/RUNFILES_DIR
+fn test_expand_runfiles_env_vars(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: expand_runfiles_env_vars");
+
+    let test_dir = config.work_dir.join("test_expand_runfiles_env_vars");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "expand_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Unexpanded references, as some CI configurations pass them
+    let reference = |name: &str, braced: bool| {
+        if cfg!(windows) {
+            format!("%{}%", name)
+        } else if braced {
+            format!("${{{}}}", name)
+        } else {
+            format!("${}", name)
+        }
+    };
+    let manifest_value = format!("{}{}expand_runfiles.runfiles_manifest", reference("STUB_TEST_ROOT", false), PATH_SEP);
+    let dir_value = format!("{}{}expand_runfiles.runfiles", reference("STUB_TEST_ROOT", true), PATH_SEP);
+    let cases = [("RUNFILES_MANIFEST_FILE", &manifest_value, &runfiles.manifest_path), ("RUNFILES_DIR", &dir_value, &runfiles.runfiles_dir)];
+
+    let run = |stub_path: &Path, var: &str, value: &str| -> Result<(String, i32), String> {
+        let mut cmd = Command::new(stub_path);
+        cmd.env_remove("RUNFILES_MANIFEST_FILE")
+            .env_remove("RUNFILES_DIR")
+            .env("STUB_TEST_ROOT", &test_dir)
+            .env(var, value);
+        let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
+        Ok((String::from_utf8_lossy(&output.stdout).to_string(), output.status.code().unwrap_or(-1)))
+    };
+
+    // Without the option the references are used literally and nothing is found
+    let plain_stub = test_dir.join(format!("plain_stub{}", EXE_EXT));
+    finalize_stub(config, &plain_stub, &[&print_env_rlocation], &[0])?;
+    let (stdout, exit_code) = run(&plain_stub, "RUNFILES_MANIFEST_FILE", &manifest_value)?;
+    if exit_code == 0 {
+        return Err(format!("Stub without --expand-runfiles-env-vars expanded the manifest path: {}", stdout));
+    }
+
+    let expand_stub = test_dir.join(format!("expand_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &expand_stub.to_string_lossy(),
+        "--transform", "0",
+        "--expand-runfiles-env-vars",
+        "--", &print_env_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    for (var, value, expected) in cases {
+        let (stdout, exit_code) = run(&expand_stub, var, value)?;
+        if exit_code != 0 {
+            return Err(format!("Stub failed with {}={}: {}", var, value, stdout));
+        }
+        // The expanded value is what the target sees
+        let exported = format!("ENV:{}={}", var, expected.display());
+        if !stdout.contains(&exported) {
+            return Err(format!("Expected {} in output: {}", exported, stdout));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Canonical external repository keys (bzlmod `+ext+name`, legacy `name~override`) resolve as-is
+fn test_external_repo_keys(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: external_repo_keys");
+
+    let test_dir = config.work_dir.join("test_external_repo_keys");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "external_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("+tools_ext+print_env/bin/print-env{}", EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    let data_rlocation = "rules_data~override/data/test.txt";
+    runfiles.add_file_content(data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("external_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, data_rlocation], &[0, 1])?;
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let expected_data = normalize(&runfiles.entries[data_rlocation].to_string_lossy());
+    for use_manifest in [true, false] {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+        if exit_code != 0 {
+            return Err(format!(
+                "Stub (use_manifest={}) failed with exit code {}: {}{}",
+                use_manifest, exit_code, stdout, stderr
+            ));
+        }
+        let args_line = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+        let args: Vec<String> = args_line.split('|').map(normalize).collect();
+        if args.get(1) != Some(&expected_data) {
+            return Err(format!(
+                "use_manifest={}: expected argument 1 to be {}, got {:?}",
+                use_manifest, expected_data, args
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Apparent repository names are translated through the _repo_mapping runfile
+fn test_repo_mapping(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: repo_mapping");
+
+    let test_dir = config.work_dir.join("test_repo_mapping");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "mapping_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    for canonical in ["my_dep+", "ext_dep+"] {
+        runfiles.add_file_content(&format!("{}/data/test.txt", canonical), b"data\n")
+            .map_err(|e| format!("Failed to add data file: {}", e))?;
+    }
+    // The main repository sees my_dep+ as my_dep, repositories of the ext extension see ext_dep+
+    runfiles.add_file_content("_repo_mapping", b",_main,_main\n,my_dep,my_dep+\n+ext+*,my_dep,ext_dep+\n")
+        .map_err(|e| format!("Failed to add _repo_mapping: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let stub_path = test_dir.join(format!("mapping_stub{}", EXE_EXT));
+    for (source_repo, canonical) in [(None, "my_dep+"), (Some("+ext+tool"), "ext_dep+")] {
+        let template = config.template_path.to_string_lossy();
+        let output = stub_path.to_string_lossy();
+        let mut args = vec!["--template", &template, "--output", &output, "--transform", "0,1"];
+        if let Some(source_repo) = source_repo {
+            args.extend_from_slice(&["--source-repo", source_repo]);
+        }
+        args.extend_from_slice(&["--", &print_env_rlocation, "my_dep/data/test.txt"]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let expected = normalize(&runfiles.entries[&format!("{}/data/test.txt", canonical)].to_string_lossy());
+        for use_manifest in [true, false] {
+            let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+            if exit_code != 0 {
+                return Err(format!(
+                    "Stub (source repo {:?}, use_manifest={}) failed with exit code {}: {}{}",
+                    source_repo, use_manifest, exit_code, stdout, stderr
+                ));
+            }
+            let args_line = stdout
+                .lines()
+                .find_map(|line| line.strip_prefix("ARGS:"))
+                .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+            let args: Vec<String> = args_line.split('|').map(normalize).collect();
+            if args.get(1) != Some(&expected) {
+                return Err(format!(
+                    "Source repo {:?}, use_manifest={}: expected my_dep/data/test.txt to resolve to {}, got {:?}",
+                    source_repo, use_manifest, expected, args
+                ));
+            }
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: The child's argv[0] is the resolved executable, not the stub or a duplicate
+fn test_argv0_is_resolved_path(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: argv0_is_resolved_path");
+
+    let test_dir = config.work_dir.join("test_argv0");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "argv0_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("argv0_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, "embedded"], &[0])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &["runtime"], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<&str> = args_line.split('|').collect();
+
+    // Windows builds a fresh command line whose first token becomes argv[0], so
+    // compare with separators normalized
+    let normalize = |path: &str| path.replace('\\', "/");
+    let expected_argv0 = normalize(&runfiles.entries[&print_env_rlocation].to_string_lossy());
+    if args.len() != 3 || normalize(args[0]) != expected_argv0 || args[1] != "embedded" || args[2] != "runtime" {
+        return Err(format!(
+            "Expected [{}, embedded, runtime], got {:?}",
+            expected_argv0, args
+        ));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Many runtime arguments are forwarded intact and in order
+fn test_many_runtime_args(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: many_runtime_args");
+
+    let test_dir = config.work_dir.join("test_many_runtime_args");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "many_args_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("many_args_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation], &[0])?;
+
+    // 50 arguments; every tenth one is a valid rlocation path, which must
+    // be passed through literally rather than resolved
+    let runtime_args: Vec<String> = (0..50)
+        .map(|i| {
+            if i % 10 == 0 {
+                print_env_rlocation.clone()
+            } else {
+                format!("arg-{:02}", i)
+            }
+        })
+        .collect();
+    let runtime_arg_refs: Vec<&str> = runtime_args.iter().map(String::as_str).collect();
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &runtime_arg_refs, true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<&str> = args_line.split('|').collect();
+
+    if args.len() != runtime_args.len() + 1 {
+        return Err(format!("Expected {} args, got {}: {}", runtime_args.len() + 1, args.len(), args_line));
+    }
+    if !args[0].replace('\\', "/").ends_with(&print_env_rlocation) {
+        return Err(format!("argv[0] is not the resolved print-env: {}", args[0]));
+    }
+    for (i, (actual, expected)) in args[1..].iter().zip(&runtime_args).enumerate() {
+        if actual != expected {
+            return Err(format!("Runtime arg {} is {:?}, expected {:?}", i, actual, expected));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Quotes, backslashes and empty strings in arguments survive the stub
+/// (on Windows, its command-line parsing and re-quoting for CreateProcessW)
+fn test_runtime_args_escaping(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: runtime_args_escaping");
+
+    let test_dir = config.work_dir.join("test_runtime_args_escaping");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "escaping_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let embedded = r#"say "hi" C:\dir\"#;
+    let stub_path = test_dir.join(format!("escaping_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, embedded], &[0])?;
+
+    // On a Windows command line these read a\\b, "a b", a\"b, "C:\path with space\\", "" and x\\\"y
+    let runtime_args = [r"a\\b", "a b", r#"a"b"#, r"C:\path with space\", "", r#"x\"y"#];
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &runtime_args, true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let args: Vec<&str> = args_line.split('|').skip(1).collect();
+    let expected: Vec<&str> = [embedded].into_iter().chain(runtime_args).collect();
+    if args != expected {
+        return Err(format!("Expected arguments {:?}, got {:?}", expected, args));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Default runtime arguments apply only when the stub gets none
+fn test_default_runtime_args(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: default_runtime_args");
+
+    let test_dir = config.work_dir.join("test_default_runtime_args");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "defaults_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("defaults_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--default-runtime-args", "serve",
+        "--default-runtime-args", "--port=8080",
+        "--", &print_env_rlocation, "embedded",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let cases: [(&[&str], &[&str]); 2] = [
+        // No runtime args: defaults are appended
+        (&[], &["embedded", "serve", "--port=8080"]),
+        // Any runtime arg suppresses all defaults
+        (&["runtime"], &["embedded", "runtime"]),
+    ];
+    for (runtime_args, expected) in cases {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, runtime_args, true)?;
+        if exit_code != 0 {
+            return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+        }
+
+        let args_line = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+        let args: Vec<&str> = args_line.split('|').skip(1).collect();
+        if args != expected {
+            return Err(format!(
+                "Runtime args {:?}: expected {:?} after argv[0], got {:?}",
+                runtime_args, expected, args
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A sandboxed stub runs in its root with only allowlisted environment variables
+fn test_sandbox_root(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: sandbox_root");
+
+    if cfg!(windows) {
+        report!("    SKIP (sandbox roots are Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_sandbox_root");
+    let sandbox = test_dir.join("sandbox");
+    fs::create_dir_all(sandbox.join("bin")).map_err(|e| format!("Failed to create sandbox: {}", e))?;
+    fs::create_dir_all(sandbox.join("data")).map_err(|e| format!("Failed to create sandbox: {}", e))?;
+    fs::copy(config.test_binaries_dir.join("print-env"), sandbox.join("bin/print-env"))
+        .map_err(|e| format!("Failed to copy print-env: {}", e))?;
+    fs::write(sandbox.join("data/inside.txt"), b"inside\n")
+        .map_err(|e| format!("Failed to write data file: {}", e))?;
+    fs::write(test_dir.join("outside.txt"), b"outside\n")
+        .map_err(|e| format!("Failed to write data file: {}", e))?;
+    let sandbox = fs::canonicalize(&sandbox).map_err(|e| format!("Failed to resolve sandbox: {}", e))?;
+    let sandbox_str = sandbox.to_string_lossy().to_string();
+
+    let finalize = |name: &str, data_arg: &str| -> Result<PathBuf, String> {
+        let stub_path = test_dir.join(name);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &stub_path.to_string_lossy(),
+            "--transform", "0,1",
+            "--sandbox-root", &sandbox_str,
+            "--allow-env", "SANDBOX_KEEP",
+            "--", "bin/print-env", data_arg,
+        ])?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+        Ok(stub_path)
+    };
+    let run = |stub_path: &Path| -> Result<(String, String, i32), String> {
+        let output = Command::new(stub_path)
+            .env("SANDBOX_KEEP", "kept")
+            .env("SANDBOX_DROP", "dropped")
+            .env("RUNFILES_DIR", &test_dir)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        Ok((
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            output.status.code().unwrap_or(-1),
+        ))
+    };
+
+    let stub_path = finalize("sandbox_stub", "data/inside.txt")?;
+    let (stdout, stderr, exit_code) = run(&stub_path)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+
+    let expected_args = format!("ARGS:{0}/bin/print-env|{0}/data/inside.txt", sandbox_str);
+    let expected_lines = [
+        expected_args.as_str(),
+        &format!("CWD:{}", sandbox_str),
+        &format!("ALL_ENV:RUNFILES_DIR={}", sandbox_str),
+        "ALL_ENV:SANDBOX_KEEP=kept",
+    ];
+    for expected in expected_lines {
+        if !stdout.lines().any(|line| line == expected) {
+            return Err(format!("Expected line {:?} in output: {}", expected, stdout));
+        }
+    }
+    for unexpected in ["ALL_ENV:SANDBOX_DROP=", "ALL_ENV:PATH=", "ALL_ENV:HOME="] {
+        if stdout.contains(unexpected) {
+            return Err(format!("Variable outside the allowlist leaked ({}): {}", unexpected, stdout));
+        }
+    }
+
+    // Runfiles paths can't reach outside the root
+    let escape_path = finalize("escape_stub", "../outside.txt")?;
+    let (stdout, stderr, exit_code) = run(&escape_path)?;
+    if exit_code == 0 || !stderr.contains("escapes the sandbox root") && !stdout.contains("escapes the sandbox root") {
+        return Err(format!("Expected the stub to reject ../outside.txt (exit code {}): {}{}", exit_code, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Entries past the first 64 KiB of a manifest are found
+fn test_large_manifest(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: large_manifest");
+
+    let test_dir = config.work_dir.join("test_large_manifest");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "large_manifest_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Put ~80 KiB of long padding entries ahead of the real ones, so they
+    // start beyond the first 64 KiB read
+    let manifest = fs::read_to_string(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    let mut padded = String::new();
+    for i in 0..160 {
+        padded.push_str(&format!(
+            "{}/padding/{:03}/{} C:/padding/{:03}\n",
+            WORKSPACE_NAME, i, "x".repeat(400), i
+        ));
+    }
+    if padded.len() <= 65536 {
+        return Err(format!("Padding is only {} bytes", padded.len()));
+    }
+    padded.push_str(&manifest);
+    fs::write(&runfiles.manifest_path, padded)
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("large_manifest_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, "embedded"], &[0])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+    if !stdout.contains("|embedded") {
+        return Err(format!("Unexpected output: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Lookups in a large, unsorted manifest resolve to the same entries as a linear
+/// scan (the first of duplicate keys wins)
+fn test_sorted_manifest_lookup(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: sorted_manifest_lookup");
+
+    let test_dir = config.work_dir.join("test_sorted_manifest_lookup");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "sorted_manifest_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Close to the stub's entry limit (256 on Windows, 1024 elsewhere), in an order
+    // unrelated to the keys'
+    let count = if cfg!(windows) { 250 } else { 1000 };
+    let resolved = |name: &str| test_dir.join("resolved").join(name).to_string_lossy().to_string();
+    let mut entries: Vec<(String, String)> = (0..count - 5)
+        .map(|i| (i * 389) % (count - 5))
+        .map(|i| (format!("{}/data/file_{:04}.txt", WORKSPACE_NAME, i), resolved(&format!("file_{:04}.txt", i))))
+        .collect();
+    // A key that is a prefix of others, one sorting before all of them, and a duplicate
+    entries.push((format!("{}/data/file_0", WORKSPACE_NAME), resolved("prefix")));
+    entries.push((format!("{}/Data/upper.txt", WORKSPACE_NAME), resolved("upper")));
+    entries.insert(count / 2, (format!("{}/data/dup.txt", WORKSPACE_NAME), resolved("dup_first")));
+    entries.push((format!("{}/data/dup.txt", WORKSPACE_NAME), resolved("dup_second")));
+
+    let mut manifest = fs::read_to_string(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    for (key, value) in &entries {
+        manifest.push_str(&format!("{} {}\n", key, value));
+    }
+    fs::write(&runfiles.manifest_path, manifest)
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let last = format!("{}/data/file_{:04}.txt", WORKSPACE_NAME, count - 6);
+    let keys = [
+        format!("{}/data/file_0000.txt", WORKSPACE_NAME),
+        last,
+        format!("{}/data/file_0123.txt", WORKSPACE_NAME),
+        format!("{}/data/file_0", WORKSPACE_NAME),
+        format!("{}/Data/upper.txt", WORKSPACE_NAME),
+        format!("{}/data/dup.txt", WORKSPACE_NAME),
+    ];
+    let stub_path = test_dir.join(format!("sorted_manifest_stub{}", EXE_EXT));
+    let mut args = vec![dump_json_rlocation.as_str()];
+    args.extend(keys.iter().map(String::as_str));
+    finalize_stub(config, &stub_path, &args, &(0..args.len()).collect::<Vec<_>>())?;
+
+    let start = std::time::Instant::now();
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    let elapsed = start.elapsed();
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+    let dump = parse_dump_json(&stdout)?;
+    let argv: Vec<&str> = dump["argv"]
+        .as_array()
+        .ok_or_else(|| format!("No argv in {}", dump))?
+        .iter()
+        .filter_map(|arg| arg.as_str())
+        .collect();
+
+    // What a linear scan of the manifest finds
+    let expected: Vec<&str> = keys
+        .iter()
+        .map(|key| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str()).unwrap())
+        .collect();
+    if argv.get(1..) != Some(&expected[..]) {
+        return Err(format!("Expected {:?}, got {:?}", expected, argv));
+    }
+
+    report!("    {} entries, {} lookups in {:?}", entries.len() + 1, keys.len() + 1, elapsed);
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: RUNFILES_STUB_DEBUG warns when RUNFILES_DIR and RUNFILES_MANIFEST_FILE disagree
+fn test_inconsistent_runfiles_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: inconsistent_runfiles_env");
+
+    let test_dir = config.work_dir.join("test_inconsistent_runfiles_env");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "inconsistent")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    let other_dir = test_dir.join("other.runfiles");
+    fs::create_dir_all(&other_dir).map_err(|e| format!("Failed to create dir: {}", e))?;
+
+    let stub_path = test_dir.join(format!("inconsistent_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation], &[0])?;
+
+    let warning = "WARNING: RUNFILES_DIR and RUNFILES_MANIFEST_FILE describe different runfiles trees";
+    // The manifest's own directory, spelled with a trailing separator, is consistent
+    let same_dir = format!("{}{}", runfiles.runfiles_dir.display(), PATH_SEP);
+    for (runfiles_dir, debug, warns) in [
+        (other_dir.to_string_lossy().to_string(), "1", true),
+        (other_dir.to_string_lossy().to_string(), "", false),
+        (same_dir, "1", false),
+    ] {
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env("RUNFILES_DIR", &runfiles_dir)
+            .env("RUNFILES_STUB_DEBUG", debug)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Err(format!("Stub failed with RUNFILES_DIR={}: {}", runfiles_dir, stderr));
+        }
+        if stderr.contains(warning) != warns {
+            return Err(format!(
+                "Expected warning: {} with RUNFILES_DIR={} RUNFILES_STUB_DEBUG={:?}, stderr: {}",
+                warns, runfiles_dir, debug, stderr
+            ));
+        }
+        if warns && !stderr.contains(&format!("RUNFILES_DIR={} (ignored)", runfiles_dir)) {
+            return Err(format!("Warning doesn't name the ignored directory: {}", stderr));
+        }
+        // Either way the child sees the manifest's tree
+        let expected = format!("ENV:RUNFILES_DIR={}\n", runfiles.runfiles_dir.display());
+        if !stdout.contains(&expected) {
+            return Err(format!("Expected {:?} in output: {}", expected, stdout));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: With only RUNFILES_MANIFEST_FILE, the runfiles directory next to the manifest is exported too
+fn test_manifest_runfiles_dir(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: manifest_runfiles_dir");
+
+    let test_dir = config.work_dir.join("test_manifest_runfiles_dir");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "sibling")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    // Bazel also writes the manifest into the directory as MANIFEST
+    let inside = runfiles.runfiles_dir.join("MANIFEST");
+    fs::copy(&runfiles.manifest_path, &inside).map_err(|e| format!("Failed to copy manifest: {}", e))?;
+    // A manifest without a directory next to it
+    let bare = test_dir.join("bare_manifest");
+    fs::copy(&runfiles.manifest_path, &bare).map_err(|e| format!("Failed to copy manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("manifest_dir_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&dump_json_rlocation], &[0])?;
+
+    let dir = runfiles.runfiles_dir.to_string_lossy().to_string();
+    for (manifest, expected_dir) in [(&runfiles.manifest_path, Some(&dir)), (&inside, Some(&dir)), (&bare, None)] {
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", manifest)
+            .env_remove("RUNFILES_DIR")
+            .env_remove("JAVA_RUNFILES")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(format!("Stub failed with {}: {}", manifest.display(), String::from_utf8_lossy(&output.stderr)));
+        }
+        let env = &parse_dump_json(&stdout)?["env"];
+        let expected = expected_dir.map_or(serde_json::Value::Null, |dir| dir.as_str().into());
+        if env["RUNFILES_MANIFEST_FILE"] != *manifest.to_string_lossy()
+            || env["RUNFILES_DIR"] != expected
+            || env["JAVA_RUNFILES"] != expected
+        {
+            return Err(format!("Expected RUNFILES_DIR {} with {}, got {}", expected, manifest.display(), env));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: With only <stub>.runfiles_manifest next to the stub, the exported RUNFILES_DIR
+/// and JAVA_RUNFILES are the <stub>.runfiles directory derived from it
+fn test_sibling_manifest_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: sibling_manifest_env");
+
+    let test_dir = config.work_dir.join("test_sibling_manifest_env");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Named after the stub, so the manifest is <stub>.runfiles_manifest
+    let stub_name = format!("sibling_env_stub{}", EXE_EXT);
+    let mut runfiles = RunfilesSetup::new(&test_dir, &stub_name)
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    // The entry points at the binary outside the tree, so only the manifest finds it
+    let orch_rlocation = format!("{}/bin/orchestrator{}", WORKSPACE_NAME, EXE_EXT);
+    let orchestrator = config.test_binaries_dir.join(format!("orchestrator{}", EXE_EXT));
+    runfiles.entries.insert(orch_rlocation.clone(), orchestrator);
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(&stub_name);
+    finalize_stub(config, &stub_path, &[&orch_rlocation, "env-check"], &[0])?;
+
+    let output = Command::new(&stub_path)
+        .env_remove("RUNFILES_DIR")
+        .env_remove("RUNFILES_MANIFEST_FILE")
+        .env_remove("JAVA_RUNFILES")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(format!("Stub failed: {}{}", stdout, String::from_utf8_lossy(&output.stderr)));
+    }
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let reported = |name: &str| -> Result<String, String> {
+        let prefix = format!("ORCHESTRATOR:ENV_CHECK:{}=", name);
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+            .map(normalize)
+            .ok_or_else(|| format!("env-check did not report {}: {}", name, stdout))
+    };
+    let expected_dir = normalize(&runfiles.runfiles_dir.to_string_lossy());
+    let expected_manifest = normalize(&runfiles.manifest_path.to_string_lossy());
+    for (name, expected) in [
+        ("RUNFILES_MANIFEST_FILE", &expected_manifest),
+        ("RUNFILES_DIR", &expected_dir),
+        ("JAVA_RUNFILES", &expected_dir),
+    ] {
+        let value = reported(name)?;
+        if value != *expected {
+            return Err(format!("Expected {}={}, got {}", name, expected, value));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: In directory mode the stub also exports the directory's manifest
+/// (<dir>/MANIFEST, else <dir>_manifest) as RUNFILES_MANIFEST_FILE
+fn test_directory_mode_manifest_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: directory_mode_manifest_env");
+
+    let test_dir = config.work_dir.join("test_directory_mode_manifest_env");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "dir_mode")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+    let inside = runfiles.runfiles_dir.join("MANIFEST");
+    fs::copy(&runfiles.manifest_path, &inside).map_err(|e| format!("Failed to copy manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("dir_mode_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&dump_json_rlocation], &[0])?;
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let check = |expected_manifest: Option<&Path>| -> Result<(), String> {
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_DIR", &runfiles.runfiles_dir)
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .env_remove("JAVA_RUNFILES")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(format!("Stub failed: {}{}", stdout, String::from_utf8_lossy(&output.stderr)));
+        }
+        let env = &parse_dump_json(&stdout)?["env"];
+        let exported = |name: &str| env[name].as_str().map(normalize);
+        let expected_manifest = expected_manifest.map(|path| normalize(&path.to_string_lossy()));
+        if exported("RUNFILES_MANIFEST_FILE") != expected_manifest
+            || exported("RUNFILES_DIR") != Some(normalize(&runfiles.runfiles_dir.to_string_lossy()))
+        {
+            return Err(format!("Expected RUNFILES_MANIFEST_FILE {:?}, got {}", expected_manifest, env));
+        }
+        Ok(())
+    };
+
+    // MANIFEST inside the directory is preferred over <dir>_manifest next to it
+    check(Some(&inside))?;
+    fs::remove_file(&inside).map_err(|e| format!("Failed to remove manifest: {}", e))?;
+    check(Some(&runfiles.manifest_path))?;
+    fs::remove_file(&runfiles.manifest_path).map_err(|e| format!("Failed to remove manifest: {}", e))?;
+    check(None)?;
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Files missing from a runfiles directory are looked up in its manifest
+/// (<dir>_manifest or <dir>/MANIFEST) instead (Linux and macOS)
+fn test_directory_manifest_fallback(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: directory_manifest_fallback");
+
+    if cfg!(windows) {
+        report!("    SKIP (Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_directory_manifest_fallback");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let runfiles = RunfilesSetup::new(&test_dir, "incomplete")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env", WORKSPACE_NAME);
+    let data_rlocation = format!("{}/data/file.txt", WORKSPACE_NAME);
+
+    // The program is only listed in the manifest; the data file is in the tree, but
+    // the manifest points elsewhere, so the tree has to win for it
+    let outside = test_dir.join("outside");
+    fs::create_dir_all(&outside).map_err(|e| format!("Failed to create dir: {}", e))?;
+    let print_env = outside.join("print-env");
+    fs::copy(config.test_binaries_dir.join("print-env"), &print_env)
+        .map_err(|e| format!("Failed to copy print-env: {}", e))?;
+    let in_tree = runfiles.runfiles_dir.join(&data_rlocation);
+    fs::create_dir_all(in_tree.parent().unwrap()).map_err(|e| format!("Failed to create dir: {}", e))?;
+    fs::write(&in_tree, "data\n").map_err(|e| format!("Failed to write data file: {}", e))?;
+    let manifest = format!(
+        "{} {}\n{} {}\n",
+        print_env_rlocation,
+        print_env.display(),
+        data_rlocation,
+        outside.join("file.txt").display()
+    );
+
+    let stub_path = test_dir.join("fallback_stub");
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, &data_rlocation], &[0, 1])?;
+
+    let sibling = runfiles.manifest_path.clone();
+    let inside = runfiles.runfiles_dir.join("MANIFEST");
+    for (manifest_path, other) in [(&sibling, &inside), (&inside, &sibling)] {
+        let _ = fs::remove_file(other);
+        fs::write(manifest_path, &manifest).map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_DIR", &runfiles.runfiles_dir)
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() {
+            return Err(format!(
+                "Stub failed with {}: {}{}",
+                manifest_path.display(),
+                stdout,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        let expected = format!("ARGS:{}|{}", print_env.display(), in_tree.display());
+        if !stdout.lines().any(|line| line == expected) {
+            return Err(format!("Expected {:?} with {}: {}", expected, manifest_path.display(), stdout));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Placeholders survive `strip`, so stripped templates still finalize (macOS's
+/// __DATA,__runfiles section in particular; Linux runs the same check)
+fn test_stripped_template(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: stripped_template");
+
+    if cfg!(windows) {
+        report!("    SKIP (no strip on Windows)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_stripped_template");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let stripped_path = test_dir.join("stripped_template");
+    fs::copy(&config.template_path, &stripped_path)
+        .map_err(|e| format!("Failed to copy template: {}", e))?;
+    match Command::new("strip").arg(&stripped_path).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => return Err(format!("strip failed on the template: {}", status)),
+        Err(_) => {
+            report!("    SKIP (strip not available)");
+            return Ok(());
+        }
+    }
+
+    // Every placeholder of the template must still be there
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let stripped = fs::read(&stripped_path).map_err(|e| format!("Failed to read stripped template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let arg_placeholder = vec![b'@'; metadata_field(&template, metadata_pos, 28) as usize];
+    let count = |data: &[u8], pattern: &[u8]| {
+        let mut count = 0;
+        let mut pos = 0;
+        while let Some(offset) = find_bytes(&data[pos..], pattern) {
+            count += 1;
+            pos += offset + pattern.len();
+        }
+        count
+    };
+    for pattern in [&b"@@RUNFILES_"[..], &arg_placeholder] {
+        let (before, after) = (count(&template, pattern), count(&stripped, pattern));
+        if before != after {
+            return Err(format!(
+                "strip removed placeholders ({} of {} {:?} left); keep the placeholder section when linking",
+                after,
+                before,
+                String::from_utf8_lossy(&pattern[..pattern.len().min(16)])
+            ));
+        }
+    }
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "stripped_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join("stripped_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &stripped_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--", &add_rlocation, "5", "10",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer rejected the stripped template: {}", stderr));
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 || !stdout.contains("SUM:15") {
+        return Err(format!("Stub from the stripped template failed ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --zero-unused leaves no ARG placeholder behind, and the stub still runs
+fn test_zero_unused(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: zero_unused");
+
+    let test_dir = config.work_dir.join("test_zero_unused");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "zero_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let placeholder = vec![b'@'; metadata_field(&template, metadata_pos, 28) as usize];
+    let count_placeholders = |data: &[u8]| {
+        let mut count = 0;
+        let mut pos = 0;
+        while let Some(offset) = find_bytes(&data[pos..], &placeholder) {
+            count += 1;
+            pos += offset + placeholder.len();
+        }
+        count
+    };
+    let template_count = count_placeholders(&template);
+
+    let stub_path = test_dir.join(format!("zero_stub{}", EXE_EXT));
+    for zero_unused in [false, true] {
+        let template_arg = config.template_path.to_string_lossy();
+        let output = stub_path.to_string_lossy();
+        let mut args = vec!["--template", &template_arg, "--output", &output, "--transform", "0"];
+        if zero_unused {
+            args.push("--zero-unused");
+        }
+        args.extend_from_slice(&["--", &add_rlocation, "5", "10"]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let stub = fs::read(&stub_path).map_err(|e| format!("Failed to read stub: {}", e))?;
+        let expected = if zero_unused { 0 } else { template_count - 3 };
+        let count = count_placeholders(&stub);
+        if count != expected {
+            return Err(format!(
+                "zero_unused={}: expected {} ARG placeholders in the output, found {} (template has {})",
+                zero_unused, expected, count, template_count
+            ));
+        }
+
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+        if exit_code != 0 || !stdout.contains("SUM:15") {
+            return Err(format!(
+                "zero_unused={}: stub failed with exit code {}: {}{}",
+                zero_unused, exit_code, stdout, stderr
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --quiet finalization prints nothing, even when there is something to warn about
+fn test_quiet(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: quiet");
+
+    let test_dir = config.work_dir.join("test_quiet");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let stub_path = test_dir.join(format!("quiet_stub{}", EXE_EXT));
+    let template = config.template_path.to_string_lossy();
+    let output = stub_path.to_string_lossy();
+
+    // The trailing whitespace would normally produce a warning
+    let (stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &template,
+        "--output", &output,
+        "--quiet",
+        "--", "tool ",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Quiet finalization failed: {}", stderr));
+    }
+    if !stdout.is_empty() || !stderr.is_empty() {
+        return Err(format!("Quiet finalization produced output: stdout={:?} stderr={:?}", stdout, stderr));
+    }
+    if !stub_path.exists() {
+        return Err("Quiet finalization didn't write the stub".to_string());
+    }
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &template,
+        "--output", &output,
+        "--quiet", "--verbose",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("cannot be used with") {
+        return Err(format!("--quiet and --verbose were accepted together: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --dry-run reports the replacements at the offsets a real run patches, without writing
+fn test_dry_run(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: dry_run");
+
+    let test_dir = config.work_dir.join("test_dry_run");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let output = test_dir.join(format!("stub{}", EXE_EXT));
+    let _ = fs::remove_file(&output);
+
+    let template_arg = config.template_path.to_string_lossy();
+    let output_arg = output.to_string_lossy();
+    let args = ["--template", &template_arg, "--output", &output_arg, "--transform", "0", "--", "_main/bin/tool", "--flag"];
+    let (stdout, stderr, exit_code) = run_finalizer(config, &[&["--dry-run"], &args[..]].concat())?;
+    if exit_code != 0 {
+        return Err(format!("Dry run failed: {}", stderr));
+    }
+    if output.exists() {
+        return Err("Dry run wrote the output file".to_string());
+    }
+
+    // "<offset>  <placeholder>  <value>" per replacement
+    let offset_of = |name: &str| -> Result<usize, String> {
+        let line = stdout
+            .lines()
+            .find(|line| line.split_whitespace().nth(1) == Some(name))
+            .ok_or_else(|| format!("Dry run did not report {}: {}", name, stdout))?;
+        let offset = line.split_whitespace().next().unwrap_or_default();
+        usize::from_str_radix(offset.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid offset in {:?}", line))
+    };
+    let metadata_line = stdout.lines().find(|line| line.contains("METADATA")).unwrap_or_default();
+    if !metadata_line.contains("argc=2 transform_flags=0b1") {
+        return Err(format!("Unexpected metadata in dry run: {}", stdout));
+    }
+    if !stdout.contains("\"_main/bin/tool\"") || !stdout.contains("\"--flag\"") {
+        return Err(format!("Dry run is missing argument values: {}", stdout));
+    }
+    // All placeholders lie in the section named on the first line
+    if !stdout.starts_with(&format!("Section {} at ", PLACEHOLDER_SECTION)) || stdout.contains("(outside ") {
+        return Err(format!("Expected every placeholder in section {}: {}", PLACEHOLDER_SECTION, stdout));
+    }
+
+    // The reported offsets are where a real run writes the values
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    let stub = fs::read(&output).map_err(|e| format!("Failed to read stub: {}", e))?;
+    for (name, value) in [("ARG0", &b"_main/bin/tool\0"[..]), ("ARG1", b"--flag\0"), ("METADATA", METADATA_FINALIZED_MAGIC)] {
+        let offset = offset_of(name)?;
+        if !stub[offset..].starts_with(value) {
+            return Err(format!("{} is not at the reported offset {:#x}", name, offset));
+        }
+    }
+
+    // Errors surface in a dry run too
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let too_long = "x".repeat(metadata_field(&template, metadata_pos, 28) as usize);
+    let (stdout, stderr, exit_code) = run_finalizer(config, &["--dry-run", "--template", &template_arg, "--", &too_long])?;
+    if exit_code == 0 || !stderr.contains("Argument 0 too long") || !stdout.is_empty() {
+        return Err(format!("Dry run accepted a too long argument: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --inspect reads the metadata and arguments back out of a finalized stub
+fn test_inspect(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: inspect");
+
+    let test_dir = config.work_dir.join("test_inspect");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let stub_path = test_dir.join(format!("stub{}", EXE_EXT));
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0,2",
+        "--resolve-indirection",
+        "--", "_main/bin/tool", "--say=\"hi\"", "_main/data/input.txt",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let (stdout, stderr, exit_code) = run_finalizer(config, &["--inspect", &stub_path.to_string_lossy()])?;
+    if exit_code != 0 {
+        return Err(format!("Inspect failed: {}", stderr));
+    }
+    let section_line = format!("section:         {}\n", PLACEHOLDER_SECTION);
+    for expected in [
+        &section_line,
+        "argc:            3",
+        "transform_flags: 0b101",
+        "--export-runfiles-env --resolve-indirection",
+        "argv[0]:         \"_main/bin/tool\" (transformed)",
+        "argv[1]:         \"--say=\\\"hi\\\"\"\n",
+        "argv[2]:         \"_main/data/input.txt\" (transformed)",
+    ] {
+        if !stdout.contains(expected) {
+            return Err(format!("Inspect output is missing {:?}: {}", expected, stdout));
+        }
+    }
+
+    let (stdout, stderr, exit_code) = run_finalizer(config, &["--inspect", &stub_path.to_string_lossy(), "--json"])?;
+    let expected_args = concat!(
+        r#""args": [{"value": "_main/bin/tool", "transform": true}, "#,
+        r#"{"value": "--say=\"hi\"", "transform": false}, "#,
+        r#"{"value": "_main/data/input.txt", "transform": true}]"#,
+    );
+    let expected_section = format!("\"section\": \"{}\"", PLACEHOLDER_SECTION);
+    if exit_code != 0
+        || !stdout.contains(expected_args)
+        || !stdout.contains(r#""transform_flags": 5"#)
+        || !stdout.contains(&expected_section)
+    {
+        return Err(format!("Unexpected JSON ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    // A template has nothing to report
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &["--inspect", &config.template_path.to_string_lossy()])?;
+    if exit_code == 0 || !stderr.contains("This is a template") {
+        return Err(format!("Inspect accepted a template: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Random finalizer configurations read back unchanged through metadata::StubConfig,
+/// the reader behind --inspect
+fn test_config_round_trip(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: config_round_trip");
+
+    let test_dir = config.work_dir.join("test_config_round_trip");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let stub_path = test_dir.join(format!("stub{}", EXE_EXT));
+
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let metadata_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let arg_size = metadata::Metadata::decode(&template[metadata_pos..])
+        .ok_or("Metadata block is truncated")?
+        .arg_size as usize;
+
+    // Set ROUND_TRIP_SEED to replay a failure
+    let seed = match env::var("ROUND_TRIP_SEED") {
+        Ok(seed) => seed.parse().map_err(|_| format!("Invalid ROUND_TRIP_SEED {:?}", seed))?,
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64 | 1),
+    };
+    report!("    Seed: {}", seed);
+    // xorshift64
+    let mut state = seed;
+    let mut next = |bound: usize| -> usize {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % bound as u64) as usize
+    };
+
+    // Quotes, backslashes and spaces stress the command line; '{' is left out so
+    // no argument turns into a {RUNFILES_ROOT} token
+    let alphabet: Vec<char> = (' '..='~').filter(|&c| c != '{').chain(['é', '→']).collect();
+    for round in 0..32 {
+        let argc = 1 + next(10);
+        let transform_mask = next(1 << argc) as u32;
+        let export_env = next(2) == 1;
+        let args: Vec<String> = (0..argc)
+            .map(|_| {
+                // Every so often, an argument that fills its placeholder
+                let max_len = if next(8) == 0 { arg_size - 1 } else { next(64) };
+                let mut arg = String::new();
+                loop {
+                    let c = alphabet[next(alphabet.len())];
+                    if arg.len() + c.len_utf8() > max_len {
+                        break;
+                    }
+                    arg.push(c);
+                }
+                arg
+            })
+            .collect();
+
+        let transform: Vec<String> = (0..argc).filter(|i| transform_mask & (1 << i) != 0).map(|i| i.to_string()).collect();
+        let transform = transform.join(",");
+        let export = format!("--export-runfiles-env={}", export_env);
+        let mut finalizer_args = vec![
+            "--template", config.template_path.to_str().unwrap(),
+            "--output", stub_path.to_str().unwrap(),
+            "--quiet",
+            &export,
+        ];
+        if transform_mask != 0 {
+            finalizer_args.extend_from_slice(&["--transform", &transform]);
+        }
+        finalizer_args.push("--");
+        finalizer_args.extend(args.iter().map(String::as_str));
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &finalizer_args)?;
+        if exit_code != 0 {
+            return Err(format!("Round {} (seed {}): finalizer failed: {}", round, seed, stderr));
+        }
+
+        let stub = fs::read(&stub_path).map_err(|e| format!("Failed to read stub: {}", e))?;
+        let read = metadata::StubConfig::read(&stub, 0..stub.len())
+            .map_err(|e| format!("Round {} (seed {}): {}", round, seed, e))?;
+        let read_args: Vec<&[u8]> = read.args.iter().map(Vec::as_slice).collect();
+        let expected_args: Vec<&[u8]> = args.iter().map(String::as_bytes).collect();
+        if read.metadata.argc as usize != argc
+            || read.metadata.transform_flags != transform_mask
+            || read.metadata.arg_size as usize != arg_size
+            || (read.metadata.flags & metadata::FLAG_EXPORT_ENV != 0) != export_env
+            || read_args != expected_args
+        {
+            return Err(format!(
+                "Round {} (seed {}): expected argc={} transform_flags={} export={} args={:?}, read back {:?}",
+                round, seed, argc, transform_mask, export_env, args, read
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --export-java-runfiles=false and friends leave single variables out of the export
+fn test_selective_runfiles_export(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: selective_runfiles_export");
+
+    let test_dir = config.work_dir.join("test_selective_runfiles_export");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "selective")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let manifest = runfiles.manifest_path.to_string_lossy().to_string();
+    let dir = runfiles.runfiles_dir.to_string_lossy().to_string();
+    let parent_java = "/parent/java_runfiles";
+    let parent_dir = "/parent/runfiles_dir";
+    // Options, expected RUNFILES_MANIFEST_FILE, RUNFILES_DIR, JAVA_RUNFILES. An inherited
+    // RUNFILES_MANIFEST_FILE is the one the stub used, so it looks the same either way.
+    let cases: [(&[&str], [&str; 3]); 3] = [
+        (&["--export-java-runfiles=false"], [&manifest, &dir, parent_java]),
+        (&["--export-runfiles-dir=false"], [&manifest, parent_dir, &dir]),
+        (&["--export-runfiles-manifest=false", "--export-runfiles-dir=false"], [&manifest, parent_dir, &dir]),
+    ];
+    for (i, (options, expected)) in cases.iter().enumerate() {
+        let stub_path = test_dir.join(format!("selective_stub{}{}", i, EXE_EXT));
+        let mut args = vec![
+            "--template", config.template_path.to_str().unwrap(),
+            "--output", stub_path.to_str().unwrap(),
+            "--transform", "0",
+        ];
+        args.extend_from_slice(options);
+        args.extend_from_slice(&["--", &dump_json_rlocation]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed for {:?}: {}", options, stderr));
+        }
+
+        // The stub looks up through the manifest; the parent's other values are unrelated
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &manifest)
+            .env("RUNFILES_DIR", parent_dir)
+            .env("JAVA_RUNFILES", parent_java)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("Stub failed for {:?}: {}", options, String::from_utf8_lossy(&output.stderr)));
+        }
+        let env = &parse_dump_json(&String::from_utf8_lossy(&output.stdout))?["env"];
+        if env["RUNFILES_MANIFEST_FILE"] != expected[0] || env["RUNFILES_DIR"] != expected[1] || env["JAVA_RUNFILES"] != expected[2] {
+            return Err(format!("Unexpected environment for {:?}: {}", options, env));
+        }
+    }
+
+    // Without any export there's nothing to leave out
+    let stub_path = test_dir.join(format!("selective_stub_invalid{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", config.template_path.to_str().unwrap(),
+        "--output", stub_path.to_str().unwrap(),
+        "--export-runfiles-env=false",
+        "--export-java-runfiles=false",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("have no effect with --export-runfiles-env=false") {
+        return Err(format!("Expected the finalizer to reject the combination, got ({}): {}", exit_code, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --set-env variables reach the target and replace inherited values
+fn test_set_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: set_env");
+
+    let test_dir = config.work_dir.join("test_set_env");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "set_env")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let finalize = |stub_path: &Path, extra: &[&str]| -> Result<(String, i32), String> {
+        let mut args = vec![
+            "--template", config.template_path.to_str().unwrap(),
+            "--output", stub_path.to_str().unwrap(),
+            "--transform", "0",
+        ];
+        args.extend_from_slice(extra);
+        args.extend_from_slice(&["--", &dump_json_rlocation]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        Ok((stderr, exit_code))
+    };
+
+    // With and without the runfiles export, which builds the environment differently
+    for (i, export) in ["--export-runfiles-env=true", "--export-runfiles-env=false"].iter().enumerate() {
+        let stub_path = test_dir.join(format!("set_env_stub{}{}", i, EXE_EXT));
+        let (stderr, exit_code) = finalize(&stub_path, &[
+            export,
+            "--set-env", "SET_ENV_FIXED=fixed value",
+            "--set-env", "SET_ENV_EMPTY=",
+            "--set-env", "SET_ENV_EQUALS=a=b",
+        ])?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let output = Command::new(&stub_path)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env("SET_ENV_FIXED", "inherited")
+            .env("SET_ENV_OTHER", "inherited")
+            .env("DUMP_JSON_ENV", "SET_ENV_FIXED,SET_ENV_EMPTY,SET_ENV_EQUALS,SET_ENV_OTHER")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("Stub failed ({}): {}", export, String::from_utf8_lossy(&output.stderr)));
+        }
+        let env = &parse_dump_json(&String::from_utf8_lossy(&output.stdout))?["env"];
+        if env["SET_ENV_FIXED"] != "fixed value"
+            || env["SET_ENV_EMPTY"] != ""
+            || env["SET_ENV_EQUALS"] != "a=b"
+            || env["SET_ENV_OTHER"] != "inherited"
+        {
+            return Err(format!("Unexpected environment ({}): {}", export, env));
+        }
+    }
+
+    // Malformed, runfiles and oversized variables are rejected
+    let stub_path = test_dir.join(format!("set_env_invalid{}", EXE_EXT));
+    let too_long = format!("SET_ENV_LONG={}", "x".repeat(1024));
+    for (value, expected) in [
+        ("NO_EQUALS", "expected NAME=VALUE"),
+        ("=value", "expected NAME=VALUE"),
+        ("RUNFILES_DIR=/elsewhere", "--set-env can't set RUNFILES_DIR"),
+        (too_long.as_str(), "--set-env variables too long"),
+    ] {
+        let (stderr, exit_code) = finalize(&stub_path, &["--set-env", value])?;
+        if exit_code == 0 || !stderr.contains(expected) {
+            return Err(format!("Expected {:?} for --set-env {:?}, got ({}): {}", expected, value, exit_code, stderr));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --umask and --rlimit apply to the target, in exec and spawn mode
+fn test_process_limits(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: process_limits");
+
+    if cfg!(windows) {
+        report!("    SKIP (umask and resource limits are Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_process_limits");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let finalize = |stub_path: &Path, extra: &[&str]| -> Result<(String, i32), String> {
+        let mut args = vec![
+            "--template", config.template_path.to_str().unwrap(),
+            "--output", stub_path.to_str().unwrap(),
+            "--export-runfiles-env=false",
+        ];
+        args.extend_from_slice(extra);
+        // The target creates the file named by its first runtime argument
+        args.extend_from_slice(&["--", "/bin/sh", "-c", "touch \"$0\" && ulimit -n && ulimit -Hn"]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        Ok((stderr, exit_code))
+    };
+
+    for spawn in [false, true] {
+        if spawn && !cfg!(target_os = "linux") {
+            continue;
+        }
+        let mode = if spawn { "spawn" } else { "exec" };
+        let stub_path = test_dir.join(format!("limits_{}", mode));
+        let created = test_dir.join(format!("created_{}", mode));
+        let _ = fs::remove_file(&created);
+
+        let mut extra = vec!["--umask", "027", "--rlimit", "nofile=123"];
+        if spawn {
+            extra.push("--spawn");
+        }
+        let (stderr, exit_code) = finalize(&stub_path, &extra)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let output = Command::new(&stub_path)
+            .arg(&created)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("Stub failed ({}): {}", mode, String::from_utf8_lossy(&output.stderr)));
+        }
+        // Soft and hard limit
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stdout != "123\n123\n" {
+            return Err(format!("Expected nofile limits of 123 ({}), got: {:?}", mode, stdout));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = fs::metadata(&created)
+                .map_err(|e| format!("Target didn't create {} ({}): {}", created.display(), mode, e))?
+                .permissions()
+                .mode();
+            // touch creates files with 0666, minus the umask
+            if permissions & 0o777 != 0o640 {
+                return Err(format!("Expected mode 640 under umask 027 ({}), got {:o}", mode, permissions & 0o777));
+            }
+        }
+    }
+
+    // Malformed masks and limits are rejected
+    let stub_path = test_dir.join("limits_invalid");
+    for (option, value, expected) in [
+        ("--umask", "8", "Invalid --umask value"),
+        ("--umask", "1000", "Invalid --umask value"),
+        ("--rlimit", "nofile", "expected RESOURCE=VALUE"),
+        ("--rlimit", "files=10", "Unknown resource"),
+        ("--rlimit", "nofile=-1", "expected a number or \"unlimited\""),
+    ] {
+        let (stderr, exit_code) = finalize(&stub_path, &[option, value])?;
+        if exit_code == 0 || !stderr.contains(expected) {
+            return Err(format!("Expected {:?} for {} {:?}, got ({}): {}", expected, option, value, exit_code, stderr));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --runfiles-stub-version as the first runtime argument prints the version without runfiles
+fn test_version_sentinel(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: version_sentinel");
+
+    let test_dir = config.work_dir.join("test_version_sentinel");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "version_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let dump_json_rlocation = format!("{}/bin/dump-json{}", WORKSPACE_NAME, EXE_EXT);
+    let dump_json = config.test_binaries_dir.join(format!("dump-json{}", EXE_EXT));
+    runfiles.add_file(&dump_json_rlocation, &dump_json)
+        .map_err(|e| format!("Failed to add dump-json: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("version_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&dump_json_rlocation, "embedded"], &[0])?;
+
+    // No runfiles anywhere
+    let output = Command::new(&stub_path)
+        .arg("--runfiles-stub-version")
+        .arg("ignored")
+        .env_remove("RUNFILES_DIR")
+        .env_remove("RUNFILES_MANIFEST_FILE")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let version = lines.next().unwrap_or("");
+    let settings = lines.next().unwrap_or("");
+    if !output.status.success()
+        || version.strip_prefix("runfiles-stub ").is_none_or(str::is_empty)
+        || !settings.starts_with("metadata_version=1 arg_size=")
+        || !settings.ends_with(" argc=2 transform_flags=1 flags=1")
+    {
+        return Err(format!(
+            "Unexpected version output ({}): {}{}",
+            output.status, stdout, String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    // Anywhere else it is an ordinary argument
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &["first", "--runfiles-stub-version"], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed: {}", stderr));
+    }
+    let argv = &parse_dump_json(&stdout)?["argv"];
+    if argv.as_array().map(|argv| &argv[1..]) != Some(&["embedded".into(), "first".into(), "--runfiles-stub-version".into()][..]) {
+        return Err(format!("Expected the argument to be passed through, got {}", argv));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --keep-inherited-runfiles-env passes the parent's runfiles variables through a pass-through stub
+fn test_keep_inherited_runfiles_env(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: keep_inherited_runfiles_env");
+
+    let test_dir = config.work_dir.join("test_keep_inherited_runfiles_env");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "keep_env_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    runfiles.add_file_content(&format!("{}/data/file.txt", WORKSPACE_NAME), b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Nothing to transform: the program is embedded by absolute path
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    let print_env = print_env.to_string_lossy();
+    let finalize = |stub_path: &Path, extra: &[&str]| -> Result<(), String> {
+        let stub = stub_path.to_string_lossy();
+        let mut args = vec!["--template", config.template_path.to_str().unwrap(), "--output", &stub];
+        args.extend_from_slice(extra);
+        args.extend_from_slice(&["--", &print_env]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+        Ok(())
+    };
+    let run = |stub_path: &Path, manifest: Option<&Path>, dir: &Path| -> Result<String, String> {
+        let mut cmd = Command::new(stub_path);
+        cmd.env_remove("RUNFILES_MANIFEST_FILE").env_remove("JAVA_RUNFILES").env("RUNFILES_DIR", dir);
+        if let Some(manifest) = manifest {
+            cmd.env("RUNFILES_MANIFEST_FILE", manifest);
+        }
+        let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        if !output.status.success() {
+            return Err(format!("Stub failed: {}{}", stdout, String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(stdout)
+    };
+    // The parent's directory differs from the one next to the manifest
+    let parent_dir = test_dir.join("parent.runfiles");
+    fs::create_dir_all(&parent_dir).map_err(|e| format!("Failed to create dir: {}", e))?;
+    let inherited_dir = format!("ENV:RUNFILES_DIR={}", parent_dir.display());
+    let inherited_manifest = format!("ENV:RUNFILES_MANIFEST_FILE={}", runfiles.manifest_path.display());
+
+    // By default the stub exports what it found: the manifest, which it prefers, and
+    // the directory next to it
+    let plain_stub = test_dir.join(format!("plain_stub{}", EXE_EXT));
+    finalize(&plain_stub, &[])?;
+    let stdout = run(&plain_stub, Some(&runfiles.manifest_path), &parent_dir)?;
+    let derived_dir = format!("ENV:RUNFILES_DIR={}\n", runfiles.runfiles_dir.display());
+    if stdout.contains(&inherited_dir) || !stdout.contains(&derived_dir) {
+        return Err(format!("Stub without the option kept RUNFILES_DIR: {}", stdout));
+    }
+
+    let keep_stub = test_dir.join(format!("keep_stub{}", EXE_EXT));
+    finalize(&keep_stub, &["--keep-inherited-runfiles-env"])?;
+    let stdout = run(&keep_stub, Some(&runfiles.manifest_path), &parent_dir)?;
+    if !stdout.contains(&inherited_dir) || !stdout.contains(&inherited_manifest) {
+        return Err(format!("Expected {} and {} in output: {}", inherited_dir, inherited_manifest, stdout));
+    }
+
+    // Runfiles the stub can't find are passed on instead of being an error
+    let missing = test_dir.join("missing.runfiles");
+    let stdout = run(&keep_stub, None, &missing)?;
+    let expected = format!("ENV:RUNFILES_DIR={}", missing.display());
+    if !stdout.contains(&expected) {
+        return Err(format!("Expected {} in output: {}", expected, stdout));
+    }
+
+    // Exporting nothing leaves nothing to keep
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+        "--export-runfiles-env=false",
+        "--keep-inherited-runfiles-env",
+        "--", &print_env,
+    ])?;
+    if exit_code == 0 || !stderr.contains("--keep-inherited-runfiles-env has no effect") {
+        return Err(format!("Expected the option to be rejected without export: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A stub finalized to stdout and saved to a file works like one written with --output
+fn test_finalize_to_stdout(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: finalize_to_stdout");
+
+    let test_dir = config.work_dir.join("test_finalize_to_stdout");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "stdout_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let args = [print_env_rlocation.as_str(), "embedded"];
+
+    // Reference stub written with --output
+    let output_stub = test_dir.join(format!("output_stub{}", EXE_EXT));
+    finalize_stub(config, &output_stub, &args, &[0])?;
+
+    // Same stub written to stdout, as in `finalize-stub ... > stub`
+    let output = Command::new(&config.finalizer_path)
+        .arg("--template").arg(&config.template_path)
+        .args(["--transform", "0", "--"])
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run finalizer: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Finalizer failed: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+    let stdout_stub = test_dir.join(format!("stdout_stub{}", EXE_EXT));
+    fs::write(&stdout_stub, &output.stdout)
+        .map_err(|e| format!("Failed to write stub: {}", e))?;
+
+    // Unlike --output, stdout can't carry the executable bit
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&stdout_stub, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    let expected = fs::read(&output_stub).map_err(|e| format!("Failed to read stub: {}", e))?;
+    if output.stdout != expected {
+        return Err(format!(
+            "Stub written to stdout differs from --output ({} vs {} bytes)",
+            output.stdout.len(),
+            expected.len()
+        ));
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stdout_stub, &runfiles, &["runtime"], true)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+    if !stdout.lines().any(|line| line.starts_with("ARGS:") && line.ends_with("|embedded|runtime")) {
+        return Err(format!("Unexpected output: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --exec runs a program other than argv[0], which keeps its embedded value
+fn test_exec_path(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: exec_path");
+
+    let test_dir = config.work_dir.join("test_exec_path");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "exec_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    let data_rlocation = format!("{}/data/test.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let expected_data = normalize(&runfiles.entries[&data_rlocation].to_string_lossy());
+
+    // Exec as an rlocation path and as an absolute path; argv[0] is a plain name either way
+    let absolute_exec = print_env.to_string_lossy().to_string();
+    for exec in [print_env_rlocation.as_str(), absolute_exec.as_str()] {
+        let stub_path = test_dir.join(format!("exec_stub{}", EXE_EXT));
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &stub_path.to_string_lossy(),
+            "--exec", exec,
+            "--transform", "1",
+            "--", "custom-name", &data_rlocation,
+        ])?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &["runtime"], true)?;
+        if exit_code != 0 {
+            return Err(format!("Stub with --exec {} failed with exit code {}: {}", exec, exit_code, stderr));
+        }
+
+        let args_line = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+        let args: Vec<String> = args_line.split('|').map(normalize).collect();
+        if args != ["custom-name", expected_data.as_str(), "runtime"] {
+            return Err(format!(
+                "--exec {}: expected [custom-name, {}, runtime], got {:?}",
+                exec, expected_data, args
+            ));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --argv0 changes the name the target sees, not the program that runs
+fn test_argv0_override(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: argv0_override");
+
+    if cfg!(windows) {
+        report!("    SKIP (--argv0 is Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_argv0_override");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "argv0_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env", WORKSPACE_NAME);
+    let print_env = config.test_binaries_dir.join("print-env");
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join("argv0_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--argv0", &print_env_rlocation,
+        "--", &print_env_rlocation, "embedded",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    // The resolved program runs in both modes, seeing its rlocation path as argv[0]
+    for use_manifest in [true, false] {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &["runtime"], use_manifest)?;
+        let expected = format!("ARGS:{}|embedded|runtime", print_env_rlocation);
+        if exit_code != 0 || !stdout.lines().any(|line| line == expected) {
+            return Err(format!("Expected {:?} (manifest: {}, exit code {}): {}{}", expected, use_manifest, exit_code, stdout, stderr));
+        }
+    }
+
+    // --exec already keeps the first argument as argv[0]
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+        "--exec", &print_env_rlocation,
+        "--argv0", "name",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("cannot be used with") {
+        return Err(format!("Expected --argv0 to conflict with --exec: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --chdir runs the program in a directory of the runfiles tree
+fn test_chdir(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: chdir");
+
+    let test_dir = config.work_dir.join("test_chdir");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "chdir_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("chdir_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--chdir", WORKSPACE_NAME,
+        "--", &print_env_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    // The manifest only lists files, so both modes find the directory in the
+    // runfiles tree. Canonicalize both sides, as temp dirs may sit behind symlinks.
+    let expected = fs::canonicalize(runfiles.runfiles_dir.join(WORKSPACE_NAME))
+        .map_err(|e| format!("Failed to canonicalize runfiles dir: {}", e))?;
+    for use_manifest in [true, false] {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+        if exit_code != 0 {
+            return Err(format!("Stub failed with exit code {} (manifest: {}): {}", exit_code, use_manifest, stderr));
+        }
+        let cwd = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("CWD:"))
+            .ok_or_else(|| format!("No CWD line in output: {}", stdout))?;
+        let cwd = fs::canonicalize(cwd).map_err(|e| format!("Failed to canonicalize {}: {}", cwd, e))?;
+        if cwd != expected {
+            return Err(format!("Expected CWD {} (manifest: {}), got {}", expected.display(), use_manifest, cwd.display()));
+        }
+    }
+
+    // A missing directory is reported instead of running in the wrong place
+    let missing_stub = test_dir.join(format!("missing_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &missing_stub.to_string_lossy(),
+        "--transform", "0",
+        "--chdir", &format!("{}/missing", WORKSPACE_NAME),
+        "--", &print_env_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+    let (stdout, stderr, exit_code) = run_stub(config, &missing_stub, &runfiles, &[], false)?;
+    if exit_code == 0 || stdout.contains("CWD:") {
+        return Err(format!("Expected the stub to fail for a missing directory: {}{}", stdout, stderr));
+    }
+
+    // Both options set the working directory
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &test_dir.join(format!("rejected_stub{}", EXE_EXT)).to_string_lossy(),
+        "--chdir", WORKSPACE_NAME,
+        "--sandbox-root", "sandbox",
+        "--", &print_env_rlocation,
+    ])?;
+    if exit_code == 0 || !stderr.contains("cannot be used with") {
+        return Err(format!("Expected --chdir to conflict with --sandbox-root: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A failed execve names the error and the path it tried (Linux and macOS)
+fn test_exec_failure_reported(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: exec_failure_reported");
+
+    if cfg!(windows) {
+        report!("    SKIP (Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_exec_failure_reported");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let runfiles = RunfilesSetup::new(&test_dir, "exec_failure_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let not_executable = test_dir.join("not_executable");
+    fs::write(&not_executable, "data").map_err(|e| format!("Failed to write file: {}", e))?;
+    let unknown_format = test_dir.join("unknown_format");
+    fs::write(&unknown_format, b"\x00\x01\x02\x03").map_err(|e| format!("Failed to write file: {}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&not_executable, fs::Permissions::from_mode(0o644))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+        fs::set_permissions(&unknown_format, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    let cases = [
+        (test_dir.join("missing"), "no such file (ENOENT)"),
+        (not_executable, "permission denied (EACCES)"),
+        (unknown_format, "not an executable format (ENOEXEC)"),
+    ];
+    for (program, expected) in cases {
+        let program = program.to_string_lossy();
+        let stub_path = test_dir.join("exec_failure_stub");
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &stub_path.to_string_lossy(),
+            "--exec", &program,
+            "--", "tool",
+        ])?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+
+        let (_stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+        let expected = format!("ERROR: execve failed: {}: {}", expected, program);
+        if exit_code == 0 || !stderr.contains(&expected) {
+            return Err(format!("Expected {:?} (exit code {}): {}", expected, exit_code, stderr));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --runtime-args-position splices the runtime arguments between embedded ones
+fn test_runtime_args_position(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: runtime_args_position");
+
+    let test_dir = config.work_dir.join("test_runtime_args_position");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "runtime_pos_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("runtime_pos_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--runtime-args-position", "2",
+        "--", &print_env_rlocation, "pre", "post",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    // The runtime arguments land between the embedded ones; without any the order is unchanged
+    for (runtime_args, expected) in [(&["r1", "r2"][..], "|pre|r1|r2|post"), (&[][..], "|pre|post")] {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, runtime_args, true)?;
+        let args = stdout.lines().find(|line| line.starts_with("ARGS:")).unwrap_or("");
+        if exit_code != 0 || !args.ends_with(expected) {
+            return Err(format!("Expected arguments ending in {:?} (exit code {}): {}{}", expected, exit_code, stdout, stderr));
+        }
+    }
+
+    // Position 0 would put the runtime arguments before the program
+    for position in ["0", "4"] {
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+            "--runtime-args-position", position,
+            "--", &print_env_rlocation, "pre", "post",
+        ])?;
+        if exit_code == 0 || !stderr.contains("out of range") {
+            return Err(format!("Expected --runtime-args-position {} to be rejected: {}", position, stderr));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: {RUNFILES_ROOT} in an embedded argument expands to the runfiles directory
+fn test_runfiles_root_token(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: runfiles_root_token");
+
+    let test_dir = config.work_dir.join("test_runfiles_root_token");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "root_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("root_stub{}", EXE_EXT));
+    let data_arg = format!("--data-dir={{RUNFILES_ROOT}}/{}/data", WORKSPACE_NAME);
+    finalize_stub(config, &stub_path, &[&print_env_rlocation, &data_arg], &[0])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], false)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stderr));
+    }
+    let args_line = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("ARGS:"))
+        .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+    let expected = format!("--data-dir={}/{}/data", runfiles.runfiles_dir.to_string_lossy(), WORKSPACE_NAME);
+    let args: Vec<&str> = args_line.split('|').collect();
+    if args.len() != 2 || args[1] != expected {
+        return Err(format!("Expected argv[1] to be {:?}, got {:?}", expected, args));
+    }
+
+    // A manifest next to its runfiles directory names it...
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 || !stdout.contains(&format!("|{}", expected)) {
+        return Err(format!("Expected {:?} with the manifest ({}): {}{}", expected, exit_code, stdout, stderr));
+    }
+
+    // ... a bare one doesn't name a directory to substitute
+    let bare_manifest = test_dir.join("bare_manifest.txt");
+    fs::copy(&runfiles.manifest_path, &bare_manifest).map_err(|e| format!("Failed to copy manifest: {}", e))?;
+    let output = Command::new(&stub_path)
+        .env("RUNFILES_MANIFEST_FILE", &bare_manifest)
+        .env_remove("RUNFILES_DIR")
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() || !stderr.contains("{RUNFILES_ROOT} requires a runfiles directory") {
+        return Err(format!("Expected manifest-only runfiles to be rejected ({:?}): {}", output.status.code(), stderr));
+    }
+
+    // The token can't be combined with rlocation resolution
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0,1",
+        "--", &print_env_rlocation, &data_arg,
+    ])?;
+    if exit_code == 0 || !stderr.contains("can't contain {RUNFILES_ROOT}") {
+        return Err(format!("Finalizer accepted a transformed {{RUNFILES_ROOT}} argument: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Concurrent finalizations to the same output leave one complete stub behind
+fn test_concurrent_finalize(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: concurrent_finalize");
+
+    let test_dir = config.work_dir.join("test_concurrent_finalize");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "concurrent_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Two writers that produce different stubs
+    let writers = ["first", "second"];
+    let mut expected = Vec::new();
+    for writer in writers {
+        let reference = test_dir.join(format!("reference_{}{}", writer, EXE_EXT));
+        finalize_stub(config, &reference, &[&print_env_rlocation, writer], &[0])?;
+        expected.push(fs::read(&reference).map_err(|e| format!("Failed to read stub: {}", e))?);
+    }
+
+    let stub_path = test_dir.join(format!("concurrent_stub{}", EXE_EXT));
+    for round in 0..10 {
+        let children = writers
+            .iter()
+            .map(|writer| {
+                Command::new(&config.finalizer_path)
+                    .arg("--template").arg(&config.template_path)
+                    .arg("--output").arg(&stub_path)
+                    .args(["--transform", "0", "--", &print_env_rlocation, writer])
+                    .spawn()
+                    .map_err(|e| format!("Failed to run finalizer: {}", e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for mut child in children {
+            let status = child.wait().map_err(|e| format!("Failed to wait for finalizer: {}", e))?;
+            if !status.success() {
+                return Err(format!("Round {}: finalizer failed with {}", round, status));
+            }
+        }
+
+        let written = fs::read(&stub_path).map_err(|e| format!("Failed to read stub: {}", e))?;
+        if !expected.contains(&written) {
+            return Err(format!("Round {}: output is a mix of both finalizations", round));
+        }
+    }
+
+    let leftovers: Vec<_> = fs::read_dir(&test_dir)
+        .map_err(|e| format!("Failed to list test dir: {}", e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".tmp"))
+        .collect();
+    if !leftovers.is_empty() {
+        return Err(format!("Temporary files left behind: {:?}", leftovers));
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code != 0 || !stdout.contains("ARGS:") {
+        return Err(format!("Stub failed with exit code {}: {}{}", exit_code, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A missing output directory is an error unless --create-dirs creates it
+fn test_create_output_dirs(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: create_output_dirs");
+
+    let test_dir = config.work_dir.join("test_create_output_dirs");
+    // Start without the nested directories from an earlier run
+    let _ = fs::remove_dir_all(&test_dir);
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let output_dir = test_dir.join("nested").join("bin");
+    let output = output_dir.join(format!("stub{}", EXE_EXT));
+    let finalize = |extra: &[&str]| {
+        let template = config.template_path.to_string_lossy();
+        let output = output.to_string_lossy();
+        let mut args = vec!["--template", &template, "--output", &output];
+        args.extend_from_slice(extra);
+        args.extend(["--", "tool"]);
+        run_finalizer(config, &args)
+    };
+
+    let (_stdout, stderr, exit_code) = finalize(&[])?;
+    if exit_code == 0 || !stderr.contains("Output directory does not exist") || !stderr.contains("--create-dirs") {
+        return Err(format!("Expected a missing output directory error: {}", stderr));
+    }
+
+    // A dry run reports the directory without creating it
+    let (stdout, stderr, exit_code) = finalize(&["--create-dirs", "--dry-run"])?;
+    if exit_code != 0 || !stdout.contains("Would create output directory") || output_dir.exists() {
+        return Err(format!("Dry run with --create-dirs misbehaved (exit code {}): {}{}", exit_code, stdout, stderr));
+    }
+
+    // Nothing is created for a template that fails validation
+    let not_a_template = test_dir.join("not_a_template.txt");
+    fs::write(&not_a_template, "not an executable\n").map_err(|e| format!("Failed to write file: {}", e))?;
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &not_a_template.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--create-dirs",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || test_dir.join("nested").exists() {
+        return Err(format!("Invalid template left output directories behind (exit code {}): {}", exit_code, stderr));
+    }
+
+    let (_stdout, stderr, exit_code) = finalize(&["--create-dirs"])?;
+    if exit_code != 0 || !output.is_file() {
+        return Err(format!("--create-dirs did not produce the stub (exit code {}): {}", exit_code, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --mode sets the permissions of the output (Linux and macOS)
+fn test_output_mode(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: output_mode");
+
+    if cfg!(windows) {
+        report!("    SKIP (Linux and macOS only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_output_mode");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    for (mode, expected) in [(None, 0o755), (Some("644"), 0o644), (Some("0750"), 0o750)] {
+        let output = test_dir.join(format!("stub_{:o}", expected));
+        let mut args = vec![
+            "--template".to_string(), config.template_path.to_string_lossy().into_owned(),
+            "--output".to_string(), output.to_string_lossy().into_owned(),
+        ];
+        if let Some(mode) = mode {
+            args.extend(["--mode".to_string(), mode.to_string()]);
+        }
+        args.extend(["--".to_string(), "tool".to_string()]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed with --mode {:?}: {}", mode, stderr));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let actual = fs::metadata(&output)
+                .map_err(|e| format!("Failed to stat output: {}", e))?
+                .permissions()
+                .mode() & 0o7777;
+            if actual != expected {
+                return Err(format!("Expected mode {:o} with --mode {:?}, got {:o}", expected, mode, actual));
+            }
+        }
+    }
+
+    for (mode, expected) in [("rwx", "expected octal permissions"), ("4755", "setuid, setgid or sticky")] {
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+            "--mode", mode,
+            "--", "tool",
+        ])?;
+        if exit_code == 0 || !stderr.contains(expected) {
+            return Err(format!("Invalid --mode {:?} was accepted: {}", mode, stderr));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: A manifest with more entries than the stub can hold is an error, not silently cut off
+fn test_manifest_entry_limit(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: manifest_entry_limit");
+
+    let test_dir = config.work_dir.join("test_manifest_entry_limit");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "entry_limit_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // More entries than any platform's limit
+    let mut manifest = fs::read_to_string(&runfiles.manifest_path)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    for i in 0..2000 {
+        manifest.push_str(&format!("{}/padding/{} /padding/{}\n", WORKSPACE_NAME, i, i));
+    }
+    fs::write(&runfiles.manifest_path, manifest)
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let stub_path = test_dir.join(format!("entry_limit_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env_rlocation], &[0])?;
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code == 0 || !stderr.contains("entries") {
+        return Err(format!(
+            "Expected an error about too many manifest entries (exit code {}): {}{}",
+            exit_code, stdout, stderr
+        ));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --allow-rlocation-prefix limits which transformed arguments are resolved
+fn test_rlocation_prefixes(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: rlocation_prefixes");
+
+    let test_dir = config.work_dir.join("test_rlocation_prefixes");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "prefix_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    let data_rlocation = format!("{}/data/test.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let normalize = |path: &str| path.replace('\\', "/");
+    let expected_data = normalize(&runfiles.entries[&data_rlocation].to_string_lossy());
+    let bin_prefix = format!("{}/bin/", WORKSPACE_NAME);
+    let data_prefix = format!("{}/data/", WORKSPACE_NAME);
+    let stub_path = test_dir.join(format!("prefix_stub{}", EXE_EXT));
+
+    let finalize = |extra: &[&str]| -> Result<(), String> {
+        let template = config.template_path.to_string_lossy();
+        let output = stub_path.to_string_lossy();
+        let mut finalizer_args = vec!["--template", &template, "--output", &output, "--transform", "0,1"];
+        finalizer_args.extend_from_slice(extra);
+        finalizer_args.extend_from_slice(&["--", &print_env_rlocation, &data_rlocation]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &finalizer_args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+        Ok(())
+    };
+
+    // Both prefixes allowed: both arguments are resolved; only bin/ allowed: the
+    // data path is passed through unresolved
+    for (prefixes, expected) in [
+        (vec![bin_prefix.as_str(), data_prefix.as_str()], expected_data.as_str()),
+        (vec![bin_prefix.as_str()], data_rlocation.as_str()),
+    ] {
+        let mut extra = Vec::new();
+        for prefix in &prefixes {
+            extra.extend_from_slice(&["--allow-rlocation-prefix", prefix]);
+        }
+        finalize(&extra)?;
+
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+        if exit_code != 0 {
+            return Err(format!("Stub allowing {:?} failed with exit code {}: {}{}", prefixes, exit_code, stdout, stderr));
+        }
+        let args_line = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+        let args: Vec<String> = args_line.split('|').map(normalize).collect();
+        if args.get(1).map(String::as_str) != Some(expected) {
+            return Err(format!("Allowing {:?}: expected argument 1 to be {}, got {:?}", prefixes, expected, args));
+        }
+    }
+
+    // Strict mode rejects the disallowed path instead
+    finalize(&["--allow-rlocation-prefix", &bin_prefix, "--strict-rlocation-prefixes"])?;
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    if exit_code == 0 || !stderr.contains("outside the allowed rlocation prefixes") {
+        return Err(format!(
+            "Expected the strict stub to reject argument 1 (exit code {}): {}{}",
+            exit_code, stdout, stderr
+        ));
+    }
+    // Diagnostics go to stderr, never into the output a caller may be parsing
+    if !stdout.is_empty() {
+        return Err(format!("Stub error leaked into stdout: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer refuses to overwrite its template
+fn test_template_overwrite_rejected(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: template_overwrite_rejected");
+
+    let test_dir = config.work_dir.join("test_template_overwrite");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Work on a copy so a failure can't destroy the shared template
+    let template = test_dir.join(format!("template{}", EXE_EXT));
+    fs::copy(&config.template_path, &template)
+        .map_err(|e| format!("Failed to copy template: {}", e))?;
+    let original = fs::read(&template).map_err(|e| format!("Failed to read template: {}", e))?;
+
+    // Different spellings of the template path
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut outputs = vec![
+        template.clone(),
+        test_dir.join(".").join(format!("template{}", EXE_EXT)),
+        test_dir.join("..").join("test_template_overwrite").join(format!("template{}", EXE_EXT)),
+    ];
+
+    #[cfg(unix)]
+    {
+        let link = test_dir.join("template_link");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&template, &link)
+            .map_err(|e| format!("Failed to create symlink: {}", e))?;
+        outputs.push(link);
+    }
+
+    for output in &outputs {
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &template.to_string_lossy(),
+            "--output", &output.to_string_lossy(),
+            "--", "tool", "arg",
+        ])?;
+
+        if exit_code == 0 {
+            return Err(format!("Finalizer accepted output {} equal to the template", output.display()));
+        }
+        if !stderr.contains("Output path cannot be the same as template path") {
+            return Err(format!("Unexpected finalizer error for {}: {}", output.display(), stderr));
+        }
+
+        let after = fs::read(&template).map_err(|e| format!("Failed to read template: {}", e))?;
+        if after != original {
+            return Err(format!("Template was modified when finalizing to {}", output.display()));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer rejects a template that isn't an executable
+fn test_template_not_executable(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: template_not_executable");
+
+    let test_dir = config.work_dir.join("test_template_not_executable");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // A text file, even one mentioning the placeholders, isn't a template
+    let template = test_dir.join("template.txt");
+    fs::write(&template, "Not a binary: @@RUNFILES_ARGC@@ @@RUNFILES_METADATA@@\n")
+        .map_err(|e| format!("Failed to write template: {}", e))?;
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &template.to_string_lossy(),
+        "--output", &test_dir.join(format!("stub{}", EXE_EXT)).to_string_lossy(),
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("is not a recognized executable template") {
+        return Err(format!("Expected the text file to be rejected ({}): {}", exit_code, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: The hidden completions subcommand prints a script for each supported shell
+fn test_shell_completions(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: shell_completions");
+
+    for (shell, marker) in [
+        ("bash", "_finalize-stub()"),
+        ("zsh", "#compdef finalize-stub"),
+        ("fish", "complete -c finalize-stub"),
+        ("powershell", "Register-ArgumentCompleter"),
+    ] {
+        let (stdout, stderr, exit_code) = run_finalizer(config, &["completions", shell])?;
+        if exit_code != 0 || !stdout.contains(marker) || !stdout.contains("transform-value") {
+            return Err(format!("Unexpected {} completions ({}): {}{}", shell, exit_code, stdout, stderr));
+        }
+    }
+
+    // The subcommand stays out of the help text
+    let (stdout, _stderr, _exit_code) = run_finalizer(config, &["--help"])?;
+    if stdout.contains("completions") {
+        return Err(format!("--help lists the completions subcommand: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --inline-transform-marker selects the same arguments as the equivalent --transform
+fn test_inline_transform_marker(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: inline_transform_marker");
+
+    let test_dir = config.work_dir.join("test_inline_transform_marker");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "inline_marker_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let add_rlocation = format!("{}/bin/add-numbers{}", WORKSPACE_NAME, EXE_EXT);
+    let add_binary = config.test_binaries_dir.join(format!("add-numbers{}", EXE_EXT));
+    runfiles.add_file(&add_rlocation, &add_binary)
+        .map_err(|e| format!("Failed to add add-numbers: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let indices_stub = test_dir.join(format!("indices_stub{}", EXE_EXT));
+    finalize_stub(config, &indices_stub, &[&add_rlocation, "100", "200"], &[0])?;
+
+    let marked_rlocation = format!("@{}", add_rlocation);
+    let marker_stub = test_dir.join(format!("marker_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &marker_stub.to_string_lossy(),
+        "--inline-transform-marker", "@",
+        "--", &marked_rlocation, "100", "200",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let expected = fs::read(&indices_stub).map_err(|e| format!("Failed to read stub: {}", e))?;
+    let actual = fs::read(&marker_stub).map_err(|e| format!("Failed to read stub: {}", e))?;
+    if actual != expected {
+        return Err("Stub finalized with an inline marker differs from the one finalized with --transform 0".to_string());
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &marker_stub, &runfiles, &[], true)?;
+    if exit_code != 0 || !stdout.contains("SUM:300") {
+        return Err(format!("Unexpected output ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    // Selecting arguments both ways at once is rejected
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+        "--inline-transform-marker", "@",
+        "--transform", "0",
+        "--", &marked_rlocation,
+    ])?;
+    if exit_code == 0 || !stderr.contains("cannot be used with") {
+        return Err(format!("Expected --transform to conflict with --inline-transform-marker: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: --transform-value resolves only the value of a `--flag=rlocation` argument
+fn test_transform_flag_value(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: transform_flag_value");
+
+    let test_dir = config.work_dir.join("test_transform_flag_value");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "flag_value_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let print_env_rlocation = format!("{}/bin/print-env{}", WORKSPACE_NAME, EXE_EXT);
+    let print_env_binary = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    runfiles.add_file(&print_env_rlocation, &print_env_binary)
+        .map_err(|e| format!("Failed to add print-env: {}", e))?;
+    let data_rlocation = format!("{}/data/x.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"flag value data")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    let data_arg = format!("--data={}", data_rlocation);
+    let stub_path = test_dir.join(format!("stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0",
+        "--transform-value", "1",
+        "--", &print_env_rlocation, &data_arg,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    for use_manifest in [true, false] {
+        let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], use_manifest)?;
+        if exit_code != 0 {
+            return Err(format!("Stub failed ({}): {}{}", exit_code, stdout, stderr));
+        }
+        let args = stdout.lines().find_map(|line| line.strip_prefix("ARGS:"))
+            .ok_or_else(|| format!("No ARGS line in output: {}", stdout))?;
+        let value = args.split('|').find_map(|arg| arg.strip_prefix("--data="))
+            .ok_or_else(|| format!("--data= argument missing: {}", args))?;
+        if value == data_rlocation || fs::read_to_string(value).ok().as_deref() != Some("flag value data") {
+            return Err(format!("--data= value was not resolved (manifest: {}): {}", use_manifest, args));
+        }
+    }
+
+    let (stdout, _stderr, _exit_code) = run_finalizer(config, &["--inspect", &stub_path.to_string_lossy()])?;
+    let expected = format!("argv[1]:         {:?} (value transformed)", data_arg);
+    if !stdout.contains(&expected) {
+        return Err(format!("Inspect output is missing {:?}: {}", expected, stdout));
+    }
+
+    // An argument without '=' has no value to resolve
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &test_dir.join("rejected_stub").to_string_lossy(),
+        "--transform-value", "1",
+        "--", &print_env_rlocation, "--data",
+    ])?;
+    if exit_code == 0 || !stderr.contains("Argument 1 has no `=` to split for --transform-value") {
+        return Err(format!("Expected an argument without '=' to be rejected: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer rejects --transform indices past the last argument
+fn test_transform_index_out_of_range(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: transform_index_out_of_range");
+
+    let test_dir = config.work_dir.join("test_transform_index_out_of_range");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let output = test_dir.join(format!("stub{}", EXE_EXT));
+
+    let cases: [(&str, &str); 2] = [
+        ("5", "--transform 5 is out of range: 2 arguments given (valid indices: 0-1)"),
+        ("0,1,3,4", "--transform 3 is out of range: 2 arguments given (valid indices: 0-1)"),
+    ];
+    for (transform, message) in cases {
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+            "--template", &config.template_path.to_string_lossy(),
+            "--output", &output.to_string_lossy(),
+            "--transform", transform,
+            "--", "tool", "arg",
+        ])?;
+        if exit_code == 0 || !stderr.contains(message) {
+            return Err(format!("Finalizer accepted --transform {} for 2 arguments ({}): {}", transform, exit_code, stderr));
+        }
+    }
+    if output.exists() {
+        return Err("Finalizer wrote a stub despite the invalid --transform".to_string());
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Self-extracting stub runs with only its bundled runfiles
+fn test_self_extracting(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: self_extracting");
+
+    let test_dir = config.work_dir.join("test_self_extracting");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // Build the runfiles tree that gets bundled into the stub
+    let mut runfiles = RunfilesSetup::new(&test_dir, "bundle")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    let data_rlocation = format!("{}/data/test.txt", WORKSPACE_NAME);
+    runfiles.add_file(&hash_rlocation, &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    runfiles.add_file_content(&data_rlocation, b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add test.txt: {}", e))?;
+
+    let stub_path = test_dir.join(format!("self_extracting_stub{}", EXE_EXT));
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--transform", "0,1",
+        "--self-extracting", &runfiles.runfiles_dir.to_string_lossy(),
+        "--", &hash_rlocation, &data_rlocation,
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    // Remove the original tree so only the bundled copy can satisfy the lookups
+    fs::remove_dir_all(&runfiles.runfiles_dir)
+        .map_err(|e| format!("Failed to remove runfiles dir: {}", e))?;
+
+    let temp_dir = test_dir.join("tmp");
+    fs::create_dir_all(&temp_dir).map_err(|e| format!("Failed to create temp dir: {}", e))?;
+
+    // Run twice: the first run extracts, the second reuses the extracted tree
+    for run in ["extract", "reuse"] {
+        let mut cmd = Command::new(&stub_path);
+        cmd.env_remove("RUNFILES_DIR");
+        cmd.env_remove("RUNFILES_MANIFEST_FILE");
+        cmd.env("TMPDIR", &temp_dir);
+        cmd.env("TMP", &temp_dir);
+        cmd.env("TEMP", &temp_dir);
+
+        let output = cmd.output().map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let exit_code = output.status.code().unwrap_or(-1);
+
+        if exit_code != 0 {
+            return Err(format!("Stub ({}) failed with exit code {}: {}", run, exit_code, stdout));
+        }
+
+        // SHA256 of "Hello, World!\n"
+        if !stdout.to_lowercase().contains("c98c24b677eff") {
+            return Err(format!("Unexpected output ({}): {}", run, stdout));
+        }
+    }
+
+    // Exactly one extracted tree, published under its final name
+    let extracted: Vec<_> = fs::read_dir(&temp_dir)
+        .map_err(|e| format!("Failed to read temp dir: {}", e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    if extracted.len() != 1 || !extracted[0].starts_with("runfiles-stub-") || extracted[0].contains('.') {
+        return Err(format!("Unexpected extraction directories: {:?}", extracted));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // The extracted tree is private to the user
+        let mode = fs::metadata(temp_dir.join(&extracted[0]))
+            .map_err(|e| format!("Failed to stat extracted tree: {}", e))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            return Err(format!("Extracted tree has mode {:o}, expected 700", mode & 0o777));
+        }
+
+        // A world-writable tree planted under the final name (as another user could in
+        // a shared /tmp) must not be used
+        let squat_temp_dir = test_dir.join("squat_tmp");
+        let squat = squat_temp_dir.join(&extracted[0]);
+        let squat_data = squat.join(&data_rlocation);
+        let squat_binary = squat.join(&hash_rlocation);
+        for file in [&squat_data, &squat_binary] {
+            fs::create_dir_all(file.parent().unwrap())
+                .map_err(|e| format!("Failed to create squatted tree: {}", e))?;
+        }
+        fs::copy(&hash_binary, &squat_binary)
+            .map_err(|e| format!("Failed to copy hash-file: {}", e))?;
+        fs::write(&squat_data, b"planted\n").map_err(|e| format!("Failed to write planted file: {}", e))?;
+        fs::set_permissions(&squat, fs::Permissions::from_mode(0o777))
+            .map_err(|e| format!("Failed to chmod squatted tree: {}", e))?;
+
+        let output = Command::new(&stub_path)
+            .env_remove("RUNFILES_DIR")
+            .env_remove("RUNFILES_MANIFEST_FILE")
+            .env("TMPDIR", &squat_temp_dir)
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if !output.status.success() || !stdout.to_lowercase().contains("c98c24b677eff") {
+            return Err(format!("Stub used the squatted tree (exit {:?}): {}", output.status.code(), stdout));
+        }
+        if fs::read(&squat_data).ok().as_deref() != Some(&b"planted\n"[..]) {
+            return Err("Stub modified the squatted tree".to_string());
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Manifest redirects are followed once with --resolve-indirection
+fn test_manifest_indirection(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: manifest_indirection");
+
+    let test_dir = config.work_dir.join("test_manifest_indirection");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "indirection_stub")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+
+    let hash_binary = config.test_binaries_dir.join(format!("hash-file{}", EXE_EXT));
+    let hash_rlocation = format!("{}/bin/hash-file{}", WORKSPACE_NAME, EXE_EXT);
+    let real_rlocation = format!("{}/data/real.txt", WORKSPACE_NAME);
+    let alias_rlocation = format!("{}/data/alias.txt", WORKSPACE_NAME);
+    let cycle_rlocation = format!("{}/data/cycle.txt", WORKSPACE_NAME);
+    runfiles.add_file(&hash_rlocation, &hash_binary)
+        .map_err(|e| format!("Failed to add hash-file: {}", e))?;
+    runfiles.add_file_content(&real_rlocation, b"Hello, World!\n")
+        .map_err(|e| format!("Failed to add real.txt: {}", e))?;
+    runfiles.add_redirect(&alias_rlocation, &real_rlocation);
+    runfiles.add_redirect(&cycle_rlocation, &cycle_rlocation);
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Runs a stub from an empty directory, so relative paths can't accidentally resolve
+    let run_in_empty_dir = |stub_path: &Path| -> Result<(String, i32), String> {
+        let empty_dir = test_dir.join("empty");
+        fs::create_dir_all(&empty_dir).map_err(|e| format!("Failed to create dir: {}", e))?;
+        let output = Command::new(stub_path)
+            .current_dir(&empty_dir)
+            .env("RUNFILES_MANIFEST_FILE", &runfiles.manifest_path)
+            .env_remove("RUNFILES_DIR")
+            .output()
+            .map_err(|e| format!("Failed to run stub: {}", e))?;
+        Ok((String::from_utf8_lossy(&output.stdout).to_string(), output.status.code().unwrap_or(-1)))
+    };
+
+    let finalize = |name: &str, data_rlocation: &str, indirection: bool| -> Result<PathBuf, String> {
+        let stub_path = test_dir.join(format!("{}{}", name, EXE_EXT));
+        let template = config.template_path.to_string_lossy();
+        let output = stub_path.to_string_lossy();
+        let mut args = vec!["--template", &template, "--output", &output, "--transform", "0,1"];
+        if indirection {
+            args.push("--resolve-indirection");
+        }
+        args.extend(["--", &hash_rlocation, data_rlocation]);
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed: {}", stderr));
+        }
+        Ok(stub_path)
+    };
+
+    // With indirection the redirect resolves to the real file
+    let stub_path = finalize("redirect_stub", &alias_rlocation, true)?;
+    let (stdout, exit_code) = run_in_empty_dir(&stub_path)?;
+    if exit_code != 0 {
+        return Err(format!("Stub failed with exit code {}: {}", exit_code, stdout));
+    }
+    // SHA256 of "Hello, World!\n"
+    if !stdout.to_lowercase().contains("c98c24b677eff") {
+        return Err(format!("Unexpected output: {}", stdout));
+    }
+
+    // Without the flag the relative value is passed through unchanged
+    let stub_path = finalize("no_redirect_stub", &alias_rlocation, false)?;
+    let (stdout, exit_code) = run_in_empty_dir(&stub_path)?;
+    if exit_code == 0 {
+        return Err(format!("Redirect was followed without --resolve-indirection: {}", stdout));
+    }
+
+    // A self-referencing entry terminates instead of looping
+    let stub_path = finalize("cycle_stub", &cycle_rlocation, true)?;
+    let (stdout, exit_code) = run_in_empty_dir(&stub_path)?;
+    if exit_code == 0 {
+        return Err(format!("Self-referencing redirect unexpectedly resolved: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Start a spawn-mode stub, send it `signal` and return the child's exit code and output
+///
+/// The stub's target must print "PPID=$PPID" first, which is checked against the
+/// stub's PID to make sure the target really runs as a child.
+fn signal_spawned_stub(stub_path: &Path, signal: &str) -> Result<(i32, String), String> {
+    let mut child = Command::new(stub_path)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+
+    let mut stdout = std::io::BufReader::new(child.stdout.take().ok_or("No stdout")?);
+    let mut first_line = String::new();
+    std::io::BufRead::read_line(&mut stdout, &mut first_line)
+        .map_err(|e| format!("Failed to read stub output: {}", e))?;
+
+    let expected = format!("PPID={}", child.id());
+    if first_line.trim() != expected {
+        let _ = child.kill();
+        return Err(format!("Target is not a child of the stub: got '{}', expected '{}'", first_line.trim(), expected));
+    }
+
+    let status = Command::new("kill")
+        .args([&format!("-{}", signal), &child.id().to_string()])
+        .status()
+        .map_err(|e| format!("Failed to run kill: {}", e))?;
+    if !status.success() {
+        let _ = child.kill();
+        return Err(format!("Failed to send SIG{} to the stub", signal));
+    }
+
+    let mut rest = String::new();
+    std::io::Read::read_to_string(&mut stdout, &mut rest)
+        .map_err(|e| format!("Failed to read stub output: {}", e))?;
+    let exit_code = child.wait()
+        .map_err(|e| format!("Failed to wait for stub: {}", e))?
+        .code()
+        .unwrap_or(-1);
+
+    Ok((exit_code, rest))
+}
+
+/// Test: Spawn mode runs the target as a child and forwards SIGTERM and SIGINT to it
+fn test_spawn_signal_forwarding(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: spawn_signal_forwarding");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (spawn mode is Linux only)");
+        return Ok(());
+    }
 
-    for (_name, test_fn) in &tests {
-        match test_fn(&config) {
-            Ok(()) => {
-                passed += 1;
+    let test_dir = config.work_dir.join("test_spawn_signal_forwarding");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // The child reports its parent (the stub, if spawned) and exits 42 on SIGTERM, 43 on SIGINT
+    let stub_path = test_dir.join("spawn_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--spawn",
+        "--export-runfiles-env=false",
+        "--",
+        "/bin/sh", "-c",
+        "trap 'echo TERM; exit 42' TERM; trap 'echo INT; exit 43' INT; echo PPID=$PPID; while :; do sleep 0.1; done",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    for (signal, expected_code) in [("TERM", 42), ("INT", 43)] {
+        let (exit_code, output) = signal_spawned_stub(&stub_path, signal)?;
+        if !output.contains(signal) || exit_code != expected_code {
+            return Err(format!("SIG{} was not forwarded: exit code {}, output: {}", signal, exit_code, output));
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: In spawn mode the stub stays the target's parent and exits with its status
+fn test_spawn_stable_pid(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: spawn_stable_pid");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (spawn mode is Linux only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_spawn_stable_pid");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let stub_path = test_dir.join("spawn_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--spawn",
+        "--export-runfiles-env=false",
+        "--",
+        "/bin/sh", "-c", "echo PID=$$ PPID=$PPID; exit 5",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let child = Command::new(&stub_path)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stub_pid = child.id();
+    let output = child.wait_with_output().map_err(|e| format!("Failed to wait for stub: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // The target runs under its own PID with the stub's PID as its parent
+    let expected_ppid = format!("PPID={}", stub_pid);
+    let own_pid = format!("PID={} ", stub_pid);
+    if !stdout.contains(&expected_ppid) || stdout.contains(&own_pid) {
+        return Err(format!("Expected the target to be a child of the stub (PID {}): {}", stub_pid, stdout));
+    }
+    if output.status.code() != Some(5) {
+        return Err(format!("Expected exit code 5, got {:?}", output.status.code()));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Spawn mode still reports the exit code when started with SIGCHLD ignored
+fn test_spawn_ignored_sigchld(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: spawn_ignored_sigchld");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (spawn mode is Linux only)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_spawn_ignored_sigchld");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let stub_path = test_dir.join("spawn_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--spawn",
+        "--export-runfiles-env=false",
+        "--",
+        "/bin/sh", "-c", "exit 7",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    // An ignored SIGCHLD survives exec, so bash hands it to the stub. The outer
+    // timeout turns a hang (the exit status was lost) into a failure.
+    let output = Command::new("timeout")
+        .args(["--signal=KILL", "10", "bash", "-c", "trap '' CHLD; exec \"$0\""])
+        .arg(&stub_path)
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let exit_code = output.status.code().unwrap_or(-1);
+
+    if exit_code != 7 {
+        return Err(format!(
+            "Expected exit code 7, got {}: {}",
+            exit_code,
+            String::from_utf8_lossy(&output.stdout)
+        ));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Spawn mode as PID 1 reaps orphaned grandchildren
+fn test_spawn_pid1_reaping(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: spawn_pid1_reaping");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (spawn mode is Linux only)");
+        return Ok(());
+    }
+
+    // Simulate a container: a new PID namespace makes the stub PID 1
+    let unshare = ["--user", "--map-root-user", "--pid", "--fork", "--mount-proc"];
+    let probe = Command::new("unshare").args(unshare).arg("true").output();
+    if !matches!(probe, Ok(ref output) if output.status.success()) {
+        report!("    SKIP (unshare with user and PID namespaces unavailable)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_spawn_pid1_reaping");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // The child orphans a short-lived grandchild, waits for it to exit, then lists zombies
+    let stub_path = test_dir.join("pid1_stub");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &stub_path.to_string_lossy(),
+        "--spawn",
+        "--export-runfiles-env=false",
+        "--",
+        "/bin/sh", "-c", "sh -c 'sleep 0.1 &'; sleep 0.5; grep -l '^State:.*Z' /proc/[0-9]*/status; echo DONE; exit 3",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer failed: {}", stderr));
+    }
+
+    let output = Command::new("unshare")
+        .args(unshare)
+        .arg(&stub_path)
+        .output()
+        .map_err(|e| format!("Failed to run stub: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let exit_code = output.status.code().unwrap_or(-1);
+
+    if exit_code != 3 || !stdout.contains("DONE") {
+        return Err(format!("Unexpected exit code {}: {}", exit_code, stdout));
+    }
+    if stdout.contains("/proc/") {
+        return Err(format!("Orphaned process was not reaped: {}", stdout));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer flags dynamically linked Linux templates
+fn test_dynamic_template_detected(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: dynamic_template_detected");
+
+    if !cfg!(target_os = "linux") {
+        report!("    SKIP (PT_INTERP only applies to Linux templates)");
+        return Ok(());
+    }
+
+    let test_dir = config.work_dir.join("test_dynamic_template");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // The demo binaries are ordinary std programs and thus dynamically linked
+    let dynamic_binary = config.test_binaries_dir.join("hash-file");
+    let output = test_dir.join("dynamic_stub");
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &dynamic_binary.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--require-static",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("dynamically linked (PT_INTERP") {
+        return Err(format!("--require-static accepted a dynamic template: {}", stderr));
+    }
+
+    // Without --require-static it is only a warning (finalizing then fails for lack of placeholders)
+    let (_stdout, stderr, _exit_code) = run_finalizer(config, &[
+        "--template", &dynamic_binary.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--", "tool",
+    ])?;
+    if !stderr.contains("Warning: template is dynamically linked") {
+        return Err(format!("No warning for a dynamic template: {}", stderr));
+    }
+
+    // The real template is static
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--require-static",
+        "--", "tool",
+    ])?;
+    if exit_code != 0 || stderr.contains("dynamically linked") {
+        return Err(format!("Static template was rejected: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer flags templates built for another platform than the stub is meant for
+fn test_template_platform_mismatch(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: template_platform_mismatch");
+
+    let test_dir = config.work_dir.join("test_template_platform_mismatch");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+    let output = test_dir.join(format!("platform_stub{}", EXE_EXT));
+
+    let template = fs::read(&config.template_path)
+        .map_err(|e| format!("Failed to read template: {}", e))?;
+    let (arch, os) = template_platform(&template).ok_or("Unrecognized template format")?;
+
+    // The template runs where it is finalized (a 32-bit template may run on a
+    // 64-bit host) and matches a --target for its own platform
+    let own_target = match os {
+        "linux" => format!("{}-unknown-linux-gnu", if arch == "x86" { "i686" } else { arch }),
+        "macos" => format!("{}-apple-darwin", arch),
+        _ => format!("{}-pc-windows-msvc", if arch == "x86" { "i686" } else { arch }),
+    };
+    for target in [None, Some(own_target.as_str())] {
+        let mut args = vec![
+            "--template".to_string(), config.template_path.to_string_lossy().to_string(),
+            "--output".to_string(), output.to_string_lossy().to_string(),
+            "--strict".to_string(),
+        ];
+        if let Some(target) = target {
+            args.extend(["--target".to_string(), target.to_string()]);
+        }
+        args.extend(["--".to_string(), "tool".to_string()]);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &args)?;
+        if exit_code != 0 || stderr.contains("template is built for") {
+            return Err(format!("Template was flagged (--target {:?}): {}", target, stderr));
+        }
+    }
+
+    // ... but not a stub meant for another platform
+    let other_target = if os == "windows" { "aarch64-unknown-linux-gnu" } else { "x86_64-pc-windows-msvc" };
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--target", other_target,
+        "--", "tool",
+    ])?;
+    let expected = format!("Warning: template is built for {}-{}, but the stub is meant to run on", arch, os);
+    if exit_code != 0 || !stderr.contains(&expected) {
+        return Err(format!("Expected {:?} (exit code {}): {}", expected, exit_code, stderr));
+    }
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--target", other_target,
+        "--strict",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("template is built for") {
+        return Err(format!("--strict accepted a mismatched template: {}", stderr));
+    }
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &config.template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--target", "mips-unknown-linux-gnu",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("not a platform the stub supports") {
+        return Err(format!("Unsupported --target was accepted: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Architecture and OS a template binary is built for, named like the finalizer
+/// reports them (e.g. ("x86", "linux"))
+fn template_platform(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?));
+    let u32_at = |at: usize| Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    if data.starts_with(b"\x7fELF") {
+        let arch = match u16_at(0x12)? {
+            0x03 => "x86",
+            0x3E => "x86_64",
+            0xB7 => "aarch64",
+            0xF3 => "riscv64",
+            _ => return None,
+        };
+        Some((arch, "linux"))
+    } else if data.starts_with(&[0xCF, 0xFA, 0xED, 0xFE]) {
+        let arch = match u32_at(4)? {
+            0x0100_0007 => "x86_64",
+            0x0100_000C => "aarch64",
+            _ => return None,
+        };
+        Some((arch, "macos"))
+    } else if data.starts_with(b"MZ") {
+        let arch = match u16_at(u32_at(0x3C)? as usize + 4)? {
+            0x014C => "x86",
+            0x8664 => "x86_64",
+            0xAA64 => "aarch64",
+            _ => return None,
+        };
+        Some((arch, "windows"))
+    } else {
+        None
+    }
+}
+
+/// Section the host platform's template keeps its placeholders in
+const PLACEHOLDER_SECTION: &str = if cfg!(windows) {
+    ".runfiles"
+} else if cfg!(target_os = "macos") {
+    "__DATA,__runfiles"
+} else {
+    ".runfiles_stubs"
+};
+
+/// Magic of the packed metadata block in templates and finalized stubs
+const METADATA_TEMPLATE_MAGIC: &[u8] = b"@@RUNFILES_METADATA@@";
+const METADATA_FINALIZED_MAGIC: &[u8] = b"RUNFILES_METADATA\0\0\0\0\0\0\0";
+
+fn find_bytes(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|w| w == pattern)
+}
+
+fn metadata_field(data: &[u8], block: usize, offset: usize) -> u32 {
+    let at = block + offset;
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Test: Finalizer writes the packed metadata layout the stub reads
+fn test_metadata_round_trip(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: metadata_round_trip");
+
+    let test_dir = config.work_dir.join("test_metadata_round_trip");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let template = fs::read(&config.template_path)
+        .map_err(|e| format!("Failed to read template: {}", e))?;
+    let template_pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    // The argument size is chosen at build time (RUNFILES_ARG_SIZE), 256 by default
+    let arg_size = metadata_field(&template, template_pos, 28);
+    if metadata_field(&template, template_pos, 24) != 1 || arg_size < 2 {
+        return Err("Template metadata has an unexpected version or argument size".to_string());
+    }
+    let features = metadata_field(&template, template_pos, 44);
+
+    let cases: [(&[&str], u32, u32); 3] = [
+        (&["--transform", "0,2", "--resolve-indirection"], 0b101, 0b101),
+        (&["--export-runfiles-env=false"], 0, 0),
+        (&["--transform", "1", "--export-runfiles-env=false"], 0b10, 0),
+    ];
+
+    for (i, (flags, transform_flags, metadata_flags)) in cases.iter().enumerate() {
+        let output = test_dir.join(format!("stub{}{}", i, EXE_EXT));
+        let output_str = output.to_string_lossy();
+        let mut finalizer_args = vec!["--template", config.template_path.to_str().unwrap(), "--output", &output_str];
+        finalizer_args.extend_from_slice(flags);
+        finalizer_args.extend_from_slice(&["--", "a", "b", "c"]);
+
+        let (_stdout, stderr, exit_code) = run_finalizer(config, &finalizer_args)?;
+        if exit_code != 0 {
+            return Err(format!("Finalizer failed for {:?}: {}", flags, stderr));
+        }
+
+        let stub = fs::read(&output).map_err(|e| format!("Failed to read stub: {}", e))?;
+        if find_bytes(&stub, METADATA_TEMPLATE_MAGIC).is_some() {
+            return Err("Finalized stub still contains the template metadata magic".to_string());
+        }
+        // The finalized block sits where the template's was (Mach-O signing
+        // only appends), so there is no need to search for it
+        let pos = template_pos;
+        if !stub[pos..].starts_with(METADATA_FINALIZED_MAGIC) {
+            return Err(format!("Finalized metadata magic missing at {:#x}", pos));
+        }
+
+        let decoded = (
+            metadata_field(&stub, pos, 24),
+            metadata_field(&stub, pos, 28),
+            metadata_field(&stub, pos, 32),
+            metadata_field(&stub, pos, 36),
+            metadata_field(&stub, pos, 40),
+            metadata_field(&stub, pos, 44),
+        );
+        let expected = (1, arg_size, 3, *transform_flags, *metadata_flags, features);
+        if decoded != expected {
+            return Err(format!(
+                "Metadata for {:?} decoded as {:?}, expected {:?}",
+                flags, decoded, expected
+            ));
+        }
+        // args_offset points at ARG0
+        let args_offset = metadata_field(&stub, pos, 48) as i32 as isize;
+        if !stub[(pos as isize + args_offset) as usize..].starts_with(b"a\0") {
+            return Err(format!("args_offset {} does not point at ARG0", args_offset));
+        }
+        if stub[pos + 52..pos + 64].iter().any(|&b| b != 0) {
+            return Err("Reserved metadata bytes are not zero".to_string());
+        }
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer checks the template's metadata version and features
+fn test_metadata_compatibility(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: metadata_compatibility");
+
+    let test_dir = config.work_dir.join("test_metadata_compatibility");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let template = fs::read(&config.template_path)
+        .map_err(|e| format!("Failed to read template: {}", e))?;
+    let pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let output = test_dir.join(format!("stub{}", EXE_EXT));
+
+    // A template from a future format version must be rejected, not misread
+    let mut future = template.clone();
+    future[pos + 24..pos + 28].copy_from_slice(&2u32.to_le_bytes());
+    let future_template = test_dir.join(format!("future_template{}", EXE_EXT));
+    fs::write(&future_template, &future).map_err(|e| format!("Failed to write template: {}", e))?;
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &future_template.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("metadata format version 2 is not supported") {
+        return Err(format!("Finalizer accepted a future metadata version: {}", stderr));
+    }
+
+    // Requesting a feature the template does not advertise is an error
+    let mut limited = template.clone();
+    limited[pos + 44..pos + 48].copy_from_slice(&1u32.to_le_bytes());
+    let limited_template = test_dir.join(format!("limited_template{}", EXE_EXT));
+    fs::write(&limited_template, &limited).map_err(|e| format!("Failed to write template: {}", e))?;
+
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &limited_template.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--resolve-indirection",
+        "--", "tool",
+    ])?;
+    if exit_code == 0 || !stderr.contains("does not support --resolve-indirection") {
+        return Err(format!("Finalizer accepted an unsupported feature: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Finalizer uses the argument size advertised in the template's metadata
+fn test_metadata_arg_size(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: metadata_arg_size");
+
+    let test_dir = config.work_dir.join("test_metadata_arg_size");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    // A synthetic template: an otherwise empty ELF header, a metadata block
+    // advertising 512-byte arguments, and ten 512-byte placeholders
+    const ARG_SIZE: usize = 512;
+    let mut template = vec![0u8; 128];
+    template[..4].copy_from_slice(b"\x7fELF");
+    let block = &mut template[64..];
+    block[..METADATA_TEMPLATE_MAGIC.len()].copy_from_slice(METADATA_TEMPLATE_MAGIC);
+    block[24..28].copy_from_slice(&1u32.to_le_bytes());
+    block[28..32].copy_from_slice(&(ARG_SIZE as u32).to_le_bytes());
+    block[44..48].copy_from_slice(&1u32.to_le_bytes());
+    let args_start = template.len();
+    for _ in 0..10 {
+        template.extend_from_slice(&[b'@'; ARG_SIZE]);
+    }
+
+    let template_path = test_dir.join("template_512");
+    fs::write(&template_path, &template).map_err(|e| format!("Failed to write template: {}", e))?;
+
+    // Longer than the 256 bytes of the standard templates
+    let long_arg = "x".repeat(400);
+    let output = test_dir.join("stub_512");
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--", &long_arg, "second",
+    ])?;
+    if exit_code != 0 {
+        return Err(format!("Finalizer rejected a 512-byte argument template: {}", stderr));
+    }
+
+    let stub = fs::read(&output).map_err(|e| format!("Failed to read stub: {}", e))?;
+    let arg0 = &stub[args_start..args_start + ARG_SIZE];
+    let arg1 = &stub[args_start + ARG_SIZE..args_start + 2 * ARG_SIZE];
+    if !arg0.starts_with(long_arg.as_bytes()) || arg0[long_arg.len()..].iter().any(|&b| b != 0) {
+        return Err("ARG0 was not written into the 512-byte placeholder".to_string());
+    }
+    if !arg1.starts_with(b"second") || arg1[6..].iter().any(|&b| b != 0) {
+        return Err("ARG1 was not written at the 512-byte boundary".to_string());
+    }
+    if stub[args_start + 2 * ARG_SIZE..].iter().any(|&b| b != b'@') {
+        return Err("Unused placeholders were modified".to_string());
+    }
+
+    // Arguments must still fit the advertised size
+    let too_long = "x".repeat(ARG_SIZE + 1);
+    let (_stdout, stderr, exit_code) = run_finalizer(config, &[
+        "--template", &template_path.to_string_lossy(),
+        "--output", &output.to_string_lossy(),
+        "--", &too_long,
+    ])?;
+    if exit_code == 0 || !stderr.contains("513 bytes > 511 bytes max") {
+        return Err(format!("Finalizer accepted an argument larger than the template's: {}", stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Test: Without --transform nothing is resolved, and a stub whose metadata
+/// was never written refuses to run rather than picking a default
+fn test_transform_defaults(config: &TestConfig) -> Result<(), String> {
+    report!("  Running test: transform_defaults");
+
+    let test_dir = config.work_dir.join("test_transform_defaults");
+    fs::create_dir_all(&test_dir).map_err(|e| format!("Failed to create test dir: {}", e))?;
+
+    let mut runfiles = RunfilesSetup::new(&test_dir, "transform_defaults_runfiles")
+        .map_err(|e| format!("Failed to create runfiles: {}", e))?;
+    let data_rlocation = format!("{}/data/input.txt", WORKSPACE_NAME);
+    runfiles.add_file_content(&data_rlocation, b"data\n")
+        .map_err(|e| format!("Failed to add data file: {}", e))?;
+    runfiles.write_manifest()
+        .map_err(|e| format!("Failed to write manifest: {}", e))?;
+
+    // Finalized without --transform: transform_flags is written as 0 and
+    // every argument, even a valid rlocation path, is passed through literally
+    let print_env_binary = config.test_binaries_dir.join(format!("print-env{}", EXE_EXT));
+    let print_env = print_env_binary.to_string_lossy();
+    let stub_path = test_dir.join(format!("no_transform_stub{}", EXE_EXT));
+    finalize_stub(config, &stub_path, &[&print_env, &data_rlocation], &[])?;
+
+    let template = fs::read(&config.template_path).map_err(|e| format!("Failed to read template: {}", e))?;
+    let pos = find_bytes(&template, METADATA_TEMPLATE_MAGIC)
+        .ok_or("Metadata block not found in template")?;
+    let mut stub = fs::read(&stub_path).map_err(|e| format!("Failed to read stub: {}", e))?;
+    if metadata_field(&stub, pos, 36) != 0 {
+        return Err(format!("Expected transform_flags 0, got {:#x}", metadata_field(&stub, pos, 36)));
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &stub_path, &runfiles, &[], true)?;
+    let expected = format!("|{}", data_rlocation);
+    if exit_code != 0 || !stdout.lines().any(|line| line.starts_with("ARGS:") && line.ends_with(&expected)) {
+        return Err(format!("Untransformed argument was not passed literally ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    // Restoring the template magic leaves the block as the finalizer found it.
+    // There is no runtime default (neither "none" nor "all"): the stub exits
+    // before looking at transform_flags
+    stub[pos..pos + METADATA_TEMPLATE_MAGIC.len()].copy_from_slice(METADATA_TEMPLATE_MAGIC);
+    stub[pos + 36..pos + 40].copy_from_slice(&u32::MAX.to_le_bytes());
+    // Editing a Mach-O invalidates its signature, so run the untouched template there
+    let unfinalized = if cfg!(target_os = "macos") { template } else { stub };
+    let unfinalized_path = test_dir.join(format!("unfinalized_stub{}", EXE_EXT));
+    fs::write(&unfinalized_path, &unfinalized).map_err(|e| format!("Failed to write stub: {}", e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&unfinalized_path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to set permissions: {}", e))?;
+    }
+
+    let (stdout, stderr, exit_code) = run_stub(config, &unfinalized_path, &runfiles, &[], true)?;
+    if exit_code != 1 || !stdout.is_empty() || !stderr.contains("metadata block has not been replaced") {
+        return Err(format!("Stub with unfinalized metadata ran ({}): {}{}", exit_code, stdout, stderr));
+    }
+
+    report!("    PASS");
+
+    Ok(())
+}
+
+/// Outcome of one test, for the JUnit report
+struct TestResult {
+    name: String,
+    elapsed: Duration,
+    result: Result<(), String>,
+}
+
+/// Escapes text for an XML attribute or element, keeping newlines as character
+/// references and replacing control characters XML can't represent
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            '\n' => out.push_str("&#10;"),
+            '\t' | '\r' => out.push_str(&format!("&#{};", c as u32)),
+            c if (c as u32) < 0x20 => out.push('\u{FFFD}'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders the results as a JUnit XML `<testsuite>`, one `<testcase>` per test run
+fn junit_report(results: &[TestResult], elapsed: Duration) -> String {
+    let failures = results.iter().filter(|test| test.result.is_err()).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuite name=\"runfiles-stub\" tests=\"{}\" failures=\"{}\" errors=\"0\" time=\"{:.3}\">\n",
+        results.len(),
+        failures,
+        elapsed.as_secs_f64()
+    ));
+    for test in results {
+        let testcase = format!(
+            "  <testcase classname=\"runfiles-stub\" name=\"{}\" time=\"{:.3}\"",
+            xml_escape(&test.name),
+            test.elapsed.as_secs_f64()
+        );
+        match &test.result {
+            Ok(()) => xml.push_str(&format!("{}/>\n", testcase)),
+            Err(e) => {
+                let message = xml_escape(e);
+                xml.push_str(&format!("{}>\n", testcase));
+                xml.push_str(&format!("    <failure message=\"{}\">{}</failure>\n", message, message));
+                xml.push_str("  </testcase>\n");
             }
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+fn main() -> ExitCode {
+    println!("=== Runfiles Stub Test Suite ===");
+    println!();
+
+    let config = match TestConfig::from_args() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Use --help for usage information");
+            return ExitCode::from(1);
+        }
+    };
+
+    // Clean and recreate work directory
+    if config.work_dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&config.work_dir) {
+            eprintln!("Warning: Failed to clean work dir: {}", e);
+        }
+    }
+    if let Err(e) = fs::create_dir_all(&config.work_dir) {
+        eprintln!("Error: Failed to create work dir: {}", e);
+        return ExitCode::from(1);
+    }
+
+    println!("Configuration:");
+    println!("  Template:      {}", config.template_path.display());
+    println!("  Finalizer:     {}", config.finalizer_path.display());
+    println!("  Test binaries: {}", config.test_binaries_dir.display());
+    println!("  Work dir:      {}", config.work_dir.display());
+    println!();
+
+    let tests: Vec<(&str, TestFn)> = vec![
+        ("hash_file", test_hash_file),
+        ("add_numbers_runtime_args", test_add_numbers_runtime_args),
+        ("merge_json", test_merge_json),
+        ("orchestrator_env_propagation", test_orchestrator_env_propagation),
+        ("exit_code_propagation", test_exit_code_propagation),
+        ("windows_start_failures", test_windows_start_failures),
+        ("windows_version_resource", test_windows_version_resource),
+        ("windows_long_paths", test_windows_long_paths),
+        ("windows_long_env_var", test_windows_long_env_var),
+        ("windows_ctrl_break", test_windows_ctrl_break),
+        ("mixed_arguments", test_mixed_arguments),
+        ("exact_argv_and_env", test_exact_argv_and_env),
+        ("command_line_order", test_command_line_order),
+        ("run_timeout", test_run_timeout),
+        ("executable_without_extension", test_executable_without_extension),
+        ("fallback_runfiles_dir", test_fallback_runfiles_dir),
+        ("fallback_runfiles_manifest", test_fallback_runfiles_manifest),
+        ("directory_manifest_fallback", test_directory_manifest_fallback),
+        ("manifest_runfiles_dir", test_manifest_runfiles_dir),
+        ("sibling_manifest_env", test_sibling_manifest_env),
+        ("directory_mode_manifest_env", test_directory_mode_manifest_env),
+        ("inconsistent_runfiles_env", test_inconsistent_runfiles_env),
+        ("path_lookup", test_path_lookup),
+        ("one_stub_all_discovery_modes", test_one_stub_all_discovery_modes),
+        ("without_procfs", test_without_procfs),
+        ("empty_argv", test_empty_argv),
+        ("large_environment", test_large_environment),
+        ("large_manifest", test_large_manifest),
+        ("sorted_manifest_lookup", test_sorted_manifest_lookup),
+        ("manifest_entry_limit", test_manifest_entry_limit),
+        ("rlocation_prefixes", test_rlocation_prefixes),
+        ("print_env", test_print_env),
+        ("stray_placeholder_rejected", test_stray_placeholder_rejected),
+        ("template_overwrite_rejected", test_template_overwrite_rejected),
+        ("template_not_executable", test_template_not_executable),
+        ("shell_completions", test_shell_completions),
+        ("transform_index_out_of_range", test_transform_index_out_of_range),
+        ("inline_transform_marker", test_inline_transform_marker),
+        ("transform_flag_value", test_transform_flag_value),
+        ("trim_args", test_trim_args),
+        ("args_file", test_args_file),
+        ("arg_size_boundary", test_arg_size_boundary),
+        ("long_runfiles_dir_path", test_long_runfiles_dir_path),
+        ("quiet", test_quiet),
+        ("zero_unused", test_zero_unused),
+        ("stripped_template", test_stripped_template),
+        ("finalize_to_stdout", test_finalize_to_stdout),
+        ("dry_run", test_dry_run),
+        ("inspect", test_inspect),
+        ("config_round_trip", test_config_round_trip),
+        ("selective_runfiles_export", test_selective_runfiles_export),
+        ("set_env", test_set_env),
+        ("process_limits", test_process_limits),
+        ("version_sentinel", test_version_sentinel),
+        ("concurrent_finalize", test_concurrent_finalize),
+        ("output_mode", test_output_mode),
+        ("create_output_dirs", test_create_output_dirs),
+        ("debug_file", test_debug_file),
+        ("no_fd_leak", test_no_fd_leak),
+        ("percent_decode", test_percent_decode),
+        ("escaped_manifest_entries", test_escaped_manifest_entries),
+        ("manifest_newline_entries", test_manifest_newline_entries),
+        ("expand_runfiles_env_vars", test_expand_runfiles_env_vars),
+        ("keep_inherited_runfiles_env", test_keep_inherited_runfiles_env),
+        ("non_ascii_paths", test_non_ascii_paths),
+        ("external_repo_keys", test_external_repo_keys),
+        ("repo_mapping", test_repo_mapping),
+        ("many_runtime_args", test_many_runtime_args),
+        ("runtime_args_escaping", test_runtime_args_escaping),
+        ("argv0_is_resolved_path", test_argv0_is_resolved_path),
+        ("exec_path", test_exec_path),
+        ("argv0_override", test_argv0_override),
+        ("chdir", test_chdir),
+        ("exec_failure_reported", test_exec_failure_reported),
+        ("runtime_args_position", test_runtime_args_position),
+        ("runfiles_root_token", test_runfiles_root_token),
+        ("default_runtime_args", test_default_runtime_args),
+        ("sandbox_root", test_sandbox_root),
+        ("self_extracting", test_self_extracting),
+        ("manifest_indirection", test_manifest_indirection),
+        ("spawn_signal_forwarding", test_spawn_signal_forwarding),
+        ("spawn_stable_pid", test_spawn_stable_pid),
+        ("spawn_pid1_reaping", test_spawn_pid1_reaping),
+        ("spawn_ignored_sigchld", test_spawn_ignored_sigchld),
+        ("dynamic_template_detected", test_dynamic_template_detected),
+        ("template_platform_mismatch", test_template_platform_mismatch),
+        ("metadata_round_trip", test_metadata_round_trip),
+        ("metadata_compatibility", test_metadata_compatibility),
+        ("metadata_arg_size", test_metadata_arg_size),
+        ("transform_defaults", test_transform_defaults),
+    ];
+
+    let total = tests.len();
+    let tests: Vec<(&str, TestFn)> = tests
+        .into_iter()
+        .filter(|(name, _)| config.filter.as_ref().is_none_or(|filter| name.contains(filter.as_str())))
+        .collect();
+    let skipped = total - tests.len();
+
+    let mut passed = 0;
+    let mut failed = 0;
+    let mut results = Vec::new();
+    let mut record = |name: &str, elapsed: Duration, result: Result<(), String>| {
+        match &result {
+            Ok(()) => passed += 1,
             Err(e) => {
                 println!("  FAILED: {}", e);
                 failed += 1;
             }
         }
+        results.push(TestResult { name: name.to_string(), elapsed, result });
+    };
+
+    match &config.filter {
+        Some(filter) => println!("Running {} tests matching {:?} ({} filtered out)...", tests.len(), filter, skipped),
+        None => println!("Running {} tests...", tests.len()),
+    }
+    println!();
+
+    let suite_start = Instant::now();
+    if config.jobs == 1 {
+        for (name, test_fn) in &tests {
+            let start = Instant::now();
+            let result = test_fn(&config);
+            record(name, start.elapsed(), result);
+        }
+    } else {
+        // Workers take the next test from the list and send back its collected
+        // output; each test has its own subdirectory of the (already recreated)
+        // work dir, so they don't share files
+        let next_test = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..config.jobs.min(tests.len()) {
+                let sender = sender.clone();
+                let (next_test, tests, config) = (&next_test, &tests, &config);
+                scope.spawn(move || {
+                    loop {
+                        let index = next_test.fetch_add(1, Ordering::Relaxed);
+                        let Some((_name, test_fn)) = tests.get(index) else {
+                            break;
+                        };
+                        TEST_OUTPUT.with(|output| *output.borrow_mut() = Some(String::new()));
+                        let start = Instant::now();
+                        let result = test_fn(config);
+                        let elapsed = start.elapsed();
+                        let output = TEST_OUTPUT.with(|output| output.borrow_mut().take()).unwrap_or_default();
+                        if sender.send((index, output, elapsed, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            // Report in list order: hold results back until all earlier tests are done
+            let mut done = HashMap::new();
+            let mut next_report = 0;
+            for (index, output, elapsed, result) in receiver {
+                done.insert(index, (output, elapsed, result));
+                while let Some((output, elapsed, result)) = done.remove(&next_report) {
+                    print!("{}", output);
+                    record(tests[next_report].0, elapsed, result);
+                    next_report += 1;
+                }
+            }
+        });
     }
+    let suite_elapsed = suite_start.elapsed();
 
     println!();
     println!("=== Results ===");
     println!("Passed: {}", passed);
     println!("Failed: {}", failed);
+    if skipped > 0 {
+        println!("Filtered out: {}", skipped);
+    }
     println!();
 
+    if let Some(path) = &config.junit {
+        if let Err(e) = fs::write(path, junit_report(&results, suite_elapsed)) {
+            eprintln!("Error: Failed to write JUnit report {}: {}", path.display(), e);
+            return ExitCode::from(1);
+        }
+    }
+
     if failed > 0 {
         ExitCode::from(1)
     } else {
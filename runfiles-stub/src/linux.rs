@@ -5,7 +5,7 @@ fn panic(_info: &PanicInfo) -> ! {
     exit(1);
 }
 
-// Compiler intrinsics (memcpy, memset)
+// Compiler intrinsics (memcpy, memmove, memset)
 #[no_mangle]
 pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
     let mut i = 0;
@@ -16,6 +16,21 @@ pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut
     dest
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    // Copy backwards when the destination overlaps the end of the source
+    if (dest as usize) > (src as usize) {
+        let mut i = n;
+        while i > 0 {
+            i -= 1;
+            *dest.add(i) = *src.add(i);
+        }
+    } else {
+        memcpy(dest, src, n);
+    }
+    dest
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
     let mut i = 0;
@@ -61,27 +76,121 @@ mod syscall_numbers {
     pub const SYS_WRITE: usize = 1;
     pub const SYS_OPEN: usize = 2;
     pub const SYS_CLOSE: usize = 3;
+    pub const SYS_LSEEK: usize = 8;
     pub const SYS_ACCESS: usize = 21;
+    pub const SYS_CHDIR: usize = 80;
+    pub const SYS_READLINK: usize = 89;
+    pub const SYS_GETPID: usize = 39;
     pub const SYS_EXECVE: usize = 59;
     pub const SYS_EXIT: usize = 60;
+    pub const SYS_RENAME: usize = 82;
+    pub const SYS_MKDIR: usize = 83;
+    pub const SYS_UMASK: usize = 95;
+    pub const SYS_GETEUID: usize = 107;
+    pub const SYS_NEWFSTATAT: usize = 262;
+    pub const SYS_UNLINKAT: usize = 263;
+    pub const SYS_PRLIMIT64: usize = 302;
+    pub const AT_FDCWD: i32 = -100;
+    // struct stat
+    pub const STAT_MODE_OFFSET: usize = 24;
+    pub const STAT_UID_OFFSET: usize = 28;
 }
 
-#[cfg(target_arch = "aarch64")]
+// aarch64 and riscv64 share the generic syscall table
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 mod syscall_numbers {
     pub const SYS_READ: usize = 63;
     pub const SYS_WRITE: usize = 64;
     pub const SYS_OPENAT: usize = 56;  // openat is used on aarch64
     pub const SYS_CLOSE: usize = 57;
+    pub const SYS_LSEEK: usize = 62;
     pub const SYS_FACCESSAT: usize = 48;  // faccessat is used on aarch64
+    pub const SYS_CHDIR: usize = 49;
+    pub const SYS_READLINKAT: usize = 78;  // readlinkat is used on aarch64
+    pub const SYS_GETPID: usize = 172;
     pub const SYS_EXECVE: usize = 221;
     pub const SYS_EXIT: usize = 93;
+    pub const SYS_RENAMEAT: usize = 38;  // renameat is used on aarch64
+    pub const SYS_MKDIRAT: usize = 34;  // mkdirat is used on aarch64
+    pub const SYS_UMASK: usize = 166;
+    pub const SYS_GETEUID: usize = 175;
+    pub const SYS_NEWFSTATAT: usize = 79;
+    pub const SYS_UNLINKAT: usize = 35;
+    pub const SYS_PRLIMIT64: usize = 261;
     pub const AT_FDCWD: i32 = -100;  // Special fd for openat/faccessat to work like open/access
+    // struct stat (generic layout)
+    pub const STAT_MODE_OFFSET: usize = 16;
+    pub const STAT_UID_OFFSET: usize = 24;
+}
+
+#[cfg(target_arch = "x86")]
+mod syscall_numbers {
+    pub const SYS_EXIT: usize = 1;
+    pub const SYS_READ: usize = 3;
+    pub const SYS_WRITE: usize = 4;
+    pub const SYS_OPEN: usize = 5;
+    pub const SYS_CLOSE: usize = 6;
+    pub const SYS_EXECVE: usize = 11;
+    pub const SYS_CHDIR: usize = 12;
+    pub const SYS_GETPID: usize = 20;
+    pub const SYS_ACCESS: usize = 33;
+    pub const SYS_RENAME: usize = 38;
+    pub const SYS_MKDIR: usize = 39;
+    pub const SYS_READLINK: usize = 85;
+    pub const SYS_UMASK: usize = 60;
+    pub const SYS_LLSEEK: usize = 140;  // _llseek takes a 64-bit offset, lseek doesn't
+    pub const SYS_GETEUID: usize = 201;  // geteuid32; geteuid only returns 16-bit ids
+    pub const SYS_NEWFSTATAT: usize = 300;  // fstatat64, filling a struct stat64
+    pub const SYS_UNLINKAT: usize = 301;
+    pub const SYS_PRLIMIT64: usize = 340;
+    pub const AT_FDCWD: i32 = -100;
+    // struct stat64
+    pub const STAT_MODE_OFFSET: usize = 16;
+    pub const STAT_UID_OFFSET: usize = 24;
 }
 
 use syscall_numbers::*;
 
 const O_RDONLY: i32 = 0;
+const O_WRONLY: i32 = 1;
+const O_CREAT: i32 = 0o100;
+const O_EXCL: i32 = 0o200;
+const O_APPEND: i32 = 0o2000;
+// Internal fds must not leak into the target, whether it is exec'd or spawned
+const O_CLOEXEC: i32 = 0o2000000;
+const SEEK_SET: i32 = 0;
+const SEEK_CUR: i32 = 1;
+const SEEK_END: i32 = 2;
+const EEXIST: i32 = 17;
 const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+// i386 syscalls go through int 0x80 with the arguments in ebx, ecx, edx, esi
+// and edi. LLVM reserves ebx and esi, so they can't be asm operands: the
+// number and arguments are loaded from memory after saving both registers.
+#[cfg(target_arch = "x86")]
+unsafe fn syscall(number: usize, args: [usize; 5]) -> isize {
+    let block = [number, args[0], args[1], args[2], args[3], args[4]];
+    let ret: isize;
+    core::arch::asm!(
+        "push ebx",
+        "push esi",
+        "mov ebx, [eax + 4]",
+        "mov ecx, [eax + 8]",
+        "mov edx, [eax + 12]",
+        "mov esi, [eax + 16]",
+        "mov edi, [eax + 20]",
+        "mov eax, [eax]",
+        "int 0x80",
+        "pop esi",
+        "pop ebx",
+        inout("eax") block.as_ptr() => ret,
+        lateout("ecx") _,
+        lateout("edx") _,
+        lateout("edi") _,
+    );
+    ret
+}
 
 #[cfg(target_arch = "x86_64")]
 fn exit(code: i32) -> ! {
@@ -107,6 +216,33 @@ fn exit(code: i32) -> ! {
     }
 }
 
+#[cfg(target_arch = "riscv64")]
+fn exit(code: i32) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_EXIT,
+            in("a0") code,
+            options(noreturn)
+        );
+    }
+}
+
+// ebx can't be an operand (see syscall), but exit never returns, so the asm may
+// clobber it without saving
+#[cfg(target_arch = "x86")]
+fn exit(code: i32) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "mov ebx, {code}",
+            "int 0x80",
+            code = in(reg) code,
+            in("eax") SYS_EXIT,
+            options(noreturn)
+        );
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 fn write(fd: i32, buf: &[u8]) -> isize {
     let ret: isize;
@@ -141,16 +277,41 @@ fn write(fd: i32, buf: &[u8]) -> isize {
     ret
 }
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(target_arch = "riscv64")]
+fn write(fd: i32, buf: &[u8]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_WRITE,
+            in("a0") fd,
+            in("a1") buf.as_ptr(),
+            in("a2") buf.len(),
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn write(fd: i32, buf: &[u8]) -> isize {
+    unsafe { syscall(SYS_WRITE, [fd as usize, buf.as_ptr() as usize, buf.len(), 0, 0]) }
+}
+
 fn open(path: &[u8]) -> i32 {
+    open_with(path, O_RDONLY | O_CLOEXEC, 0)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn open_with(path: &[u8], flags: i32, mode: u32) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
             "syscall",
             in("rax") SYS_OPEN,
             in("rdi") path.as_ptr(),
-            in("rsi") O_RDONLY,
-            in("rdx") 0,
+            in("rsi") flags,
+            in("rdx") mode,
             lateout("rax") ret,
             lateout("rcx") _,
             lateout("r11") _,
@@ -160,7 +321,7 @@ fn open(path: &[u8]) -> i32 {
 }
 
 #[cfg(target_arch = "aarch64")]
-fn open(path: &[u8]) -> i32 {
+fn open_with(path: &[u8], flags: i32, mode: u32) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
@@ -168,14 +329,36 @@ fn open(path: &[u8]) -> i32 {
             in("x8") SYS_OPENAT,
             in("x0") AT_FDCWD,
             in("x1") path.as_ptr(),
-            in("x2") O_RDONLY,
-            in("x3") 0,
+            in("x2") flags,
+            in("x3") mode,
             lateout("x0") ret,
         );
     }
     ret
 }
 
+#[cfg(target_arch = "riscv64")]
+fn open_with(path: &[u8], flags: i32, mode: u32) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_OPENAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") flags,
+            in("a3") mode,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn open_with(path: &[u8], flags: i32, mode: u32) -> i32 {
+    unsafe { syscall(SYS_OPEN, [path.as_ptr() as usize, flags as usize, mode as usize, 0, 0]) as i32 }
+}
+
 #[cfg(target_arch = "x86_64")]
 fn read(fd: i32, buf: &mut [u8]) -> isize {
     let ret: isize;
@@ -210,6 +393,27 @@ fn read(fd: i32, buf: &mut [u8]) -> isize {
     ret
 }
 
+#[cfg(target_arch = "riscv64")]
+fn read(fd: i32, buf: &mut [u8]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_READ,
+            in("a0") fd,
+            in("a1") buf.as_ptr(),
+            in("a2") buf.len(),
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn read(fd: i32, buf: &mut [u8]) -> isize {
+    unsafe { syscall(SYS_READ, [fd as usize, buf.as_mut_ptr() as usize, buf.len(), 0, 0]) }
+}
+
 #[cfg(target_arch = "x86_64")]
 fn close(fd: i32) {
     unsafe {
@@ -236,51 +440,162 @@ fn close(fd: i32) {
     }
 }
 
-// Check if a path exists using access() syscall with F_OK (0)
+#[cfg(target_arch = "riscv64")]
+fn close(fd: i32) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_CLOSE,
+            in("a0") fd,
+            lateout("a0") _,
+        );
+    }
+}
+
+#[cfg(target_arch = "x86")]
+fn close(fd: i32) {
+    unsafe {
+        syscall(SYS_CLOSE, [fd as usize, 0, 0, 0, 0]);
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
-fn path_exists(path: &[u8]) -> bool {
+fn lseek(fd: i32, offset: i64, whence: i32) -> i64 {
+    let ret: i64;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_LSEEK,
+            in("rdi") fd,
+            in("rsi") offset,
+            in("rdx") whence,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn lseek(fd: i32, offset: i64, whence: i32) -> i64 {
+    let ret: i64;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_LSEEK,
+            in("x0") fd,
+            in("x1") offset,
+            in("x2") whence,
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn lseek(fd: i32, offset: i64, whence: i32) -> i64 {
+    let ret: i64;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_LSEEK,
+            in("a0") fd,
+            in("a1") offset,
+            in("a2") whence,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+// lseek only takes a 32-bit offset here; _llseek splits the offset in two
+// halves and stores the resulting position through a pointer
+#[cfg(target_arch = "x86")]
+fn lseek(fd: i32, offset: i64, whence: i32) -> i64 {
+    let mut result: i64 = 0;
+    let ret = unsafe {
+        syscall(
+            SYS_LLSEEK,
+            [
+                fd as usize,
+                (offset >> 32) as usize,
+                offset as u32 as usize,
+                &mut result as *mut i64 as usize,
+                whence as usize,
+            ],
+        )
+    };
+    if ret < 0 {
+        ret as i64
+    } else {
+        result
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn mkdir(path: &[u8], mode: u32) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
             "syscall",
-            in("rax") SYS_ACCESS,
+            in("rax") SYS_MKDIR,
             in("rdi") path.as_ptr(),
-            in("rsi") 0i32,  // F_OK = 0 (check existence)
+            in("rsi") mode,
             lateout("rax") ret,
             lateout("rcx") _,
             lateout("r11") _,
         );
     }
-    ret == 0
+    ret
 }
 
 #[cfg(target_arch = "aarch64")]
-fn path_exists(path: &[u8]) -> bool {
+fn mkdir(path: &[u8], mode: u32) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
             "svc #0",
-            in("x8") SYS_FACCESSAT,
+            in("x8") SYS_MKDIRAT,
             in("x0") AT_FDCWD,
             in("x1") path.as_ptr(),
-            in("x2") 0i32,  // F_OK = 0 (check existence)
-            in("x3") 0i32,  // flags = 0
+            in("x2") mode,
             lateout("x0") ret,
         );
     }
-    ret == 0
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn mkdir(path: &[u8], mode: u32) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_MKDIRAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") mode,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn mkdir(path: &[u8], mode: u32) -> i32 {
+    unsafe { syscall(SYS_MKDIR, [path.as_ptr() as usize, mode as usize, 0, 0, 0]) as i32 }
 }
 
 #[cfg(target_arch = "x86_64")]
-fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+fn rename(from: &[u8], to: &[u8]) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
             "syscall",
-            in("rax") SYS_EXECVE,
-            in("rdi") filename,
-            in("rsi") argv,
-            in("rdx") envp,
+            in("rax") SYS_RENAME,
+            in("rdi") from.as_ptr(),
+            in("rsi") to.as_ptr(),
             lateout("rax") ret,
             lateout("rcx") _,
             lateout("r11") _,
@@ -290,96 +605,731 @@ fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -
 }
 
 #[cfg(target_arch = "aarch64")]
-fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+fn rename(from: &[u8], to: &[u8]) -> i32 {
     let ret: i32;
     unsafe {
         core::arch::asm!(
             "svc #0",
-            in("x8") SYS_EXECVE,
-            in("x0") filename,
-            in("x1") argv,
-            in("x2") envp,
+            in("x8") SYS_RENAMEAT,
+            in("x0") AT_FDCWD,
+            in("x1") from.as_ptr(),
+            in("x2") AT_FDCWD,
+            in("x3") to.as_ptr(),
             lateout("x0") ret,
         );
     }
     ret
 }
 
-// String utilities
-fn print(s: &[u8]) {
-    write(STDOUT, s);
+#[cfg(target_arch = "riscv64")]
+fn rename(from: &[u8], to: &[u8]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_RENAMEAT,
+            in("a0") AT_FDCWD,
+            in("a1") from.as_ptr(),
+            in("a2") AT_FDCWD,
+            in("a3") to.as_ptr(),
+            lateout("a0") ret,
+        );
+    }
+    ret
 }
 
-fn print_number(mut n: usize) {
-    let mut buf = [0u8; 20]; // Enough for 64-bit numbers
-    let mut i = 0;
+#[cfg(target_arch = "x86")]
+fn rename(from: &[u8], to: &[u8]) -> i32 {
+    unsafe { syscall(SYS_RENAME, [from.as_ptr() as usize, to.as_ptr() as usize, 0, 0, 0]) as i32 }
+}
 
-    if n == 0 {
-        write(STDOUT, b"0");
-        return;
+#[cfg(target_arch = "x86_64")]
+fn getpid() -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_GETPID,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
     }
+    ret
+}
 
-    while n > 0 {
-        buf[i] = b'0' + (n % 10) as u8;
-        n /= 10;
-        i += 1;
+#[cfg(target_arch = "aarch64")]
+fn getpid() -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_GETPID,
+            lateout("x0") ret,
+        );
     }
+    ret
+}
 
-    // Print in reverse order
-    while i > 0 {
-        i -= 1;
-        write(STDOUT, &buf[i..i+1]);
+#[cfg(target_arch = "riscv64")]
+fn getpid() -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_GETPID,
+            lateout("a0") ret,
+        );
     }
+    ret
 }
 
-fn str_eq(a: &[u8], b: &[u8]) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-    for i in 0..a.len() {
-        if a[i] != b[i] {
-            return false;
-        }
+#[cfg(target_arch = "x86")]
+fn getpid() -> i32 {
+    unsafe { syscall(SYS_GETPID, [0; 5]) as i32 }
+}
+
+// Check if a path exists using access() syscall with F_OK (0)
+#[cfg(target_arch = "x86_64")]
+fn path_exists(path: &[u8]) -> bool {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_ACCESS,
+            in("rdi") path.as_ptr(),
+            in("rsi") 0i32,  // F_OK = 0 (check existence)
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
     }
-    true
+    ret == 0
 }
 
-fn str_starts_with(haystack: &[u8], needle: &[u8]) -> bool {
-    if haystack.len() < needle.len() {
-        return false;
+#[cfg(target_arch = "aarch64")]
+fn path_exists(path: &[u8]) -> bool {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_FACCESSAT,
+            in("x0") AT_FDCWD,
+            in("x1") path.as_ptr(),
+            in("x2") 0i32,  // F_OK = 0 (check existence)
+            in("x3") 0i32,  // flags = 0
+            lateout("x0") ret,
+        );
     }
-    str_eq(&haystack[..needle.len()], needle)
+    ret == 0
 }
 
-fn find_byte(haystack: &[u8], needle: u8) -> Option<usize> {
-    for i in 0..haystack.len() {
-        if haystack[i] == needle {
-            return Some(i);
-        }
+#[cfg(target_arch = "riscv64")]
+fn path_exists(path: &[u8]) -> bool {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_FACCESSAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") 0i32,  // F_OK = 0 (check existence)
+            in("a3") 0i32,  // flags = 0
+            lateout("a0") ret,
+        );
     }
-    None
+    ret == 0
 }
 
-// Static buffer for reading environment during initialization
-// Using a static buffer here to avoid stack overflow from large stack allocation
-static mut GET_ENV_BUF: [u8; MAX_ENV_SIZE] = [0; MAX_ENV_SIZE];
+#[cfg(target_arch = "x86")]
+fn path_exists(path: &[u8]) -> bool {
+    // F_OK = 0 (check existence)
+    unsafe { syscall(SYS_ACCESS, [path.as_ptr() as usize, 0, 0, 0, 0]) == 0 }
+}
 
-// Environment variable reading
-fn get_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
-    let environ_buf = unsafe { &mut GET_ENV_BUF };
-    let fd = open(b"/proc/self/environ\0");
+// Large enough for struct stat on every architecture (144 bytes on x86_64)
+const STAT_SIZE: usize = 256;
+const AT_SYMLINK_NOFOLLOW: i32 = 0x100;
+const AT_REMOVEDIR: i32 = 0x200;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+// lstat: fills buf with the struct stat of the NUL-terminated path itself
+#[cfg(target_arch = "x86_64")]
+fn lstat(path: &[u8], buf: &mut [u8; STAT_SIZE]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_NEWFSTATAT,
+            in("rdi") AT_FDCWD,
+            in("rsi") path.as_ptr(),
+            in("rdx") buf.as_mut_ptr(),
+            in("r10") AT_SYMLINK_NOFOLLOW,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn lstat(path: &[u8], buf: &mut [u8; STAT_SIZE]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_NEWFSTATAT,
+            in("x0") AT_FDCWD,
+            in("x1") path.as_ptr(),
+            in("x2") buf.as_mut_ptr(),
+            in("x3") AT_SYMLINK_NOFOLLOW,
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn lstat(path: &[u8], buf: &mut [u8; STAT_SIZE]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_NEWFSTATAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") buf.as_mut_ptr(),
+            in("a3") AT_SYMLINK_NOFOLLOW,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn lstat(path: &[u8], buf: &mut [u8; STAT_SIZE]) -> i32 {
+    unsafe {
+        syscall(SYS_NEWFSTATAT, [AT_FDCWD as usize, path.as_ptr() as usize, buf.as_mut_ptr() as usize, AT_SYMLINK_NOFOLLOW as usize, 0]) as i32
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn geteuid() -> u32 {
+    let ret: u32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_GETEUID,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn geteuid() -> u32 {
+    let ret: u32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_GETEUID,
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn geteuid() -> u32 {
+    let ret: u32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_GETEUID,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn geteuid() -> u32 {
+    unsafe { syscall(SYS_GETEUID, [0; 5]) as u32 }
+}
+
+// unlink, or rmdir with AT_REMOVEDIR
+#[cfg(target_arch = "x86_64")]
+fn unlinkat(path: &[u8], flags: i32) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_UNLINKAT,
+            in("rdi") AT_FDCWD,
+            in("rsi") path.as_ptr(),
+            in("rdx") flags,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn unlinkat(path: &[u8], flags: i32) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_UNLINKAT,
+            in("x0") AT_FDCWD,
+            in("x1") path.as_ptr(),
+            in("x2") flags,
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn unlinkat(path: &[u8], flags: i32) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_UNLINKAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") flags,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn unlinkat(path: &[u8], flags: i32) -> i32 {
+    unsafe { syscall(SYS_UNLINKAT, [AT_FDCWD as usize, path.as_ptr() as usize, flags as usize, 0, 0]) as i32 }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn chdir(path: &[u8]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_CHDIR,
+            in("rdi") path.as_ptr(),
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn chdir(path: &[u8]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_CHDIR,
+            in("x0") path.as_ptr(),
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn chdir(path: &[u8]) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_CHDIR,
+            in("a0") path.as_ptr(),
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn chdir(path: &[u8]) -> i32 {
+    unsafe { syscall(SYS_CHDIR, [path.as_ptr() as usize, 0, 0, 0, 0]) as i32 }
+}
+
+// Returns the number of bytes written to buf (not NUL-terminated), or a negative errno
+#[cfg(target_arch = "x86_64")]
+fn readlink(path: &[u8], buf: &mut [u8]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_READLINK,
+            in("rdi") path.as_ptr(),
+            in("rsi") buf.as_mut_ptr(),
+            in("rdx") buf.len(),
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn readlink(path: &[u8], buf: &mut [u8]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_READLINKAT,
+            in("x0") AT_FDCWD,
+            in("x1") path.as_ptr(),
+            in("x2") buf.as_mut_ptr(),
+            in("x3") buf.len(),
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn readlink(path: &[u8], buf: &mut [u8]) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_READLINKAT,
+            in("a0") AT_FDCWD,
+            in("a1") path.as_ptr(),
+            in("a2") buf.as_mut_ptr(),
+            in("a3") buf.len(),
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn readlink(path: &[u8], buf: &mut [u8]) -> isize {
+    unsafe { syscall(SYS_READLINK, [path.as_ptr() as usize, buf.as_mut_ptr() as usize, buf.len(), 0, 0]) }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_EXECVE,
+            in("rdi") filename,
+            in("rsi") argv,
+            in("rdx") envp,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_EXECVE,
+            in("x0") filename,
+            in("x1") argv,
+            in("x2") envp,
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_EXECVE,
+            in("a0") filename,
+            in("a1") argv,
+            in("a2") envp,
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn execve(filename: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32 {
+    unsafe { syscall(SYS_EXECVE, [filename as usize, argv as usize, envp as usize, 0, 0]) as i32 }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn umask(mask: u32) {
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_UMASK,
+            in("rdi") mask,
+            lateout("rax") _,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn umask(mask: u32) {
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_UMASK,
+            in("x0") mask,
+            lateout("x0") _,
+        );
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+fn umask(mask: u32) {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_UMASK,
+            in("a0") mask,
+            lateout("a0") _,
+        );
+    }
+}
+
+#[cfg(target_arch = "x86")]
+fn umask(mask: u32) {
+    unsafe {
+        syscall(SYS_UMASK, [mask as usize, 0, 0, 0, 0]);
+    }
+}
+
+// Soft and hard limit, as used by prlimit64
+#[repr(C)]
+struct Rlimit {
+    current: u64,
+    max: u64,
+}
+
+// Sets a resource limit of the stub itself (pid 0); the target inherits it
+#[cfg(target_arch = "x86_64")]
+fn setrlimit(resource: u32, limit: &Rlimit) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") SYS_PRLIMIT64,
+            in("rdi") 0usize,
+            in("rsi") resource,
+            in("rdx") limit as *const Rlimit,
+            in("r10") 0usize,  // old limit: not needed
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+fn setrlimit(resource: u32, limit: &Rlimit) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") SYS_PRLIMIT64,
+            in("x0") 0usize,
+            in("x1") resource,
+            in("x2") limit as *const Rlimit,
+            in("x3") 0usize,  // old limit: not needed
+            lateout("x0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+fn setrlimit(resource: u32, limit: &Rlimit) -> i32 {
+    let ret: i32;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SYS_PRLIMIT64,
+            in("a0") 0usize,
+            in("a1") resource,
+            in("a2") limit as *const Rlimit,
+            in("a3") 0usize,  // old limit: not needed
+            lateout("a0") ret,
+        );
+    }
+    ret
+}
+
+#[cfg(target_arch = "x86")]
+fn setrlimit(resource: u32, limit: &Rlimit) -> i32 {
+    // No old limit
+    unsafe { syscall(SYS_PRLIMIT64, [0, resource as usize, limit as *const Rlimit as usize, 0, 0]) as i32 }
+}
+
+// Diagnostics go to stderr so they never mix with the target's output
+fn eprint(s: &[u8]) {
+    write(STDERR, s);
+}
+
+fn eprint_number(mut n: usize) {
+    let mut buf = [0u8; 20]; // Enough for 64-bit numbers
+    let mut i = 0;
+
+    if n == 0 {
+        write(STDERR, b"0");
+        return;
+    }
+
+    while n > 0 {
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        i += 1;
+    }
+
+    // Print in reverse order
+    while i > 0 {
+        i -= 1;
+        write(STDERR, &buf[i..i+1]);
+    }
+}
+
+// String utilities
+fn str_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    for i in 0..a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+    }
+    true
+}
+
+// Byte-wise ordering, the order manifest keys are sorted in
+fn str_cmp(a: &[u8], b: &[u8]) -> core::cmp::Ordering {
+    for i in 0..a.len().min(b.len()) {
+        if a[i] != b[i] {
+            return a[i].cmp(&b[i]);
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn str_starts_with(haystack: &[u8], needle: &[u8]) -> bool {
+    if haystack.len() < needle.len() {
+        return false;
+    }
+    str_eq(&haystack[..needle.len()], needle)
+}
+
+fn find_byte(haystack: &[u8], needle: u8) -> Option<usize> {
+    for i in 0..haystack.len() {
+        if haystack[i] == needle {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// The environment the kernel placed on the initial stack, after argv's NULL terminator.
+// Set once at startup by _start_rust.
+static mut STACK_ENVP: *const *const u8 = core::ptr::null();
+
+// The stack environment, or None if it is empty (then /proc/self/environ is tried instead)
+fn stack_envp() -> Option<*const *const u8> {
+    unsafe {
+        if STACK_ENVP.is_null() || (*STACK_ENVP).is_null() {
+            None
+        } else {
+            Some(STACK_ENVP)
+        }
+    }
+}
+
+// Read /proc/self/environ into buf, returning the number of bytes read (0 if it
+// can't be read). procfs returns at most a page per read(), so read until EOF or
+// until buf is full.
+fn read_proc_environ(buf: &mut [u8]) -> usize {
+    let fd = open(b"/proc/self/environ\0");
     if fd < 0 {
-        return None;
+        return 0;
     }
 
-    let bytes_read = read(fd, environ_buf);
+    let mut total = 0;
+    while total < buf.len() {
+        let bytes_read = read(fd, &mut buf[total..]);
+        if bytes_read <= 0 {
+            break;
+        }
+        total += bytes_read as usize;
+    }
     close(fd);
+    total
+}
+
+// Static buffer for reading environment during initialization
+// Using a static buffer here to avoid stack overflow from large stack allocation
+static mut GET_ENV_BUF: [u8; MAX_ENV_SIZE] = [0; MAX_ENV_SIZE];
+
+// Environment variable reading
+fn get_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
+    if let Some(envp) = stack_envp() {
+        let mut i = 0;
+        unsafe {
+            while !(*envp.add(i)).is_null() {
+                let entry_ptr = *envp.add(i);
+                let mut entry_len = 0;
+                while *entry_ptr.add(entry_len) != 0 {
+                    entry_len += 1;
+                }
+                let entry = core::slice::from_raw_parts(entry_ptr, entry_len);
+                if let Some(eq_pos) = find_byte(entry, b'=') {
+                    if str_eq(&entry[..eq_pos], name) {
+                        let value = &entry[eq_pos + 1..];
+                        let copy_len = value.len().min(buf.len());
+                        buf[..copy_len].copy_from_slice(&value[..copy_len]);
+                        return Some(copy_len);
+                    }
+                }
+                i += 1;
+            }
+        }
+        return None;
+    }
 
-    if bytes_read <= 0 {
+    let environ_buf = unsafe { &mut GET_ENV_BUF };
+    let bytes_read = read_proc_environ(environ_buf);
+    if bytes_read == 0 {
         return None;
     }
 
-    let environ_data = &environ_buf[..bytes_read as usize];
+    let environ_data = &environ_buf[..bytes_read];
     let mut pos = 0;
 
     while pos < environ_data.len() {
@@ -406,9 +1356,67 @@ fn get_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
     None
 }
 
+// Reads RUNFILES_MANIFEST_FILE or RUNFILES_DIR. With FLAG_EXPAND_ENV, `$VAR` and
+// `${VAR}` references in the value are replaced (unset variables are kept as-is).
+fn get_runfiles_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let len = get_env_var(name, buf)?;
+    if unsafe { METADATA.flags } & FLAG_EXPAND_ENV == 0 || find_byte(&buf[..len], b'$').is_none() {
+        return Some(len);
+    }
+    let mut value = [0u8; MAX_PATH_LEN];
+    value.get_mut(..len)?.copy_from_slice(&buf[..len]);
+    expand_env_refs(&value[..len], buf)
+}
+
+// Name and length of the `$VAR` or `${VAR}` reference at the start of s
+fn env_reference(s: &[u8]) -> Option<(&[u8], usize)> {
+    if s.first() != Some(&b'$') {
+        return None;
+    }
+    let braced = s.get(1) == Some(&b'{');
+    let start = if braced { 2 } else { 1 };
+    let mut end = start;
+    while end < s.len() && (s[end].is_ascii_alphanumeric() || s[end] == b'_') {
+        end += 1;
+    }
+    if end == start || s[start].is_ascii_digit() {
+        return None;
+    }
+    if !braced {
+        return Some((&s[start..end], end));
+    }
+    if s.get(end) != Some(&b'}') {
+        return None;
+    }
+    Some((&s[start..end], end + 1))
+}
+
+// Copies value to out with environment variable references replaced by their
+// values. Returns None if the result doesn't fit.
+fn expand_env_refs(value: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut out_len = 0;
+    let mut i = 0;
+    while i < value.len() {
+        if let Some((name, reference_len)) = env_reference(&value[i..]) {
+            let mut var = [0u8; MAX_PATH_LEN];
+            if let Some(var_len) = get_env_var(name, &mut var) {
+                out.get_mut(out_len..out_len + var_len)?.copy_from_slice(&var[..var_len]);
+                out_len += var_len;
+                i += reference_len;
+                continue;
+            }
+        }
+        *out.get_mut(out_len)? = value[i];
+        out_len += 1;
+        i += 1;
+    }
+    Some(out_len)
+}
+
 // Manifest entry storage (simplified - using static arrays)
 const MAX_ENTRIES: usize = 1024;
-const MAX_PATH_LEN: usize = 256;
+// Paths hold at least a whole embedded argument, so a long rlocation path still resolves
+const MAX_PATH_LEN: usize = if ARG_SIZE > 256 { ARG_SIZE } else { 256 };
 
 struct ManifestEntry {
     key: [u8; MAX_PATH_LEN],
@@ -419,11 +1427,13 @@ struct ManifestEntry {
 
 struct Manifest {
     entries: [ManifestEntry; MAX_ENTRIES],
+    // Entry indices ordered by key, for lookup's binary search
+    order: [u16; MAX_ENTRIES],
     count: usize,
 }
 
 impl Manifest {
-    fn new() -> Self {
+    const fn new() -> Self {
         const EMPTY_ENTRY: ManifestEntry = ManifestEntry {
             key: [0; MAX_PATH_LEN],
             key_len: 0,
@@ -433,13 +1443,15 @@ impl Manifest {
 
         Self {
             entries: [EMPTY_ENTRY; MAX_ENTRIES],
+            order: [0; MAX_ENTRIES],
             count: 0,
         }
     }
 
-    fn add_entry(&mut self, key: &[u8], value: &[u8]) {
+    // Returns false if the manifest is full
+    fn add_entry(&mut self, key: &[u8], value: &[u8]) -> bool {
         if self.count >= MAX_ENTRIES {
-            return;
+            return false;
         }
 
         let entry = &mut self.entries[self.count];
@@ -452,36 +1464,160 @@ impl Manifest {
         entry.value_len = value_len;
 
         self.count += 1;
+        true
     }
 
-    fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+    fn key(&self, index: u16) -> &[u8] {
+        let entry = &self.entries[index as usize];
+        &entry.key[..entry.key_len]
+    }
+
+    // Order the entries by key once they are all added. Bazel writes manifests
+    // sorted, so the insertion sort is usually a single pass; being stable, it
+    // keeps the first of duplicate keys first.
+    fn sort(&mut self) {
         for i in 0..self.count {
-            let entry = &self.entries[i];
-            if str_eq(&entry.key[..entry.key_len], key) {
-                return Some(&entry.value[..entry.value_len]);
+            let index = i as u16;
+            let mut j = i;
+            while j > 0 && str_cmp(self.key(self.order[j - 1]), self.key(index)).is_gt() {
+                self.order[j] = self.order[j - 1];
+                j -= 1;
             }
+            self.order[j] = index;
         }
-        None
     }
+
+    fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+        // Find the first entry whose key is not less than `key`
+        let mut low = 0;
+        let mut high = self.count;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if str_cmp(self.key(self.order[mid]), key).is_lt() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let index = *self.order[..self.count].get(low)?;
+        let entry = &self.entries[index as usize];
+        if str_eq(&entry.key[..entry.key_len], key) {
+            Some(&entry.value[..entry.value_len])
+        } else {
+            None
+        }
+    }
+}
+
+// Check if a path is absolute
+fn is_absolute_path(path: &[u8]) -> bool {
+    !path.is_empty() && path[0] == b'/'
+}
+
+// Undo Bazel's manifest escaping: \s is a space, \n a newline and \b a backslash.
+// Returns the unescaped length; like unescaped entries, the result is truncated to out.
+fn unescape_manifest_field(field: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < field.len() && len < MAX_PATH_LEN {
+        let c = if field[i] == b'\\' && i + 1 < field.len() {
+            i += 1;
+            match field[i] {
+                b's' => b' ',
+                b'n' => b'\n',
+                b'b' => b'\\',
+                other => other,
+            }
+        } else {
+            field[i]
+        };
+        out[len] = c;
+        len += 1;
+        i += 1;
+    }
+    len
+}
+
+// Split a manifest line into its key and value. Entries whose paths contain spaces,
+// newlines or backslashes are escaped by Bazel and marked with a leading space;
+// those are unescaped into key_buf and value_buf.
+fn split_manifest_line<'a>(
+    line: &'a [u8],
+    key_buf: &'a mut [u8; MAX_PATH_LEN],
+    value_buf: &'a mut [u8; MAX_PATH_LEN],
+) -> Option<(&'a [u8], &'a [u8])> {
+    if line.first() == Some(&b' ') {
+        let line = &line[1..];
+        let space_pos = find_byte(line, b' ')?;
+        let key_len = unescape_manifest_field(&line[..space_pos], key_buf);
+        let value_len = unescape_manifest_field(&line[space_pos + 1..], value_buf);
+        return Some((&key_buf[..key_len], &value_buf[..value_len]));
+    }
+    let space_pos = find_byte(line, b' ')?;
+    Some((&line[..space_pos], &line[space_pos + 1..]))
+}
+
+// Manifests (and the repository mapping) are read whole into this buffer
+const MAX_MANIFEST_SIZE: usize = 1048576;
+static mut MANIFEST_BUF: [u8; MAX_MANIFEST_SIZE] = [0; MAX_MANIFEST_SIZE];
+
+// Report a runfiles file the stub can't load completely and exit
+fn runfiles_file_too_large(path: &[u8], limit: usize, unit: &[u8]) -> ! {
+    eprint(b"ERROR: Runfiles file ");
+    eprint(&path[..str_len(path)]);
+    eprint(b" has more than ");
+    eprint_number(limit);
+    eprint(unit);
+    exit(1);
 }
 
-// Load manifest file
-fn load_manifest(path: &[u8]) -> Option<Manifest> {
+// Read a whole runfiles file into MANIFEST_BUF. Returns None if it can't be read or is empty.
+fn read_runfiles_file(path: &[u8]) -> Option<&'static [u8]> {
     let fd = open(path);
     if fd < 0 {
         return None;
     }
 
-    let mut file_buf = [0u8; 65536];
-    let bytes_read = read(fd, &mut file_buf);
+    // read() may return fewer bytes than requested, so read until EOF
+    let buf = unsafe { &mut MANIFEST_BUF };
+    let mut total = 0;
+    loop {
+        if total == buf.len() {
+            // A full buffer is only complete if nothing follows
+            let mut probe = [0u8; 1];
+            if read(fd, &mut probe) > 0 {
+                close(fd);
+                runfiles_file_too_large(path, MAX_MANIFEST_SIZE, b" bytes\n");
+            }
+            break;
+        }
+        let bytes_read = read(fd, &mut buf[total..]);
+        if bytes_read < 0 {
+            close(fd);
+            return None;
+        }
+        if bytes_read == 0 {
+            break;
+        }
+        total += bytes_read as usize;
+    }
     close(fd);
 
-    if bytes_read <= 0 {
+    if total == 0 {
         return None;
     }
+    Some(unsafe { &MANIFEST_BUF[..total] })
+}
+
+// The loaded manifest; too large for the stack once paths grow with ARG_SIZE
+static mut MANIFEST: Manifest = Manifest::new();
 
-    let mut manifest = Manifest::new();
-    let data = &file_buf[..bytes_read as usize];
+// Load manifest file into MANIFEST
+fn load_manifest(path: &[u8]) -> Option<&'static Manifest> {
+    let data = read_runfiles_file(path)?;
+
+    let manifest = unsafe { &mut *core::ptr::addr_of_mut!(MANIFEST) };
+    manifest.count = 0;
     let mut pos = 0;
 
     while pos < data.len() {
@@ -492,46 +1628,287 @@ fn load_manifest(path: &[u8]) -> Option<Manifest> {
 
         let line = &data[line_start..pos];
 
-        if let Some(space_pos) = find_byte(line, b' ') {
-            let key = &line[..space_pos];
-            let value = &line[space_pos + 1..];
-            manifest.add_entry(key, value);
+        let mut key_buf = [0u8; MAX_PATH_LEN];
+        let mut value_buf = [0u8; MAX_PATH_LEN];
+        if let Some((key, value)) = split_manifest_line(line, &mut key_buf, &mut value_buf) {
+            if !manifest.add_entry(key, value) {
+                runfiles_file_too_large(path, MAX_ENTRIES, b" entries\n");
+            }
         }
 
         pos += 1;
     }
 
+    manifest.sort();
+
     Some(manifest)
 }
 
+// Look up an rlocation path in a manifest
+fn resolve_in_manifest(manifest: &Manifest, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
+    let mut resolved = manifest.lookup(path)?;
+    // Optional single level of indirection: a relative value names another
+    // manifest entry. A value naming its own key is a cycle and not followed.
+    if follow_indirection && !is_absolute_path(resolved) && !str_eq(resolved, path) {
+        resolved = manifest.lookup(resolved)?;
+    }
+    let mut result = [0u8; MAX_PATH_LEN];
+    let len = resolved.len().min(MAX_PATH_LEN);
+    result[..len].copy_from_slice(&resolved[..len]);
+    Some(result)
+}
+
+// Manifest of a runfiles directory (<dir>/MANIFEST or <dir>_manifest), loaded into
+// MANIFEST the first time a file is missing from the directory.
+// 0: not tried yet, 1: loaded, 2: there is none
+static mut DIR_MANIFEST_STATE: u8 = 0;
+
+fn dir_manifest(dir: &[u8]) -> Option<&'static Manifest> {
+    unsafe {
+        if DIR_MANIFEST_STATE == 0 {
+            DIR_MANIFEST_STATE = 2;
+            for suffix in [b"/MANIFEST\0" as &[u8], b"_manifest\0"] {
+                let mut path = [0u8; MAX_PATH_LEN + 16];
+                path[..dir.len()].copy_from_slice(dir);
+                path[dir.len()..dir.len() + suffix.len()].copy_from_slice(suffix);
+                if load_manifest(&path[..dir.len() + suffix.len()]).is_some() {
+                    DIR_MANIFEST_STATE = 1;
+                    break;
+                }
+            }
+        }
+        if DIR_MANIFEST_STATE == 1 {
+            Some(&*core::ptr::addr_of!(MANIFEST))
+        } else {
+            None
+        }
+    }
+}
+
+// Bzlmod repository mapping
+// The _repo_mapping runfile maps the apparent repository names used in a repository to
+// canonical ones, one "source,apparent,canonical" line per mapping. Only the lines for
+// the stub's own repository are kept; a source ending in '*' stands for every
+// repository with that prefix.
+const MAX_REPO_MAPPINGS: usize = 256;
+
+struct RepoMapping {
+    apparent: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    apparent_lens: [usize; MAX_REPO_MAPPINGS],
+    canonical: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    canonical_lens: [usize; MAX_REPO_MAPPINGS],
+    count: usize,
+}
+
+// Loaded once with the runfiles, empty if there is no _repo_mapping
+static mut REPO_MAPPING: RepoMapping = RepoMapping {
+    apparent: [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    apparent_lens: [0; MAX_REPO_MAPPINGS],
+    canonical: [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    canonical_lens: [0; MAX_REPO_MAPPINGS],
+    count: 0,
+};
+
+impl RepoMapping {
+    // Returns false if the mapping is full
+    fn add(&mut self, apparent: &[u8], canonical: &[u8]) -> bool {
+        if self.count >= MAX_REPO_MAPPINGS {
+            return false;
+        }
+
+        let apparent_len = apparent.len().min(MAX_PATH_LEN);
+        let canonical_len = canonical.len().min(MAX_PATH_LEN);
+        self.apparent[self.count][..apparent_len].copy_from_slice(&apparent[..apparent_len]);
+        self.apparent_lens[self.count] = apparent_len;
+        self.canonical[self.count][..canonical_len].copy_from_slice(&canonical[..canonical_len]);
+        self.canonical_lens[self.count] = canonical_len;
+
+        self.count += 1;
+        true
+    }
+
+    fn lookup(&self, apparent: &[u8]) -> Option<&[u8]> {
+        for i in 0..self.count {
+            if str_eq(&self.apparent[i][..self.apparent_lens[i]], apparent) {
+                return Some(&self.canonical[i][..self.canonical_lens[i]]);
+            }
+        }
+        None
+    }
+
+    // Keep the mappings of source_repo from a _repo_mapping file
+    fn load(&mut self, path: &[u8], source_repo: &[u8]) {
+        self.count = 0;
+        let data = match read_runfiles_file(path) {
+            Some(data) => data,
+            None => return,
+        };
+
+        for line in data.split(|&b| b == b'\n') {
+            let mut fields = line.split(|&b| b == b',');
+            let (source, apparent, canonical) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some(apparent), Some(canonical)) => (source, apparent, canonical),
+                _ => continue,
+            };
+            let applies = match source.last() {
+                Some(b'*') => str_starts_with(source_repo, &source[..source.len() - 1]),
+                _ => str_eq(source, source_repo),
+            };
+            if applies && !self.add(apparent, canonical) {
+                runfiles_file_too_large(path, MAX_REPO_MAPPINGS, b" entries\n");
+            }
+        }
+    }
+
+    // Replace the apparent repository name at the start of an rlocation path with its
+    // canonical name. Returns None if it has no mapping.
+    fn map(&self, path: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+        let repo_len = find_byte(path, b'/').unwrap_or(path.len());
+        let canonical = self.lookup(&path[..repo_len])?;
+        let rest = &path[repo_len..];
+        if canonical.len() + rest.len() > MAX_PATH_LEN {
+            return None;
+        }
+        out[..canonical.len()].copy_from_slice(canonical);
+        out[canonical.len()..canonical.len() + rest.len()].copy_from_slice(rest);
+        Some(canonical.len() + rest.len())
+    }
+}
+
 // Runfiles implementation
 enum RunfilesMode {
-    ManifestBased(Manifest),
+    ManifestBased(&'static Manifest),
     DirectoryBased([u8; MAX_PATH_LEN], usize),
 }
 
+// The runfiles directory Bazel places next to a manifest: <dir>_manifest (which
+// includes <name>.runfiles_manifest) or <dir>/MANIFEST. None unless it exists.
+fn manifest_runfiles_dir(manifest: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let is_manifest_file = manifest.ends_with(b"_manifest") || manifest.ends_with(b"/MANIFEST");
+    // Both suffixes are 9 bytes long
+    let len = manifest.len().checked_sub(9).filter(|&len| is_manifest_file && len > 0 && len < MAX_PATH_LEN)?;
+    let mut dir = [0u8; MAX_PATH_LEN];
+    dir[..len].copy_from_slice(&manifest[..len]);
+    // dir[len] is the NUL path_exists needs
+    if !path_exists(&dir[..len + 1]) {
+        return None;
+    }
+    Some((dir, len))
+}
+
+// The manifest Bazel writes for a runfiles directory: <dir>/MANIFEST or <dir>_manifest
+// (which includes <name>.runfiles_manifest). Exported in directory mode for children
+// that only read manifests. None unless it exists.
+fn runfiles_dir_manifest(dir: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let mut dir_len = dir.len();
+    while dir_len > 1 && dir[dir_len - 1] == b'/' {
+        dir_len -= 1;
+    }
+    for suffix in [b"/MANIFEST" as &[u8], b"_manifest"] {
+        let len = dir_len + suffix.len();
+        if len >= MAX_PATH_LEN {
+            continue;
+        }
+        let mut path = [0u8; MAX_PATH_LEN];
+        path[..dir_len].copy_from_slice(&dir[..dir_len]);
+        path[dir_len..len].copy_from_slice(suffix);
+        // path[len] is the NUL path_exists needs
+        if path_exists(&path[..len + 1]) {
+            return Some((path, len));
+        }
+    }
+    None
+}
+
+// With RUNFILES_STUB_DEBUG set, warns when RUNFILES_DIR names another tree than
+// RUNFILES_MANIFEST_FILE, which takes precedence for lookups and the export
+fn check_runfiles_dir(manifest: &[u8], manifest_dir: Option<&[u8]>) {
+    if get_env_var(b"RUNFILES_STUB_DEBUG", &mut [0u8; 64]).is_none_or(|len| len == 0) {
+        return;
+    }
+    let mut dir = [0u8; MAX_PATH_LEN];
+    let mut len = match get_runfiles_env_var(b"RUNFILES_DIR", &mut dir) {
+        Some(len) if len > 0 => len,
+        _ => return,
+    };
+    while len > 1 && dir[len - 1] == b'/' {
+        len -= 1;
+    }
+    if manifest_dir == Some(&dir[..len]) {
+        return;
+    }
+    eprint(b"WARNING: RUNFILES_DIR and RUNFILES_MANIFEST_FILE describe different runfiles trees\n");
+    eprint(b"  RUNFILES_MANIFEST_FILE=");
+    eprint(manifest);
+    eprint(b" (used)\n  RUNFILES_DIR=");
+    eprint(&dir[..len]);
+    eprint(b" (ignored)\n  Runfiles directory of the manifest: ");
+    eprint(manifest_dir.unwrap_or(b"(none)"));
+    eprint(b"\n");
+}
+
 struct Runfiles {
     mode: RunfilesMode,
     // Paths for environment variables (when export_runfiles_env is true)
     manifest_path: Option<([u8; MAX_PATH_LEN], usize)>, // RUNFILES_MANIFEST_FILE
     dir_path: Option<([u8; MAX_PATH_LEN], usize)>,      // RUNFILES_DIR and JAVA_RUNFILES
+    // Directory found through the environment or next to the executable: without
+    // symlink support it may lack files that its manifest lists
+    manifest_fallback: bool,
 }
 
 impl Runfiles {
     fn create(executable_path: Option<&[u8]>) -> Option<Self> {
+        let runfiles = Self::discover(executable_path)?;
+        runfiles.load_repo_mapping();
+        Some(runfiles)
+    }
+
+    // Runfiles directory chosen by the stub itself (sandbox root or extracted tree)
+    fn from_dir(dir: [u8; MAX_PATH_LEN], len: usize) -> Self {
+        let runfiles = Self {
+            mode: RunfilesMode::DirectoryBased(dir, len),
+            manifest_path: None,
+            dir_path: Some((dir, len)),
+            manifest_fallback: false,
+        };
+        runfiles.load_repo_mapping();
+        runfiles
+    }
+
+    // Load the repository mapping of the stub's source repository for rlocation()
+    fn load_repo_mapping(&self) {
+        unsafe {
+            let source_repo: &[u8] = if METADATA.flags & FLAG_SOURCE_REPO != 0 {
+                &SOURCE_REPO[..str_len(&SOURCE_REPO)]
+            } else {
+                b""
+            };
+            if let Some(path) = self.resolve(b"_repo_mapping", false) {
+                REPO_MAPPING.load(&path, source_repo);
+            }
+        }
+    }
+
+    // Find the runfiles through the environment or next to the executable
+    fn discover(executable_path: Option<&[u8]>) -> Option<Self> {
         let mut manifest_path = [0u8; MAX_PATH_LEN];
 
         // Try RUNFILES_MANIFEST_FILE first
-        if let Some(len) = get_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
             if len > 0 {
                 let mut path_with_null = [0u8; MAX_PATH_LEN + 1];
                 path_with_null[..len].copy_from_slice(&manifest_path[..len]);
 
                 if let Some(manifest) = load_manifest(&path_with_null[..len + 1]) {
+                    let dir_path = manifest_runfiles_dir(&manifest_path[..len]);
+                    check_runfiles_dir(&manifest_path[..len], dir_path.as_ref().map(|(dir, len)| &dir[..*len]));
                     return Some(Self {
                         mode: RunfilesMode::ManifestBased(manifest),
                         manifest_path: Some((manifest_path, len)),
-                        dir_path: None,
+                        // Exported too, for libraries that only use directories
+                        dir_path,
+                        manifest_fallback: false,
                     });
                 }
             }
@@ -539,12 +1916,14 @@ impl Runfiles {
 
         // Try RUNFILES_DIR
         let mut runfiles_dir = [0u8; MAX_PATH_LEN];
-        if let Some(len) = get_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
             if len > 0 {
                 return Some(Self {
                     mode: RunfilesMode::DirectoryBased(runfiles_dir, len),
-                    manifest_path: None,
+                    // Exported too, for libraries that only read manifests
+                    manifest_path: runfiles_dir_manifest(&runfiles_dir[..len]),
                     dir_path: Some((runfiles_dir, len)),
+                    manifest_fallback: true,
                 });
             }
         }
@@ -579,140 +1958,868 @@ impl Runfiles {
                             0
                         };
 
-                        let mut manifest_path_without_null = [0u8; MAX_PATH_LEN];
-                        manifest_path_without_null[..manifest_file_len].copy_from_slice(&manifest_file_path[..manifest_file_len]);
+                        let mut manifest_path_without_null = [0u8; MAX_PATH_LEN];
+                        manifest_path_without_null[..manifest_file_len].copy_from_slice(&manifest_file_path[..manifest_file_len]);
+
+                        return Some(Self {
+                            mode: RunfilesMode::ManifestBased(manifest),
+                            manifest_path: Some((manifest_path_without_null, manifest_file_len)),
+                            dir_path: if dir_len > 0 { Some((dir_path, dir_len)) } else { None },
+                            manifest_fallback: false,
+                        });
+                    }
+                }
+
+                // Try <executable>.runfiles directory
+                if exe_len + 10 < MAX_PATH_LEN {  // +10 for ".runfiles\0"
+                    let mut runfiles_dir = [0u8; MAX_PATH_LEN];
+
+                    // Copy executable path
+                    runfiles_dir[..exe_len].copy_from_slice(&exe_path[..exe_len]);
+
+                    // Append ".runfiles"
+                    runfiles_dir[exe_len..exe_len + 9].copy_from_slice(b".runfiles");
+                    runfiles_dir[exe_len + 9] = 0; // null terminator
+
+                    // Check if directory exists using access() syscall
+                    if path_exists(&runfiles_dir[..exe_len + 10]) {
+                        return Some(Self {
+                            mode: RunfilesMode::DirectoryBased(runfiles_dir, exe_len + 9),
+                            manifest_path: runfiles_dir_manifest(&runfiles_dir[..exe_len + 9]),
+                            dir_path: Some((runfiles_dir, exe_len + 9)),
+                            manifest_fallback: true,
+                        });
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn rlocation(&self, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
+        // Apparent repository names are translated through the repository mapping;
+        // paths without a mapping are looked up unchanged
+        let mut mapped = [0u8; MAX_PATH_LEN];
+        let path = match unsafe { REPO_MAPPING.map(path, &mut mapped) } {
+            Some(len) => &mapped[..len],
+            None => path,
+        };
+        self.resolve(path, follow_indirection)
+    }
+
+    // Look up an rlocation path as-is
+    fn resolve(&self, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
+        // If path is absolute, don't resolve through runfiles
+        if path.len() > 0 && path[0] == b'/' {
+            return None;
+        }
+
+        match &self.mode {
+            RunfilesMode::ManifestBased(manifest) => resolve_in_manifest(manifest, path, follow_indirection),
+            RunfilesMode::DirectoryBased(dir, dir_len) => {
+                let dir = &dir[..(*dir_len).min(MAX_PATH_LEN)];
+                let separator = dir.last().is_some_and(|&c| c != b'/');
+
+                // The result is passed to execve as a C string, so the NUL must fit too;
+                // a truncated path would name a different file
+                let len = dir.len() + separator as usize + path.len();
+                if len >= MAX_PATH_LEN {
+                    eprint(b"ERROR: Runfiles path too long: ");
+                    eprint(dir);
+                    if separator {
+                        eprint(b"/");
+                    }
+                    eprint(path);
+                    eprint(b"\nPaths are limited to ");
+                    eprint_number(MAX_PATH_LEN - 1);
+                    eprint(b" bytes\n");
+                    exit(1);
+                }
+
+                let mut result = [0u8; MAX_PATH_LEN];
+                result[..dir.len()].copy_from_slice(dir);
+                let mut pos = dir.len();
+                if separator {
+                    result[pos] = b'/';
+                    pos += 1;
+                }
+                result[pos..pos + path.len()].copy_from_slice(path);
+                result[len] = 0;
+
+                // A file missing from the tree may still be listed in its manifest
+                if self.manifest_fallback && !path_exists(&result[..len + 1]) {
+                    if let Some(resolved) = dir_manifest(dir).and_then(|m| resolve_in_manifest(m, path, follow_indirection)) {
+                        return Some(resolved);
+                    }
+                }
+
+                Some(result)
+            }
+        }
+    }
+}
+
+// Self-extracting stubs
+// With --self-extracting the finalizer appends a runfiles archive to the stub,
+// located through a trailer at the very end of the file (all integers little-endian):
+//   entry:   u32 path_len | path | u32 mode | u64 data_len | data
+//   trailer: u64 archive_offset | u64 entry_count | u64 archive_hash | "RFSTUBSX"
+// The archive is extracted once per content hash to $TMPDIR/runfiles-stub-<hash>.
+// Extraction goes to a fresh private (0700) staging directory that is renamed into
+// place, so concurrent first runs never see a partially extracted tree. $TMPDIR is
+// usually shared, so an existing tree is only reused if this user made it.
+const SELF_EXTRACT_MAGIC: &[u8; 8] = b"RFSTUBSX";
+const SELF_EXTRACT_TRAILER_SIZE: usize = 32;
+const MAX_EXTRACT_PATH_LEN: usize = MAX_PATH_LEN * 2;
+
+// Static buffer for copying file contents out of the archive
+static mut EXTRACT_BUF: [u8; 65536] = [0; 65536];
+
+fn read_exact(fd: i32, buf: &mut [u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = read(fd, &mut buf[pos..]);
+        if n <= 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
+
+fn write_all(fd: i32, buf: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = write(fd, &buf[pos..]);
+        if n <= 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
 
-                        return Some(Self {
-                            mode: RunfilesMode::ManifestBased(manifest),
-                            manifest_path: Some((manifest_path_without_null, manifest_file_len)),
-                            dir_path: if dir_len > 0 { Some((dir_path, dir_len)) } else { None },
-                        });
-                    }
-                }
+// Append `n` as decimal to buf at pos, returns the new position
+fn append_decimal(buf: &mut [u8], mut pos: usize, mut n: u64) -> Option<usize> {
+    let mut digits = [0u8; 20];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        n /= 10;
+        count += 1;
+        if n == 0 {
+            break;
+        }
+    }
+    if pos + count > buf.len() {
+        return None;
+    }
+    while count > 0 {
+        count -= 1;
+        buf[pos] = digits[count];
+        pos += 1;
+    }
+    Some(pos)
+}
 
-                // Try <executable>.runfiles directory
-                if exe_len + 10 < MAX_PATH_LEN {  // +10 for ".runfiles\0"
-                    let mut runfiles_dir = [0u8; MAX_PATH_LEN];
+// Append `n` as 16 lowercase hex digits to buf at pos, returns the new position
+fn append_hex(buf: &mut [u8], mut pos: usize, n: u64) -> Option<usize> {
+    if pos + 16 > buf.len() {
+        return None;
+    }
+    for i in 0..16 {
+        let nibble = ((n >> ((15 - i) * 4)) & 0xF) as u8;
+        buf[pos] = if nibble < 10 { b'0' + nibble } else { b'a' + nibble - 10 };
+        pos += 1;
+    }
+    Some(pos)
+}
 
-                    // Copy executable path
-                    runfiles_dir[..exe_len].copy_from_slice(&exe_path[..exe_len]);
+// Create every parent directory of the NUL-terminated path in path[..len] below
+// path[..start], which must already exist
+fn create_parent_dirs(path: &mut [u8], start: usize, len: usize) {
+    for i in start + 1..len {
+        if path[i] == b'/' {
+            path[i] = 0;
+            mkdir(&path[..i + 1], 0o755);
+            path[i] = b'/';
+        }
+    }
+}
 
-                    // Append ".runfiles"
-                    runfiles_dir[exe_len..exe_len + 9].copy_from_slice(b".runfiles");
-                    runfiles_dir[exe_len + 9] = 0; // null terminator
+// Whether the NUL-terminated path is a directory only this user can have written:
+// a real directory (not a symlink) owned by the effective user, without group or
+// other write permission
+fn is_private_dir(path: &[u8]) -> bool {
+    let mut st = [0u8; STAT_SIZE];
+    if lstat(path, &mut st) != 0 {
+        return false;
+    }
+    let mode = read_u32_le(&st[STAT_MODE_OFFSET..]);
+    let uid = read_u32_le(&st[STAT_UID_OFFSET..]);
+    mode & S_IFMT == S_IFDIR && mode & 0o022 == 0 && uid == geteuid()
+}
 
-                    // Check if directory exists using access() syscall
-                    if path_exists(&runfiles_dir[..exe_len + 10]) {
-                        return Some(Self {
-                            mode: RunfilesMode::DirectoryBased(runfiles_dir, exe_len + 9),
-                            manifest_path: None,
-                            dir_path: Some((runfiles_dir, exe_len + 9)),
-                        });
-                    }
-                }
-            }
+// Extract the runfiles archive appended to this executable.
+// Writes the extracted runfiles directory to dir_out and returns its length.
+fn extract_self(executable_path: Option<&[u8]>, dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    let mut fd = open(b"/proc/self/exe\0");
+    if fd < 0 {
+        // Fall back to argv[0] if procfs is not mounted
+        let exe_path = executable_path?;
+        let mut exe_with_null = [0u8; MAX_PATH_LEN + 1];
+        let exe_len = exe_path.len().min(MAX_PATH_LEN);
+        exe_with_null[..exe_len].copy_from_slice(&exe_path[..exe_len]);
+        fd = open(&exe_with_null[..exe_len + 1]);
+        if fd < 0 {
+            return None;
         }
+    }
+    let result = extract_archive(fd, dir_out);
+    close(fd);
+    result
+}
 
-        None
+fn extract_archive(fd: i32, dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    // Read the trailer
+    let mut trailer = [0u8; SELF_EXTRACT_TRAILER_SIZE];
+    if lseek(fd, -(SELF_EXTRACT_TRAILER_SIZE as i64), SEEK_END) < 0 || !read_exact(fd, &mut trailer) {
+        return None;
+    }
+    if !str_eq(&trailer[24..32], SELF_EXTRACT_MAGIC) {
+        return None;
+    }
+    let archive_offset = read_u64_le(&trailer[0..8]);
+    let entry_count = read_u64_le(&trailer[8..16]);
+    let archive_hash = read_u64_le(&trailer[16..24]);
+
+    // Final directory: $TMPDIR/runfiles-stub-<hash> (falling back to /tmp)
+    let mut dir_len = match get_env_var(b"TMPDIR", dir_out) {
+        Some(len) if len > 0 && len < MAX_PATH_LEN => len,
+        _ => {
+            dir_out[..4].copy_from_slice(b"/tmp");
+            4
+        }
+    };
+    while dir_len > 1 && dir_out[dir_len - 1] == b'/' {
+        dir_len -= 1;
+    }
+    let prefix = b"/runfiles-stub-";
+    if dir_len + prefix.len() + 16 >= MAX_PATH_LEN {
+        return None;
+    }
+    dir_out[dir_len..dir_len + prefix.len()].copy_from_slice(prefix);
+    dir_len = append_hex(dir_out, dir_len + prefix.len(), archive_hash)?;
+    dir_out[dir_len] = 0;
+
+    // Reuse a previous extraction (only ever published complete) if this user made
+    // it; anything else at that path may have been planted by another user
+    if is_private_dir(&dir_out[..dir_len + 1]) {
+        return Some(dir_len);
     }
 
-    fn rlocation(&self, path: &[u8]) -> Option<[u8; MAX_PATH_LEN]> {
-        // If path is absolute, don't resolve through runfiles
-        if path.len() > 0 && path[0] == b'/' {
+    // Staging directory: <final>.<pid>.<n>, which mkdir creates fresh and private,
+    // so nothing below it can have been planted
+    let mut path = [0u8; MAX_EXTRACT_PATH_LEN];
+    path[..dir_len].copy_from_slice(&dir_out[..dir_len]);
+    path[dir_len] = b'.';
+    let pid_len = append_decimal(&mut path, dir_len + 1, getpid() as u64)?;
+    path[pid_len] = b'.';
+    let mut attempt = 0;
+    let staging_len = loop {
+        let len = append_decimal(&mut path, pid_len + 1, attempt)?;
+        path[len] = 0;
+        let ret = mkdir(&path[..len + 1], 0o700);
+        if ret == 0 {
+            break len;
+        }
+        attempt += 1;
+        if ret != -EEXIST || attempt == 100 {
             return None;
         }
+    };
 
-        match &self.mode {
-            RunfilesMode::ManifestBased(manifest) => {
-                if let Some(resolved) = manifest.lookup(path) {
-                    let mut result = [0u8; MAX_PATH_LEN];
-                    let len = resolved.len().min(MAX_PATH_LEN);
-                    result[..len].copy_from_slice(&resolved[..len]);
-                    return Some(result);
-                }
-                None
-            }
-            RunfilesMode::DirectoryBased(dir, dir_len) => {
-                let mut result = [0u8; MAX_PATH_LEN];
-                let mut pos = 0;
+    if lseek(fd, archive_offset as i64, SEEK_SET) < 0 || !extract_entries(fd, entry_count, &mut path, staging_len) {
+        remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+        return None;
+    }
 
-                // Copy directory
-                let copy_len = (*dir_len).min(MAX_PATH_LEN);
-                result[..copy_len].copy_from_slice(&dir[..copy_len]);
-                pos += copy_len;
+    // Publish the tree. If another process of this user won the race, its identical
+    // tree is used and ours removed.
+    path[staging_len] = 0;
+    let published = rename(&path[..staging_len + 1], &dir_out[..dir_len + 1]) == 0;
+    if published || is_private_dir(&dir_out[..dir_len + 1]) {
+        if !published {
+            remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+        }
+        return Some(dir_len);
+    }
 
-                // Add separator if needed
-                if pos < MAX_PATH_LEN && pos > 0 && result[pos - 1] != b'/' {
-                    result[pos] = b'/';
-                    pos += 1;
-                }
+    // The final path is taken by something this user can't trust, so the private
+    // staging directory serves as a one-off copy
+    if staging_len >= MAX_PATH_LEN {
+        remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+        return None;
+    }
+    dir_out[..staging_len + 1].copy_from_slice(&path[..staging_len + 1]);
+    Some(staging_len)
+}
 
-                // Copy path
-                let path_len = path.len().min(MAX_PATH_LEN - pos);
-                result[pos..pos + path_len].copy_from_slice(&path[..path_len]);
+// Write the archive entries (read from fd's current position) below the staging
+// directory in path[..staging_len]
+fn extract_entries(fd: i32, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) -> bool {
+    path[staging_len] = b'/';
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(EXTRACT_BUF) };
+    for _ in 0..entry_count {
+        // Entry path, appended to the staging directory
+        let mut header = [0u8; 12];
+        if !read_exact(fd, &mut header[..4]) {
+            return false;
+        }
+        let path_len = read_u32_le(&header[..4]) as usize;
+        let path_start = staging_len + 1;
+        if path_start + path_len + 1 > MAX_EXTRACT_PATH_LEN {
+            return false;
+        }
+        if !read_exact(fd, &mut path[path_start..path_start + path_len]) {
+            return false;
+        }
+        let full_len = path_start + path_len;
+        path[full_len] = 0;
 
-                Some(result)
+        // Mode and data length
+        if !read_exact(fd, &mut header) {
+            return false;
+        }
+        let mode = read_u32_le(&header[..4]);
+        let mut remaining = read_u64_le(&header[4..12]);
+
+        create_parent_dirs(&mut path[..], staging_len, full_len);
+        let out = open_with(&path[..full_len + 1], O_WRONLY | O_CREAT | O_EXCL | O_CLOEXEC, mode);
+        if out < 0 {
+            return false;
+        }
+        while remaining > 0 {
+            let chunk = (remaining as usize).min(buf.len());
+            if !read_exact(fd, &mut buf[..chunk]) || !write_all(out, &buf[..chunk]) {
+                close(out);
+                return false;
+            }
+            remaining -= chunk as u64;
+        }
+        close(out);
+    }
+    true
+}
+
+// Remove what an extraction wrote below the staging directory in path[..staging_len]
+// by walking the archive again: each file, then its parent directories (which only
+// succeeds once they are empty), and finally the staging directory itself
+fn remove_staging(fd: i32, archive_offset: u64, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) {
+    path[staging_len] = b'/';
+    let path_start = staging_len + 1;
+    if lseek(fd, archive_offset as i64, SEEK_SET) >= 0 {
+        for _ in 0..entry_count {
+            let mut header = [0u8; 12];
+            if !read_exact(fd, &mut header[..4]) {
+                break;
+            }
+            let path_len = read_u32_le(&header[..4]) as usize;
+            if path_start + path_len + 1 > MAX_EXTRACT_PATH_LEN
+                || !read_exact(fd, &mut path[path_start..path_start + path_len])
+                || !read_exact(fd, &mut header)
+            {
+                break;
+            }
+            let full_len = path_start + path_len;
+            path[full_len] = 0;
+            unlinkat(&path[..full_len + 1], 0);
+            for i in (path_start..full_len).rev() {
+                if path[i] == b'/' {
+                    path[i] = 0;
+                    unlinkat(&path[..i + 1], AT_REMOVEDIR);
+                    path[i] = b'/';
+                }
+            }
+            if lseek(fd, read_u64_le(&header[4..12]) as i64, SEEK_CUR) < 0 {
+                break;
             }
         }
     }
+    path[staging_len] = 0;
+    unlinkat(&path[..staging_len + 1], AT_REMOVEDIR);
 }
 
 // Placeholders for stub runner (will be replaced in final binary)
 // Each placeholder uses a distinctive pattern starting with @@RUNFILES_
-const ARG_SIZE: usize = 256;
-
-#[used]
-#[link_section = ".runfiles_stubs"]
-static mut ARGC_PLACEHOLDER: [u8; 32] = *b"@@RUNFILES_ARGC@@\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+use crate::ARG_SIZE;
+
+// Scalar settings, packed into one block that the finalizer patches as a unit.
+// Fields are little-endian; `version` identifies the layout so the finalizer can
+// tell templates apart. `features` is set here and lists the flags this template
+// understands. Only the variable-length arguments live in separate placeholders.
+const METADATA_VERSION: u32 = 1;
+
+// Magic of a finalized metadata block (templates carry "@@RUNFILES_METADATA@@")
+const METADATA_MAGIC: &[u8] = b"RUNFILES_METADATA";
+
+// Bits of StubMetadata::flags and StubMetadata::features
+const FLAG_EXPORT_ENV: u32 = 1 << 0;
+const FLAG_SELF_EXTRACT: u32 = 1 << 1;
+const FLAG_INDIRECTION: u32 = 1 << 2;
+const FLAG_SPAWN: u32 = 1 << 3;
+const FLAG_PERCENT_DECODE: u32 = 1 << 4;
+const FLAG_DEFAULT_ARGS: u32 = 1 << 5;
+const FLAG_SANDBOX: u32 = 1 << 6;
+const FLAG_EXEC: u32 = 1 << 7;
+const FLAG_EXPAND_ROOT: u32 = 1 << 8;
+const FLAG_RLOCATION_PREFIXES: u32 = 1 << 9;
+const FLAG_STRICT_PREFIXES: u32 = 1 << 10;
+const FLAG_SOURCE_REPO: u32 = 1 << 11;
+const FLAG_EXPAND_ENV: u32 = 1 << 12;
+const FLAG_KEEP_ENV: u32 = 1 << 13;
+const FLAG_NO_EXPORT_MANIFEST: u32 = 1 << 14;
+const FLAG_NO_EXPORT_DIR: u32 = 1 << 15;
+const FLAG_NO_EXPORT_JAVA: u32 = 1 << 16;
+const FLAG_SET_ENV: u32 = 1 << 17;
+const FLAG_UMASK: u32 = 1 << 18;
+const FLAG_RLIMIT: u32 = 1 << 19;
+const FLAG_TRANSFORM_VALUE: u32 = 1 << 20;
+const FLAG_ARGV0: u32 = 1 << 21;
+const FLAG_RUNTIME_POS: u32 = 1 << 22;
+const FLAG_CHDIR: u32 = 1 << 23;
+
+// Section holding every placeholder below; the finalizer looks it up by this
+// name (ELF_PLACEHOLDER_SECTION in finalize-stub/src/binary.rs matches)
+macro_rules! placeholder_section {
+    () => {
+        ".runfiles_stubs"
+    };
+}
 
-#[used]
-#[link_section = ".runfiles_stubs"]
-static mut TRANSFORM_FLAGS: [u8; 32] = *b"@@RUNFILES_TRANSFORM_FLAGS@@\0\0\0\0";
+#[repr(C)]
+struct StubMetadata {
+    magic: [u8; 24],
+    version: u32,
+    arg_size: u32,
+    argc: u32,
+    transform_flags: u32,
+    flags: u32,
+    // Only read by the finalizer
+    #[allow(dead_code)]
+    features: u32,
+    // Set by the finalizer for tools reading the stub
+    #[allow(dead_code)]
+    args_offset: i32,
+    value_flags: u32,
+    #[allow(dead_code)]
+    reserved: [u8; 8],
+}
 
 #[used]
-#[link_section = ".runfiles_stubs"]
-static mut EXPORT_RUNFILES_ENV: [u8; 32] = *b"@@RUNFILES_EXPORT_ENV@@\0\0\0\0\0\0\0\0\0";
+#[link_section = placeholder_section!()]
+static mut METADATA: StubMetadata = StubMetadata {
+    magic: *b"@@RUNFILES_METADATA@@\0\0\0",
+    version: METADATA_VERSION,
+    arg_size: ARG_SIZE as u32,
+    argc: 0,
+    transform_flags: 0,
+    flags: 0,
+    features: FLAG_EXPORT_ENV | FLAG_SELF_EXTRACT | FLAG_INDIRECTION | FLAG_PERCENT_DECODE | FLAG_DEFAULT_ARGS
+        | FLAG_SANDBOX | FLAG_EXEC | FLAG_EXPAND_ROOT | FLAG_RLOCATION_PREFIXES | FLAG_STRICT_PREFIXES
+        | FLAG_SOURCE_REPO | FLAG_EXPAND_ENV | FLAG_KEEP_ENV
+        | FLAG_NO_EXPORT_MANIFEST | FLAG_NO_EXPORT_DIR | FLAG_NO_EXPORT_JAVA | FLAG_SET_ENV
+        | FLAG_UMASK | FLAG_RLIMIT | FLAG_TRANSFORM_VALUE | FLAG_ARGV0 | FLAG_RUNTIME_POS | FLAG_CHDIR
+        | if cfg!(feature = "spawn") { FLAG_SPAWN } else { 0 },
+    args_offset: 0,
+    value_flags: 0,
+    reserved: [0; 8],
+};
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG0_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG1_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG2_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG3_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG4_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG5_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG6_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG7_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG8_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles_stubs"]
+#[link_section = placeholder_section!()]
 static mut ARG9_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
+// Default runtime arguments, used only when the stub is run without any.
+// Finalized as NUL-terminated strings followed by an empty one.
+const DEFAULT_ARGS_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut DEFAULT_ARGS: [u8; DEFAULT_ARGS_SIZE] = {
+    let magic = b"@@RUNFILES_DEFAULT_ARGS@@";
+    let mut buf = [0u8; DEFAULT_ARGS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Index of the embedded argument the runtime arguments go before
+// (--runtime-args-position), as NUL-terminated decimal digits. Without it they
+// follow the last embedded argument.
+const RUNTIME_POS_SIZE: usize = 32;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RUNTIME_POS: [u8; RUNTIME_POS_SIZE] = {
+    let magic = b"@@RUNFILES_RUNTIME_POS@@";
+    let mut buf = [0u8; RUNTIME_POS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Directory to run the target in (--chdir), as a NUL-terminated path. A relative
+// path is resolved through the runfiles.
+const CHDIR_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut CHDIR: [u8; CHDIR_SIZE] = {
+    let magic = b"@@RUNFILES_CHDIR@@";
+    let mut buf = [0u8; CHDIR_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Program to run instead of argv[0] (--exec), as a NUL-terminated path.
+// Relative paths are resolved through runfiles like transformed arguments.
+const EXEC_PATH_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut EXEC_PATH: [u8; EXEC_PATH_SIZE] = {
+    let magic = b"@@RUNFILES_EXEC_PATH@@";
+    let mut buf = [0u8; EXEC_PATH_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Name the target sees as argv[0] (--argv0), as a NUL-terminated string. The
+// program run is still the (resolved) first argument.
+const ARGV0_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARGV0: [u8; ARGV0_SIZE] = {
+    let magic = b"@@RUNFILES_ARGV0@@";
+    let mut buf = [0u8; ARGV0_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Hermetic sandbox: the stub changes into this directory, uses it as the
+// runfiles tree and passes on only the allowlisted environment variables.
+// The root is a NUL-terminated absolute path; the allowlist holds
+// NUL-terminated variable names followed by an empty one.
+const ENV_ALLOWLIST_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SANDBOX_ROOT: [u8; MAX_PATH_LEN] = {
+    let magic = b"@@RUNFILES_SANDBOX_ROOT@@";
+    let mut buf = [0u8; MAX_PATH_LEN];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ENV_ALLOWLIST: [u8; ENV_ALLOWLIST_SIZE] = {
+    let magic = b"@@RUNFILES_ENV_ALLOWLIST@@";
+    let mut buf = [0u8; ENV_ALLOWLIST_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Rlocation prefixes transformed arguments must start with to be resolved
+// (--allow-rlocation-prefix), as NUL-terminated strings followed by an empty one.
+const RLOCATION_PREFIXES_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RLOCATION_PREFIXES: [u8; RLOCATION_PREFIXES_SIZE] = {
+    let magic = b"@@RUNFILES_RLOCATION_PREFIXES@@";
+    let mut buf = [0u8; RLOCATION_PREFIXES_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Variables set for the target (--set-env), as NUL-terminated NAME=value strings
+// followed by an empty one. They replace inherited values.
+const SET_ENV_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SET_ENV: [u8; SET_ENV_SIZE] = {
+    let magic = b"@@RUNFILES_SET_ENV@@";
+    let mut buf = [0u8; SET_ENV_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// File-creation mask for the target (--umask), as NUL-terminated octal digits
+const UMASK_SIZE: usize = 32;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut UMASK: [u8; UMASK_SIZE] = {
+    let magic = b"@@RUNFILES_UMASK@@";
+    let mut buf = [0u8; UMASK_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Resource limits for the target (--rlimit), as NUL-terminated name=value strings
+// followed by an empty one. The value is a decimal number or "unlimited".
+const RLIMITS_SIZE: usize = 512;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RLIMITS: [u8; RLIMITS_SIZE] = {
+    let magic = b"@@RUNFILES_RLIMITS@@";
+    let mut buf = [0u8; RLIMITS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Canonical name of the repository the stub belongs to (--source-repo), as a
+// NUL-terminated string. Without FLAG_SOURCE_REPO it is the main repository ("").
+const SOURCE_REPO_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SOURCE_REPO: [u8; SOURCE_REPO_SIZE] = {
+    let magic = b"@@RUNFILES_SOURCE_REPO@@";
+    let mut buf = [0u8; SOURCE_REPO_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Check whether an rlocation path starts with one of a NUL-separated list of prefixes
+fn rlocation_allowed(path: &[u8], prefixes: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < prefixes.len() && prefixes[pos] != 0 {
+        let len = str_len(&prefixes[pos..]);
+        if str_starts_with(path, &prefixes[pos..pos + len]) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
+// Check whether a NAME=value entry names a variable in a NUL-separated allowlist
+fn env_name_allowed(entry: &[u8], allowlist: &[u8]) -> bool {
+    let name = match find_byte(entry, b'=') {
+        Some(pos) => &entry[..pos],
+        None => entry,
+    };
+    let mut pos = 0;
+    while pos < allowlist.len() && allowlist[pos] != 0 {
+        let len = str_len(&allowlist[pos..]);
+        if str_eq(&allowlist[pos..pos + len], name) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
+// Check whether a NAME=value entry names a variable in a NUL-separated list of
+// NAME=value entries
+fn env_name_set(entry: &[u8], vars: &[u8]) -> bool {
+    let name = match find_byte(entry, b'=') {
+        Some(pos) => &entry[..pos],
+        None => entry,
+    };
+    let mut pos = 0;
+    while pos < vars.len() && vars[pos] != 0 {
+        let len = str_len(&vars[pos..]);
+        let var = &vars[pos..pos + len];
+        if var.get(name.len()) == Some(&b'=') && str_starts_with(var, name) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
+// Resource names accepted by --rlimit and their RLIMIT_* numbers
+const RLIMIT_RESOURCES: [(&[u8], u32); 9] = [
+    (b"cpu", 0),
+    (b"fsize", 1),
+    (b"data", 2),
+    (b"stack", 3),
+    (b"core", 4),
+    (b"nproc", 6),
+    (b"nofile", 7),
+    (b"memlock", 8),
+    (b"as", 9),
+];
+const RLIM_INFINITY: u64 = u64::MAX;
+
+// Apply the embedded umask (--umask) and resource limits (--rlimit) to the stub
+// itself; the target inherits them across execve (and fork in spawn mode)
+fn apply_process_limits(flags: u32) {
+    unsafe {
+        if flags & FLAG_UMASK != 0 {
+            let digits = &*core::ptr::addr_of!(UMASK);
+            let mut mask = 0u32;
+            for &c in &digits[..str_len(digits)] {
+                mask = mask * 8 + (c - b'0') as u32;
+            }
+            umask(mask);
+        }
+        if flags & FLAG_RLIMIT == 0 {
+            return;
+        }
+        let limits = &*core::ptr::addr_of!(RLIMITS);
+        let mut pos = 0;
+        while pos < limits.len() && limits[pos] != 0 {
+            let len = str_len(&limits[pos..]);
+            let entry = &limits[pos..pos + len];
+            pos += len + 1;
+
+            let (name, value) = match find_byte(entry, b'=') {
+                Some(eq) => (&entry[..eq], &entry[eq + 1..]),
+                None => (entry, &entry[len..]),
+            };
+            let resource = RLIMIT_RESOURCES.iter().find(|(known, _)| str_eq(known, name)).map(|&(_, number)| number);
+            let limit = if str_eq(value, b"unlimited") {
+                Some(RLIM_INFINITY)
+            } else {
+                parse_decimal(value)
+            };
+            let (Some(resource), Some(limit)) = (resource, limit) else {
+                eprint(b"ERROR: Invalid embedded resource limit: ");
+                eprint(entry);
+                eprint(b"\n");
+                exit(1);
+            };
+            let ret = setrlimit(resource, &Rlimit { current: limit, max: limit });
+            if ret != 0 {
+                eprint(b"ERROR: Failed to set resource limit ");
+                eprint(entry);
+                eprint(b"\n");
+                exit(1);
+            }
+        }
+    }
+}
+
+fn parse_decimal(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n = 0u64;
+    for &c in digits {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((c - b'0') as u64)?;
+    }
+    Some(n)
+}
+
+// Check whether a relative path has a ".." component
+fn has_parent_component(path: &[u8]) -> bool {
+    path.split(|&b| b == b'/').any(|component| component == b"..")
+}
+
 // Get the length of a null-terminated string (Rust-style, takes slice)
 fn str_len(s: &[u8]) -> usize {
     let mut len = 0;
@@ -722,12 +2829,124 @@ fn str_len(s: &[u8]) -> usize {
     len
 }
 
-// Check if placeholder is still in template state
-fn is_template_placeholder(placeholder: &[u8]) -> bool {
-    if placeholder.len() < 17 {
-        return false;
+// Check if placeholder is still in template state
+fn is_template_placeholder(placeholder: &[u8]) -> bool {
+    if placeholder.len() < 17 {
+        return false;
+    }
+    str_starts_with(placeholder, b"@@RUNFILES_")
+}
+
+// Percent-decodes `input` (e.g. "%20" -> ' ') into `out`, returns the decoded length.
+// Malformed escapes are copied verbatim.
+fn percent_decode(input: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    fn hex_value(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut i = 0;
+    let mut len = 0;
+    while i < input.len() && len < MAX_PATH_LEN {
+        let escaped = if input[i] == b'%' && i + 2 < input.len() {
+            match (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                out[len] = byte;
+                i += 3;
+            }
+            None => {
+                out[len] = input[i];
+                i += 1;
+            }
+        }
+        len += 1;
+    }
+    len
+}
+
+// Token in embedded arguments that expands to the runfiles directory
+const RUNFILES_ROOT_TOKEN: &[u8] = b"{RUNFILES_ROOT}";
+
+// Copy `arg` to `out` with each RUNFILES_ROOT_TOKEN replaced by `root`.
+// Returns the length, or None if the result (plus a NUL) doesn't fit.
+fn expand_runfiles_root(arg: &[u8], root: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut len = 0;
+    while pos < arg.len() {
+        let piece = if str_starts_with(&arg[pos..], RUNFILES_ROOT_TOKEN) {
+            pos += RUNFILES_ROOT_TOKEN.len();
+            root
+        } else {
+            pos += 1;
+            &arg[pos - 1..pos]
+        };
+        if len + piece.len() >= out.len() {
+            return None;
+        }
+        out[len..len + piece.len()].copy_from_slice(piece);
+        len += piece.len();
+    }
+    out[len] = 0;
+    Some(len)
+}
+
+// Appends the effective configuration and argv to the file named by
+// RUNFILES_STUB_DEBUG_FILE, for debugging stubs that run without a terminal
+fn write_debug_file(metadata: &StubMetadata, runfiles: Option<&Runfiles>, argv: &[*const u8]) {
+    let mut path = [0u8; MAX_PATH_LEN + 1];
+    let len = match get_env_var(b"RUNFILES_STUB_DEBUG_FILE", &mut path[..MAX_PATH_LEN]) {
+        Some(len) if len > 0 => len,
+        _ => return,
+    };
+    path[len] = 0;
+
+    let fd = open_with(&path[..len + 1], O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC, 0o644);
+    if fd < 0 {
+        return;
+    }
+
+    let mut number = [0u8; 20];
+    let mut write_number = |fd, n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(fd, &number[..len]);
+    };
+
+    write_all(fd, b"runfiles-stub: argc=");
+    write_number(fd, metadata.argc);
+    write_all(fd, b" transform_flags=");
+    write_number(fd, metadata.transform_flags);
+    write_all(fd, b" flags=");
+    write_number(fd, metadata.flags);
+    write_all(fd, match runfiles.map(|rf| &rf.mode) {
+        Some(RunfilesMode::ManifestBased(_)) => b" runfiles=manifest\n" as &[u8],
+        Some(RunfilesMode::DirectoryBased(..)) => b" runfiles=directory\n",
+        None => b" runfiles=none\n",
+    });
+
+    for (i, &arg) in argv.iter().enumerate() {
+        write_all(fd, b"argv[");
+        write_number(fd, i as u32);
+        write_all(fd, b"]=");
+        let mut len = 0;
+        while unsafe { *arg.add(len) } != 0 {
+            len += 1;
+        }
+        write_all(fd, unsafe { core::slice::from_raw_parts(arg, len) });
+        write_all(fd, b"\n");
     }
-    str_starts_with(placeholder, b"@@RUNFILES_")
+
+    close(fd);
 }
 
 // Environment variable storage
@@ -742,33 +2961,29 @@ const MAX_ENV_VARS: usize = 1024;     // Max number of environment variables
 static mut ENVIRON_DATA: [u8; MAX_ENV_SIZE] = [0; MAX_ENV_SIZE];
 static mut ENVIRON_PTRS: [*const u8; MAX_ENV_VARS + 1] = [core::ptr::null(); MAX_ENV_VARS + 1];
 
-// Read and parse environment variables from /proc/self/environ
+// Get the process environment: the stack envp if there is one, otherwise
+// parsed from /proc/self/environ
 fn get_environ() -> *const *const u8 {
+    if let Some(envp) = stack_envp() {
+        return envp;
+    }
+
     unsafe {
         // Read environment from /proc/self/environ
-        let fd = open(b"/proc/self/environ\0");
-        if fd < 0 {
+        let data_len = read_proc_environ(&mut ENVIRON_DATA);
+        if data_len == 0 {
             // If we can't read environ, return empty environment
             ENVIRON_PTRS[0] = core::ptr::null();
             return ENVIRON_PTRS.as_ptr();
         }
 
-        let bytes_read = read(fd, &mut ENVIRON_DATA);
-        close(fd);
-
-        if bytes_read <= 0 {
-            ENVIRON_PTRS[0] = core::ptr::null();
-            return ENVIRON_PTRS.as_ptr();
-        }
-
         // Check if environment data was truncated
-        let data_len = bytes_read as usize;
         if data_len >= MAX_ENV_SIZE {
-            print(b"ERROR: Environment data exceeds buffer limit of ");
-            print_number(MAX_ENV_SIZE);
-            print(b" bytes\n");
-            print(b"Environment was truncated. This indicates the total environment size is too large.\n");
-            print(b"Consider reducing the number or size of environment variables.\n");
+            eprint(b"ERROR: Environment data exceeds buffer limit of ");
+            eprint_number(MAX_ENV_SIZE);
+            eprint(b" bytes\n");
+            eprint(b"Environment was truncated. This indicates the total environment size is too large.\n");
+            eprint(b"Consider reducing the number or size of environment variables.\n");
             exit(1);
         }
 
@@ -798,10 +3013,10 @@ fn get_environ() -> *const *const u8 {
 
         // Check if we hit the max number of environment variables
         if env_count >= MAX_ENV_VARS && pos < data_len {
-            print(b"ERROR: Number of environment variables exceeds limit of ");
-            print_number(MAX_ENV_VARS);
-            print(b"\n");
-            print(b"Consider reducing the number of environment variables.\n");
+            eprint(b"ERROR: Number of environment variables exceeds limit of ");
+            eprint_number(MAX_ENV_VARS);
+            eprint(b"\n");
+            eprint(b"Consider reducing the number of environment variables.\n");
             exit(1);
         }
 
@@ -817,15 +3032,22 @@ fn get_environ() -> *const *const u8 {
 static mut MODIFIED_ENV_DATA: [u8; MAX_ENV_SIZE] = [0; MAX_ENV_SIZE];
 static mut MODIFIED_ENV_PTRS: [*const u8; MAX_ENV_VARS + 1] = [core::ptr::null(); MAX_ENV_VARS + 1];
 
-fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
+// With FLAG_KEEP_ENV, a runfiles variable the parent set is passed on as-is
+// instead of being replaced with the stub's own value
+fn keep_inherited(name: &[u8]) -> bool {
+    let keep = unsafe { METADATA.flags } & FLAG_KEEP_ENV != 0;
+    keep && get_env_var(name, &mut [0u8; 1]).is_some_and(|len| len > 0)
+}
+
+// With an allowlist, inherited variables not named in it are dropped
+fn build_runfiles_environ(runfiles: Option<&Runfiles>, allowlist: Option<&[u8]>) -> *const *const u8 {
     unsafe {
         let base_env = get_environ();
 
-        // If no runfiles info, just return base environment
-        let rf = match runfiles {
-            Some(r) => r,
-            None => return base_env,
-        };
+        // If there's nothing to add, just return base environment
+        if runfiles.is_none() && METADATA.flags & FLAG_SET_ENV == 0 {
+            return base_env;
+        }
 
         let mut new_env_count = 0;
         let mut data_pos = 0;
@@ -856,38 +3078,66 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
             true
         };
 
+        // Variables left out of the export are passed on as the parent set them, like kept ones
+        let flags = METADATA.flags;
+        let keep_manifest = flags & FLAG_NO_EXPORT_MANIFEST != 0 || keep_inherited(b"RUNFILES_MANIFEST_FILE");
+        let keep_dir = flags & FLAG_NO_EXPORT_DIR != 0 || keep_inherited(b"RUNFILES_DIR");
+        let keep_java = flags & FLAG_NO_EXPORT_JAVA != 0 || keep_inherited(b"JAVA_RUNFILES");
+
         // Add runfiles environment variables first
-        if let Some((path, len)) = rf.manifest_path {
+        if let Some((path, len)) = runfiles.and_then(|rf| rf.manifest_path).filter(|_| !keep_manifest) {
             if !add_env_var(b"RUNFILES_MANIFEST_FILE", &path[..len]) {
-                print(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\n");
-                print(b"Environment buffer limit exceeded. Total size limit: ");
-                print_number(MAX_ENV_SIZE);
-                print(b" bytes, max variables: ");
-                print_number(MAX_ENV_VARS);
-                print(b"\n");
+                eprint(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes, max variables: ");
+                eprint_number(MAX_ENV_VARS);
+                eprint(b"\n");
                 exit(1);
             }
         }
 
-        if let Some((path, len)) = rf.dir_path {
-            if !add_env_var(b"RUNFILES_DIR", &path[..len]) {
-                print(b"ERROR: Failed to add RUNFILES_DIR to environment\n");
-                print(b"Environment buffer limit exceeded. Total size limit: ");
-                print_number(MAX_ENV_SIZE);
-                print(b" bytes, max variables: ");
-                print_number(MAX_ENV_VARS);
-                print(b"\n");
+        if let Some((path, len)) = runfiles.and_then(|rf| rf.dir_path) {
+            if !keep_dir && !add_env_var(b"RUNFILES_DIR", &path[..len]) {
+                eprint(b"ERROR: Failed to add RUNFILES_DIR to environment\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes, max variables: ");
+                eprint_number(MAX_ENV_VARS);
+                eprint(b"\n");
+                exit(1);
+            }
+            if !keep_java && !add_env_var(b"JAVA_RUNFILES", &path[..len]) {
+                eprint(b"ERROR: Failed to add JAVA_RUNFILES to environment\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes, max variables: ");
+                eprint_number(MAX_ENV_VARS);
+                eprint(b"\n");
                 exit(1);
             }
-            if !add_env_var(b"JAVA_RUNFILES", &path[..len]) {
-                print(b"ERROR: Failed to add JAVA_RUNFILES to environment\n");
-                print(b"Environment buffer limit exceeded. Total size limit: ");
-                print_number(MAX_ENV_SIZE);
-                print(b" bytes, max variables: ");
-                print_number(MAX_ENV_VARS);
-                print(b"\n");
+        }
+
+        // Variables embedded with --set-env; inherited ones with the same names are skipped below
+        let set_env = &*core::ptr::addr_of!(SET_ENV);
+        let mut pos = 0;
+        while flags & FLAG_SET_ENV != 0 && pos < set_env.len() && set_env[pos] != 0 {
+            let len = str_len(&set_env[pos..]);
+            let var = &set_env[pos..pos + len];
+            // The finalizer only embeds NAME=value entries
+            let eq = find_byte(var, b'=').unwrap_or(len);
+            if !add_env_var(&var[..eq], var.get(eq + 1..).unwrap_or(b"")) {
+                eprint(b"ERROR: Failed to add ");
+                eprint(&var[..eq]);
+                eprint(b" to environment\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes, max variables: ");
+                eprint_number(MAX_ENV_VARS);
+                eprint(b"\n");
                 exit(1);
             }
+            pos += len + 1;
         }
 
         // Copy existing environment (skip runfiles vars that we're setting)
@@ -903,11 +3153,17 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
             let env_slice = core::slice::from_raw_parts(env_ptr, env_len);
 
             // Skip if this is a runfiles var we're replacing
-            let is_runfiles_var = env_slice.starts_with(b"RUNFILES_MANIFEST_FILE=")
-                || env_slice.starts_with(b"RUNFILES_DIR=")
-                || env_slice.starts_with(b"JAVA_RUNFILES=");
+            let is_runfiles_var = !keep_manifest && env_slice.starts_with(b"RUNFILES_MANIFEST_FILE=")
+                || !keep_dir && env_slice.starts_with(b"RUNFILES_DIR=")
+                || !keep_java && env_slice.starts_with(b"JAVA_RUNFILES=")
+                || flags & FLAG_SET_ENV != 0 && env_name_set(env_slice, set_env);
+
+            let is_allowed = match allowlist {
+                Some(list) => env_name_allowed(env_slice, list),
+                None => true,
+            };
 
-            if !is_runfiles_var {
+            if !is_runfiles_var && is_allowed {
                 if data_pos + env_len + 1 <= MAX_ENV_SIZE && new_env_count < MAX_ENV_VARS {
                     MODIFIED_ENV_PTRS[new_env_count] = MODIFIED_ENV_DATA.as_ptr().add(data_pos);
                     new_env_count += 1;
@@ -926,18 +3182,18 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
 
         // Check if any environment variables were dropped
         if env_dropped {
-            print(b"ERROR: Failed to copy all environment variables\n");
-            print(b"Environment buffer limit exceeded. Total size limit: ");
-            print_number(MAX_ENV_SIZE);
-            print(b" bytes, max variables: ");
-            print_number(MAX_ENV_VARS);
-            print(b"\n");
-            print(b"Current usage: ");
-            print_number(data_pos);
-            print(b" bytes, ");
-            print_number(new_env_count);
-            print(b" variables\n");
-            print(b"Consider reducing the number or size of environment variables.\n");
+            eprint(b"ERROR: Failed to copy all environment variables\n");
+            eprint(b"Environment buffer limit exceeded. Total size limit: ");
+            eprint_number(MAX_ENV_SIZE);
+            eprint(b" bytes, max variables: ");
+            eprint_number(MAX_ENV_VARS);
+            eprint(b"\n");
+            eprint(b"Current usage: ");
+            eprint_number(data_pos);
+            eprint(b" bytes, ");
+            eprint_number(new_env_count);
+            eprint(b" variables\n");
+            eprint(b"Consider reducing the number or size of environment variables.\n");
             exit(1);
         }
 
@@ -948,6 +3204,559 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
     }
 }
 
+// Readable names for the execve errors users actually run into
+fn exec_error_description(errno: usize) -> Option<&'static [u8]> {
+    let description: &'static [u8] = match errno {
+        2 => b"no such file (ENOENT)",
+        7 => b"argument list too long (E2BIG)",
+        8 => b"not an executable format (ENOEXEC)",
+        13 => b"permission denied (EACCES)",
+        20 => b"a path component is not a directory (ENOTDIR)",
+        _ => return None,
+    };
+    Some(description)
+}
+
+// Report a failed execve (ret is the negative errno) with the path it tried
+fn exec_failed(executable: *const u8, ret: i32) -> ! {
+    let errno = ret.unsigned_abs() as usize;
+    eprint(b"ERROR: execve failed: ");
+    match exec_error_description(errno) {
+        Some(description) => eprint(description),
+        None => {
+            eprint(b"errno ");
+            eprint_number(errno);
+        }
+    }
+    eprint(b": ");
+    eprint(unsafe { core::slice::from_raw_parts(executable, strlen(executable)) });
+    eprint(b"\n");
+    exit(1);
+}
+
+// Spawn mode
+// Instead of replacing itself via execve, the stub runs the target as a child and
+// exits with its status. Forwarded signals and SIGCHLD are blocked and consumed
+// synchronously with rt_sigtimedwait, so no signal handlers are needed. Blocked
+// signals are also the only way PID 1 receives signals it has no handler for.
+// When running as PID 1 (container entrypoint), orphaned processes are re-parented
+// to the stub, so it reaps every child instead of just the target.
+// Only compiled with the "spawn" cargo feature (on by default).
+#[cfg(feature = "spawn")]
+mod spawn {
+    use super::*;
+
+    // Syscall numbers only needed by spawn mode
+    #[cfg(target_arch = "x86_64")]
+    mod syscall_numbers {
+        pub const SYS_RT_SIGACTION: usize = 13;
+        pub const SYS_RT_SIGPROCMASK: usize = 14;
+        pub const SYS_FORK: usize = 57;
+        pub const SYS_WAIT4: usize = 61;
+        pub const SYS_KILL: usize = 62;
+        pub const SYS_RT_SIGTIMEDWAIT: usize = 128;
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    mod syscall_numbers {
+        pub const SYS_RT_SIGACTION: usize = 134;
+        pub const SYS_RT_SIGPROCMASK: usize = 135;
+        pub const SYS_CLONE: usize = 220;  // aarch64 and riscv64 have no fork, clone(SIGCHLD) is equivalent
+        pub const SYS_WAIT4: usize = 260;
+        pub const SYS_KILL: usize = 129;
+        pub const SYS_RT_SIGTIMEDWAIT: usize = 137;
+    }
+
+    #[cfg(target_arch = "x86")]
+    mod syscall_numbers {
+        pub const SYS_FORK: usize = 2;
+        pub const SYS_KILL: usize = 37;
+        pub const SYS_WAIT4: usize = 114;
+        pub const SYS_RT_SIGACTION: usize = 174;
+        pub const SYS_RT_SIGPROCMASK: usize = 175;
+        pub const SYS_RT_SIGTIMEDWAIT: usize = 177;
+    }
+
+    use syscall_numbers::*;
+
+    // Signals (identical numbering on x86_64, aarch64, riscv64 and x86)
+    const SIGHUP: i32 = 1;
+    const SIGINT: i32 = 2;
+    const SIGQUIT: i32 = 3;
+    const SIGUSR1: i32 = 10;
+    const SIGUSR2: i32 = 12;
+    const SIGTERM: i32 = 15;
+    const SIGCHLD: i32 = 17;
+    const SIG_BLOCK: i32 = 0;
+    const SIG_SETMASK: i32 = 2;
+    const SIG_DFL: usize = 0;
+    const WNOHANG: i32 = 1;
+
+    #[cfg(target_arch = "x86_64")]
+    fn fork() -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_FORK,
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn fork() -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_CLONE,
+                in("x0") SIGCHLD as usize,  // flags: only the exit signal
+                in("x1") 0usize,            // child stack: share the parent's (copy-on-write)
+                in("x2") 0usize,
+                in("x3") 0usize,
+                in("x4") 0usize,
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn fork() -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_CLONE,
+                in("a0") SIGCHLD as usize,  // flags: only the exit signal
+                in("a1") 0usize,            // child stack: share the parent's (copy-on-write)
+                in("a2") 0usize,
+                in("a3") 0usize,
+                in("a4") 0usize,
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn fork() -> i32 {
+        unsafe { syscall(SYS_FORK, [0; 5]) as i32 }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn wait4(pid: i32, status: &mut i32, options: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_WAIT4,
+                in("rdi") pid,
+                in("rsi") status as *mut i32,
+                in("rdx") options,
+                in("r10") 0usize,  // rusage: not needed
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn wait4(pid: i32, status: &mut i32, options: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_WAIT4,
+                in("x0") pid,
+                in("x1") status as *mut i32,
+                in("x2") options,
+                in("x3") 0usize,  // rusage: not needed
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn wait4(pid: i32, status: &mut i32, options: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_WAIT4,
+                in("a0") pid,
+                in("a1") status as *mut i32,
+                in("a2") options,
+                in("a3") 0usize,  // rusage: not needed
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn wait4(pid: i32, status: &mut i32, options: i32) -> i32 {
+        // rusage: not needed
+        unsafe { syscall(SYS_WAIT4, [pid as usize, status as *mut i32 as usize, options as usize, 0, 0]) as i32 }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn kill(pid: i32, sig: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_KILL,
+                in("rdi") pid,
+                in("rsi") sig,
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn kill(pid: i32, sig: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_KILL,
+                in("x0") pid,
+                in("x1") sig,
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn kill(pid: i32, sig: i32) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_KILL,
+                in("a0") pid,
+                in("a1") sig,
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn kill(pid: i32, sig: i32) -> i32 {
+        unsafe { syscall(SYS_KILL, [pid as usize, sig as usize, 0, 0, 0]) as i32 }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn rt_sigprocmask(how: i32, set: *const u64, oldset: *mut u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_RT_SIGPROCMASK,
+                in("rdi") how,
+                in("rsi") set,
+                in("rdx") oldset,
+                in("r10") 8usize,  // sizeof(kernel sigset_t)
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn rt_sigprocmask(how: i32, set: *const u64, oldset: *mut u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_RT_SIGPROCMASK,
+                in("x0") how,
+                in("x1") set,
+                in("x2") oldset,
+                in("x3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn rt_sigprocmask(how: i32, set: *const u64, oldset: *mut u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_RT_SIGPROCMASK,
+                in("a0") how,
+                in("a1") set,
+                in("a2") oldset,
+                in("a3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn rt_sigprocmask(how: i32, set: *const u64, oldset: *mut u64) -> i32 {
+        // 8 = sizeof(kernel sigset_t)
+        unsafe { syscall(SYS_RT_SIGPROCMASK, [how as usize, set as usize, oldset as usize, 8, 0]) as i32 }
+    }
+
+    // Wait for one of the signals in `set` (which must be blocked), returns its number
+    #[cfg(target_arch = "x86_64")]
+    fn rt_sigtimedwait(set: &u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_RT_SIGTIMEDWAIT,
+                in("rdi") set as *const u64,
+                in("rsi") 0usize,  // siginfo: not needed
+                in("rdx") 0usize,  // timeout: wait forever
+                in("r10") 8usize,  // sizeof(kernel sigset_t)
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn rt_sigtimedwait(set: &u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_RT_SIGTIMEDWAIT,
+                in("x0") set as *const u64,
+                in("x1") 0usize,  // siginfo: not needed
+                in("x2") 0usize,  // timeout: wait forever
+                in("x3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn rt_sigtimedwait(set: &u64) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_RT_SIGTIMEDWAIT,
+                in("a0") set as *const u64,
+                in("a1") 0usize,  // siginfo: not needed
+                in("a2") 0usize,  // timeout: wait forever
+                in("a3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn rt_sigtimedwait(set: &u64) -> i32 {
+        // No siginfo, no timeout; 8 = sizeof(kernel sigset_t)
+        unsafe { syscall(SYS_RT_SIGTIMEDWAIT, [set as *const u64 as usize, 0, 0, 8, 0]) as i32 }
+    }
+
+    // Kernel sigaction layout (flags is an unsigned long; riscv64 has no restorer)
+    #[repr(C)]
+    struct KernelSigaction {
+        handler: usize,
+        flags: usize,
+        #[cfg(not(target_arch = "riscv64"))]
+        restorer: usize,
+        mask: u64,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn rt_sigaction(sig: i32, act: &KernelSigaction) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "syscall",
+                in("rax") SYS_RT_SIGACTION,
+                in("rdi") sig,
+                in("rsi") act as *const KernelSigaction,
+                in("rdx") 0usize,  // old action: not needed
+                in("r10") 8usize,  // sizeof(kernel sigset_t)
+                lateout("rax") ret,
+                lateout("rcx") _,
+                lateout("r11") _,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn rt_sigaction(sig: i32, act: &KernelSigaction) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "svc #0",
+                in("x8") SYS_RT_SIGACTION,
+                in("x0") sig,
+                in("x1") act as *const KernelSigaction,
+                in("x2") 0usize,  // old action: not needed
+                in("x3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("x0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    fn rt_sigaction(sig: i32, act: &KernelSigaction) -> i32 {
+        let ret: i32;
+        unsafe {
+            core::arch::asm!(
+                "ecall",
+                in("a7") SYS_RT_SIGACTION,
+                in("a0") sig,
+                in("a1") act as *const KernelSigaction,
+                in("a2") 0usize,  // old action: not needed
+                in("a3") 8usize,  // sizeof(kernel sigset_t)
+                lateout("a0") ret,
+            );
+        }
+        ret
+    }
+
+    #[cfg(target_arch = "x86")]
+    fn rt_sigaction(sig: i32, act: &KernelSigaction) -> i32 {
+        // No old action; 8 = sizeof(kernel sigset_t)
+        unsafe { syscall(SYS_RT_SIGACTION, [sig as usize, act as *const KernelSigaction as usize, 0, 8, 0]) as i32 }
+    }
+
+    // Signal set as used by the rt_sig* syscalls (one bit per signal)
+    #[derive(Clone, Copy)]
+    struct SigSet(u64);
+
+    impl SigSet {
+        const EMPTY: SigSet = SigSet(0);
+
+        fn with(self, sig: i32) -> SigSet {
+            SigSet(self.0 | (1u64 << (sig - 1)))
+        }
+    }
+
+    // Block the signals in `set`, returns the previous mask
+    fn block_signals(set: SigSet) -> SigSet {
+        let mut old: u64 = 0;
+        rt_sigprocmask(SIG_BLOCK, &set.0, &mut old);
+        SigSet(old)
+    }
+
+    fn set_signal_mask(set: SigSet) {
+        rt_sigprocmask(SIG_SETMASK, &set.0, core::ptr::null_mut());
+    }
+
+    // Wait for one of the (blocked) signals in `set`, returns its number or a negative errno
+    fn wait_for_signal(set: SigSet) -> i32 {
+        rt_sigtimedwait(&set.0)
+    }
+
+    // Restore the default disposition of a signal (no handler, not ignored)
+    fn reset_signal(sig: i32) {
+        let act = KernelSigaction {
+            handler: SIG_DFL,
+            flags: 0,
+            #[cfg(not(target_arch = "riscv64"))]
+            restorer: 0,
+            mask: 0,
+        };
+        rt_sigaction(sig, &act);
+    }
+
+    // Wait for `pid` without blocking; returns (pid, status) of a reaped child, if any
+    fn try_wait(pid: i32) -> Option<(i32, i32)> {
+        let mut status: i32 = 0;
+        let reaped = wait4(pid, &mut status, WNOHANG);
+        if reaped > 0 {
+            Some((reaped, status))
+        } else {
+            None
+        }
+    }
+
+    const FORWARDED_SIGNALS: [i32; 6] = [SIGHUP, SIGINT, SIGQUIT, SIGUSR1, SIGUSR2, SIGTERM];
+
+    // Convert a wait4 status to a shell-style exit code
+    fn exit_code_from_status(status: i32) -> i32 {
+        let term_sig = status & 0x7f;
+        if term_sig == 0 {
+            (status >> 8) & 0xff
+        } else {
+            128 + term_sig
+        }
+    }
+
+    pub fn spawn_and_wait(executable: *const u8, argv: *const *const u8, envp: *const *const u8) -> ! {
+        let mut wait_set = SigSet::EMPTY.with(SIGCHLD);
+        for sig in FORWARDED_SIGNALS {
+            wait_set = wait_set.with(sig);
+        }
+
+        // An inherited SIG_IGN for SIGCHLD makes the kernel reap children itself,
+        // and we would never learn the target's exit status
+        reset_signal(SIGCHLD);
+
+        // Block before forking so no signal arrives between fork and the wait loop
+        let old_set = block_signals(wait_set);
+
+        let child = fork();
+        if child == 0 {
+            // Child: restore the original mask, which execve preserves
+            set_signal_mask(old_set);
+            let ret = execve(executable, argv, envp);
+            exec_failed(executable, ret);
+        }
+        if child < 0 {
+            eprint(b"ERROR: fork failed with code -");
+            eprint_number((-child) as usize);
+            eprint(b"\n");
+            exit(1);
+        }
+
+        let is_init = getpid() == 1;
+        loop {
+            let sig = wait_for_signal(wait_set);
+            if sig == SIGCHLD {
+                // Reap everything that has exited; PID 1 also reaps orphaned grandchildren
+                while let Some((reaped, status)) = try_wait(if is_init { -1 } else { child }) {
+                    if reaped == child {
+                        exit(exit_code_from_status(status));
+                    }
+                }
+            } else if sig > 0 {
+                kill(child, sig);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "spawn")]
+use spawn::spawn_and_wait;
+
 #[cfg(target_arch = "x86_64")]
 core::arch::global_asm!(
     ".global _start",
@@ -964,76 +3773,139 @@ core::arch::global_asm!(
     "b _start_rust",                // Jump to the actual start function
 );
 
+#[cfg(target_arch = "riscv64")]
+core::arch::global_asm!(
+    ".global _start",
+    "_start:",
+    ".option push",
+    ".option norelax",
+    "la gp, __global_pointer$",     // The linker relaxes data accesses relative to gp
+    ".option pop",
+    "mv a0, sp",                    // Pass stack pointer as first argument
+    "j _start_rust",                // Jump to the actual start function
+);
+
+#[cfg(target_arch = "x86")]
+core::arch::global_asm!(
+    ".global _start",
+    "_start:",
+    "mov eax, esp",                 // Stack pointer at entry
+    "sub esp, 12",                  // Keep the stack 16-byte aligned at the call
+    "push eax",                     // Pass it as the first (stack) argument
+    "call _start_rust",             // Call the actual start function
+);
+// Reserved first runtime argument: the stub prints its version and embedded
+// configuration instead of running the target, without needing runfiles
+const VERSION_SENTINEL: &[u8] = b"--runfiles-stub-version";
+
+fn print_version(metadata: &StubMetadata) {
+    let mut number = [0u8; 20];
+    let mut write_number = |n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(STDOUT, &number[..len]);
+    };
+    write_all(STDOUT, b"runfiles-stub ");
+    write_all(STDOUT, env!("CARGO_PKG_VERSION").as_bytes());
+    write_all(STDOUT, b"\nmetadata_version=");
+    write_number(metadata.version);
+    write_all(STDOUT, b" arg_size=");
+    write_number(metadata.arg_size);
+    write_all(STDOUT, b" argc=");
+    write_number(metadata.argc);
+    write_all(STDOUT, b" transform_flags=");
+    write_number(metadata.transform_flags);
+    write_all(STDOUT, b" flags=");
+    write_number(metadata.flags);
+    write_all(STDOUT, b"\n");
+}
+
+
 #[no_mangle]
 pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
     unsafe {
-        // Stack layout: [sp] = argc, [sp + 8] = argv[0], [sp + 16] = argv[1], ...
+        // Stack layout: [sp] = argc, followed by argv[0], argv[1], ... (one word each)
         let runtime_argc = *initial_sp;
-        let runtime_argv = (initial_sp as usize + 8) as *const *const u8;
+        let runtime_argv = initial_sp.add(1) as *const *const u8;
 
-        // Check if ARGC is still a placeholder
-        if is_template_placeholder(&ARGC_PLACEHOLDER) {
-            print(b"ERROR: This is a template stub runner.\n");
-            print(b"You must finalize it by replacing the placeholders before use.\n");
-            print(b"The ARGC_PLACEHOLDER has not been replaced.\n");
-            exit(1);
-        }
+        // envp follows argv's NULL terminator
+        STACK_ENVP = runtime_argv.add(runtime_argc + 1);
 
-        // Parse argc from placeholder
-        let argc_str = &ARGC_PLACEHOLDER;
-        let argc_len = str_len(argc_str);
-        if argc_len == 0 {
-            print(b"ERROR: ARGC is empty\n");
+        // Check if the metadata is still a placeholder
+        let metadata = &METADATA;
+        if is_template_placeholder(&metadata.magic) {
+            eprint(b"ERROR: This is a template stub runner.\n");
+            eprint(b"You must finalize it by replacing the placeholders before use.\n");
+            eprint(b"The metadata block has not been replaced.\n");
             exit(1);
         }
 
-        // Parse argc as decimal number
-        let mut argc: usize = 0;
-        for i in 0..argc_len {
-            let c = argc_str[i];
-            if c >= b'0' && c <= b'9' {
-                argc = argc * 10 + (c - b'0') as usize;
-            } else {
-                print(b"ERROR: ARGC contains non-digit characters\n");
-                exit(1);
-            }
+        if !str_starts_with(&metadata.magic, METADATA_MAGIC)
+            || metadata.version != METADATA_VERSION
+            || metadata.arg_size != ARG_SIZE as u32
+        {
+            eprint(b"ERROR: Unsupported stub metadata (was it finalized for another template version?)\n");
+            exit(1);
         }
 
+        let argc = metadata.argc as usize;
         if argc == 0 || argc > 10 {
-            print(b"ERROR: Invalid argc (must be 1-10)\n");
+            eprint(b"ERROR: Invalid argc (must be 1-10)\n");
             exit(1);
         }
 
-        // Parse transform flags (bitmask of which args to transform)
-        let flags_str = &TRANSFORM_FLAGS;
-        let flags_len = str_len(flags_str);
-        let mut transform_flags: u32 = 0;
-
-        if !is_template_placeholder(flags_str) && flags_len > 0 {
-            // Parse as decimal number (bitmask)
-            for i in 0..flags_len {
-                let c = flags_str[i];
-                if c >= b'0' && c <= b'9' {
-                    transform_flags = transform_flags * 10 + (c - b'0') as u32;
-                } else {
-                    print(b"ERROR: TRANSFORM_FLAGS contains non-digit characters\n");
-                    exit(1);
-                }
+        if runtime_argc > 1 {
+            let arg = *runtime_argv.add(1);
+            if (0..=VERSION_SENTINEL.len()).all(|i| *arg.add(i) == VERSION_SENTINEL.get(i).copied().unwrap_or(0)) {
+                print_version(metadata);
+                exit(0);
             }
         }
-        // If flags not set, default to transforming all args
-        if flags_len == 0 || is_template_placeholder(flags_str) {
-            transform_flags = 0xFFFFFFFF; // Transform all by default
-        }
 
-        // Parse export_runfiles_env flag
-        let export_env_str = &EXPORT_RUNFILES_ENV;
-        let export_env_len = str_len(export_env_str);
-        let export_runfiles_env = if !is_template_placeholder(export_env_str) && export_env_len > 0 {
-            export_env_str[0] == b'1'
-        } else {
-            true // Default to true if not set
-        };
+        // Bitmask of which args to transform
+        let transform_flags = metadata.transform_flags;
+
+        // Transformed `--flag=value` arguments of which only the value is resolved
+        let value_flags = metadata.value_flags;
+
+        let export_runfiles_env = metadata.flags & FLAG_EXPORT_ENV != 0;
+
+        // Runfiles archive appended to this executable
+        let self_extract = metadata.flags & FLAG_SELF_EXTRACT != 0;
+
+        // Relative manifest values are followed once
+        let follow_indirection = metadata.flags & FLAG_INDIRECTION != 0;
+
+        // Embedded rlocation paths are percent-encoded
+        let percent_decode_keys = metadata.flags & FLAG_PERCENT_DECODE != 0;
+
+        // Run inside the sandbox root with a scrubbed environment
+        let sandbox = metadata.flags & FLAG_SANDBOX != 0;
+        let sandbox_root = &*core::ptr::addr_of!(SANDBOX_ROOT);
+
+        // Set the variables embedded with --set-env
+        let set_env = metadata.flags & FLAG_SET_ENV != 0;
+
+        // Run EXEC_PATH instead of argv[0]
+        let exec = metadata.flags & FLAG_EXEC != 0;
+        let exec_buf = &*core::ptr::addr_of!(EXEC_PATH);
+
+        // Untransformed arguments may contain RUNFILES_ROOT_TOKEN
+        let expand_root = metadata.flags & FLAG_EXPAND_ROOT != 0;
+
+        // Transformed arguments outside RLOCATION_PREFIXES are not resolved
+        let restrict_prefixes = metadata.flags & FLAG_RLOCATION_PREFIXES != 0;
+
+        // ... and are an error instead of being passed through
+        let strict_prefixes = metadata.flags & FLAG_STRICT_PREFIXES != 0;
+
+        // Run the target as a child instead of replacing the stub
+        #[cfg(feature = "spawn")]
+        let spawn = metadata.flags & FLAG_SPAWN != 0;
+
+        // Run the target in the CHDIR directory
+        let change_dir = metadata.flags & FLAG_CHDIR != 0;
+        let chdir_buf = &*core::ptr::addr_of!(CHDIR);
+        let chdir_key = &chdir_buf[..str_len(chdir_buf)];
 
         // Check if any arguments need transformation
         // Create a mask for only the arguments we have (argc args)
@@ -1043,7 +3915,13 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
             (1u32 << argc) - 1
         };
         let needs_transform = (transform_flags & argc_mask) != 0;
-        let needs_runfiles = needs_transform || export_runfiles_env;
+        let args_need_runfiles = needs_transform || expand_root
+            || exec && !is_absolute_path(&exec_buf[..str_len(exec_buf)])
+            || change_dir && !is_absolute_path(chdir_key);
+        let needs_runfiles = args_need_runfiles || export_runfiles_env;
+
+        // Exporting alone can do without runfiles when inherited variables are kept
+        let keep_inherited_env = metadata.flags & FLAG_KEEP_ENV != 0;
 
         // Get executable path from runtime argv[0] (the stub's actual path) for runfiles fallback
         let executable_path = if runtime_argc > 0 {
@@ -1061,13 +3939,47 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
             None
         };
 
+        // A stub found through PATH gets a bare name as argv[0], and one exec'd with an
+        // empty argv none at all. Neither says where <executable>.runfiles is; use the
+        // stub's own path instead
+        let mut self_path = [0u8; MAX_PATH_LEN];
+        let executable_path = match executable_path {
+            Some(path) if find_byte(path, b'/').is_some() => Some(path),
+            other => {
+                let len = readlink(b"/proc/self/exe\0", &mut self_path);
+                if len > 0 && (len as usize) < MAX_PATH_LEN {
+                    Some(&self_path[..len as usize])
+                } else {
+                    other
+                }
+            }
+        };
+
         // Initialize runfiles only if needed
-        let runfiles = if needs_runfiles {
+        // A sandbox root or bundled runfiles tree always takes precedence over the environment
+        let runfiles = if sandbox {
+            let root = sandbox_root;
+            let len = str_len(root);
+            let mut dir = [0u8; MAX_PATH_LEN];
+            dir[..len].copy_from_slice(&root[..len]);
+            Some(Runfiles::from_dir(dir, len))
+        } else if self_extract {
+            let mut dir = [0u8; MAX_PATH_LEN];
+            if let Some(len) = extract_self(executable_path, &mut dir) {
+                Some(Runfiles::from_dir(dir, len))
+            } else {
+                eprint(b"ERROR: Failed to extract bundled runfiles\n");
+                eprint(b"Check that $TMPDIR (or /tmp) is writable\n");
+                exit(1);
+            }
+        } else if needs_runfiles {
             if let Some(rf) = Runfiles::create(executable_path) {
                 Some(rf)
+            } else if keep_inherited_env && !args_need_runfiles {
+                None
             } else {
-                print(b"ERROR: Failed to initialize runfiles\n");
-                print(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles/ directory exists\n");
+                eprint(b"ERROR: Failed to initialize runfiles\n");
+                eprint(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles/ directory exists\n");
                 exit(1);
             }
         } else {
@@ -1099,10 +4011,10 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
             let arg_len = str_len(arg_data);
 
             if arg_len == 0 {
-                print(b"ERROR: Argument ");
+                eprint(b"ERROR: Argument ");
                 let digit = [b'0' + i as u8];
-                print(&digit);
-                print(b" is empty\n");
+                eprint(&digit);
+                eprint(b" is empty\n");
                 exit(1);
             }
 
@@ -1111,11 +4023,57 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
             // Check if this argument should be transformed
             let should_transform = (transform_flags & (1 << i)) != 0;
 
-            if should_transform {
+            // Only the part after the first '=' of a `--flag=value` argument is an rlocation path
+            let value_start = if should_transform && value_flags & (1 << i) != 0 {
+                find_byte(arg_slice, b'=').map_or(0, |eq| eq + 1)
+            } else {
+                0
+            };
+            let value = &arg_slice[value_start..];
+
+            // The rlocation key, percent-decoded if requested
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if should_transform && percent_decode_keys {
+                let len = percent_decode(value, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                value
+            };
+
+            // Inside a sandbox, transformed paths must stay below the root
+            if should_transform && sandbox && (is_absolute_path(key) || has_parent_component(key)) {
+                eprint(b"ERROR: Argument ");
+                let digit = [b'0' + i as u8];
+                eprint(&digit);
+                eprint(b" escapes the sandbox root\n");
+                exit(1);
+            }
+
+            // Only keys below an allowed prefix are looked up
+            let allowed = !restrict_prefixes || rlocation_allowed(key, &RLOCATION_PREFIXES);
+            if should_transform && !allowed && strict_prefixes {
+                eprint(b"ERROR: Argument ");
+                let digit = [b'0' + i as u8];
+                eprint(&digit);
+                eprint(b" is outside the allowed rlocation prefixes\n");
+                exit(1);
+            }
+
+            if should_transform && allowed {
                 // Try to resolve through runfiles (which we know exists if we need transformation)
                 if let Some(ref rf) = runfiles {
-                    if let Some(resolved) = rf.rlocation(arg_slice) {
-                        resolved_paths[i] = resolved;
+                    if let Some(resolved) = rf.rlocation(key, follow_indirection) {
+                        // A `--flag=` part is kept in front of the resolved value
+                        let len = str_len(&resolved);
+                        if value_start + len >= MAX_PATH_LEN {
+                            eprint(b"ERROR: Argument ");
+                            let digit = [b'0' + i as u8];
+                            eprint(&digit);
+                            eprint(b" is too long after resolving its value\n");
+                            exit(1);
+                        }
+                        resolved_paths[i][..value_start].copy_from_slice(&arg_slice[..value_start]);
+                        resolved_paths[i][value_start..value_start + len].copy_from_slice(&resolved[..len]);
                     } else {
                         // If not found in runfiles, use the path as-is
                         let copy_len = arg_len.min(MAX_PATH_LEN);
@@ -1127,6 +4085,26 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
                     let copy_len = arg_len.min(MAX_PATH_LEN);
                     resolved_paths[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
                 }
+            } else if should_transform {
+                // Outside the allowed prefixes: use the path as-is
+                let copy_len = arg_len.min(MAX_PATH_LEN);
+                resolved_paths[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
+            } else if expand_root {
+                // Substitute the runfiles directory
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((dir, len)) => &dir[..*len],
+                    None => {
+                        eprint(b"ERROR: {RUNFILES_ROOT} requires a runfiles directory (not just a manifest)\n");
+                        exit(1);
+                    }
+                };
+                if expand_runfiles_root(arg_slice, root, &mut resolved_paths[i]).is_none() {
+                    eprint(b"ERROR: Argument ");
+                    let digit = [b'0' + i as u8];
+                    eprint(&digit);
+                    eprint(b" is too long after expanding {RUNFILES_ROOT}\n");
+                    exit(1);
+                }
             } else {
                 // Use path as-is without transformation
                 let copy_len = arg_len.min(MAX_PATH_LEN);
@@ -1141,7 +4119,7 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
         if runtime_argc > 1 {
             for i in 1..runtime_argc {
                 if total_argc >= 128 {
-                    print(b"ERROR: Too many total arguments (embedded + runtime > 128)\n");
+                    eprint(b"ERROR: Too many total arguments (embedded + runtime > 128)\n");
                     exit(1);
                 }
 
@@ -1164,32 +4142,135 @@ pub extern "C" fn _start_rust(initial_sp: *const usize) -> ! {
             }
         }
 
+        // Default runtime arguments apply only when the caller passed none
+        if runtime_argc <= 1 && metadata.flags & FLAG_DEFAULT_ARGS != 0 {
+            let defaults = &DEFAULT_ARGS;
+            let mut pos = 0;
+            while pos < defaults.len() && defaults[pos] != 0 {
+                if total_argc >= 128 {
+                    eprint(b"ERROR: Too many total arguments (embedded + default > 128)\n");
+                    exit(1);
+                }
+
+                let arg_len = str_len(&defaults[pos..]);
+                let copy_len = arg_len.min(MAX_PATH_LEN);
+                resolved_paths[total_argc][..copy_len].copy_from_slice(&defaults[pos..pos + copy_len]);
+
+                resolved_ptrs[total_argc] = resolved_paths[total_argc].as_ptr();
+                total_argc += 1;
+                pos += arg_len + 1;
+            }
+        }
+
+        // Runtime arguments go before the embedded argument at RUNTIME_POS instead of last
+        if metadata.flags & FLAG_RUNTIME_POS != 0 {
+            let digits = &*core::ptr::addr_of!(RUNTIME_POS);
+            let mut pos = 0;
+            for &c in &digits[..str_len(digits)] {
+                pos = pos * 10 + (c - b'0') as usize;
+            }
+            if (1..argc).contains(&pos) {
+                resolved_ptrs[pos..total_argc].rotate_right(total_argc - argc);
+            }
+        }
+
         // NULL-terminate the argv array
         resolved_ptrs[total_argc] = core::ptr::null();
 
-        // Get the executable path (first argument)
-        let executable = resolved_ptrs[0];
+        // The target sees the --argv0 name, but the first argument is still what runs
+        let first_arg = resolved_ptrs[0];
+        if metadata.flags & FLAG_ARGV0 != 0 {
+            resolved_ptrs[0] = core::ptr::addr_of!(ARGV0) as *const u8;
+        }
+
+        write_debug_file(metadata, runfiles.as_ref(), &resolved_ptrs[..total_argc]);
+
+        // Get the executable path: EXEC_PATH if set, otherwise the first argument
+        let mut exec_path = [0u8; MAX_PATH_LEN + 1];
+        let executable = if exec {
+            let exec_len = str_len(exec_buf);
+            let exec_slice = &exec_buf[..exec_len];
+
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if percent_decode_keys {
+                let len = percent_decode(exec_slice, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                exec_slice
+            };
+
+            if sandbox && (is_absolute_path(key) || has_parent_component(key)) {
+                eprint(b"ERROR: Exec path escapes the sandbox root\n");
+                exit(1);
+            }
+
+            match runfiles.as_ref().and_then(|rf| rf.rlocation(key, follow_indirection)) {
+                Some(resolved) => exec_path[..MAX_PATH_LEN].copy_from_slice(&resolved),
+                None => exec_path[..exec_len].copy_from_slice(exec_slice),
+            }
+            exec_path.as_ptr()
+        } else {
+            first_arg
+        };
 
-        // Build environment (with runfiles vars if export_runfiles_env is true)
-        let envp = if export_runfiles_env {
-            build_runfiles_environ(runfiles.as_ref())
+        // Build environment (with runfiles vars if export_runfiles_env is true, and --set-env vars)
+        let envp = if sandbox {
+            build_runfiles_environ(runfiles.as_ref(), Some(&*core::ptr::addr_of!(ENV_ALLOWLIST)))
+        } else if export_runfiles_env || set_env {
+            build_runfiles_environ(runfiles.as_ref().filter(|_| export_runfiles_env), None)
         } else {
             get_environ()
         };
 
+        apply_process_limits(metadata.flags);
+
+        // Runfiles manifests only list files, so a directory missing from one is
+        // looked up below the runfiles directory
+        if change_dir {
+            let key = chdir_key;
+            let mut dir = [0u8; MAX_PATH_LEN + 1];
+            if is_absolute_path(key) {
+                dir[..key.len()].copy_from_slice(key);
+            } else if let Some(resolved) = runfiles.as_ref().and_then(|rf| rf.rlocation(key, follow_indirection)) {
+                dir[..MAX_PATH_LEN].copy_from_slice(&resolved);
+            } else {
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((root, len)) if len + 1 + key.len() < MAX_PATH_LEN => &root[..*len],
+                    _ => {
+                        eprint(b"ERROR: Working directory not found in the runfiles: ");
+                        eprint(key);
+                        eprint(b"\n");
+                        exit(1);
+                    }
+                };
+                dir[..root.len()].copy_from_slice(root);
+                dir[root.len()] = b'/';
+                dir[root.len() + 1..root.len() + 1 + key.len()].copy_from_slice(key);
+            }
+            if chdir(&dir) != 0 {
+                eprint(b"ERROR: Failed to change into the working directory ");
+                eprint(&dir[..str_len(&dir)]);
+                eprint(b"\n");
+                exit(1);
+            }
+        }
+
+        // The finalizer leaves room for the root's NUL terminator
+        if sandbox && chdir(sandbox_root) != 0 {
+            eprint(b"ERROR: Failed to change into the sandbox root\n");
+            exit(1);
+        }
+
+        // Run the target as a child in spawn mode
+        #[cfg(feature = "spawn")]
+        if spawn {
+            spawn_and_wait(executable, resolved_ptrs.as_ptr(), envp);
+        }
+
         // Execute the target program
         let ret = execve(executable, resolved_ptrs.as_ptr(), envp);
 
         // If execve returns, it failed
-        print(b"ERROR: execve failed with code ");
-        let digit = if ret < 0 {
-            print(b"-");
-            (-ret) as u8 + b'0'
-        } else {
-            ret as u8 + b'0'
-        };
-        print(&[digit]);
-        print(b"\n");
-        exit(1);
+        exec_failed(executable, ret);
     }
 }
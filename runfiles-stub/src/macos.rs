@@ -16,12 +16,46 @@ extern "C" {
     fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
     fn close(fd: i32) -> i32;
     fn access(path: *const u8, mode: i32) -> i32;
+    fn chdir(path: *const u8) -> i32;
     fn execve(path: *const u8, argv: *const *const u8, envp: *const *const u8) -> i32;
+    fn lseek(fd: i32, offset: i64, whence: i32) -> i64;
+    fn mkdir(path: *const u8, mode: u16) -> i32;
+    fn rename(from: *const u8, to: *const u8) -> i32;
+    fn unlink(path: *const u8) -> i32;
+    fn rmdir(path: *const u8) -> i32;
+    // The 64-bit inode struct stat (the default on aarch64)
+    #[cfg_attr(target_arch = "x86_64", link_name = "lstat$INODE64")]
+    fn lstat(path: *const u8, buf: *mut Stat) -> i32;
+    fn geteuid() -> u32;
+    fn getpid() -> i32;
+    fn umask(mask: u16) -> u16;
+    fn setrlimit(resource: i32, limit: *const Rlimit) -> i32;
+    fn _NSGetExecutablePath(buf: *mut u8, bufsize: *mut u32) -> i32;
+    // Address of the calling thread's errno
+    fn __error() -> *mut i32;
 
     // Access to environment - macOS provides this
     static mut environ: *const *const u8;
 }
 
+// The fields of struct stat the stub reads, padded to the full struct size
+#[repr(C)]
+struct Stat {
+    dev: i32,
+    mode: u16,
+    nlink: u16,
+    ino: u64,
+    uid: u32,
+    rest: [u8; 124],
+}
+
+// Soft and hard limit (struct rlimit)
+#[repr(C)]
+struct Rlimit {
+    current: u64,
+    max: u64,
+}
+
 // Check if a path exists using access() with F_OK
 fn path_exists(path: &[u8]) -> bool {
     unsafe {
@@ -31,21 +65,34 @@ fn path_exists(path: &[u8]) -> bool {
 
 // File open flags
 const O_RDONLY: i32 = 0;
+const O_WRONLY: i32 = 1;
+const O_CREAT: i32 = 0x200;
+const O_EXCL: i32 = 0x800;
+const O_APPEND: i32 = 0x8;
+// Internal fds must not leak into the target
+const O_CLOEXEC: i32 = 0x1000000;
+const SEEK_SET: i32 = 0;
+const SEEK_CUR: i32 = 1;
+const SEEK_END: i32 = 2;
+const EEXIST: i32 = 17;
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
 const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
 
-// String utilities
-fn print(s: &[u8]) {
+// Diagnostics go to stderr so they never mix with the target's output
+fn eprint(s: &[u8]) {
     unsafe {
-        write(STDOUT, s.as_ptr(), s.len());
+        write(STDERR, s.as_ptr(), s.len());
     }
 }
 
-fn print_number(mut n: usize) {
+fn eprint_number(mut n: usize) {
     let mut buf = [0u8; 20]; // Enough for 64-bit numbers
     let mut i = 0;
 
     if n == 0 {
-        print(b"0");
+        eprint(b"0");
         return;
     }
 
@@ -58,10 +105,45 @@ fn print_number(mut n: usize) {
     // Print in reverse order
     while i > 0 {
         i -= 1;
-        print(&buf[i..i+1]);
+        eprint(&buf[i..i+1]);
+    }
+}
+
+// Readable names for the execve errors users actually run into
+fn exec_error_description(errno: i32) -> Option<&'static [u8]> {
+    let description: &'static [u8] = match errno {
+        2 => b"no such file (ENOENT)",
+        7 => b"argument list too long (E2BIG)",
+        8 => b"not an executable format (ENOEXEC)",
+        13 => b"permission denied (EACCES)",
+        20 => b"a path component is not a directory (ENOTDIR)",
+        86 => b"bad CPU type in executable (EBADARCH)",
+        _ => return None,
+    };
+    Some(description)
+}
+
+// Report a failed execve with the path it tried
+fn exec_failed(executable: *const u8, errno: i32) -> ! {
+    eprint(b"ERROR: execve failed: ");
+    match exec_error_description(errno) {
+        Some(description) => eprint(description),
+        None => {
+            eprint(b"errno ");
+            eprint_number(errno as usize);
+        }
+    }
+    eprint(b": ");
+    let mut len = 0;
+    while unsafe { *executable.add(len) } != 0 {
+        len += 1;
     }
+    eprint(unsafe { core::slice::from_raw_parts(executable, len) });
+    eprint(b"\n");
+    unsafe { exit(1) }
 }
 
+// String utilities
 fn str_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
@@ -74,6 +156,16 @@ fn str_eq(a: &[u8], b: &[u8]) -> bool {
     true
 }
 
+// Byte-wise ordering, the order manifest keys are sorted in
+fn str_cmp(a: &[u8], b: &[u8]) -> core::cmp::Ordering {
+    for i in 0..a.len().min(b.len()) {
+        if a[i] != b[i] {
+            return a[i].cmp(&b[i]);
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
 fn str_starts_with(haystack: &[u8], needle: &[u8]) -> bool {
     if haystack.len() < needle.len() {
         return false;
@@ -130,9 +222,67 @@ fn get_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
     None
 }
 
+// Reads RUNFILES_MANIFEST_FILE or RUNFILES_DIR. With FLAG_EXPAND_ENV, `$VAR` and
+// `${VAR}` references in the value are replaced (unset variables are kept as-is).
+fn get_runfiles_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let len = get_env_var(name, buf)?;
+    if unsafe { METADATA.flags } & FLAG_EXPAND_ENV == 0 || find_byte(&buf[..len], b'$').is_none() {
+        return Some(len);
+    }
+    let mut value = [0u8; MAX_PATH_LEN];
+    value.get_mut(..len)?.copy_from_slice(&buf[..len]);
+    expand_env_refs(&value[..len], buf)
+}
+
+// Name and length of the `$VAR` or `${VAR}` reference at the start of s
+fn env_reference(s: &[u8]) -> Option<(&[u8], usize)> {
+    if s.first() != Some(&b'$') {
+        return None;
+    }
+    let braced = s.get(1) == Some(&b'{');
+    let start = if braced { 2 } else { 1 };
+    let mut end = start;
+    while end < s.len() && (s[end].is_ascii_alphanumeric() || s[end] == b'_') {
+        end += 1;
+    }
+    if end == start || s[start].is_ascii_digit() {
+        return None;
+    }
+    if !braced {
+        return Some((&s[start..end], end));
+    }
+    if s.get(end) != Some(&b'}') {
+        return None;
+    }
+    Some((&s[start..end], end + 1))
+}
+
+// Copies value to out with environment variable references replaced by their
+// values. Returns None if the result doesn't fit.
+fn expand_env_refs(value: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut out_len = 0;
+    let mut i = 0;
+    while i < value.len() {
+        if let Some((name, reference_len)) = env_reference(&value[i..]) {
+            let mut var = [0u8; MAX_PATH_LEN];
+            if let Some(var_len) = get_env_var(name, &mut var) {
+                out.get_mut(out_len..out_len + var_len)?.copy_from_slice(&var[..var_len]);
+                out_len += var_len;
+                i += reference_len;
+                continue;
+            }
+        }
+        *out.get_mut(out_len)? = value[i];
+        out_len += 1;
+        i += 1;
+    }
+    Some(out_len)
+}
+
 // Manifest entry storage
 const MAX_ENTRIES: usize = 1024;
-const MAX_PATH_LEN: usize = 256;
+// Paths hold at least a whole embedded argument, so a long rlocation path still resolves
+const MAX_PATH_LEN: usize = if ARG_SIZE > 256 { ARG_SIZE } else { 256 };
 
 struct ManifestEntry {
     key: [u8; MAX_PATH_LEN],
@@ -143,11 +293,13 @@ struct ManifestEntry {
 
 struct Manifest {
     entries: [ManifestEntry; MAX_ENTRIES],
+    // Entry indices ordered by key, for lookup's binary search
+    order: [u16; MAX_ENTRIES],
     count: usize,
 }
 
 impl Manifest {
-    fn new() -> Self {
+    const fn new() -> Self {
         const EMPTY_ENTRY: ManifestEntry = ManifestEntry {
             key: [0; MAX_PATH_LEN],
             key_len: 0,
@@ -157,13 +309,15 @@ impl Manifest {
 
         Self {
             entries: [EMPTY_ENTRY; MAX_ENTRIES],
+            order: [0; MAX_ENTRIES],
             count: 0,
         }
     }
 
-    fn add_entry(&mut self, key: &[u8], value: &[u8]) {
+    // Returns false if the manifest is full
+    fn add_entry(&mut self, key: &[u8], value: &[u8]) -> bool {
         if self.count >= MAX_ENTRIES {
-            return;
+            return false;
         }
 
         let entry = &mut self.entries[self.count];
@@ -176,37 +330,163 @@ impl Manifest {
         entry.value_len = value_len;
 
         self.count += 1;
+        true
     }
 
-    fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+    fn key(&self, index: u16) -> &[u8] {
+        let entry = &self.entries[index as usize];
+        &entry.key[..entry.key_len]
+    }
+
+    // Order the entries by key once they are all added. Bazel writes manifests
+    // sorted, so the insertion sort is usually a single pass; being stable, it
+    // keeps the first of duplicate keys first.
+    fn sort(&mut self) {
         for i in 0..self.count {
-            let entry = &self.entries[i];
-            if str_eq(&entry.key[..entry.key_len], key) {
-                return Some(&entry.value[..entry.value_len]);
+            let index = i as u16;
+            let mut j = i;
+            while j > 0 && str_cmp(self.key(self.order[j - 1]), self.key(index)).is_gt() {
+                self.order[j] = self.order[j - 1];
+                j -= 1;
             }
+            self.order[j] = index;
         }
-        None
     }
+
+    fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+        // Find the first entry whose key is not less than `key`
+        let mut low = 0;
+        let mut high = self.count;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if str_cmp(self.key(self.order[mid]), key).is_lt() {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let index = *self.order[..self.count].get(low)?;
+        let entry = &self.entries[index as usize];
+        if str_eq(&entry.key[..entry.key_len], key) {
+            Some(&entry.value[..entry.value_len])
+        } else {
+            None
+        }
+    }
+}
+
+// Check if a path is absolute
+fn is_absolute_path(path: &[u8]) -> bool {
+    !path.is_empty() && path[0] == b'/'
+}
+
+// Undo Bazel's manifest escaping: \s is a space, \n a newline and \b a backslash.
+// Returns the unescaped length; like unescaped entries, the result is truncated to out.
+fn unescape_manifest_field(field: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < field.len() && len < MAX_PATH_LEN {
+        let c = if field[i] == b'\\' && i + 1 < field.len() {
+            i += 1;
+            match field[i] {
+                b's' => b' ',
+                b'n' => b'\n',
+                b'b' => b'\\',
+                other => other,
+            }
+        } else {
+            field[i]
+        };
+        out[len] = c;
+        len += 1;
+        i += 1;
+    }
+    len
+}
+
+// Split a manifest line into its key and value. Entries whose paths contain spaces,
+// newlines or backslashes are escaped by Bazel and marked with a leading space;
+// those are unescaped into key_buf and value_buf.
+fn split_manifest_line<'a>(
+    line: &'a [u8],
+    key_buf: &'a mut [u8; MAX_PATH_LEN],
+    value_buf: &'a mut [u8; MAX_PATH_LEN],
+) -> Option<(&'a [u8], &'a [u8])> {
+    if line.first() == Some(&b' ') {
+        let line = &line[1..];
+        let space_pos = find_byte(line, b' ')?;
+        let key_len = unescape_manifest_field(&line[..space_pos], key_buf);
+        let value_len = unescape_manifest_field(&line[space_pos + 1..], value_buf);
+        return Some((&key_buf[..key_len], &value_buf[..value_len]));
+    }
+    let space_pos = find_byte(line, b' ')?;
+    Some((&line[..space_pos], &line[space_pos + 1..]))
+}
+
+// Manifests (and the repository mapping) are read whole into this buffer
+const MAX_MANIFEST_SIZE: usize = 1048576;
+static mut MANIFEST_BUF: [u8; MAX_MANIFEST_SIZE] = [0; MAX_MANIFEST_SIZE];
+
+// Report a runfiles file the stub can't load completely and exit
+fn runfiles_file_too_large(path: &[u8], limit: usize, unit: &[u8]) -> ! {
+    eprint(b"ERROR: Runfiles file ");
+    eprint(&path[..strlen(path)]);
+    eprint(b" has more than ");
+    eprint_number(limit);
+    eprint(unit);
+    unsafe { exit(1) }
 }
 
-// Load manifest file
-fn load_manifest(path: &[u8]) -> Option<Manifest> {
+// Read a whole runfiles file into MANIFEST_BUF. Returns None if it can't be read or is empty.
+fn read_runfiles_file(path: &[u8]) -> Option<&'static [u8]> {
     unsafe {
-        let fd = open(path.as_ptr(), O_RDONLY);
+        let fd = open(path.as_ptr(), O_RDONLY | O_CLOEXEC);
         if fd < 0 {
             return None;
         }
 
-        let mut file_buf = [0u8; 65536];
-        let bytes_read = read(fd, file_buf.as_mut_ptr(), file_buf.len());
+        // read() may return fewer bytes than requested, so read until EOF
+        let buf = &mut MANIFEST_BUF;
+        let mut total = 0;
+        loop {
+            if total == buf.len() {
+                // A full buffer is only complete if nothing follows
+                let mut probe = [0u8; 1];
+                if read(fd, probe.as_mut_ptr(), 1) > 0 {
+                    close(fd);
+                    runfiles_file_too_large(path, MAX_MANIFEST_SIZE, b" bytes\n");
+                }
+                break;
+            }
+            let bytes_read = read(fd, buf.as_mut_ptr().add(total), buf.len() - total);
+            if bytes_read < 0 {
+                close(fd);
+                return None;
+            }
+            if bytes_read == 0 {
+                break;
+            }
+            total += bytes_read as usize;
+        }
         close(fd);
 
-        if bytes_read <= 0 {
+        if total == 0 {
             return None;
         }
+        Some(&MANIFEST_BUF[..total])
+    }
+}
+
+// The loaded manifest; too large for the stack once paths grow with ARG_SIZE
+static mut MANIFEST: Manifest = Manifest::new();
 
-        let mut manifest = Manifest::new();
-        let data = &file_buf[..bytes_read as usize];
+// Load manifest file into MANIFEST
+fn load_manifest(path: &[u8]) -> Option<&'static Manifest> {
+    unsafe {
+        let data = read_runfiles_file(path)?;
+
+        let manifest = &mut *core::ptr::addr_of_mut!(MANIFEST);
+        manifest.count = 0;
         let mut pos = 0;
 
         while pos < data.len() {
@@ -217,47 +497,288 @@ fn load_manifest(path: &[u8]) -> Option<Manifest> {
 
             let line = &data[line_start..pos];
 
-            if let Some(space_pos) = find_byte(line, b' ') {
-                let key = &line[..space_pos];
-                let value = &line[space_pos + 1..];
-                manifest.add_entry(key, value);
+            let mut key_buf = [0u8; MAX_PATH_LEN];
+            let mut value_buf = [0u8; MAX_PATH_LEN];
+            if let Some((key, value)) = split_manifest_line(line, &mut key_buf, &mut value_buf) {
+                if !manifest.add_entry(key, value) {
+                    runfiles_file_too_large(path, MAX_ENTRIES, b" entries\n");
+                }
             }
 
             pos += 1;
         }
 
+        manifest.sort();
+
         Some(manifest)
     }
 }
 
+// Look up an rlocation path in a manifest
+fn resolve_in_manifest(manifest: &Manifest, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
+    let mut resolved = manifest.lookup(path)?;
+    // Optional single level of indirection: a relative value names another
+    // manifest entry. A value naming its own key is a cycle and not followed.
+    if follow_indirection && !is_absolute_path(resolved) && !str_eq(resolved, path) {
+        resolved = manifest.lookup(resolved)?;
+    }
+    let mut result = [0u8; MAX_PATH_LEN];
+    let len = resolved.len().min(MAX_PATH_LEN);
+    result[..len].copy_from_slice(&resolved[..len]);
+    Some(result)
+}
+
+// Manifest of a runfiles directory (<dir>/MANIFEST or <dir>_manifest), loaded into
+// MANIFEST the first time a file is missing from the directory.
+// 0: not tried yet, 1: loaded, 2: there is none
+static mut DIR_MANIFEST_STATE: u8 = 0;
+
+fn dir_manifest(dir: &[u8]) -> Option<&'static Manifest> {
+    unsafe {
+        if DIR_MANIFEST_STATE == 0 {
+            DIR_MANIFEST_STATE = 2;
+            for suffix in [b"/MANIFEST\0" as &[u8], b"_manifest\0"] {
+                let mut path = [0u8; MAX_PATH_LEN + 16];
+                path[..dir.len()].copy_from_slice(dir);
+                path[dir.len()..dir.len() + suffix.len()].copy_from_slice(suffix);
+                if load_manifest(&path[..dir.len() + suffix.len()]).is_some() {
+                    DIR_MANIFEST_STATE = 1;
+                    break;
+                }
+            }
+        }
+        if DIR_MANIFEST_STATE == 1 {
+            Some(&*core::ptr::addr_of!(MANIFEST))
+        } else {
+            None
+        }
+    }
+}
+
+// Bzlmod repository mapping
+// The _repo_mapping runfile maps the apparent repository names used in a repository to
+// canonical ones, one "source,apparent,canonical" line per mapping. Only the lines for
+// the stub's own repository are kept; a source ending in '*' stands for every
+// repository with that prefix.
+const MAX_REPO_MAPPINGS: usize = 256;
+
+struct RepoMapping {
+    apparent: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    apparent_lens: [usize; MAX_REPO_MAPPINGS],
+    canonical: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    canonical_lens: [usize; MAX_REPO_MAPPINGS],
+    count: usize,
+}
+
+// Loaded once with the runfiles, empty if there is no _repo_mapping
+static mut REPO_MAPPING: RepoMapping = RepoMapping {
+    apparent: [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    apparent_lens: [0; MAX_REPO_MAPPINGS],
+    canonical: [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS],
+    canonical_lens: [0; MAX_REPO_MAPPINGS],
+    count: 0,
+};
+
+impl RepoMapping {
+    // Returns false if the mapping is full
+    fn add(&mut self, apparent: &[u8], canonical: &[u8]) -> bool {
+        if self.count >= MAX_REPO_MAPPINGS {
+            return false;
+        }
+
+        let apparent_len = apparent.len().min(MAX_PATH_LEN);
+        let canonical_len = canonical.len().min(MAX_PATH_LEN);
+        self.apparent[self.count][..apparent_len].copy_from_slice(&apparent[..apparent_len]);
+        self.apparent_lens[self.count] = apparent_len;
+        self.canonical[self.count][..canonical_len].copy_from_slice(&canonical[..canonical_len]);
+        self.canonical_lens[self.count] = canonical_len;
+
+        self.count += 1;
+        true
+    }
+
+    fn lookup(&self, apparent: &[u8]) -> Option<&[u8]> {
+        for i in 0..self.count {
+            if str_eq(&self.apparent[i][..self.apparent_lens[i]], apparent) {
+                return Some(&self.canonical[i][..self.canonical_lens[i]]);
+            }
+        }
+        None
+    }
+
+    // Keep the mappings of source_repo from a _repo_mapping file
+    fn load(&mut self, path: &[u8], source_repo: &[u8]) {
+        self.count = 0;
+        let data = match read_runfiles_file(path) {
+            Some(data) => data,
+            None => return,
+        };
+
+        for line in data.split(|&b| b == b'\n') {
+            let mut fields = line.split(|&b| b == b',');
+            let (source, apparent, canonical) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some(apparent), Some(canonical)) => (source, apparent, canonical),
+                _ => continue,
+            };
+            let applies = match source.last() {
+                Some(b'*') => str_starts_with(source_repo, &source[..source.len() - 1]),
+                _ => str_eq(source, source_repo),
+            };
+            if applies && !self.add(apparent, canonical) {
+                runfiles_file_too_large(path, MAX_REPO_MAPPINGS, b" entries\n");
+            }
+        }
+    }
+
+    // Replace the apparent repository name at the start of an rlocation path with its
+    // canonical name. Returns None if it has no mapping.
+    fn map(&self, path: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+        let repo_len = find_byte(path, b'/').unwrap_or(path.len());
+        let canonical = self.lookup(&path[..repo_len])?;
+        let rest = &path[repo_len..];
+        if canonical.len() + rest.len() > MAX_PATH_LEN {
+            return None;
+        }
+        out[..canonical.len()].copy_from_slice(canonical);
+        out[canonical.len()..canonical.len() + rest.len()].copy_from_slice(rest);
+        Some(canonical.len() + rest.len())
+    }
+}
+
 // Runfiles implementation
 enum RunfilesMode {
-    ManifestBased(Manifest),
+    ManifestBased(&'static Manifest),
     DirectoryBased([u8; MAX_PATH_LEN], usize),
 }
 
+// The runfiles directory Bazel places next to a manifest: <dir>_manifest (which
+// includes <name>.runfiles_manifest) or <dir>/MANIFEST. None unless it exists.
+fn manifest_runfiles_dir(manifest: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let is_manifest_file = manifest.ends_with(b"_manifest") || manifest.ends_with(b"/MANIFEST");
+    // Both suffixes are 9 bytes long
+    let len = manifest.len().checked_sub(9).filter(|&len| is_manifest_file && len > 0 && len < MAX_PATH_LEN)?;
+    let mut dir = [0u8; MAX_PATH_LEN];
+    dir[..len].copy_from_slice(&manifest[..len]);
+    // dir[len] is the NUL path_exists needs
+    if !path_exists(&dir[..len + 1]) {
+        return None;
+    }
+    Some((dir, len))
+}
+
+// The manifest Bazel writes for a runfiles directory: <dir>/MANIFEST or <dir>_manifest
+// (which includes <name>.runfiles_manifest). Exported in directory mode for children
+// that only read manifests. None unless it exists.
+fn runfiles_dir_manifest(dir: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let mut dir_len = dir.len();
+    while dir_len > 1 && dir[dir_len - 1] == b'/' {
+        dir_len -= 1;
+    }
+    for suffix in [b"/MANIFEST" as &[u8], b"_manifest"] {
+        let len = dir_len + suffix.len();
+        if len >= MAX_PATH_LEN {
+            continue;
+        }
+        let mut path = [0u8; MAX_PATH_LEN];
+        path[..dir_len].copy_from_slice(&dir[..dir_len]);
+        path[dir_len..len].copy_from_slice(suffix);
+        // path[len] is the NUL path_exists needs
+        if path_exists(&path[..len + 1]) {
+            return Some((path, len));
+        }
+    }
+    None
+}
+
+// With RUNFILES_STUB_DEBUG set, warns when RUNFILES_DIR names another tree than
+// RUNFILES_MANIFEST_FILE, which takes precedence for lookups and the export
+fn check_runfiles_dir(manifest: &[u8], manifest_dir: Option<&[u8]>) {
+    if get_env_var(b"RUNFILES_STUB_DEBUG", &mut [0u8; 64]).is_none_or(|len| len == 0) {
+        return;
+    }
+    let mut dir = [0u8; MAX_PATH_LEN];
+    let mut len = match get_runfiles_env_var(b"RUNFILES_DIR", &mut dir) {
+        Some(len) if len > 0 => len,
+        _ => return,
+    };
+    while len > 1 && dir[len - 1] == b'/' {
+        len -= 1;
+    }
+    if manifest_dir == Some(&dir[..len]) {
+        return;
+    }
+    eprint(b"WARNING: RUNFILES_DIR and RUNFILES_MANIFEST_FILE describe different runfiles trees\n");
+    eprint(b"  RUNFILES_MANIFEST_FILE=");
+    eprint(manifest);
+    eprint(b" (used)\n  RUNFILES_DIR=");
+    eprint(&dir[..len]);
+    eprint(b" (ignored)\n  Runfiles directory of the manifest: ");
+    eprint(manifest_dir.unwrap_or(b"(none)"));
+    eprint(b"\n");
+}
+
 struct Runfiles {
     mode: RunfilesMode,
     // Paths for environment variables (when export_runfiles_env is true)
     manifest_path: Option<([u8; MAX_PATH_LEN], usize)>, // RUNFILES_MANIFEST_FILE
     dir_path: Option<([u8; MAX_PATH_LEN], usize)>,      // RUNFILES_DIR and JAVA_RUNFILES
+    // Directory found through the environment or next to the executable: without
+    // symlink support it may lack files that its manifest lists
+    manifest_fallback: bool,
 }
 
 impl Runfiles {
     fn create(executable_path: Option<&[u8]>) -> Option<Self> {
+        let runfiles = Self::discover(executable_path)?;
+        runfiles.load_repo_mapping();
+        Some(runfiles)
+    }
+
+    // Runfiles directory chosen by the stub itself (sandbox root or extracted tree)
+    fn from_dir(dir: [u8; MAX_PATH_LEN], len: usize) -> Self {
+        let runfiles = Self {
+            mode: RunfilesMode::DirectoryBased(dir, len),
+            manifest_path: None,
+            dir_path: Some((dir, len)),
+            manifest_fallback: false,
+        };
+        runfiles.load_repo_mapping();
+        runfiles
+    }
+
+    // Load the repository mapping of the stub's source repository for rlocation()
+    fn load_repo_mapping(&self) {
+        unsafe {
+            let source_repo: &[u8] = if METADATA.flags & FLAG_SOURCE_REPO != 0 {
+                &SOURCE_REPO[..strlen(&SOURCE_REPO)]
+            } else {
+                b""
+            };
+            if let Some(path) = self.resolve(b"_repo_mapping", false) {
+                REPO_MAPPING.load(&path, source_repo);
+            }
+        }
+    }
+
+    // Find the runfiles through the environment or next to the executable
+    fn discover(executable_path: Option<&[u8]>) -> Option<Self> {
         let mut manifest_path = [0u8; MAX_PATH_LEN];
 
         // Try RUNFILES_MANIFEST_FILE first
-        if let Some(len) = get_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
             if len > 0 {
                 let mut path_with_null = [0u8; MAX_PATH_LEN + 1];
                 path_with_null[..len].copy_from_slice(&manifest_path[..len]);
 
                 if let Some(manifest) = load_manifest(&path_with_null[..len + 1]) {
+                    let dir_path = manifest_runfiles_dir(&manifest_path[..len]);
+                    check_runfiles_dir(&manifest_path[..len], dir_path.as_ref().map(|(dir, len)| &dir[..*len]));
                     return Some(Self {
                         mode: RunfilesMode::ManifestBased(manifest),
                         manifest_path: Some((manifest_path, len)),
-                        dir_path: None,
+                        // Exported too, for libraries that only use directories
+                        dir_path,
+                        manifest_fallback: false,
                     });
                 }
             }
@@ -265,12 +786,14 @@ impl Runfiles {
 
         // Try RUNFILES_DIR
         let mut runfiles_dir = [0u8; MAX_PATH_LEN];
-        if let Some(len) = get_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
             if len > 0 {
                 return Some(Self {
                     mode: RunfilesMode::DirectoryBased(runfiles_dir, len),
-                    manifest_path: None,
+                    // Exported too, for libraries that only read manifests
+                    manifest_path: runfiles_dir_manifest(&runfiles_dir[..len]),
                     dir_path: Some((runfiles_dir, len)),
+                    manifest_fallback: true,
                 });
             }
         }
@@ -312,6 +835,7 @@ impl Runfiles {
                             mode: RunfilesMode::ManifestBased(manifest),
                             manifest_path: Some((manifest_path_without_null, manifest_file_len)),
                             dir_path: if dir_len > 0 { Some((dir_path, dir_len)) } else { None },
+                            manifest_fallback: false,
                         });
                     }
                 }
@@ -331,8 +855,9 @@ impl Runfiles {
                     if path_exists(&runfiles_dir[..exe_len + 10]) {
                         return Some(Self {
                             mode: RunfilesMode::DirectoryBased(runfiles_dir, exe_len + 9),
-                            manifest_path: None,
+                            manifest_path: runfiles_dir_manifest(&runfiles_dir[..exe_len + 9]),
                             dir_path: Some((runfiles_dir, exe_len + 9)),
+                            manifest_fallback: true,
                         });
                     }
                 }
@@ -342,40 +867,62 @@ impl Runfiles {
         None
     }
 
-    fn rlocation(&self, path: &[u8]) -> Option<[u8; MAX_PATH_LEN]> {
+    fn rlocation(&self, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
+        // Apparent repository names are translated through the repository mapping;
+        // paths without a mapping are looked up unchanged
+        let mut mapped = [0u8; MAX_PATH_LEN];
+        let path = match unsafe { REPO_MAPPING.map(path, &mut mapped) } {
+            Some(len) => &mapped[..len],
+            None => path,
+        };
+        self.resolve(path, follow_indirection)
+    }
+
+    // Look up an rlocation path as-is
+    fn resolve(&self, path: &[u8], follow_indirection: bool) -> Option<[u8; MAX_PATH_LEN]> {
         // If path is absolute, don't resolve through runfiles
         if path.len() > 0 && path[0] == b'/' {
             return None;
         }
 
         match &self.mode {
-            RunfilesMode::ManifestBased(manifest) => {
-                if let Some(resolved) = manifest.lookup(path) {
-                    let mut result = [0u8; MAX_PATH_LEN];
-                    let len = resolved.len().min(MAX_PATH_LEN);
-                    result[..len].copy_from_slice(&resolved[..len]);
-                    return Some(result);
-                }
-                None
-            }
+            RunfilesMode::ManifestBased(manifest) => resolve_in_manifest(manifest, path, follow_indirection),
             RunfilesMode::DirectoryBased(dir, dir_len) => {
-                let mut result = [0u8; MAX_PATH_LEN];
-                let mut pos = 0;
-
-                // Copy directory
-                let copy_len = (*dir_len).min(MAX_PATH_LEN);
-                result[..copy_len].copy_from_slice(&dir[..copy_len]);
-                pos += copy_len;
+                let dir = &dir[..(*dir_len).min(MAX_PATH_LEN)];
+                let separator = dir.last().is_some_and(|&c| c != b'/');
+
+                // The result is passed to execve as a C string, so the NUL must fit too;
+                // a truncated path would name a different file
+                let len = dir.len() + separator as usize + path.len();
+                if len >= MAX_PATH_LEN {
+                    eprint(b"ERROR: Runfiles path too long: ");
+                    eprint(dir);
+                    if separator {
+                        eprint(b"/");
+                    }
+                    eprint(path);
+                    eprint(b"\nPaths are limited to ");
+                    eprint_number(MAX_PATH_LEN - 1);
+                    eprint(b" bytes\n");
+                    unsafe { exit(1) }
+                }
 
-                // Add separator if needed
-                if pos < MAX_PATH_LEN && pos > 0 && result[pos - 1] != b'/' {
+                let mut result = [0u8; MAX_PATH_LEN];
+                result[..dir.len()].copy_from_slice(dir);
+                let mut pos = dir.len();
+                if separator {
                     result[pos] = b'/';
                     pos += 1;
                 }
+                result[pos..pos + path.len()].copy_from_slice(path);
+                result[len] = 0;
 
-                // Copy path
-                let path_len = path.len().min(MAX_PATH_LEN - pos);
-                result[pos..pos + path_len].copy_from_slice(&path[..path_len]);
+                // A file missing from the tree may still be listed in its manifest
+                if self.manifest_fallback && !path_exists(&result[..len + 1]) {
+                    if let Some(resolved) = dir_manifest(dir).and_then(|m| resolve_in_manifest(m, path, follow_indirection)) {
+                        return Some(resolved);
+                    }
+                }
 
                 Some(result)
             }
@@ -396,7 +943,15 @@ const MAX_ENV_VARS: usize = 1024;     // Max number of environment variables
 static mut MODIFIED_ENV_DATA: [u8; MAX_ENV_SIZE] = [0; MAX_ENV_SIZE];
 static mut MODIFIED_ENV_PTRS: [*const u8; MAX_ENV_VARS + 1] = [core::ptr::null(); MAX_ENV_VARS + 1];
 
-fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
+// With FLAG_KEEP_ENV, a runfiles variable the parent set is passed on as-is
+// instead of being replaced with the stub's own value
+fn keep_inherited(name: &[u8]) -> bool {
+    let keep = unsafe { METADATA.flags } & FLAG_KEEP_ENV != 0;
+    keep && get_env_var(name, &mut [0u8; 1]).is_some_and(|len| len > 0)
+}
+
+// With an allowlist, inherited variables not named in it are dropped
+fn build_runfiles_environ(runfiles: Option<&Runfiles>, allowlist: Option<&[u8]>) -> *const *const u8 {
     unsafe {
         let mut data_pos = 0usize;
         let mut ptr_idx = 0usize;
@@ -437,16 +992,22 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
             true
         };
 
+        // Variables left out of the export are passed on as the parent set them, like kept ones
+        let flags = METADATA.flags;
+        let keep_manifest = flags & FLAG_NO_EXPORT_MANIFEST != 0 || keep_inherited(b"RUNFILES_MANIFEST_FILE");
+        let keep_dir = flags & FLAG_NO_EXPORT_DIR != 0 || keep_inherited(b"RUNFILES_DIR");
+        let keep_java = flags & FLAG_NO_EXPORT_JAVA != 0 || keep_inherited(b"JAVA_RUNFILES");
+
         // Add RUNFILES_MANIFEST_FILE if we have it
-        if let Some(rf) = runfiles {
+        if let Some(rf) = runfiles.filter(|_| !keep_manifest) {
             if let Some((ref path, len)) = rf.manifest_path {
                 if !add_env_var(b"RUNFILES_MANIFEST_FILE", &path[..len]) {
-                    print(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\n");
-                    print(b"Environment buffer limit exceeded. Total size limit: ");
-                    print_number(MAX_ENV_SIZE);
-                    print(b" bytes, max variables: ");
-                    print_number(MAX_ENV_VARS);
-                    print(b"\n");
+                    eprint(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\n");
+                    eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                    eprint_number(MAX_ENV_SIZE);
+                    eprint(b" bytes, max variables: ");
+                    eprint_number(MAX_ENV_VARS);
+                    eprint(b"\n");
                     exit(1);
                 }
             }
@@ -455,27 +1016,49 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
         // Add RUNFILES_DIR if we have it
         if let Some(rf) = runfiles {
             if let Some((ref path, len)) = rf.dir_path {
-                if !add_env_var(b"RUNFILES_DIR", &path[..len]) {
-                    print(b"ERROR: Failed to add RUNFILES_DIR to environment\n");
-                    print(b"Environment buffer limit exceeded. Total size limit: ");
-                    print_number(MAX_ENV_SIZE);
-                    print(b" bytes, max variables: ");
-                    print_number(MAX_ENV_VARS);
-                    print(b"\n");
+                if !keep_dir && !add_env_var(b"RUNFILES_DIR", &path[..len]) {
+                    eprint(b"ERROR: Failed to add RUNFILES_DIR to environment\n");
+                    eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                    eprint_number(MAX_ENV_SIZE);
+                    eprint(b" bytes, max variables: ");
+                    eprint_number(MAX_ENV_VARS);
+                    eprint(b"\n");
                     exit(1);
                 }
-                if !add_env_var(b"JAVA_RUNFILES", &path[..len]) {
-                    print(b"ERROR: Failed to add JAVA_RUNFILES to environment\n");
-                    print(b"Environment buffer limit exceeded. Total size limit: ");
-                    print_number(MAX_ENV_SIZE);
-                    print(b" bytes, max variables: ");
-                    print_number(MAX_ENV_VARS);
-                    print(b"\n");
+                if !keep_java && !add_env_var(b"JAVA_RUNFILES", &path[..len]) {
+                    eprint(b"ERROR: Failed to add JAVA_RUNFILES to environment\n");
+                    eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                    eprint_number(MAX_ENV_SIZE);
+                    eprint(b" bytes, max variables: ");
+                    eprint_number(MAX_ENV_VARS);
+                    eprint(b"\n");
                     exit(1);
                 }
             }
         }
 
+        // Variables embedded with --set-env; inherited ones with the same names are skipped below
+        let set_env = &*core::ptr::addr_of!(SET_ENV);
+        let mut pos = 0;
+        while flags & FLAG_SET_ENV != 0 && pos < set_env.len() && set_env[pos] != 0 {
+            let len = strlen(&set_env[pos..]);
+            let var = &set_env[pos..pos + len];
+            // The finalizer only embeds NAME=value entries
+            let eq = find_byte(var, b'=').unwrap_or(len);
+            if !add_env_var(&var[..eq], var.get(eq + 1..).unwrap_or(b"")) {
+                eprint(b"ERROR: Failed to add ");
+                eprint(&var[..eq]);
+                eprint(b" to environment\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes, max variables: ");
+                eprint_number(MAX_ENV_VARS);
+                eprint(b"\n");
+                exit(1);
+            }
+            pos += len + 1;
+        }
+
         // Copy existing environment, filtering out runfiles vars
         let mut env_ptr = environ;
         let mut env_dropped = false;
@@ -491,9 +1074,11 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
             let entry = core::slice::from_raw_parts(entry_ptr, len);
 
             // Check if this is a runfiles variable we should skip
-            let should_skip = str_starts_with(entry, b"RUNFILES_MANIFEST_FILE=")
-                || str_starts_with(entry, b"RUNFILES_DIR=")
-                || str_starts_with(entry, b"JAVA_RUNFILES=");
+            let should_skip = !keep_manifest && str_starts_with(entry, b"RUNFILES_MANIFEST_FILE=")
+                || !keep_dir && str_starts_with(entry, b"RUNFILES_DIR=")
+                || !keep_java && str_starts_with(entry, b"JAVA_RUNFILES=")
+                || flags & FLAG_SET_ENV != 0 && env_name_set(entry, set_env)
+                || allowlist.is_some_and(|list| !env_name_allowed(entry, list));
 
             if !should_skip {
                 // Copy this environment variable
@@ -515,18 +1100,18 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
 
         // Check if any environment variables were dropped
         if env_dropped {
-            print(b"ERROR: Failed to copy all environment variables\n");
-            print(b"Environment buffer limit exceeded. Total size limit: ");
-            print_number(MAX_ENV_SIZE);
-            print(b" bytes, max variables: ");
-            print_number(MAX_ENV_VARS);
-            print(b"\n");
-            print(b"Current usage: ");
-            print_number(data_pos);
-            print(b" bytes, ");
-            print_number(ptr_idx);
-            print(b" variables\n");
-            print(b"Consider reducing the number or size of environment variables.\n");
+            eprint(b"ERROR: Failed to copy all environment variables\n");
+            eprint(b"Environment buffer limit exceeded. Total size limit: ");
+            eprint_number(MAX_ENV_SIZE);
+            eprint(b" bytes, max variables: ");
+            eprint_number(MAX_ENV_VARS);
+            eprint(b"\n");
+            eprint(b"Current usage: ");
+            eprint_number(data_pos);
+            eprint(b" bytes, ");
+            eprint_number(ptr_idx);
+            eprint(b" variables\n");
+            eprint(b"Consider reducing the number or size of environment variables.\n");
             exit(1);
         }
 
@@ -537,145 +1122,998 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *const *const u8 {
     }
 }
 
-// Placeholders for stub runner (will be replaced in final binary)
-const ARG_SIZE: usize = 256;
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARGC_PLACEHOLDER: [u8; 32] = *b"@@RUNFILES_ARGC@@\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut TRANSFORM_FLAGS: [u8; 32] = *b"@@RUNFILES_TRANSFORM_FLAGS@@\0\0\0\0";
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut EXPORT_RUNFILES_ENV: [u8; 32] = *b"@@RUNFILES_EXPORT_ENV@@\0\0\0\0\0\0\0\0\0";
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG0_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG1_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG2_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG3_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG4_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
-
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG5_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+// Self-extracting stubs
+// With --self-extracting the finalizer appends a runfiles archive to the stub,
+// located through a trailer at the very end of the file (all integers little-endian):
+//   entry:   u32 path_len | path | u32 mode | u64 data_len | data
+//   trailer: u64 archive_offset | u64 entry_count | u64 archive_hash | "RFSTUBSX"
+// The archive is extracted once per content hash to $TMPDIR/runfiles-stub-<hash>.
+// Extraction goes to a fresh private (0700) staging directory that is renamed into
+// place, so concurrent first runs never see a partially extracted tree. $TMPDIR is
+// usually shared, so an existing tree is only reused if this user made it.
+// The archive lives after the code signature, which doesn't cover it.
+const SELF_EXTRACT_MAGIC: &[u8; 8] = b"RFSTUBSX";
+const SELF_EXTRACT_TRAILER_SIZE: usize = 32;
+const MAX_EXTRACT_PATH_LEN: usize = MAX_PATH_LEN * 2;
+
+// Static buffer for copying file contents out of the archive
+static mut EXTRACT_BUF: [u8; 65536] = [0; 65536];
+
+fn read_exact(fd: i32, buf: &mut [u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = unsafe { read(fd, buf[pos..].as_mut_ptr(), buf.len() - pos) };
+        if n <= 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
 
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG6_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+fn write_all(fd: i32, buf: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let n = unsafe { write(fd, buf[pos..].as_ptr(), buf.len() - pos) };
+        if n <= 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
 
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG7_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
 
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG8_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
 
-#[used]
-#[link_section = "__DATA,__runfiles"]
-static mut ARG9_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+// Append `n` as decimal to buf at pos, returns the new position
+fn append_decimal(buf: &mut [u8], mut pos: usize, mut n: u64) -> Option<usize> {
+    let mut digits = [0u8; 20];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        n /= 10;
+        count += 1;
+        if n == 0 {
+            break;
+        }
+    }
+    if pos + count > buf.len() {
+        return None;
+    }
+    while count > 0 {
+        count -= 1;
+        buf[pos] = digits[count];
+        pos += 1;
+    }
+    Some(pos)
+}
 
-// Get the length of a null-terminated string
-fn strlen(s: &[u8]) -> usize {
-    let mut len = 0;
-    while len < s.len() && s[len] != 0 {
-        len += 1;
+// Append `n` as 16 lowercase hex digits to buf at pos, returns the new position
+fn append_hex(buf: &mut [u8], mut pos: usize, n: u64) -> Option<usize> {
+    if pos + 16 > buf.len() {
+        return None;
     }
-    len
+    for i in 0..16 {
+        let nibble = ((n >> ((15 - i) * 4)) & 0xF) as u8;
+        buf[pos] = if nibble < 10 { b'0' + nibble } else { b'a' + nibble - 10 };
+        pos += 1;
+    }
+    Some(pos)
 }
 
-// Check if placeholder is still in template state
-fn is_template_placeholder(placeholder: &[u8]) -> bool {
-    if placeholder.len() < 17 {
-        return false;
+// Create every parent directory of the NUL-terminated path in path[..len] below
+// path[..start], which must already exist
+fn create_parent_dirs(path: &mut [u8], start: usize, len: usize) {
+    for i in start + 1..len {
+        if path[i] == b'/' {
+            path[i] = 0;
+            unsafe {
+                mkdir(path.as_ptr(), 0o755);
+            }
+            path[i] = b'/';
+        }
     }
-    str_starts_with(placeholder, b"@@RUNFILES_")
 }
 
-#[no_mangle]
-pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
+// Whether the NUL-terminated path is a directory only this user can have written:
+// a real directory (not a symlink) owned by the effective user, without group or
+// other write permission
+fn is_private_dir(path: &[u8]) -> bool {
     unsafe {
-        // Check if ARGC is still a placeholder
-        if is_template_placeholder(&ARGC_PLACEHOLDER) {
-            print(b"ERROR: This is a template stub runner.\n");
-            print(b"You must finalize it by replacing the placeholders before use.\n");
-            print(b"The ARGC_PLACEHOLDER has not been replaced.\n");
-            exit(1);
+        let mut st: Stat = core::mem::zeroed();
+        if lstat(path.as_ptr(), &mut st) != 0 {
+            return false;
         }
+        st.mode & S_IFMT == S_IFDIR && st.mode & 0o022 == 0 && st.uid == geteuid()
+    }
+}
 
-        // Parse argc from placeholder
-        let argc_str = &ARGC_PLACEHOLDER;
-        let argc_len = strlen(argc_str);
-        if argc_len == 0 {
-            print(b"ERROR: ARGC is empty\n");
-            exit(1);
+// Extract the runfiles archive appended to this executable.
+// Writes the extracted runfiles directory to dir_out and returns its length.
+fn extract_self(dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    let mut exe_path = [0u8; MAX_EXTRACT_PATH_LEN];
+    let mut exe_path_size = exe_path.len() as u32;
+    unsafe {
+        if _NSGetExecutablePath(exe_path.as_mut_ptr(), &mut exe_path_size) != 0 {
+            return None;
         }
-
-        // Parse argc as decimal number
-        let mut argc: usize = 0;
-        for i in 0..argc_len {
-            let c = argc_str[i];
-            if c >= b'0' && c <= b'9' {
-                argc = argc * 10 + (c - b'0') as usize;
-            } else {
-                print(b"ERROR: ARGC contains non-digit characters\n");
-                exit(1);
-            }
+        let fd = open(exe_path.as_ptr(), O_RDONLY | O_CLOEXEC);
+        if fd < 0 {
+            return None;
         }
-
-        if argc == 0 || argc > 10 {
-            print(b"ERROR: Invalid argc (must be 1-10)\n");
-            exit(1);
+        let result = extract_archive(fd, dir_out);
+        close(fd);
+        result
+    }
+}
+
+fn extract_archive(fd: i32, dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    unsafe {
+        // Read the trailer
+        let mut trailer = [0u8; SELF_EXTRACT_TRAILER_SIZE];
+        if lseek(fd, -(SELF_EXTRACT_TRAILER_SIZE as i64), SEEK_END) < 0 || !read_exact(fd, &mut trailer) {
+            return None;
+        }
+        if !str_eq(&trailer[24..32], SELF_EXTRACT_MAGIC) {
+            return None;
+        }
+        let archive_offset = read_u64_le(&trailer[0..8]);
+        let entry_count = read_u64_le(&trailer[8..16]);
+        let archive_hash = read_u64_le(&trailer[16..24]);
+
+        // Final directory: $TMPDIR/runfiles-stub-<hash> (falling back to /tmp)
+        let mut dir_len = match get_env_var(b"TMPDIR", dir_out) {
+            Some(len) if len > 0 && len < MAX_PATH_LEN => len,
+            _ => {
+                dir_out[..4].copy_from_slice(b"/tmp");
+                4
+            }
+        };
+        while dir_len > 1 && dir_out[dir_len - 1] == b'/' {
+            dir_len -= 1;
+        }
+        let prefix = b"/runfiles-stub-";
+        if dir_len + prefix.len() + 16 >= MAX_PATH_LEN {
+            return None;
+        }
+        dir_out[dir_len..dir_len + prefix.len()].copy_from_slice(prefix);
+        dir_len = append_hex(dir_out, dir_len + prefix.len(), archive_hash)?;
+        dir_out[dir_len] = 0;
+
+        // Reuse a previous extraction (only ever published complete) if this user made
+        // it; anything else at that path may have been planted by another user
+        if is_private_dir(&dir_out[..dir_len + 1]) {
+            return Some(dir_len);
         }
 
-        // Parse transform flags (bitmask of which args to transform)
-        let flags_str = &TRANSFORM_FLAGS;
-        let flags_len = strlen(flags_str);
-        let mut transform_flags: u32 = 0;
+        // Staging directory: <final>.<pid>.<n>, which mkdir creates fresh and private,
+        // so nothing below it can have been planted
+        let mut path = [0u8; MAX_EXTRACT_PATH_LEN];
+        path[..dir_len].copy_from_slice(&dir_out[..dir_len]);
+        path[dir_len] = b'.';
+        let pid_len = append_decimal(&mut path, dir_len + 1, getpid() as u64)?;
+        path[pid_len] = b'.';
+        let mut attempt = 0;
+        let staging_len = loop {
+            let len = append_decimal(&mut path, pid_len + 1, attempt)?;
+            path[len] = 0;
+            if mkdir(path.as_ptr(), 0o700) == 0 {
+                break len;
+            }
+            attempt += 1;
+            if *__error() != EEXIST || attempt == 100 {
+                return None;
+            }
+        };
 
-        if !is_template_placeholder(flags_str) && flags_len > 0 {
-            // Parse as decimal number (bitmask)
-            for i in 0..flags_len {
-                let c = flags_str[i];
-                if c >= b'0' && c <= b'9' {
-                    transform_flags = transform_flags * 10 + (c - b'0') as u32;
-                } else {
-                    print(b"ERROR: TRANSFORM_FLAGS contains non-digit characters\n");
-                    exit(1);
+        if lseek(fd, archive_offset as i64, SEEK_SET) < 0 || !extract_entries(fd, entry_count, &mut path, staging_len) {
+            remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+            return None;
+        }
+
+        // Publish the tree. If another process of this user won the race, its identical
+        // tree is used and ours removed.
+        path[staging_len] = 0;
+        let published = rename(path.as_ptr(), dir_out.as_ptr()) == 0;
+        if published || is_private_dir(&dir_out[..dir_len + 1]) {
+            if !published {
+                remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+            }
+            return Some(dir_len);
+        }
+
+        // The final path is taken by something this user can't trust, so the private
+        // staging directory serves as a one-off copy
+        if staging_len >= MAX_PATH_LEN {
+            remove_staging(fd, archive_offset, entry_count, &mut path, staging_len);
+            return None;
+        }
+        dir_out[..staging_len + 1].copy_from_slice(&path[..staging_len + 1]);
+        Some(staging_len)
+    }
+}
+
+// Write the archive entries (read from fd's current position) below the staging
+// directory in path[..staging_len]
+fn extract_entries(fd: i32, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) -> bool {
+    path[staging_len] = b'/';
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(EXTRACT_BUF) };
+    for _ in 0..entry_count {
+        // Entry path, appended to the staging directory
+        let mut header = [0u8; 12];
+        if !read_exact(fd, &mut header[..4]) {
+            return false;
+        }
+        let path_len = read_u32_le(&header[..4]) as usize;
+        let path_start = staging_len + 1;
+        if path_start + path_len + 1 > MAX_EXTRACT_PATH_LEN {
+            return false;
+        }
+        if !read_exact(fd, &mut path[path_start..path_start + path_len]) {
+            return false;
+        }
+        let full_len = path_start + path_len;
+        path[full_len] = 0;
+
+        // Mode and data length
+        if !read_exact(fd, &mut header) {
+            return false;
+        }
+        let mode = read_u32_le(&header[..4]);
+        let mut remaining = read_u64_le(&header[4..12]);
+
+        create_parent_dirs(&mut path[..], staging_len, full_len);
+        let out = unsafe { open(path.as_ptr(), O_WRONLY | O_CREAT | O_EXCL | O_CLOEXEC, mode) };
+        if out < 0 {
+            return false;
+        }
+        while remaining > 0 {
+            let chunk = (remaining as usize).min(buf.len());
+            if !read_exact(fd, &mut buf[..chunk]) || !write_all(out, &buf[..chunk]) {
+                unsafe { close(out) };
+                return false;
+            }
+            remaining -= chunk as u64;
+        }
+        unsafe { close(out) };
+    }
+    true
+}
+
+// Remove what an extraction wrote below the staging directory in path[..staging_len]
+// by walking the archive again: each file, then its parent directories (which only
+// succeeds once they are empty), and finally the staging directory itself
+fn remove_staging(fd: i32, archive_offset: u64, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) {
+    path[staging_len] = b'/';
+    let path_start = staging_len + 1;
+    unsafe {
+        if lseek(fd, archive_offset as i64, SEEK_SET) >= 0 {
+            for _ in 0..entry_count {
+                let mut header = [0u8; 12];
+                if !read_exact(fd, &mut header[..4]) {
+                    break;
+                }
+                let path_len = read_u32_le(&header[..4]) as usize;
+                if path_start + path_len + 1 > MAX_EXTRACT_PATH_LEN
+                    || !read_exact(fd, &mut path[path_start..path_start + path_len])
+                    || !read_exact(fd, &mut header)
+                {
+                    break;
+                }
+                let full_len = path_start + path_len;
+                path[full_len] = 0;
+                unlink(path.as_ptr());
+                for i in (path_start..full_len).rev() {
+                    if path[i] == b'/' {
+                        path[i] = 0;
+                        rmdir(path.as_ptr());
+                        path[i] = b'/';
+                    }
+                }
+                if lseek(fd, read_u64_le(&header[4..12]) as i64, SEEK_CUR) < 0 {
+                    break;
                 }
             }
         }
-        // If flags not set, default to transforming all args
-        if flags_len == 0 || is_template_placeholder(flags_str) {
-            transform_flags = 0xFFFFFFFF; // Transform all by default
+        path[staging_len] = 0;
+        rmdir(path.as_ptr());
+    }
+}
+
+// Placeholders for stub runner (will be replaced in final binary)
+use crate::ARG_SIZE;
+
+// Scalar settings, packed into one block that the finalizer patches as a unit.
+// Fields are little-endian; `version` identifies the layout so the finalizer can
+// tell templates apart. `features` is set here and lists the flags this template
+// understands. Only the variable-length arguments live in separate placeholders.
+const METADATA_VERSION: u32 = 1;
+
+// Magic of a finalized metadata block (templates carry "@@RUNFILES_METADATA@@")
+const METADATA_MAGIC: &[u8] = b"RUNFILES_METADATA";
+
+// Bits of StubMetadata::flags and StubMetadata::features
+const FLAG_EXPORT_ENV: u32 = 1 << 0;
+const FLAG_SELF_EXTRACT: u32 = 1 << 1;
+const FLAG_INDIRECTION: u32 = 1 << 2;
+// 1 << 3 is spawn mode (Linux only)
+const FLAG_PERCENT_DECODE: u32 = 1 << 4;
+const FLAG_DEFAULT_ARGS: u32 = 1 << 5;
+const FLAG_SANDBOX: u32 = 1 << 6;
+const FLAG_EXEC: u32 = 1 << 7;
+const FLAG_EXPAND_ROOT: u32 = 1 << 8;
+const FLAG_RLOCATION_PREFIXES: u32 = 1 << 9;
+const FLAG_STRICT_PREFIXES: u32 = 1 << 10;
+const FLAG_SOURCE_REPO: u32 = 1 << 11;
+const FLAG_EXPAND_ENV: u32 = 1 << 12;
+const FLAG_KEEP_ENV: u32 = 1 << 13;
+const FLAG_NO_EXPORT_MANIFEST: u32 = 1 << 14;
+const FLAG_NO_EXPORT_DIR: u32 = 1 << 15;
+const FLAG_NO_EXPORT_JAVA: u32 = 1 << 16;
+const FLAG_SET_ENV: u32 = 1 << 17;
+const FLAG_UMASK: u32 = 1 << 18;
+const FLAG_RLIMIT: u32 = 1 << 19;
+const FLAG_TRANSFORM_VALUE: u32 = 1 << 20;
+const FLAG_ARGV0: u32 = 1 << 21;
+const FLAG_RUNTIME_POS: u32 = 1 << 22;
+const FLAG_CHDIR: u32 = 1 << 23;
+
+// Section holding every placeholder below; the finalizer looks it up by this
+// name (MACHO_PLACEHOLDER_SEGMENT and MACHO_PLACEHOLDER_SECTION in finalize-stub/src/binary.rs match)
+macro_rules! placeholder_section {
+    () => {
+        "__DATA,__runfiles"
+    };
+}
+
+#[repr(C)]
+struct StubMetadata {
+    magic: [u8; 24],
+    version: u32,
+    arg_size: u32,
+    argc: u32,
+    transform_flags: u32,
+    flags: u32,
+    // Only read by the finalizer
+    #[allow(dead_code)]
+    features: u32,
+    // Set by the finalizer for tools reading the stub
+    #[allow(dead_code)]
+    args_offset: i32,
+    value_flags: u32,
+    #[allow(dead_code)]
+    reserved: [u8; 8],
+}
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut METADATA: StubMetadata = StubMetadata {
+    magic: *b"@@RUNFILES_METADATA@@\0\0\0",
+    version: METADATA_VERSION,
+    arg_size: ARG_SIZE as u32,
+    argc: 0,
+    transform_flags: 0,
+    flags: 0,
+    features: FLAG_EXPORT_ENV | FLAG_SELF_EXTRACT | FLAG_INDIRECTION | FLAG_PERCENT_DECODE | FLAG_DEFAULT_ARGS
+        | FLAG_SANDBOX | FLAG_EXEC | FLAG_EXPAND_ROOT | FLAG_RLOCATION_PREFIXES | FLAG_STRICT_PREFIXES
+        | FLAG_SOURCE_REPO | FLAG_EXPAND_ENV | FLAG_KEEP_ENV
+        | FLAG_NO_EXPORT_MANIFEST | FLAG_NO_EXPORT_DIR | FLAG_NO_EXPORT_JAVA | FLAG_SET_ENV
+        | FLAG_UMASK | FLAG_RLIMIT | FLAG_TRANSFORM_VALUE | FLAG_ARGV0 | FLAG_RUNTIME_POS | FLAG_CHDIR,
+    args_offset: 0,
+    value_flags: 0,
+    reserved: [0; 8],
+};
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG0_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG1_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG2_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG3_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG4_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG5_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG6_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG7_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG8_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARG9_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
+
+// Default runtime arguments, used only when the stub is run without any.
+// Finalized as NUL-terminated strings followed by an empty one.
+const DEFAULT_ARGS_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut DEFAULT_ARGS: [u8; DEFAULT_ARGS_SIZE] = {
+    let magic = b"@@RUNFILES_DEFAULT_ARGS@@";
+    let mut buf = [0u8; DEFAULT_ARGS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Index of the embedded argument the runtime arguments go before
+// (--runtime-args-position), as NUL-terminated decimal digits. Without it they
+// follow the last embedded argument.
+const RUNTIME_POS_SIZE: usize = 32;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RUNTIME_POS: [u8; RUNTIME_POS_SIZE] = {
+    let magic = b"@@RUNFILES_RUNTIME_POS@@";
+    let mut buf = [0u8; RUNTIME_POS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Directory to run the target in (--chdir), as a NUL-terminated path. A relative
+// path is resolved through the runfiles.
+const CHDIR_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut CHDIR: [u8; CHDIR_SIZE] = {
+    let magic = b"@@RUNFILES_CHDIR@@";
+    let mut buf = [0u8; CHDIR_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Program to run instead of argv[0] (--exec), as a NUL-terminated path.
+// Relative paths are resolved through runfiles like transformed arguments.
+const EXEC_PATH_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut EXEC_PATH: [u8; EXEC_PATH_SIZE] = {
+    let magic = b"@@RUNFILES_EXEC_PATH@@";
+    let mut buf = [0u8; EXEC_PATH_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Name the target sees as argv[0] (--argv0), as a NUL-terminated string. The
+// program run is still the (resolved) first argument.
+const ARGV0_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ARGV0: [u8; ARGV0_SIZE] = {
+    let magic = b"@@RUNFILES_ARGV0@@";
+    let mut buf = [0u8; ARGV0_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Hermetic sandbox: the stub changes into this directory, uses it as the
+// runfiles tree and passes on only the allowlisted environment variables.
+// The root is a NUL-terminated absolute path; the allowlist holds
+// NUL-terminated variable names followed by an empty one.
+const ENV_ALLOWLIST_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SANDBOX_ROOT: [u8; MAX_PATH_LEN] = {
+    let magic = b"@@RUNFILES_SANDBOX_ROOT@@";
+    let mut buf = [0u8; MAX_PATH_LEN];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut ENV_ALLOWLIST: [u8; ENV_ALLOWLIST_SIZE] = {
+    let magic = b"@@RUNFILES_ENV_ALLOWLIST@@";
+    let mut buf = [0u8; ENV_ALLOWLIST_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Rlocation prefixes transformed arguments must start with to be resolved
+// (--allow-rlocation-prefix), as NUL-terminated strings followed by an empty one.
+const RLOCATION_PREFIXES_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RLOCATION_PREFIXES: [u8; RLOCATION_PREFIXES_SIZE] = {
+    let magic = b"@@RUNFILES_RLOCATION_PREFIXES@@";
+    let mut buf = [0u8; RLOCATION_PREFIXES_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Variables set for the target (--set-env), as NUL-terminated NAME=value strings
+// followed by an empty one. They replace inherited values.
+const SET_ENV_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SET_ENV: [u8; SET_ENV_SIZE] = {
+    let magic = b"@@RUNFILES_SET_ENV@@";
+    let mut buf = [0u8; SET_ENV_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// File-creation mask for the target (--umask), as NUL-terminated octal digits
+const UMASK_SIZE: usize = 32;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut UMASK: [u8; UMASK_SIZE] = {
+    let magic = b"@@RUNFILES_UMASK@@";
+    let mut buf = [0u8; UMASK_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Resource limits for the target (--rlimit), as NUL-terminated name=value strings
+// followed by an empty one. The value is a decimal number or "unlimited".
+const RLIMITS_SIZE: usize = 512;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RLIMITS: [u8; RLIMITS_SIZE] = {
+    let magic = b"@@RUNFILES_RLIMITS@@";
+    let mut buf = [0u8; RLIMITS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Canonical name of the repository the stub belongs to (--source-repo), as a
+// NUL-terminated string. Without FLAG_SOURCE_REPO it is the main repository ("").
+const SOURCE_REPO_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SOURCE_REPO: [u8; SOURCE_REPO_SIZE] = {
+    let magic = b"@@RUNFILES_SOURCE_REPO@@";
+    let mut buf = [0u8; SOURCE_REPO_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Check whether an rlocation path starts with one of a NUL-separated list of prefixes
+fn rlocation_allowed(path: &[u8], prefixes: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < prefixes.len() && prefixes[pos] != 0 {
+        let len = strlen(&prefixes[pos..]);
+        if str_starts_with(path, &prefixes[pos..pos + len]) {
+            return true;
         }
+        pos += len + 1;
+    }
+    false
+}
 
-        // Parse export_runfiles_env flag (defaults to true)
-        let export_str = &EXPORT_RUNFILES_ENV;
-        let export_len = strlen(export_str);
-        let export_runfiles_env = if !is_template_placeholder(export_str) && export_len > 0 {
-            // Parse as "1" (true) or "0" (false)
-            export_str[0] != b'0'
+// Check whether a NAME=value entry names a variable in a NUL-separated allowlist
+fn env_name_allowed(entry: &[u8], allowlist: &[u8]) -> bool {
+    let name = match find_byte(entry, b'=') {
+        Some(pos) => &entry[..pos],
+        None => entry,
+    };
+    let mut pos = 0;
+    while pos < allowlist.len() && allowlist[pos] != 0 {
+        let len = strlen(&allowlist[pos..]);
+        if str_eq(&allowlist[pos..pos + len], name) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
+// Check whether a NAME=value entry names a variable in a NUL-separated list of
+// NAME=value entries
+fn env_name_set(entry: &[u8], vars: &[u8]) -> bool {
+    let name = match find_byte(entry, b'=') {
+        Some(pos) => &entry[..pos],
+        None => entry,
+    };
+    let mut pos = 0;
+    while pos < vars.len() && vars[pos] != 0 {
+        let len = strlen(&vars[pos..]);
+        let var = &vars[pos..pos + len];
+        if var.get(name.len()) == Some(&b'=') && str_starts_with(var, name) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
+// Resource names accepted by --rlimit and their RLIMIT_* numbers
+const RLIMIT_RESOURCES: [(&[u8], i32); 9] = [
+    (b"cpu", 0),
+    (b"fsize", 1),
+    (b"data", 2),
+    (b"stack", 3),
+    (b"core", 4),
+    (b"as", 5),
+    (b"memlock", 6),
+    (b"nproc", 7),
+    (b"nofile", 8),
+];
+const RLIM_INFINITY: u64 = (1 << 63) - 1;
+
+// Apply the embedded umask (--umask) and resource limits (--rlimit) to the stub
+// itself; the target inherits them across execve (and fork in spawn mode)
+fn apply_process_limits(flags: u32) {
+    unsafe {
+        if flags & FLAG_UMASK != 0 {
+            let digits = &*core::ptr::addr_of!(UMASK);
+            let mut mask = 0u32;
+            for &c in &digits[..strlen(digits)] {
+                mask = mask * 8 + (c - b'0') as u32;
+            }
+            umask(mask as u16);
+        }
+        if flags & FLAG_RLIMIT == 0 {
+            return;
+        }
+        let limits = &*core::ptr::addr_of!(RLIMITS);
+        let mut pos = 0;
+        while pos < limits.len() && limits[pos] != 0 {
+            let len = strlen(&limits[pos..]);
+            let entry = &limits[pos..pos + len];
+            pos += len + 1;
+
+            let (name, value) = match find_byte(entry, b'=') {
+                Some(eq) => (&entry[..eq], &entry[eq + 1..]),
+                None => (entry, &entry[len..]),
+            };
+            let resource = RLIMIT_RESOURCES.iter().find(|(known, _)| str_eq(known, name)).map(|&(_, number)| number);
+            let limit = if str_eq(value, b"unlimited") {
+                Some(RLIM_INFINITY)
+            } else {
+                parse_decimal(value)
+            };
+            let (Some(resource), Some(limit)) = (resource, limit) else {
+                eprint(b"ERROR: Invalid embedded resource limit: ");
+                eprint(entry);
+                eprint(b"\n");
+                exit(1);
+            };
+            let ret = setrlimit(resource, &Rlimit { current: limit, max: limit });
+            if ret != 0 {
+                eprint(b"ERROR: Failed to set resource limit ");
+                eprint(entry);
+                eprint(b"\n");
+                exit(1);
+            }
+        }
+    }
+}
+
+fn parse_decimal(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n = 0u64;
+    for &c in digits {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((c - b'0') as u64)?;
+    }
+    Some(n)
+}
+
+// Check whether a relative path has a ".." component
+fn has_parent_component(path: &[u8]) -> bool {
+    path.split(|&b| b == b'/').any(|component| component == b"..")
+}
+
+// Get the length of a null-terminated string
+fn strlen(s: &[u8]) -> usize {
+    let mut len = 0;
+    while len < s.len() && s[len] != 0 {
+        len += 1;
+    }
+    len
+}
+
+// Check if placeholder is still in template state
+fn is_template_placeholder(placeholder: &[u8]) -> bool {
+    if placeholder.len() < 17 {
+        return false;
+    }
+    str_starts_with(placeholder, b"@@RUNFILES_")
+}
+
+// Percent-decodes `input` (e.g. "%20" -> ' ') into `out`, returns the decoded length.
+// Malformed escapes are copied verbatim.
+fn percent_decode(input: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    fn hex_value(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut i = 0;
+    let mut len = 0;
+    while i < input.len() && len < MAX_PATH_LEN {
+        let escaped = if input[i] == b'%' && i + 2 < input.len() {
+            match (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                out[len] = byte;
+                i += 3;
+            }
+            None => {
+                out[len] = input[i];
+                i += 1;
+            }
+        }
+        len += 1;
+    }
+    len
+}
+
+// Token in embedded arguments that expands to the runfiles directory
+const RUNFILES_ROOT_TOKEN: &[u8] = b"{RUNFILES_ROOT}";
+
+// Copy `arg` to `out` with each RUNFILES_ROOT_TOKEN replaced by `root`.
+// Returns the length, or None if the result (plus a NUL) doesn't fit.
+fn expand_runfiles_root(arg: &[u8], root: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut len = 0;
+    while pos < arg.len() {
+        let piece = if str_starts_with(&arg[pos..], RUNFILES_ROOT_TOKEN) {
+            pos += RUNFILES_ROOT_TOKEN.len();
+            root
         } else {
-            true // Default to true
+            pos += 1;
+            &arg[pos - 1..pos]
         };
+        if len + piece.len() >= out.len() {
+            return None;
+        }
+        out[len..len + piece.len()].copy_from_slice(piece);
+        len += piece.len();
+    }
+    out[len] = 0;
+    Some(len)
+}
+
+// Appends the effective configuration and argv to the file named by
+// RUNFILES_STUB_DEBUG_FILE, for debugging stubs that run without a terminal
+fn write_debug_file(metadata: &StubMetadata, runfiles: Option<&Runfiles>, argv: &[*const u8]) {
+    let mut path = [0u8; MAX_PATH_LEN + 1];
+    let len = match get_env_var(b"RUNFILES_STUB_DEBUG_FILE", &mut path[..MAX_PATH_LEN]) {
+        Some(len) if len > 0 => len,
+        _ => return,
+    };
+    path[len] = 0;
+
+    let fd = unsafe { open(path.as_ptr(), O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC, 0o644) };
+    if fd < 0 {
+        return;
+    }
+
+    let mut number = [0u8; 20];
+    let mut write_number = |fd, n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(fd, &number[..len]);
+    };
+
+    write_all(fd, b"runfiles-stub: argc=");
+    write_number(fd, metadata.argc);
+    write_all(fd, b" transform_flags=");
+    write_number(fd, metadata.transform_flags);
+    write_all(fd, b" flags=");
+    write_number(fd, metadata.flags);
+    write_all(fd, match runfiles.map(|rf| &rf.mode) {
+        Some(RunfilesMode::ManifestBased(_)) => b" runfiles=manifest\n" as &[u8],
+        Some(RunfilesMode::DirectoryBased(..)) => b" runfiles=directory\n",
+        None => b" runfiles=none\n",
+    });
+
+    for (i, &arg) in argv.iter().enumerate() {
+        write_all(fd, b"argv[");
+        write_number(fd, i as u32);
+        write_all(fd, b"]=");
+        let mut len = 0;
+        while unsafe { *arg.add(len) } != 0 {
+            len += 1;
+        }
+        write_all(fd, unsafe { core::slice::from_raw_parts(arg, len) });
+        write_all(fd, b"\n");
+    }
+
+    unsafe { close(fd) };
+}
+
+// Reserved first runtime argument: the stub prints its version and embedded
+// configuration instead of running the target, without needing runfiles
+const VERSION_SENTINEL: &[u8] = b"--runfiles-stub-version";
+
+fn print_version(metadata: &StubMetadata) {
+    let mut number = [0u8; 20];
+    let mut write_number = |n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(STDOUT, &number[..len]);
+    };
+    write_all(STDOUT, b"runfiles-stub ");
+    write_all(STDOUT, env!("CARGO_PKG_VERSION").as_bytes());
+    write_all(STDOUT, b"\nmetadata_version=");
+    write_number(metadata.version);
+    write_all(STDOUT, b" arg_size=");
+    write_number(metadata.arg_size);
+    write_all(STDOUT, b" argc=");
+    write_number(metadata.argc);
+    write_all(STDOUT, b" transform_flags=");
+    write_number(metadata.transform_flags);
+    write_all(STDOUT, b" flags=");
+    write_number(metadata.flags);
+    write_all(STDOUT, b"\n");
+}
+
+#[no_mangle]
+pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
+    unsafe {
+        // Check if the metadata is still a placeholder
+        let metadata = &METADATA;
+        if is_template_placeholder(&metadata.magic) {
+            eprint(b"ERROR: This is a template stub runner.\n");
+            eprint(b"You must finalize it by replacing the placeholders before use.\n");
+            eprint(b"The metadata block has not been replaced.\n");
+            exit(1);
+        }
+
+        if !str_starts_with(&metadata.magic, METADATA_MAGIC)
+            || metadata.version != METADATA_VERSION
+            || metadata.arg_size != ARG_SIZE as u32
+        {
+            eprint(b"ERROR: Unsupported stub metadata (was it finalized for another template version?)\n");
+            exit(1);
+        }
+
+        let argc = metadata.argc as usize;
+        if argc == 0 || argc > 10 {
+            eprint(b"ERROR: Invalid argc (must be 1-10)\n");
+            exit(1);
+        }
+
+        if runtime_argc > 1 {
+            let arg = *runtime_argv.add(1);
+            if (0..=VERSION_SENTINEL.len()).all(|i| *arg.add(i) == VERSION_SENTINEL.get(i).copied().unwrap_or(0)) {
+                print_version(metadata);
+                exit(0);
+            }
+        }
+
+        // Bitmask of which args to transform
+        let transform_flags = metadata.transform_flags;
+
+        // Transformed `--flag=value` arguments of which only the value is resolved
+        let value_flags = metadata.value_flags;
+
+        let export_runfiles_env = metadata.flags & FLAG_EXPORT_ENV != 0;
+
+        // Runfiles archive appended to this executable
+        let self_extract = metadata.flags & FLAG_SELF_EXTRACT != 0;
+
+        // Relative manifest values are followed once
+        let follow_indirection = metadata.flags & FLAG_INDIRECTION != 0;
+
+        // Embedded rlocation paths are percent-encoded
+        let percent_decode_keys = metadata.flags & FLAG_PERCENT_DECODE != 0;
+
+        // Run inside the sandbox root with a scrubbed environment
+        let sandbox = metadata.flags & FLAG_SANDBOX != 0;
+        let sandbox_root = &*core::ptr::addr_of!(SANDBOX_ROOT);
+
+        // Set the variables embedded with --set-env
+        let set_env = metadata.flags & FLAG_SET_ENV != 0;
+
+        // Run EXEC_PATH instead of argv[0]
+        let exec = metadata.flags & FLAG_EXEC != 0;
+        let exec_buf = &*core::ptr::addr_of!(EXEC_PATH);
+
+        // Untransformed arguments may contain RUNFILES_ROOT_TOKEN
+        let expand_root = metadata.flags & FLAG_EXPAND_ROOT != 0;
+
+        // Transformed arguments outside RLOCATION_PREFIXES are not resolved
+        let restrict_prefixes = metadata.flags & FLAG_RLOCATION_PREFIXES != 0;
+
+        // ... and are an error instead of being passed through
+        let strict_prefixes = metadata.flags & FLAG_STRICT_PREFIXES != 0;
+
+        // Run the target in the CHDIR directory
+        let change_dir = metadata.flags & FLAG_CHDIR != 0;
+        let chdir_buf = &*core::ptr::addr_of!(CHDIR);
+        let chdir_key = &chdir_buf[..strlen(chdir_buf)];
 
         // Check if any arguments need transformation
         let argc_mask = if argc >= 32 {
@@ -684,7 +2122,13 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
             (1u32 << argc) - 1
         };
         let needs_transform = (transform_flags & argc_mask) != 0;
-        let needs_runfiles = needs_transform || export_runfiles_env;
+        let args_need_runfiles = needs_transform || expand_root
+            || exec && !is_absolute_path(&exec_buf[..strlen(exec_buf)])
+            || change_dir && !is_absolute_path(chdir_key);
+        let needs_runfiles = args_need_runfiles || export_runfiles_env;
+
+        // Exporting alone can do without runfiles when inherited variables are kept
+        let keep_inherited_env = metadata.flags & FLAG_KEEP_ENV != 0;
 
         // Get executable path from runtime argv[0] for runfiles fallback
         let executable_path = if runtime_argc > 0 {
@@ -699,13 +2143,46 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
             None
         };
 
+        // A stub found through PATH gets a bare name as argv[0], which says nothing
+        // about where <executable>.runfiles is; use the stub's own path instead
+        let mut self_path = [0u8; MAX_PATH_LEN];
+        let mut self_path_size = self_path.len() as u32;
+        let executable_path = match executable_path {
+            Some(path) if find_byte(path, b'/').is_none() => {
+                if _NSGetExecutablePath(self_path.as_mut_ptr(), &mut self_path_size) == 0 {
+                    Some(&self_path[..strlen(&self_path)])
+                } else {
+                    Some(path)
+                }
+            }
+            other => other,
+        };
+
         // Initialize runfiles only if needed
-        let runfiles = if needs_runfiles {
+        // A sandbox root or bundled runfiles tree always takes precedence over the environment
+        let runfiles = if sandbox {
+            let root = sandbox_root;
+            let len = strlen(root);
+            let mut dir = [0u8; MAX_PATH_LEN];
+            dir[..len].copy_from_slice(&root[..len]);
+            Some(Runfiles::from_dir(dir, len))
+        } else if self_extract {
+            let mut dir = [0u8; MAX_PATH_LEN];
+            if let Some(len) = extract_self(&mut dir) {
+                Some(Runfiles::from_dir(dir, len))
+            } else {
+                eprint(b"ERROR: Failed to extract bundled runfiles\n");
+                eprint(b"Check that $TMPDIR (or /tmp) is writable\n");
+                exit(1);
+            }
+        } else if needs_runfiles {
             if let Some(rf) = Runfiles::create(executable_path) {
                 Some(rf)
+            } else if keep_inherited_env && !args_need_runfiles {
+                None
             } else {
-                print(b"ERROR: Failed to initialize runfiles\n");
-                print(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles/ directory exists\n");
+                eprint(b"ERROR: Failed to initialize runfiles\n");
+                eprint(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles/ directory exists\n");
                 exit(1);
             }
         } else {
@@ -738,10 +2215,10 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
             let arg_len = strlen(arg_data);
 
             if arg_len == 0 {
-                print(b"ERROR: Argument ");
+                eprint(b"ERROR: Argument ");
                 let digit = [b'0' + i as u8];
-                print(&digit);
-                print(b" is empty\n");
+                eprint(&digit);
+                eprint(b" is empty\n");
                 exit(1);
             }
 
@@ -750,11 +2227,57 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
             // Check if this argument should be transformed
             let should_transform = (transform_flags & (1 << i)) != 0;
 
-            if should_transform {
+            // Only the part after the first '=' of a `--flag=value` argument is an rlocation path
+            let value_start = if should_transform && value_flags & (1 << i) != 0 {
+                find_byte(arg_slice, b'=').map_or(0, |eq| eq + 1)
+            } else {
+                0
+            };
+            let value = &arg_slice[value_start..];
+
+            // The rlocation key, percent-decoded if requested
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if should_transform && percent_decode_keys {
+                let len = percent_decode(value, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                value
+            };
+
+            // Inside a sandbox, transformed paths must stay below the root
+            if should_transform && sandbox && (is_absolute_path(key) || has_parent_component(key)) {
+                eprint(b"ERROR: Argument ");
+                let digit = [b'0' + i as u8];
+                eprint(&digit);
+                eprint(b" escapes the sandbox root\n");
+                exit(1);
+            }
+
+            // Only keys below an allowed prefix are looked up
+            let allowed = !restrict_prefixes || rlocation_allowed(key, &RLOCATION_PREFIXES);
+            if should_transform && !allowed && strict_prefixes {
+                eprint(b"ERROR: Argument ");
+                let digit = [b'0' + i as u8];
+                eprint(&digit);
+                eprint(b" is outside the allowed rlocation prefixes\n");
+                exit(1);
+            }
+
+            if should_transform && allowed {
                 // Try to resolve through runfiles
                 if let Some(ref rf) = runfiles {
-                    if let Some(resolved) = rf.rlocation(arg_slice) {
-                        resolved_paths[i] = resolved;
+                    if let Some(resolved) = rf.rlocation(key, follow_indirection) {
+                        // A `--flag=` part is kept in front of the resolved value
+                        let len = strlen(&resolved);
+                        if value_start + len >= MAX_PATH_LEN {
+                            eprint(b"ERROR: Argument ");
+                            let digit = [b'0' + i as u8];
+                            eprint(&digit);
+                            eprint(b" is too long after resolving its value\n");
+                            exit(1);
+                        }
+                        resolved_paths[i][..value_start].copy_from_slice(&arg_slice[..value_start]);
+                        resolved_paths[i][value_start..value_start + len].copy_from_slice(&resolved[..len]);
                     } else {
                         // If not found in runfiles, use the path as-is
                         let copy_len = arg_len.min(MAX_PATH_LEN);
@@ -765,6 +2288,26 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
                     let copy_len = arg_len.min(MAX_PATH_LEN);
                     resolved_paths[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
                 }
+            } else if should_transform {
+                // Outside the allowed prefixes: use the path as-is
+                let copy_len = arg_len.min(MAX_PATH_LEN);
+                resolved_paths[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
+            } else if expand_root {
+                // Substitute the runfiles directory
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((dir, len)) => &dir[..*len],
+                    None => {
+                        eprint(b"ERROR: {RUNFILES_ROOT} requires a runfiles directory (not just a manifest)\n");
+                        exit(1);
+                    }
+                };
+                if expand_runfiles_root(arg_slice, root, &mut resolved_paths[i]).is_none() {
+                    eprint(b"ERROR: Argument ");
+                    let digit = [b'0' + i as u8];
+                    eprint(&digit);
+                    eprint(b" is too long after expanding {RUNFILES_ROOT}\n");
+                    exit(1);
+                }
             } else {
                 // Use path as-is without transformation
                 let copy_len = arg_len.min(MAX_PATH_LEN);
@@ -779,7 +2322,7 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
         if runtime_argc > 1 {
             for i in 1..runtime_argc as usize {
                 if total_argc >= 128 {
-                    print(b"ERROR: Too many total arguments (embedded + runtime > 128)\n");
+                    eprint(b"ERROR: Too many total arguments (embedded + runtime > 128)\n");
                     exit(1);
                 }
 
@@ -802,32 +2345,129 @@ pub extern "C" fn main(runtime_argc: i32, runtime_argv: *const *const u8) -> ! {
             }
         }
 
+        // Default runtime arguments apply only when the caller passed none
+        if runtime_argc <= 1 && metadata.flags & FLAG_DEFAULT_ARGS != 0 {
+            let defaults = &DEFAULT_ARGS;
+            let mut pos = 0;
+            while pos < defaults.len() && defaults[pos] != 0 {
+                if total_argc >= 128 {
+                    eprint(b"ERROR: Too many total arguments (embedded + default > 128)\n");
+                    exit(1);
+                }
+
+                let arg_len = strlen(&defaults[pos..]);
+                let copy_len = arg_len.min(MAX_PATH_LEN);
+                resolved_paths[total_argc][..copy_len].copy_from_slice(&defaults[pos..pos + copy_len]);
+
+                resolved_ptrs[total_argc] = resolved_paths[total_argc].as_ptr();
+                total_argc += 1;
+                pos += arg_len + 1;
+            }
+        }
+
+        // Runtime arguments go before the embedded argument at RUNTIME_POS instead of last
+        if metadata.flags & FLAG_RUNTIME_POS != 0 {
+            let digits = &*core::ptr::addr_of!(RUNTIME_POS);
+            let mut pos = 0;
+            for &c in &digits[..strlen(digits)] {
+                pos = pos * 10 + (c - b'0') as usize;
+            }
+            if (1..argc).contains(&pos) {
+                resolved_ptrs[pos..total_argc].rotate_right(total_argc - argc);
+            }
+        }
+
         // NULL-terminate the argv array
         resolved_ptrs[total_argc] = core::ptr::null();
 
-        // Get the executable path (first argument)
-        let executable = resolved_ptrs[0];
+        // The target sees the --argv0 name, but the first argument is still what runs
+        let first_arg = resolved_ptrs[0];
+        if metadata.flags & FLAG_ARGV0 != 0 {
+            resolved_ptrs[0] = core::ptr::addr_of!(ARGV0) as *const u8;
+        }
+
+        write_debug_file(metadata, runfiles.as_ref(), &resolved_ptrs[..total_argc]);
+
+        // Get the executable path: EXEC_PATH if set, otherwise the first argument
+        let mut exec_path = [0u8; MAX_PATH_LEN + 1];
+        let executable = if exec {
+            let exec_len = strlen(exec_buf);
+            let exec_slice = &exec_buf[..exec_len];
 
-        // Build environment with runfiles variables if export is enabled
-        let envp = if export_runfiles_env {
-            build_runfiles_environ(runfiles.as_ref())
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if percent_decode_keys {
+                let len = percent_decode(exec_slice, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                exec_slice
+            };
+
+            if sandbox && (is_absolute_path(key) || has_parent_component(key)) {
+                eprint(b"ERROR: Exec path escapes the sandbox root\n");
+                exit(1);
+            }
+
+            match runfiles.as_ref().and_then(|rf| rf.rlocation(key, follow_indirection)) {
+                Some(resolved) => exec_path[..MAX_PATH_LEN].copy_from_slice(&resolved),
+                None => exec_path[..exec_len].copy_from_slice(exec_slice),
+            }
+            exec_path.as_ptr()
+        } else {
+            first_arg
+        };
+
+        // Build environment with runfiles variables if export is enabled, and --set-env variables
+        let envp = if sandbox {
+            build_runfiles_environ(runfiles.as_ref(), Some(&*core::ptr::addr_of!(ENV_ALLOWLIST)))
+        } else if export_runfiles_env || set_env {
+            build_runfiles_environ(runfiles.as_ref().filter(|_| export_runfiles_env), None)
         } else {
             environ
         };
 
+        apply_process_limits(metadata.flags);
+
+        // Runfiles manifests only list files, so a directory missing from one is
+        // looked up below the runfiles directory
+        if change_dir {
+            let key = chdir_key;
+            let mut dir = [0u8; MAX_PATH_LEN + 1];
+            if is_absolute_path(key) {
+                dir[..key.len()].copy_from_slice(key);
+            } else if let Some(resolved) = runfiles.as_ref().and_then(|rf| rf.rlocation(key, follow_indirection)) {
+                dir[..MAX_PATH_LEN].copy_from_slice(&resolved);
+            } else {
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((root, len)) if len + 1 + key.len() < MAX_PATH_LEN => &root[..*len],
+                    _ => {
+                        eprint(b"ERROR: Working directory not found in the runfiles: ");
+                        eprint(key);
+                        eprint(b"\n");
+                        exit(1);
+                    }
+                };
+                dir[..root.len()].copy_from_slice(root);
+                dir[root.len()] = b'/';
+                dir[root.len() + 1..root.len() + 1 + key.len()].copy_from_slice(key);
+            }
+            if chdir(dir.as_ptr()) != 0 {
+                eprint(b"ERROR: Failed to change into the working directory ");
+                eprint(&dir[..strlen(&dir)]);
+                eprint(b"\n");
+                exit(1);
+            }
+        }
+
+        // The finalizer leaves room for the root's NUL terminator
+        if sandbox && chdir(sandbox_root.as_ptr()) != 0 {
+            eprint(b"ERROR: Failed to change into the sandbox root\n");
+            exit(1);
+        }
+
         // Execute the target program
-        let ret = execve(executable, resolved_ptrs.as_ptr(), envp);
+        execve(executable, resolved_ptrs.as_ptr(), envp);
 
         // If execve returns, it failed
-        print(b"ERROR: execve failed with code ");
-        let digit = if ret < 0 {
-            print(b"-");
-            (-ret) as u8 + b'0'
-        } else {
-            ret as u8 + b'0'
-        };
-        print(&[digit]);
-        print(b"\n");
-        exit(1);
+        exec_failed(executable, *__error());
     }
 }
@@ -15,3 +15,26 @@ mod platform;
 #[cfg(target_os = "windows")]
 #[path = "windows.rs"]
 mod platform;
+
+// Size of each embedded argument placeholder, fixed at build time through the
+// RUNFILES_ARG_SIZE environment variable (default 256). Templates advertise it in
+// their metadata block, so the finalizer always uses the template's size.
+const ARG_SIZE: usize = match option_env!("RUNFILES_ARG_SIZE") {
+    Some(value) => parse_arg_size(value.as_bytes()),
+    None => 256,
+};
+
+const fn parse_arg_size(value: &[u8]) -> usize {
+    assert!(!value.is_empty(), "RUNFILES_ARG_SIZE must be a decimal number");
+    let mut size = 0;
+    let mut i = 0;
+    while i < value.len() {
+        assert!(value[i].is_ascii_digit(), "RUNFILES_ARG_SIZE must be a decimal number");
+        size = size * 10 + (value[i] - b'0') as usize;
+        assert!(size <= 65536, "RUNFILES_ARG_SIZE must be at most 65536");
+        i += 1;
+    }
+    // One byte is always reserved for the terminating NUL
+    assert!(size >= 2, "RUNFILES_ARG_SIZE must be at least 2");
+    size
+}
@@ -1,5 +1,5 @@
 // Windows-specific implementation using Windows API
-// Uses kernel32.dll functions
+// Uses kernel32.dll functions, and advapi32.dll to check who owns extracted runfiles
 
 use core::panic::PanicInfo;
 
@@ -13,16 +13,34 @@ type DWORD = u32;
 type BOOL = i32;
 type HANDLE = *mut core::ffi::c_void;
 type LPVOID = *mut core::ffi::c_void;
-type LPCSTR = *const u8;
-type LPSTR = *mut u8;
+type LPCWSTR = *const u16;
+type LPWSTR = *mut u16;
 
 const INVALID_HANDLE_VALUE: HANDLE = -1isize as HANDLE;
 const STD_OUTPUT_HANDLE: DWORD = 0xFFFFFFF5u32;
+const STD_ERROR_HANDLE: DWORD = 0xFFFFFFF4u32;
 const GENERIC_READ: DWORD = 0x80000000;
+const GENERIC_WRITE: DWORD = 0x40000000;
+const FILE_SHARE_READ: DWORD = 0x00000001;
+const FILE_SHARE_WRITE: DWORD = 0x00000002;
+const FILE_APPEND_DATA: DWORD = 0x00000004;
+const CREATE_NEW: DWORD = 1;
 const OPEN_EXISTING: DWORD = 3;
+const OPEN_ALWAYS: DWORD = 4;
+const FILE_BEGIN: DWORD = 0;
+const FILE_CURRENT: DWORD = 1;
+const FILE_END: DWORD = 2;
 const FILE_ATTRIBUTE_NORMAL: DWORD = 0x80;
 const INFINITE: DWORD = 0xFFFFFFFF;
+const ERROR_FILE_NOT_FOUND: DWORD = 2;
+const ERROR_PATH_NOT_FOUND: DWORD = 3;
+const ERROR_ACCESS_DENIED: DWORD = 5;
+const ERROR_ALREADY_EXISTS: DWORD = 183;
+const ERROR_BAD_EXE_FORMAT: DWORD = 193;
+const ERROR_EXE_MACHINE_TYPE_MISMATCH: DWORD = 216;
 const CREATE_UNICODE_ENVIRONMENT: DWORD = 0x00000400;
+const CTRL_C_EVENT: DWORD = 0;
+const CTRL_BREAK_EVENT: DWORD = 1;
 
 // STARTUPINFOW structure (wide char version for CreateProcessW)
 #[repr(C)]
@@ -47,6 +65,14 @@ struct STARTUPINFOW {
     hStdError: HANDLE,
 }
 
+// SECURITY_ATTRIBUTES structure
+#[repr(C)]
+struct SECURITY_ATTRIBUTES {
+    nLength: DWORD,
+    lpSecurityDescriptor: LPVOID,
+    bInheritHandle: BOOL,
+}
+
 // PROCESS_INFORMATION structure
 #[repr(C)]
 struct PROCESS_INFORMATION {
@@ -67,8 +93,8 @@ extern "system" {
         lpNumberOfBytesWritten: *mut DWORD,
         lpOverlapped: LPVOID,
     ) -> BOOL;
-    fn CreateFileA(
-        lpFileName: LPCSTR,
+    fn CreateFileW(
+        lpFileName: LPCWSTR,
         dwDesiredAccess: DWORD,
         dwShareMode: DWORD,
         lpSecurityAttributes: LPVOID,
@@ -84,7 +110,8 @@ extern "system" {
         lpOverlapped: LPVOID,
     ) -> BOOL;
     fn CloseHandle(hObject: HANDLE) -> BOOL;
-    fn GetEnvironmentVariableA(lpName: LPCSTR, lpBuffer: LPSTR, nSize: DWORD) -> DWORD;
+    fn GetEnvironmentVariableW(lpName: LPCWSTR, lpBuffer: LPWSTR, nSize: DWORD) -> DWORD;
+    fn SetEnvironmentVariableW(lpName: LPCWSTR, lpValue: LPCWSTR) -> BOOL;
     fn CreateProcessW(
         lpApplicationName: *const u16,
         lpCommandLine: *mut u16,
@@ -100,49 +127,107 @@ extern "system" {
     fn GetCommandLineW() -> *const u16;
     fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: DWORD) -> DWORD;
     fn GetExitCodeProcess(hProcess: HANDLE, lpExitCode: *mut DWORD) -> BOOL;
+    fn TerminateProcess(hProcess: HANDLE, uExitCode: u32) -> BOOL;
+    fn SetConsoleCtrlHandler(HandlerRoutine: Option<unsafe extern "system" fn(DWORD) -> BOOL>, Add: BOOL) -> BOOL;
     fn GetLastError() -> DWORD;
+    fn GetModuleFileNameW(hModule: HANDLE, lpFilename: LPWSTR, nSize: DWORD) -> DWORD;
+    fn GetTempPathW(nBufferLength: DWORD, lpBuffer: LPWSTR) -> DWORD;
+    fn GetCurrentProcessId() -> DWORD;
+    fn CreateDirectoryW(lpPathName: LPCWSTR, lpSecurityAttributes: LPVOID) -> BOOL;
+    fn MoveFileW(lpExistingFileName: LPCWSTR, lpNewFileName: LPCWSTR) -> BOOL;
+    fn GetFileAttributesW(lpFileName: LPCWSTR) -> DWORD;
+    fn DeleteFileW(lpFileName: LPCWSTR) -> BOOL;
+    fn RemoveDirectoryW(lpPathName: LPCWSTR) -> BOOL;
+    fn GetCurrentProcess() -> HANDLE;
+    fn LocalFree(hMem: LPVOID) -> LPVOID;
+    fn GetFileSizeEx(hFile: HANDLE, lpFileSize: *mut i64) -> BOOL;
+    fn SetFilePointerEx(
+        hFile: HANDLE,
+        liDistanceToMove: i64,
+        lpNewFilePointer: *mut i64,
+        dwMoveMethod: DWORD,
+    ) -> BOOL;
+}
+
+// External Windows API functions (advapi32.dll)
+#[link(name = "advapi32")]
+extern "system" {
+    fn OpenProcessToken(ProcessHandle: HANDLE, DesiredAccess: DWORD, TokenHandle: *mut HANDLE) -> BOOL;
+    fn GetTokenInformation(
+        TokenHandle: HANDLE,
+        TokenInformationClass: DWORD,
+        TokenInformation: LPVOID,
+        TokenInformationLength: DWORD,
+        ReturnLength: *mut DWORD,
+    ) -> BOOL;
+    fn GetNamedSecurityInfoW(
+        pObjectName: LPCWSTR,
+        ObjectType: DWORD,
+        SecurityInfo: DWORD,
+        ppsidOwner: *mut *const u8,
+        ppsidGroup: *mut *const u8,
+        ppDacl: *mut *const u8,
+        ppSacl: *mut *const u8,
+        ppSecurityDescriptor: *mut LPVOID,
+    ) -> DWORD;
+    fn GetAce(pAcl: *const u8, dwAceIndex: DWORD, pAce: *mut *const u8) -> BOOL;
+    fn ConvertStringSecurityDescriptorToSecurityDescriptorW(
+        StringSecurityDescriptor: LPCWSTR,
+        StringSDRevision: DWORD,
+        SecurityDescriptor: *mut LPVOID,
+        SecurityDescriptorSize: *mut DWORD,
+    ) -> BOOL;
 }
 
 // We don't use CommandLineToArgvW to avoid shell32.dll dependency
 // Instead we implement custom command-line parsing following Windows rules
 
+// Unescaped runtime arguments, back to back. An argument never gets longer when
+// unescaped, and a command line holds at most 32767 characters.
+static mut RUNTIME_ARGS: [u16; 32768] = [0; 32768];
+
+const SPACE: u16 = b' ' as u16;
+const TAB: u16 = b'\t' as u16;
+const QUOTE: u16 = b'"' as u16;
+const BACKSLASH: u16 = b'\\' as u16;
+
 // Parse Windows command line into arguments
 // Returns number of arguments parsed (excluding argv[0])
-// Stores argument pointers in output array
+// Stores argument pointers (into RUNTIME_ARGS) in output array
+//
+// Follows the CommandLineToArgvW / MSVC runtime rules: quotes group whitespace
+// and are removed, 2n backslashes before a quote become n and the quote toggles
+// quoting, 2n+1 backslashes become n and a literal quote, and backslashes not
+// followed by a quote are literal. `""` inside quotes is a literal quote.
 fn parse_command_line(
     cmdline: *const u16,
     argv_out: &mut [*const u16; 128],
     argv_len_out: &mut [usize; 128],
 ) -> usize {
     unsafe {
+        let out = &mut *core::ptr::addr_of_mut!(RUNTIME_ARGS);
+        let mut out_len = 0usize;
         let mut pos = 0usize;
         let mut argc = 0usize;
 
         // Skip leading whitespace
-        while *cmdline.add(pos) != 0 && (*cmdline.add(pos) == b' ' as u16 || *cmdline.add(pos) == b'\t' as u16) {
+        while *cmdline.add(pos) == SPACE || *cmdline.add(pos) == TAB {
             pos += 1;
         }
 
-        // Skip argv[0] (executable path)
-        let quoted = *cmdline.add(pos) == b'"' as u16;
-        if quoted {
-            pos += 1; // Skip opening quote
-            while *cmdline.add(pos) != 0 && *cmdline.add(pos) != b'"' as u16 {
-                pos += 1;
-            }
-            if *cmdline.add(pos) == b'"' as u16 {
-                pos += 1; // Skip closing quote
-            }
-        } else {
-            while *cmdline.add(pos) != 0 && *cmdline.add(pos) != b' ' as u16 && *cmdline.add(pos) != b'\t' as u16 {
-                pos += 1;
+        // Skip argv[0] (executable path): quotes only group, backslashes are literal
+        let mut in_quotes = false;
+        while *cmdline.add(pos) != 0 && (in_quotes || (*cmdline.add(pos) != SPACE && *cmdline.add(pos) != TAB)) {
+            if *cmdline.add(pos) == QUOTE {
+                in_quotes = !in_quotes;
             }
+            pos += 1;
         }
 
         // Parse remaining arguments
         while *cmdline.add(pos) != 0 && argc < 128 {
             // Skip whitespace
-            while *cmdline.add(pos) != 0 && (*cmdline.add(pos) == b' ' as u16 || *cmdline.add(pos) == b'\t' as u16) {
+            while *cmdline.add(pos) == SPACE || *cmdline.add(pos) == TAB {
                 pos += 1;
             }
 
@@ -151,31 +236,48 @@ fn parse_command_line(
             }
 
             // Start of argument
-            let arg_start = pos;
-            let in_quotes = *cmdline.add(pos) == b'"' as u16;
-
-            if in_quotes {
-                pos += 1; // Skip opening quote
-                // Find closing quote
-                while *cmdline.add(pos) != 0 && *cmdline.add(pos) != b'"' as u16 {
-                    pos += 1;
-                }
-                // Store argument (skip quotes in length calculation)
-                argv_out[argc] = cmdline.add(arg_start + 1);
-                argv_len_out[argc] = pos - arg_start - 1;
-
-                if *cmdline.add(pos) == b'"' as u16 {
-                    pos += 1; // Skip closing quote
+            let arg_start = out_len;
+            let mut in_quotes = false;
+            loop {
+                let c = *cmdline.add(pos);
+                if c == 0 || (!in_quotes && (c == SPACE || c == TAB)) {
+                    break;
                 }
-            } else {
-                // Unquoted argument - find whitespace
-                while *cmdline.add(pos) != 0 && *cmdline.add(pos) != b' ' as u16 && *cmdline.add(pos) != b'\t' as u16 {
+                if c == BACKSLASH {
+                    let mut backslashes = 0;
+                    while *cmdline.add(pos) == BACKSLASH {
+                        backslashes += 1;
+                        pos += 1;
+                    }
+                    // Only a following quote makes backslashes escapes
+                    let escaping = *cmdline.add(pos) == QUOTE;
+                    for _ in 0..if escaping { backslashes / 2 } else { backslashes } {
+                        out[out_len] = BACKSLASH;
+                        out_len += 1;
+                    }
+                    if escaping && backslashes % 2 == 1 {
+                        out[out_len] = QUOTE;
+                        out_len += 1;
+                        pos += 1;
+                    }
+                } else if c == QUOTE {
+                    if in_quotes && *cmdline.add(pos + 1) == QUOTE {
+                        out[out_len] = QUOTE;
+                        out_len += 1;
+                        pos += 2;
+                    } else {
+                        in_quotes = !in_quotes;
+                        pos += 1;
+                    }
+                } else {
+                    out[out_len] = c;
+                    out_len += 1;
                     pos += 1;
                 }
-                argv_out[argc] = cmdline.add(arg_start);
-                argv_len_out[argc] = pos - arg_start;
             }
 
+            argv_out[argc] = out.as_ptr().add(arg_start);
+            argv_len_out[argc] = out_len - arg_start;
             argc += 1;
         }
 
@@ -183,13 +285,53 @@ fn parse_command_line(
     }
 }
 
-// String utilities
-fn print(s: &[u8]) {
+// Append `arg` to a command line being built at `pos`, quoted and escaped so that
+// parse_command_line (and the target's runtime) recovers it unchanged. Returns the
+// new position; whatever doesn't fit is dropped.
+fn append_arg(cmdline: &mut [u16], mut pos: usize, arg: &[u16], always_quote: bool) -> usize {
+    let mut push = |c: u16| {
+        if pos < cmdline.len() {
+            cmdline[pos] = c;
+            pos += 1;
+        }
+    };
+    let needs_quotes = always_quote || arg.is_empty() || arg.iter().any(|&c| c == SPACE || c == TAB);
+
+    if needs_quotes {
+        push(QUOTE);
+    }
+    let mut backslashes = 0;
+    for &c in arg {
+        if c == BACKSLASH {
+            backslashes += 1;
+        } else {
+            // Backslashes before a literal quote are doubled, plus one for the quote
+            if c == QUOTE {
+                for _ in 0..=backslashes {
+                    push(BACKSLASH);
+                }
+            }
+            backslashes = 0;
+        }
+        push(c);
+    }
+    if needs_quotes {
+        // Trailing backslashes would otherwise escape the closing quote
+        for _ in 0..backslashes {
+            push(BACKSLASH);
+        }
+        push(QUOTE);
+    }
+    pos
+}
+
+// Diagnostics go to stderr so they never mix with the target's output
+fn eprint(s: &[u8]) {
     unsafe {
-        let stdout = GetStdHandle(STD_OUTPUT_HANDLE);
+        let stderr = GetStdHandle(STD_ERROR_HANDLE);
         let mut written: DWORD = 0;
         WriteFile(
-            stdout,
+            stderr,
             s.as_ptr(),
             s.len() as DWORD,
             &mut written,
@@ -198,12 +340,12 @@ fn print(s: &[u8]) {
     }
 }
 
-fn print_number(mut n: usize) {
+fn eprint_number(mut n: usize) {
     let mut buf = [0u8; 20]; // Enough for 64-bit numbers
     let mut i = 0;
 
     if n == 0 {
-        print(b"0");
+        eprint(b"0");
         return;
     }
 
@@ -216,10 +358,11 @@ fn print_number(mut n: usize) {
     // Print in reverse order
     while i > 0 {
         i -= 1;
-        print(&buf[i..i+1]);
+        eprint(&buf[i..i+1]);
     }
 }
 
+// String utilities
 fn str_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
@@ -232,6 +375,16 @@ fn str_eq(a: &[u8], b: &[u8]) -> bool {
     true
 }
 
+// Byte-wise ordering, the order manifest keys are sorted in
+fn str_cmp(a: &[u8], b: &[u8]) -> core::cmp::Ordering {
+    for i in 0..a.len().min(b.len()) {
+        if a[i] != b[i] {
+            return a[i].cmp(&b[i]);
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
 fn str_starts_with(haystack: &[u8], needle: &[u8]) -> bool {
     if haystack.len() < needle.len() {
         return false;
@@ -248,33 +401,96 @@ fn find_byte(haystack: &[u8], needle: u8) -> Option<usize> {
     None
 }
 
-// Environment variable reading
+// Environment variable values can be up to 32767 characters, far more than any
+// path buffer. Reading them whole tells a value that is too long from an unset one.
+static mut ENV_VALUE: [u16; 32767] = [0; 32767];
+
+// Environment variable reading. The value is stored as NUL-terminated UTF-8.
+// A value that doesn't fit in buf is an error, not a silently unset variable.
 fn get_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
-    unsafe {
-        // Ensure name is null-terminated
-        let mut name_with_null = [0u8; 256];
-        let name_len = name.len().min(255);
-        name_with_null[..name_len].copy_from_slice(&name[..name_len]);
-        name_with_null[name_len] = 0;
-
-        let size = GetEnvironmentVariableA(
-            name_with_null.as_ptr(),
-            buf.as_mut_ptr(),
-            buf.len() as DWORD,
-        );
+    // Ensure name is null-terminated
+    let mut name_wide = [0u16; 256];
+    let name_len = utf8_to_wide(name, &mut name_wide[..255]);
+    name_wide[name_len] = 0;
+
+    let value = unsafe { &mut *core::ptr::addr_of_mut!(ENV_VALUE) };
+    let size = unsafe {
+        GetEnvironmentVariableW(name_wide.as_ptr(), value.as_mut_ptr(), value.len() as DWORD)
+    } as usize;
+    if size == 0 || size >= value.len() {
+        return None;
+    }
 
-        if size > 0 && size < buf.len() as DWORD {
-            Some(size as usize)
-        } else {
-            None
+    let max_len = buf.len().checked_sub(1)?;
+    let Some(len) = wide_to_utf8(&value[..size], &mut buf[..max_len]) else {
+        eprint(b"ERROR: ");
+        eprint(name);
+        eprint(b" is too long (");
+        eprint_number(size);
+        eprint(b" characters, at most ");
+        eprint_number(max_len);
+        eprint(b" bytes supported)\r\n");
+        unsafe { ExitProcess(1) }
+    };
+    buf[len] = 0;
+    Some(len)
+}
+
+// Reads RUNFILES_MANIFEST_FILE or RUNFILES_DIR. With FLAG_EXPAND_ENV, `%VAR%`
+// references in the value are replaced (unset variables are kept as-is).
+fn get_runfiles_env_var(name: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let len = get_env_var(name, buf)?;
+    if unsafe { METADATA.flags } & FLAG_EXPAND_ENV == 0 || find_byte(&buf[..len], b'%').is_none() {
+        return Some(len);
+    }
+    let mut value = [0u8; MAX_PATH_LEN];
+    value.get_mut(..len)?.copy_from_slice(&buf[..len]);
+    let max_len = buf.len().checked_sub(1)?;
+    let expanded_len = expand_env_refs(&value[..len], &mut buf[..max_len])?;
+    buf[expanded_len] = 0;
+    Some(expanded_len)
+}
+
+// Name and length of the `%VAR%` reference at the start of s
+fn env_reference(s: &[u8]) -> Option<(&[u8], usize)> {
+    if s.first() != Some(&b'%') {
+        return None;
+    }
+    let end = 1 + find_byte(&s[1..], b'%')?;
+    if end == 1 {
+        return None;
+    }
+    Some((&s[1..end], end + 1))
+}
+
+// Copies value to out with environment variable references replaced by their
+// values. Returns None if the result doesn't fit.
+fn expand_env_refs(value: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut out_len = 0;
+    let mut i = 0;
+    while i < value.len() {
+        if let Some((name, reference_len)) = env_reference(&value[i..]) {
+            let mut var = [0u8; MAX_PATH_LEN];
+            if let Some(var_len) = get_env_var(name, &mut var) {
+                out.get_mut(out_len..out_len + var_len)?.copy_from_slice(&var[..var_len]);
+                out_len += var_len;
+                i += reference_len;
+                continue;
+            }
         }
+        *out.get_mut(out_len)? = value[i];
+        out_len += 1;
+        i += 1;
     }
+    Some(out_len)
 }
 
 // Manifest entry storage - use static buffers to avoid stack overflow
 // Windows has a default 1MB stack limit, so we store large data in .bss
 const MAX_ENTRIES: usize = 256;  // Reduced from 1024 to save memory
-const MAX_PATH_LEN: usize = 512; // Increased to support longer Windows paths
+// Well past MAX_PATH (260) for deep output trees, which the file APIs reach through
+// the \\?\ prefix (see wide_path), and at least a whole embedded argument
+const MAX_PATH_LEN: usize = if ARG_SIZE > 1024 { ARG_SIZE } else { 1024 };
 
 // Static storage for manifest data (in .bss segment, not stack)
 static mut MANIFEST_KEYS: [[u8; MAX_PATH_LEN]; MAX_ENTRIES] = [[0; MAX_PATH_LEN]; MAX_ENTRIES];
@@ -282,8 +498,10 @@ static mut MANIFEST_VALUES: [[u8; MAX_PATH_LEN]; MAX_ENTRIES] = [[0; MAX_PATH_LE
 static mut MANIFEST_KEY_LENS: [usize; MAX_ENTRIES] = [0; MAX_ENTRIES];
 static mut MANIFEST_VALUE_LENS: [usize; MAX_ENTRIES] = [0; MAX_ENTRIES];
 static mut MANIFEST_COUNT: usize = 0;
+// Entry indices ordered by key, for lookup's binary search
+static mut MANIFEST_ORDER: [u16; MAX_ENTRIES] = [0; MAX_ENTRIES];
 
-// Static storage for file buffer
+// Static storage for file buffer (manifests are read through it in chunks)
 static mut FILE_BUF: [u8; 65536] = [0; 65536];
 
 // Static storage for resolved paths
@@ -301,10 +519,11 @@ impl Manifest {
         }
     }
 
-    fn add_entry(key: &[u8], value: &[u8]) {
+    // Returns false if the manifest is full
+    fn add_entry(key: &[u8], value: &[u8]) -> bool {
         unsafe {
             if MANIFEST_COUNT >= MAX_ENTRIES {
-                return;
+                return false;
             }
 
             let idx = MANIFEST_COUNT;
@@ -317,36 +536,149 @@ impl Manifest {
             MANIFEST_VALUE_LENS[idx] = value_len;
 
             MANIFEST_COUNT += 1;
+            true
         }
     }
 
-    fn lookup(key: &[u8]) -> Option<&'static [u8]> {
+    fn key(index: u16) -> &'static [u8] {
+        let index = index as usize;
+        unsafe { &MANIFEST_KEYS[index][..MANIFEST_KEY_LENS[index]] }
+    }
+
+    // Order the entries by key once they are all added. Bazel writes manifests
+    // sorted, so the insertion sort is usually a single pass; being stable, it
+    // keeps the first of duplicate keys first.
+    fn sort() {
         unsafe {
             for i in 0..MANIFEST_COUNT {
-                let entry_key = &MANIFEST_KEYS[i][..MANIFEST_KEY_LENS[i]];
-                if str_eq(entry_key, key) {
-                    return Some(&MANIFEST_VALUES[i][..MANIFEST_VALUE_LENS[i]]);
+                let index = i as u16;
+                let mut j = i;
+                while j > 0 && str_cmp(Manifest::key(MANIFEST_ORDER[j - 1]), Manifest::key(index)).is_gt() {
+                    MANIFEST_ORDER[j] = MANIFEST_ORDER[j - 1];
+                    j -= 1;
                 }
+                MANIFEST_ORDER[j] = index;
+            }
+        }
+    }
+
+    fn lookup(key: &[u8]) -> Option<&'static [u8]> {
+        unsafe {
+            // Find the first entry whose key is not less than `key`
+            let mut low = 0;
+            let mut high = MANIFEST_COUNT;
+            while low < high {
+                let mid = low + (high - low) / 2;
+                if str_cmp(Manifest::key(MANIFEST_ORDER[mid]), key).is_lt() {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            let index = *MANIFEST_ORDER[..MANIFEST_COUNT].get(low)? as usize;
+            if str_eq(&MANIFEST_KEYS[index][..MANIFEST_KEY_LENS[index]], key) {
+                Some(&MANIFEST_VALUES[index][..MANIFEST_VALUE_LENS[index]])
+            } else {
+                None
             }
-            None
         }
     }
 }
 
-// Load manifest file - uses static FILE_BUF to avoid stack overflow
-fn load_manifest(path: &[u8]) -> Option<Manifest> {
-    unsafe {
-        // Reset manifest state
-        Manifest::reset();
+// Check if a path is absolute (drive letter, UNC path, or rooted)
+fn is_absolute_path(path: &[u8]) -> bool {
+    (path.len() >= 2 && path[0].is_ascii_alphabetic() && path[1] == b':')
+        || (!path.is_empty() && (path[0] == b'\\' || path[0] == b'/'))
+}
 
-        // Ensure path is null-terminated
-        let mut path_with_null = [0u8; 1024];
-        let path_len = path.len().min(1023);
-        path_with_null[..path_len].copy_from_slice(&path[..path_len]);
-        path_with_null[path_len] = 0;
+// Report a runfiles file the stub can't load completely and exit
+fn runfiles_file_too_large(path: &[u8], limit: usize, unit: &[u8]) -> ! {
+    eprint(b"ERROR: Runfiles file ");
+    eprint(&path[..strlen(path)]);
+    eprint(b" has more than ");
+    eprint_number(limit);
+    eprint(unit);
+    unsafe { ExitProcess(1) }
+}
+
+// Undo Bazel's manifest escaping: \s is a space, \n a newline and \b a backslash.
+// Returns the unescaped length; like unescaped entries, the result is truncated to out.
+fn unescape_manifest_field(field: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < field.len() && len < MAX_PATH_LEN {
+        let c = if field[i] == b'\\' && i + 1 < field.len() {
+            i += 1;
+            match field[i] {
+                b's' => b' ',
+                b'n' => b'\n',
+                b'b' => b'\\',
+                other => other,
+            }
+        } else {
+            field[i]
+        };
+        out[len] = c;
+        len += 1;
+        i += 1;
+    }
+    len
+}
+
+// Split a manifest line into its key and value. Entries whose paths contain spaces,
+// newlines or backslashes are escaped by Bazel and marked with a leading space;
+// those are unescaped into key_buf and value_buf.
+fn split_manifest_line<'a>(
+    line: &'a [u8],
+    key_buf: &'a mut [u8; MAX_PATH_LEN],
+    value_buf: &'a mut [u8; MAX_PATH_LEN],
+) -> Option<(&'a [u8], &'a [u8])> {
+    if line.first() == Some(&b' ') {
+        let line = &line[1..];
+        let space_pos = find_byte(line, b' ')?;
+        let key_len = unescape_manifest_field(&line[..space_pos], key_buf);
+        let value_len = unescape_manifest_field(&line[space_pos + 1..], value_buf);
+        return Some((&key_buf[..key_len], &value_buf[..value_len]));
+    }
+    let space_pos = find_byte(line, b' ')?;
+    Some((&line[..space_pos], &line[space_pos + 1..]))
+}
 
-        let handle = CreateFileA(
-            path_with_null.as_ptr(),
+// Parse one "key value" manifest line. Returns false if the manifest is full.
+fn parse_manifest_line(mut line: &[u8]) -> bool {
+    // Strip trailing \r if present (Windows line endings)
+    if !line.is_empty() && line[line.len() - 1] == b'\r' {
+        line = &line[..line.len() - 1];
+    }
+
+    let mut key_buf = [0u8; MAX_PATH_LEN];
+    let mut value_buf = [0u8; MAX_PATH_LEN];
+    if let Some((key, value)) = split_manifest_line(line, &mut key_buf, &mut value_buf) {
+        return Manifest::add_entry(key, value);
+    }
+    true
+}
+
+// Load manifest file
+fn load_manifest(path: &[u8]) -> Option<Manifest> {
+    Manifest::reset();
+    if read_runfiles_lines(path, MAX_ENTRIES, parse_manifest_line) {
+        Manifest::sort();
+        Some(Manifest {})
+    } else {
+        None
+    }
+}
+
+// Pass each line of a runfiles file to parse_line, which returns false once more than
+// limit entries were parsed. Uses static FILE_BUF to avoid stack overflow; the file is
+// read in chunks, so it may be larger than the buffer as long as every line fits.
+// Returns false if the file can't be read or is empty.
+fn read_runfiles_lines(path: &[u8], limit: usize, mut parse_line: impl FnMut(&[u8]) -> bool) -> bool {
+    unsafe {
+        let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+        let handle = CreateFileW(
+            wide_path(path, &mut path_wide),
             GENERIC_READ,
             0,
             core::ptr::null_mut(),
@@ -356,51 +688,163 @@ fn load_manifest(path: &[u8]) -> Option<Manifest> {
         );
 
         if handle == INVALID_HANDLE_VALUE {
-            return None;
+            return false;
         }
 
-        // Use static FILE_BUF instead of stack allocation
-        let mut bytes_read: DWORD = 0;
-        let success = ReadFile(
-            handle,
-            FILE_BUF.as_mut_ptr() as LPVOID,
-            FILE_BUF.len() as DWORD,
-            &mut bytes_read,
-            core::ptr::null_mut(),
-        );
-        CloseHandle(handle);
+        let mut file_size: i64 = 0;
+        if GetFileSizeEx(handle, &mut file_size) == 0 || file_size == 0 {
+            CloseHandle(handle);
+            return false;
+        }
 
-        if success == 0 || bytes_read == 0 {
-            return None;
+        // Bytes at the start of FILE_BUF belonging to a line continued in the next chunk
+        let mut carried = 0usize;
+        let mut total_read: i64 = 0;
+
+        loop {
+            let mut bytes_read: DWORD = 0;
+            let success = ReadFile(
+                handle,
+                FILE_BUF.as_mut_ptr().add(carried) as LPVOID,
+                (FILE_BUF.len() - carried) as DWORD,
+                &mut bytes_read,
+                core::ptr::null_mut(),
+            );
+            if success == 0 {
+                CloseHandle(handle);
+                return false;
+            }
+            total_read += bytes_read as i64;
+
+            let end = carried + bytes_read as usize;
+            let data = &FILE_BUF[..end];
+            let mut pos = 0;
+
+            // Parse every complete line in the buffer
+            while let Some(newline) = find_byte(&data[pos..], b'\n') {
+                if !parse_line(&data[pos..pos + newline]) {
+                    CloseHandle(handle);
+                    runfiles_file_too_large(path, limit, b" entries\r\n");
+                }
+                pos += newline + 1;
+            }
+
+            // End of file: the rest is the last line, without a trailing newline
+            if bytes_read == 0 {
+                if pos < end && !parse_line(&data[pos..end]) {
+                    CloseHandle(handle);
+                    runfiles_file_too_large(path, limit, b" entries\r\n");
+                }
+                break;
+            }
+
+            // Move the incomplete line to the front for the next read
+            carried = end - pos;
+            if carried == FILE_BUF.len() {
+                // A single line larger than the buffer can't be a valid entry
+                CloseHandle(handle);
+                eprint(b"ERROR: Runfiles file ");
+                eprint(&path[..strlen(path)]);
+                eprint(b" has a line longer than 65536 bytes\r\n");
+                ExitProcess(1);
+            }
+            FILE_BUF.copy_within(pos..end, 0);
         }
+        CloseHandle(handle);
 
-        let data = &FILE_BUF[..bytes_read as usize];
-        let mut pos = 0;
+        // The file changed size while it was being read
+        total_read == file_size
+    }
+}
 
-        while pos < data.len() {
-            let line_start = pos;
-            while pos < data.len() && data[pos] != b'\n' {
-                pos += 1;
+// Bzlmod repository mapping
+// The _repo_mapping runfile maps the apparent repository names used in a repository to
+// canonical ones, one "source,apparent,canonical" line per mapping. Only the lines for
+// the stub's own repository are kept; a source ending in '*' stands for every
+// repository with that prefix.
+const MAX_REPO_MAPPINGS: usize = 256;
+
+// Loaded once with the runfiles, empty if there is no _repo_mapping
+static mut REPO_MAPPING_APPARENT: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS] = [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS];
+static mut REPO_MAPPING_CANONICAL: [[u8; MAX_PATH_LEN]; MAX_REPO_MAPPINGS] = [[0; MAX_PATH_LEN]; MAX_REPO_MAPPINGS];
+static mut REPO_MAPPING_APPARENT_LENS: [usize; MAX_REPO_MAPPINGS] = [0; MAX_REPO_MAPPINGS];
+static mut REPO_MAPPING_CANONICAL_LENS: [usize; MAX_REPO_MAPPINGS] = [0; MAX_REPO_MAPPINGS];
+static mut REPO_MAPPING_COUNT: usize = 0;
+
+struct RepoMapping {
+    // Empty struct - all data is in statics
+}
+
+impl RepoMapping {
+    // Returns false if the mapping is full
+    fn add(apparent: &[u8], canonical: &[u8]) -> bool {
+        unsafe {
+            if REPO_MAPPING_COUNT >= MAX_REPO_MAPPINGS {
+                return false;
             }
 
-            let line = &data[line_start..pos];
+            let idx = REPO_MAPPING_COUNT;
+            let apparent_len = apparent.len().min(MAX_PATH_LEN);
+            let canonical_len = canonical.len().min(MAX_PATH_LEN);
 
-            if let Some(space_pos) = find_byte(line, b' ') {
-                let key = &line[..space_pos];
-                let mut value = &line[space_pos + 1..];
+            REPO_MAPPING_APPARENT[idx][..apparent_len].copy_from_slice(&apparent[..apparent_len]);
+            REPO_MAPPING_APPARENT_LENS[idx] = apparent_len;
+            REPO_MAPPING_CANONICAL[idx][..canonical_len].copy_from_slice(&canonical[..canonical_len]);
+            REPO_MAPPING_CANONICAL_LENS[idx] = canonical_len;
 
-                // Strip trailing \r if present (Windows line endings)
-                if !value.is_empty() && value[value.len() - 1] == b'\r' {
-                    value = &value[..value.len() - 1];
-                }
+            REPO_MAPPING_COUNT += 1;
+            true
+        }
+    }
 
-                Manifest::add_entry(key, value);
+    fn lookup(apparent: &[u8]) -> Option<&'static [u8]> {
+        unsafe {
+            for i in 0..REPO_MAPPING_COUNT {
+                if str_eq(&REPO_MAPPING_APPARENT[i][..REPO_MAPPING_APPARENT_LENS[i]], apparent) {
+                    return Some(&REPO_MAPPING_CANONICAL[i][..REPO_MAPPING_CANONICAL_LENS[i]]);
+                }
             }
+            None
+        }
+    }
 
-            pos += 1;
+    // Keep the mappings of source_repo from a _repo_mapping file
+    fn load(path: &[u8], source_repo: &[u8]) {
+        unsafe {
+            REPO_MAPPING_COUNT = 0;
         }
 
-        Some(Manifest {})
+        read_runfiles_lines(path, MAX_REPO_MAPPINGS, |mut line| {
+            // Strip trailing \r if present (Windows line endings)
+            if !line.is_empty() && line[line.len() - 1] == b'\r' {
+                line = &line[..line.len() - 1];
+            }
+
+            let mut fields = line.split(|&b| b == b',');
+            let (source, apparent, canonical) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some(apparent), Some(canonical)) => (source, apparent, canonical),
+                _ => return true,
+            };
+            let applies = match source.last() {
+                Some(b'*') => str_starts_with(source_repo, &source[..source.len() - 1]),
+                _ => str_eq(source, source_repo),
+            };
+            !applies || RepoMapping::add(apparent, canonical)
+        });
+    }
+
+    // Replace the apparent repository name at the start of an rlocation path with its
+    // canonical name. Returns None if it has no mapping.
+    fn map(path: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+        let repo_len = find_byte(path, b'/').unwrap_or(path.len());
+        let canonical = RepoMapping::lookup(&path[..repo_len])?;
+        let rest = &path[repo_len..];
+        if canonical.len() + rest.len() > MAX_PATH_LEN {
+            return None;
+        }
+        out[..canonical.len()].copy_from_slice(canonical);
+        out[canonical.len()..canonical.len() + rest.len()].copy_from_slice(rest);
+        Some(canonical.len() + rest.len())
     }
 }
 
@@ -410,6 +854,77 @@ enum RunfilesMode {
     DirectoryBased([u8; MAX_PATH_LEN], usize),
 }
 
+// The runfiles directory Bazel places next to a manifest: <dir>_manifest (which
+// includes <name>.runfiles_manifest) or <dir>/MANIFEST. None unless it exists.
+fn manifest_runfiles_dir(manifest: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let is_manifest_file = [b"_manifest" as &[u8], b"/MANIFEST", b"\\MANIFEST"]
+        .iter()
+        .any(|suffix| manifest.ends_with(suffix));
+    // All suffixes are 9 bytes long
+    let len = manifest.len().checked_sub(9).filter(|&len| is_manifest_file && len > 0 && len < MAX_PATH_LEN)?;
+    let mut dir = [0u8; MAX_PATH_LEN];
+    dir[..len].copy_from_slice(&manifest[..len]);
+    if !path_exists(&dir[..len]) {
+        return None;
+    }
+    Some((dir, len))
+}
+
+// The manifest Bazel writes for a runfiles directory: <dir>\MANIFEST or <dir>_manifest
+// (which includes <name>.runfiles_manifest). Exported in directory mode for children
+// that only read manifests. None unless it exists.
+fn runfiles_dir_manifest(dir: &[u8]) -> Option<([u8; MAX_PATH_LEN], usize)> {
+    let mut dir_len = dir.len();
+    while dir_len > 1 && (dir[dir_len - 1] == b'/' || dir[dir_len - 1] == b'\\') {
+        dir_len -= 1;
+    }
+    for suffix in [b"\\MANIFEST" as &[u8], b"_manifest"] {
+        let len = dir_len + suffix.len();
+        if len >= MAX_PATH_LEN {
+            continue;
+        }
+        let mut path = [0u8; MAX_PATH_LEN];
+        path[..dir_len].copy_from_slice(&dir[..dir_len]);
+        path[dir_len..len].copy_from_slice(suffix);
+        if path_exists(&path[..len]) {
+            return Some((path, len));
+        }
+    }
+    None
+}
+
+// With RUNFILES_STUB_DEBUG set, warns when RUNFILES_DIR names another tree than
+// RUNFILES_MANIFEST_FILE, which takes precedence for lookups and the export
+fn check_runfiles_dir(manifest: &[u8], manifest_dir: Option<&[u8]>) {
+    if get_env_var(b"RUNFILES_STUB_DEBUG", &mut [0u8; 64]).is_none_or(|len| len == 0) {
+        return;
+    }
+    let mut dir = [0u8; MAX_PATH_LEN];
+    let mut len = match get_runfiles_env_var(b"RUNFILES_DIR", &mut dir) {
+        Some(len) if len > 0 => len,
+        _ => return,
+    };
+    while len > 1 && (dir[len - 1] == b'/' || dir[len - 1] == b'\\') {
+        len -= 1;
+    }
+    // Paths are case-insensitive, and either slash separates components
+    let same = |a: &[u8], b: &[u8]| {
+        let normalize = |c: u8| if c == b'/' { b'\\' } else { c.to_ascii_lowercase() };
+        a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| normalize(x) == normalize(y))
+    };
+    if manifest_dir.is_some_and(|manifest_dir| same(manifest_dir, &dir[..len])) {
+        return;
+    }
+    eprint(b"WARNING: RUNFILES_DIR and RUNFILES_MANIFEST_FILE describe different runfiles trees\n");
+    eprint(b"  RUNFILES_MANIFEST_FILE=");
+    eprint(manifest);
+    eprint(b" (used)\n  RUNFILES_DIR=");
+    eprint(&dir[..len]);
+    eprint(b" (ignored)\n  Runfiles directory of the manifest: ");
+    eprint(manifest_dir.unwrap_or(b"(none)"));
+    eprint(b"\n");
+}
+
 struct Runfiles {
     mode: RunfilesMode,
     // Paths for environment variables (when export_runfiles_env is true)
@@ -419,16 +934,51 @@ struct Runfiles {
 
 impl Runfiles {
     fn create(executable_path: Option<&[u8]>) -> Option<Self> {
+        let runfiles = Self::discover(executable_path)?;
+        runfiles.load_repo_mapping();
+        Some(runfiles)
+    }
+
+    // Runfiles directory chosen by the stub itself (the extracted tree)
+    fn from_dir(dir: [u8; MAX_PATH_LEN], len: usize) -> Self {
+        let runfiles = Self {
+            mode: RunfilesMode::DirectoryBased(dir, len),
+            manifest_path: None,
+            dir_path: Some((dir, len)),
+        };
+        runfiles.load_repo_mapping();
+        runfiles
+    }
+
+    // Load the repository mapping of the stub's source repository for rlocation()
+    fn load_repo_mapping(&self) {
+        unsafe {
+            let source_repo: &[u8] = if METADATA.flags & FLAG_SOURCE_REPO != 0 {
+                &SOURCE_REPO[..strlen(&SOURCE_REPO)]
+            } else {
+                b""
+            };
+            if let Some(path) = self.resolve(b"_repo_mapping", REPO_MAPPING_SLOT, false) {
+                RepoMapping::load(path, source_repo);
+            }
+        }
+    }
+
+    // Find the runfiles through the environment or next to the executable
+    fn discover(executable_path: Option<&[u8]>) -> Option<Self> {
         let mut manifest_path = [0u8; MAX_PATH_LEN];
 
         // Step 1: Try RUNFILES_MANIFEST_FILE envvar first
-        if let Some(len) = get_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_MANIFEST_FILE", &mut manifest_path) {
             if len > 0 {
                 if let Some(manifest) = load_manifest(&manifest_path[..len]) {
+                    let dir_path = manifest_runfiles_dir(&manifest_path[..len]);
+                    check_runfiles_dir(&manifest_path[..len], dir_path.as_ref().map(|(dir, len)| &dir[..*len]));
                     return Some(Self {
                         mode: RunfilesMode::ManifestBased(manifest),
                         manifest_path: Some((manifest_path, len)),
-                        dir_path: None,
+                        // Exported too, for libraries that only use directories
+                        dir_path,
                     });
                 }
             }
@@ -436,11 +986,12 @@ impl Runfiles {
 
         // Step 2: Try RUNFILES_DIR envvar
         let mut runfiles_dir = [0u8; MAX_PATH_LEN];
-        if let Some(len) = get_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
+        if let Some(len) = get_runfiles_env_var(b"RUNFILES_DIR", &mut runfiles_dir) {
             if len > 0 {
                 return Some(Self {
                     mode: RunfilesMode::DirectoryBased(runfiles_dir, len),
-                    manifest_path: None,
+                    // Exported too, for libraries that only read manifests
+                    manifest_path: runfiles_dir_manifest(&runfiles_dir[..len]),
                     dir_path: Some((runfiles_dir, len)),
                 });
             }
@@ -502,8 +1053,9 @@ impl Runfiles {
                     // Check if directory exists by trying to open it
                     unsafe {
                         const FILE_FLAG_BACKUP_SEMANTICS: DWORD = 0x02000000;  // Needed to open directories
-                        let handle = CreateFileA(
-                            runfiles_dir.as_ptr(),
+                        let mut runfiles_dir_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+                        let handle = CreateFileW(
+                            wide_path(&runfiles_dir, &mut runfiles_dir_wide),
                             GENERIC_READ,
                             0,
                             core::ptr::null_mut(),
@@ -516,7 +1068,7 @@ impl Runfiles {
                             // Remove null terminator for internal storage
                             return Some(Self {
                                 mode: RunfilesMode::DirectoryBased(runfiles_dir, exe_len + 9),
-                                manifest_path: None,
+                                manifest_path: runfiles_dir_manifest(&runfiles_dir[..exe_len + 9]),
                                 dir_path: Some((runfiles_dir, exe_len + 9)),
                             });
                         }
@@ -528,7 +1080,45 @@ impl Runfiles {
         None
     }
 
-    fn rlocation(&self, path: &[u8], result_idx: usize) -> Option<&'static [u8]> {
+    fn rlocation(&self, path: &[u8], result_idx: usize, follow_indirection: bool) -> Option<&'static [u8]> {
+        // Apparent repository names are translated through the repository mapping;
+        // paths without a mapping are looked up unchanged
+        let mut mapped = [0u8; MAX_PATH_LEN];
+        let path = match RepoMapping::map(path, &mut mapped) {
+            Some(len) => &mapped[..len],
+            None => path,
+        };
+        self.resolve(path, result_idx, follow_indirection)
+    }
+
+    // Like rlocation, for the program to run: callers often leave out the .exe of its
+    // rlocation path, so a path that doesn't resolve to an existing file is retried
+    // with .exe appended
+    fn rlocation_executable(&self, path: &[u8], result_idx: usize, follow_indirection: bool) -> Option<&'static [u8]> {
+        let found = self.rlocation(path, result_idx, follow_indirection);
+        let manifest_based = matches!(self.mode, RunfilesMode::ManifestBased(_));
+        let missed = match found {
+            // A manifest entry is authoritative even if its file doesn't exist
+            Some(resolved) => !manifest_based && !path_exists(resolved),
+            None => true,
+        };
+        let has_extension = path.len() >= 4 && path[path.len() - 4..].eq_ignore_ascii_case(b".exe");
+        if !missed || has_extension || path.len() + 4 > MAX_PATH_LEN {
+            return found;
+        }
+
+        let mut with_extension = [0u8; MAX_PATH_LEN];
+        with_extension[..path.len()].copy_from_slice(path);
+        with_extension[path.len()..path.len() + 4].copy_from_slice(b".exe");
+        match self.rlocation(&with_extension[..path.len() + 4], result_idx, follow_indirection) {
+            Some(resolved) if manifest_based || path_exists(resolved) => Some(resolved),
+            // Both lookups share RESOLVED_PATHS[result_idx]; restore the first result
+            _ => self.rlocation(path, result_idx, follow_indirection),
+        }
+    }
+
+    // Look up an rlocation path as-is, writing the result to RESOLVED_PATHS[result_idx]
+    fn resolve(&self, path: &[u8], result_idx: usize, follow_indirection: bool) -> Option<&'static [u8]> {
         // If path is absolute (Windows: starts with drive letter or \\), don't resolve
         if path.len() >= 2 && ((path[0].is_ascii_alphabetic() && path[1] == b':') || (path[0] == b'\\' && path[1] == b'\\')) {
             return None;
@@ -537,7 +1127,12 @@ impl Runfiles {
         match &self.mode {
             RunfilesMode::ManifestBased(_manifest) => {
                 // Use static lookup
-                if let Some(resolved) = Manifest::lookup(path) {
+                if let Some(mut resolved) = Manifest::lookup(path) {
+                    // Optional single level of indirection: a relative value names another
+                    // manifest entry. A value naming its own key is a cycle and not followed.
+                    if follow_indirection && !is_absolute_path(resolved) && !str_eq(resolved, path) {
+                        resolved = Manifest::lookup(resolved)?;
+                    }
                     unsafe {
                         let len = resolved.len().min(MAX_PATH_LEN);
                         // Copy path, converting forward slashes to backslashes
@@ -595,6 +1190,45 @@ extern "system" {
 
 static mut MODIFIED_ENV_DATA: [u16; MAX_ENV_SIZE / 2] = [0; MAX_ENV_SIZE / 2];
 
+// With FLAG_KEEP_ENV, a runfiles variable the parent set is passed on as-is
+// instead of being replaced with the stub's own value
+fn keep_inherited(name: &[u8]) -> bool {
+    if unsafe { METADATA.flags } & FLAG_KEEP_ENV == 0 {
+        return false;
+    }
+    let mut name_wide = [0u16; 32];
+    let name_len = utf8_to_wide(name, &mut name_wide[..31]);
+    name_wide[name_len] = 0;
+    // With no buffer, the result is the value's length plus the terminator
+    unsafe { GetEnvironmentVariableW(name_wide.as_ptr(), core::ptr::null_mut(), 0) > 1 }
+}
+
+// Sets the variables embedded with --set-env in the stub's own environment, so the
+// target inherits them and build_runfiles_environ copies them in sorted order
+fn apply_set_env() {
+    let set_env = unsafe { &*core::ptr::addr_of!(SET_ENV) };
+    let mut name = [0u16; SET_ENV_SIZE];
+    let mut value = [0u16; SET_ENV_SIZE];
+    let mut pos = 0;
+    while pos < set_env.len() && set_env[pos] != 0 {
+        let len = strlen(&set_env[pos..]);
+        let var = &set_env[pos..pos + len];
+        // The finalizer only embeds NAME=value entries
+        let eq = find_byte(var, b'=').unwrap_or(len);
+        let name_len = utf8_to_wide(&var[..eq], &mut name[..SET_ENV_SIZE - 1]);
+        name[name_len] = 0;
+        let value_len = utf8_to_wide(var.get(eq + 1..).unwrap_or(b""), &mut value[..SET_ENV_SIZE - 1]);
+        value[value_len] = 0;
+        if unsafe { SetEnvironmentVariableW(name.as_ptr(), value.as_ptr()) } == 0 {
+            eprint(b"ERROR: Failed to set ");
+            eprint(&var[..eq]);
+            eprint(b" in the environment\r\n");
+            unsafe { ExitProcess(1) };
+        }
+        pos += len + 1;
+    }
+}
+
 fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void {
     unsafe {
         // Windows requires environment variables to be sorted alphabetically
@@ -609,6 +1243,11 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
             pos + needed <= max_pos
         };
 
+        // Variables left out of the export are passed on as the parent set them, like kept ones
+        let skip_java = METADATA.flags & FLAG_NO_EXPORT_JAVA != 0;
+        let skip_dir = METADATA.flags & FLAG_NO_EXPORT_DIR != 0;
+        let skip_manifest = METADATA.flags & FLAG_NO_EXPORT_MANIFEST != 0;
+
         // Copy existing environment and insert runfiles vars in correct sorted position
         let env_block = GetEnvironmentStringsW();
         if env_block.is_null() {
@@ -625,10 +1264,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                 }
                 MODIFIED_ENV_DATA[data_pos] = b'=' as u16;
                 data_pos += 1;
-                for &b in value {
-                    MODIFIED_ENV_DATA[data_pos] = b as u16;
-                    data_pos += 1;
-                }
+                data_pos += utf8_to_wide(value, &mut MODIFIED_ENV_DATA[data_pos..]);
                 MODIFIED_ENV_DATA[data_pos] = 0;
                 data_pos += 1;
                 true
@@ -636,27 +1272,27 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
 
             if let Some(rf) = runfiles {
                 if let Some((ref path, len)) = rf.dir_path {
-                    if !add_env(b"JAVA_RUNFILES", &path[..len]) {
-                        print(b"ERROR: Failed to add JAVA_RUNFILES to environment\r\n");
-                        print(b"Environment buffer limit exceeded. Total size limit: ");
-                        print_number(MAX_ENV_SIZE);
-                        print(b" bytes\r\n");
+                    if !skip_java && !add_env(b"JAVA_RUNFILES", &path[..len]) {
+                        eprint(b"ERROR: Failed to add JAVA_RUNFILES to environment\r\n");
+                        eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                        eprint_number(MAX_ENV_SIZE);
+                        eprint(b" bytes\r\n");
                         ExitProcess(1);
                     }
-                    if !add_env(b"RUNFILES_DIR", &path[..len]) {
-                        print(b"ERROR: Failed to add RUNFILES_DIR to environment\r\n");
-                        print(b"Environment buffer limit exceeded. Total size limit: ");
-                        print_number(MAX_ENV_SIZE);
-                        print(b" bytes\r\n");
+                    if !skip_dir && !add_env(b"RUNFILES_DIR", &path[..len]) {
+                        eprint(b"ERROR: Failed to add RUNFILES_DIR to environment\r\n");
+                        eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                        eprint_number(MAX_ENV_SIZE);
+                        eprint(b" bytes\r\n");
                         ExitProcess(1);
                     }
                 }
                 if let Some((ref path, len)) = rf.manifest_path {
-                    if !add_env(b"RUNFILES_MANIFEST_FILE", &path[..len]) {
-                        print(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\r\n");
-                        print(b"Environment buffer limit exceeded. Total size limit: ");
-                        print_number(MAX_ENV_SIZE);
-                        print(b" bytes\r\n");
+                    if !skip_manifest && !add_env(b"RUNFILES_MANIFEST_FILE", &path[..len]) {
+                        eprint(b"ERROR: Failed to add RUNFILES_MANIFEST_FILE to environment\r\n");
+                        eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                        eprint_number(MAX_ENV_SIZE);
+                        eprint(b" bytes\r\n");
                         ExitProcess(1);
                     }
                 }
@@ -664,9 +1300,13 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
         } else {
             // Iterate through existing environment and insert runfiles vars at correct position
             let mut pos = 0;
-            let mut java_runfiles_inserted = false;
-            let mut runfiles_dir_inserted = false;
-            let mut runfiles_manifest_inserted = false;
+            // Kept variables are copied from the parent like any other
+            let keep_java = skip_java || keep_inherited(b"JAVA_RUNFILES");
+            let keep_dir = skip_dir || keep_inherited(b"RUNFILES_DIR");
+            let keep_manifest = skip_manifest || keep_inherited(b"RUNFILES_MANIFEST_FILE");
+            let mut java_runfiles_inserted = keep_java;
+            let mut runfiles_dir_inserted = keep_dir;
+            let mut runfiles_manifest_inserted = keep_manifest;
             let mut env_dropped = false;
 
             loop {
@@ -683,7 +1323,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
 
                 // Check if we should skip existing runfiles vars
                 let should_skip =
-                    (entry_len > 23 && {
+                    (!keep_manifest && entry_len > 23 && {
                         let mut matches = true;
                         for i in 0..23 {
                             if *entry_ptr.add(i) != b"RUNFILES_MANIFEST_FILE="[i] as u16 {
@@ -693,7 +1333,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                         }
                         matches
                     }) ||
-                    (entry_len > 13 && {
+                    (!keep_dir && entry_len > 13 && {
                         let mut matches = true;
                         for i in 0..13 {
                             if *entry_ptr.add(i) != b"RUNFILES_DIR="[i] as u16 {
@@ -703,7 +1343,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                         }
                         matches
                     }) ||
-                    (entry_len > 14 && {
+                    (!keep_java && entry_len > 14 && {
                         let mut matches = true;
                         for i in 0..14 {
                             if *entry_ptr.add(i) != b"JAVA_RUNFILES="[i] as u16 {
@@ -752,10 +1392,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                         MODIFIED_ENV_DATA[data_pos] = b as u16;
                                         data_pos += 1;
                                     }
-                                    for i in 0..len {
-                                        MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                        data_pos += 1;
-                                    }
+                                    data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                                     MODIFIED_ENV_DATA[data_pos] = 0;
                                     data_pos += 1;
                                 }
@@ -776,10 +1413,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                         MODIFIED_ENV_DATA[data_pos] = b as u16;
                                         data_pos += 1;
                                     }
-                                    for i in 0..len {
-                                        MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                        data_pos += 1;
-                                    }
+                                    data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                                     MODIFIED_ENV_DATA[data_pos] = 0;
                                     data_pos += 1;
                                 }
@@ -800,10 +1434,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                         MODIFIED_ENV_DATA[data_pos] = b as u16;
                                         data_pos += 1;
                                     }
-                                    for i in 0..len {
-                                        MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                        data_pos += 1;
-                                    }
+                                    data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                                     MODIFIED_ENV_DATA[data_pos] = 0;
                                     data_pos += 1;
                                 }
@@ -839,10 +1470,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                 MODIFIED_ENV_DATA[data_pos] = b as u16;
                                 data_pos += 1;
                             }
-                            for i in 0..len {
-                                MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                data_pos += 1;
-                            }
+                            data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                             MODIFIED_ENV_DATA[data_pos] = 0;
                             data_pos += 1;
                         }
@@ -860,10 +1488,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                 MODIFIED_ENV_DATA[data_pos] = b as u16;
                                 data_pos += 1;
                             }
-                            for i in 0..len {
-                                MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                data_pos += 1;
-                            }
+                            data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                             MODIFIED_ENV_DATA[data_pos] = 0;
                             data_pos += 1;
                         }
@@ -881,10 +1506,7 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
                                 MODIFIED_ENV_DATA[data_pos] = b as u16;
                                 data_pos += 1;
                             }
-                            for i in 0..len {
-                                MODIFIED_ENV_DATA[data_pos] = path[i] as u16;
-                                data_pos += 1;
-                            }
+                            data_pos += utf8_to_wide(&path[..len], &mut MODIFIED_ENV_DATA[data_pos..]);
                             MODIFIED_ENV_DATA[data_pos] = 0;
                             data_pos += 1;
                         }
@@ -895,14 +1517,14 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
             // Check if any environment variables were dropped
             if env_dropped {
                 FreeEnvironmentStringsW(env_block);
-                print(b"ERROR: Failed to copy all environment variables\r\n");
-                print(b"Environment buffer limit exceeded. Total size limit: ");
-                print_number(MAX_ENV_SIZE);
-                print(b" bytes\r\n");
-                print(b"Current usage: ");
-                print_number(data_pos * 2); // *2 because it's u16 array
-                print(b" bytes\r\n");
-                print(b"Consider reducing the number or size of environment variables.\r\n");
+                eprint(b"ERROR: Failed to copy all environment variables\r\n");
+                eprint(b"Environment buffer limit exceeded. Total size limit: ");
+                eprint_number(MAX_ENV_SIZE);
+                eprint(b" bytes\r\n");
+                eprint(b"Current usage: ");
+                eprint_number(data_pos * 2); // *2 because it's u16 array
+                eprint(b" bytes\r\n");
+                eprint(b"Consider reducing the number or size of environment variables.\r\n");
                 ExitProcess(1);
             }
 
@@ -922,61 +1544,745 @@ fn build_runfiles_environ(runfiles: Option<&Runfiles>) -> *mut core::ffi::c_void
     }
 }
 
-// Placeholders for stub runner (will be replaced in final binary)
-const ARG_SIZE: usize = 256;
+// Self-extracting stubs
+// With --self-extracting the finalizer appends a runfiles archive to the stub,
+// located through a trailer at the very end of the file (all integers little-endian):
+//   entry:   u32 path_len | path | u32 mode | u64 data_len | data
+//   trailer: u64 archive_offset | u64 entry_count | u64 archive_hash | "RFSTUBSX"
+// The archive is extracted once per content hash to %TEMP%\runfiles-stub-<hash>.
+// Extraction goes to a fresh staging directory, private to the user, that is renamed
+// into place, so concurrent first runs never see a partially extracted tree. %TEMP%
+// may be shared, so an existing tree is only reused if this user made it.
+const SELF_EXTRACT_MAGIC: &[u8; 8] = b"RFSTUBSX";
+const SELF_EXTRACT_TRAILER_SIZE: usize = 32;
+const MAX_EXTRACT_PATH_LEN: usize = MAX_PATH_LEN * 2;
+const INVALID_FILE_ATTRIBUTES: DWORD = 0xFFFFFFFF;
+const FILE_ATTRIBUTE_DIRECTORY: DWORD = 0x10;
+const FILE_ATTRIBUTE_REPARSE_POINT: DWORD = 0x400;
+
+// Protected DACL granting full access to the owner only, inherited by everything
+// extracted below the staging directory
+const PRIVATE_DIR_SDDL: &[u8] = b"D:P(A;OICI;FA;;;OW)";
+const SDDL_REVISION_1: DWORD = 1;
+const SE_FILE_OBJECT: DWORD = 1;
+const OWNER_SECURITY_INFORMATION: DWORD = 0x1;
+const DACL_SECURITY_INFORMATION: DWORD = 0x4;
+const TOKEN_QUERY: DWORD = 0x8;
+const TOKEN_OWNER_CLASS: DWORD = 4;
+const ACCESS_ALLOWED_ACE_TYPE: u8 = 0;
+const ACCESS_DENIED_ACE_TYPE: u8 = 1;
+const INHERIT_ONLY_ACE: u8 = 0x8;
+// Access rights that let a trustee change a directory or the files below it: the
+// generic write and all rights, the file write rights, DELETE, WRITE_DAC and WRITE_OWNER
+const WRITE_ACCESS_MASK: DWORD = 0x5000_0000 | 0x0001_0000 | 0x0004_0000 | 0x0008_0000 | 0x156;
+// SIDs that may hold write access besides the owner: OWNER RIGHTS, SYSTEM and the
+// Administrators group
+const TRUSTED_SIDS: [&[u8]; 3] = [
+    &[1, 1, 0, 0, 0, 0, 0, 3, 4, 0, 0, 0],
+    &[1, 1, 0, 0, 0, 0, 0, 5, 18, 0, 0, 0],
+    &[1, 2, 0, 0, 0, 0, 0, 5, 32, 0, 0, 0, 0x20, 0x02, 0, 0],
+];
+
+// Static buffer for copying file contents out of the archive
+static mut EXTRACT_BUF: [u8; 65536] = [0; 65536];
+
+fn read_exact(handle: HANDLE, buf: &mut [u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let mut n: DWORD = 0;
+        let ok = unsafe {
+            ReadFile(
+                handle,
+                buf[pos..].as_mut_ptr() as LPVOID,
+                (buf.len() - pos) as DWORD,
+                &mut n,
+                core::ptr::null_mut(),
+            )
+        };
+        if ok == 0 || n == 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
 
-#[used]
-#[link_section = ".runfiles"]
-static mut ARGC_PLACEHOLDER: [u8; 32] = *b"@@RUNFILES_ARGC@@\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+fn write_all(handle: HANDLE, buf: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let mut n: DWORD = 0;
+        let ok = unsafe {
+            WriteFile(
+                handle,
+                buf[pos..].as_ptr(),
+                (buf.len() - pos) as DWORD,
+                &mut n,
+                core::ptr::null_mut(),
+            )
+        };
+        if ok == 0 || n == 0 {
+            return false;
+        }
+        pos += n as usize;
+    }
+    true
+}
 
-#[used]
-#[link_section = ".runfiles"]
-static mut TRANSFORM_FLAGS: [u8; 32] = *b"@@RUNFILES_TRANSFORM_FLAGS@@\0\0\0\0";
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
 
-#[used]
-#[link_section = ".runfiles"]
-static mut EXPORT_RUNFILES_ENV: [u8; 32] = *b"@@RUNFILES_EXPORT_ENV@@\0\0\0\0\0\0\0\0\0";
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
 
-#[used]
-#[link_section = ".runfiles"]
+// Append `n` as decimal to buf at pos, returns the new position
+fn append_decimal(buf: &mut [u8], mut pos: usize, mut n: u64) -> Option<usize> {
+    let mut digits = [0u8; 20];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        n /= 10;
+        count += 1;
+        if n == 0 {
+            break;
+        }
+    }
+    if pos + count > buf.len() {
+        return None;
+    }
+    while count > 0 {
+        count -= 1;
+        buf[pos] = digits[count];
+        pos += 1;
+    }
+    Some(pos)
+}
+
+// Append `n` as 16 lowercase hex digits to buf at pos, returns the new position
+fn append_hex(buf: &mut [u8], mut pos: usize, n: u64) -> Option<usize> {
+    if pos + 16 > buf.len() {
+        return None;
+    }
+    for i in 0..16 {
+        let nibble = ((n >> ((15 - i) * 4)) & 0xF) as u8;
+        buf[pos] = if nibble < 10 { b'0' + nibble } else { b'a' + nibble - 10 };
+        pos += 1;
+    }
+    Some(pos)
+}
+
+// Check whether a file or directory exists
+fn path_exists(path: &[u8]) -> bool {
+    let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+    unsafe { GetFileAttributesW(wide_path(path, &mut path_wide)) != INVALID_FILE_ATTRIBUTES }
+}
+
+// Create every parent directory of the NUL-terminated path in path[..len] below
+// path[..start], which must already exist
+fn create_parent_dirs(path: &[u8], start: usize, len: usize) {
+    let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+    for i in start + 1..len {
+        if path[i] == b'\\' {
+            unsafe {
+                CreateDirectoryW(wide_path(&path[..i], &mut path_wide), core::ptr::null_mut());
+            }
+        }
+    }
+}
+
+// The bytes of a SID (revision, sub-authority count, authority, sub-authorities)
+unsafe fn sid_bytes<'a>(sid: *const u8) -> &'a [u8] {
+    core::slice::from_raw_parts(sid, 8 + 4 * *sid.add(1) as usize)
+}
+
+// Whether the NUL-terminated path is a directory only this user can have written:
+// a real directory (not a junction or symlink) owned by the user's default owner,
+// whose DACL grants write access to nobody but the owner and the system
+fn is_private_dir(path: &[u8]) -> bool {
+    let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+    let path_wide = wide_path(path, &mut path_wide);
+    unsafe {
+        let attributes = GetFileAttributesW(path_wide);
+        if attributes == INVALID_FILE_ATTRIBUTES
+            || attributes & FILE_ATTRIBUTE_DIRECTORY == 0
+            || attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0
+        {
+            return false;
+        }
+
+        // The owner new objects of this process get (TOKEN_OWNER)
+        let mut token: HANDLE = core::ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        let mut token_owner = [0u64; 16];
+        let mut size: DWORD = 0;
+        let ok = GetTokenInformation(
+            token,
+            TOKEN_OWNER_CLASS,
+            token_owner.as_mut_ptr() as LPVOID,
+            core::mem::size_of_val(&token_owner) as DWORD,
+            &mut size,
+        );
+        CloseHandle(token);
+        if ok == 0 {
+            return false;
+        }
+        let user = sid_bytes(token_owner[0] as usize as *const u8);
+
+        let mut owner: *const u8 = core::ptr::null();
+        let mut dacl: *const u8 = core::ptr::null();
+        let mut descriptor: LPVOID = core::ptr::null_mut();
+        if GetNamedSecurityInfoW(
+            path_wide,
+            SE_FILE_OBJECT,
+            OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+            &mut owner,
+            core::ptr::null_mut(),
+            &mut dacl,
+            core::ptr::null_mut(),
+            &mut descriptor,
+        ) != 0
+        {
+            return false;
+        }
+        // A NULL DACL grants everyone full access
+        let mut private = !owner.is_null() && !dacl.is_null() && sid_bytes(owner) == user;
+        if private {
+            // ACL header: revision, padding, size, then the u16 ACE count
+            let ace_count = u16::from_le_bytes([*dacl.add(4), *dacl.add(5)]);
+            for index in 0..ace_count as DWORD {
+                // ACE header: type, flags, size; then the access mask and the SID
+                let mut ace: *const u8 = core::ptr::null();
+                if GetAce(dacl, index, &mut ace) == 0 {
+                    private = false;
+                    break;
+                }
+                let (ace_type, ace_flags) = (*ace, *ace.add(1));
+                if ace_type == ACCESS_DENIED_ACE_TYPE || ace_flags & INHERIT_ONLY_ACE != 0 {
+                    continue;
+                }
+                if ace_type != ACCESS_ALLOWED_ACE_TYPE {
+                    private = false;
+                    break;
+                }
+                let mask = read_u32_le(core::slice::from_raw_parts(ace.add(4), 4));
+                let trustee = sid_bytes(ace.add(8));
+                if mask & WRITE_ACCESS_MASK != 0 && trustee != user && !TRUSTED_SIDS.contains(&trustee) {
+                    private = false;
+                    break;
+                }
+            }
+        }
+        LocalFree(descriptor);
+        private
+    }
+}
+
+// Extract the runfiles archive appended to this executable.
+// Writes the extracted runfiles directory to dir_out and returns its length.
+fn extract_self(dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    let mut exe_path = [0u16; MAX_EXTRACT_PATH_LEN];
+    unsafe {
+        let exe_len = GetModuleFileNameW(core::ptr::null_mut(), exe_path.as_mut_ptr(), exe_path.len() as DWORD);
+        if exe_len == 0 || exe_len as usize >= exe_path.len() {
+            return None;
+        }
+        // The loader keeps the image open, so reading it requires sharing
+        let handle = CreateFileW(
+            exe_path.as_ptr(),
+            GENERIC_READ,
+            FILE_SHARE_READ,
+            core::ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_ATTRIBUTE_NORMAL,
+            core::ptr::null_mut(),
+        );
+        if handle == INVALID_HANDLE_VALUE {
+            return None;
+        }
+        let result = extract_archive(handle, dir_out);
+        CloseHandle(handle);
+        result
+    }
+}
+
+fn extract_archive(handle: HANDLE, dir_out: &mut [u8; MAX_PATH_LEN]) -> Option<usize> {
+    unsafe {
+        // Read the trailer
+        let mut trailer = [0u8; SELF_EXTRACT_TRAILER_SIZE];
+        if SetFilePointerEx(handle, -(SELF_EXTRACT_TRAILER_SIZE as i64), core::ptr::null_mut(), FILE_END) == 0
+            || !read_exact(handle, &mut trailer)
+        {
+            return None;
+        }
+        if !str_eq(&trailer[24..32], SELF_EXTRACT_MAGIC) {
+            return None;
+        }
+        let archive_offset = read_u64_le(&trailer[0..8]);
+        let entry_count = read_u64_le(&trailer[8..16]);
+        let archive_hash = read_u64_le(&trailer[16..24]);
+
+        // Final directory: %TEMP%\runfiles-stub-<hash> (GetTempPathW ends with a backslash)
+        let mut temp_wide = [0u16; MAX_PATH_LEN];
+        let temp_wide_len = GetTempPathW(MAX_PATH_LEN as DWORD, temp_wide.as_mut_ptr()) as usize;
+        if temp_wide_len == 0 || temp_wide_len >= MAX_PATH_LEN {
+            return None;
+        }
+        let temp_len = wide_to_utf8(&temp_wide[..temp_wide_len], &mut dir_out[..])?;
+        let prefix = b"runfiles-stub-";
+        if temp_len + prefix.len() + 16 >= MAX_PATH_LEN {
+            return None;
+        }
+        dir_out[temp_len..temp_len + prefix.len()].copy_from_slice(prefix);
+        let dir_len = append_hex(dir_out, temp_len + prefix.len(), archive_hash)?;
+        dir_out[dir_len] = 0;
+
+        // Reuse a previous extraction (only ever published complete) if this user made
+        // it; anything else at that path may have been planted by another user
+        if is_private_dir(&dir_out[..dir_len + 1]) {
+            return Some(dir_len);
+        }
+
+        // Staging directory: <final>.<pid>.<n>, which CreateDirectoryW creates fresh
+        // with an owner-only DACL, so nothing below it can have been planted
+        let mut sddl = [0u16; 32];
+        let sddl_len = utf8_to_wide(PRIVATE_DIR_SDDL, &mut sddl[..31]);
+        sddl[sddl_len] = 0;
+        let mut descriptor: LPVOID = core::ptr::null_mut();
+        if ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            core::ptr::null_mut(),
+        ) == 0
+        {
+            return None;
+        }
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: core::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: descriptor,
+            bInheritHandle: 0,
+        };
+        let mut path = [0u8; MAX_EXTRACT_PATH_LEN];
+        path[..dir_len].copy_from_slice(&dir_out[..dir_len]);
+        path[dir_len] = b'.';
+        let pid_len = append_decimal(&mut path, dir_len + 1, GetCurrentProcessId() as u64)?;
+        path[pid_len] = b'.';
+        let mut attempt = 0;
+        let staging_len = loop {
+            let len = match append_decimal(&mut path, pid_len + 1, attempt) {
+                Some(len) => len,
+                None => break None,
+            };
+            path[len] = 0;
+            let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+            if CreateDirectoryW(wide_path(&path, &mut path_wide), &mut attributes as *mut _ as LPVOID) != 0 {
+                break Some(len);
+            }
+            attempt += 1;
+            if GetLastError() != ERROR_ALREADY_EXISTS || attempt == 100 {
+                break None;
+            }
+        };
+        LocalFree(descriptor);
+        let staging_len = staging_len?;
+
+        if SetFilePointerEx(handle, archive_offset as i64, core::ptr::null_mut(), FILE_BEGIN) == 0
+            || !extract_entries(handle, entry_count, &mut path, staging_len)
+        {
+            remove_staging(handle, archive_offset, entry_count, &mut path, staging_len);
+            return None;
+        }
+
+        // Publish the tree. If another process of this user won the race, its identical
+        // tree is used and ours removed.
+        path[staging_len] = 0;
+        let mut staging_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+        let mut dir_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+        let published = MoveFileW(
+            wide_path(&path, &mut staging_wide),
+            wide_path(&dir_out[..dir_len + 1], &mut dir_wide),
+        ) != 0;
+        if published || is_private_dir(&dir_out[..dir_len + 1]) {
+            if !published {
+                remove_staging(handle, archive_offset, entry_count, &mut path, staging_len);
+            }
+            return Some(dir_len);
+        }
+
+        // The final path is taken by something this user can't trust, so the private
+        // staging directory serves as a one-off copy
+        if staging_len >= MAX_PATH_LEN {
+            remove_staging(handle, archive_offset, entry_count, &mut path, staging_len);
+            return None;
+        }
+        dir_out[..staging_len + 1].copy_from_slice(&path[..staging_len + 1]);
+        Some(staging_len)
+    }
+}
+
+// Read the next entry's path into path[path_start..] (with '/' converted to '\\') and
+// its header; returns the end of the NUL-terminated path and the data length
+fn read_entry_header(handle: HANDLE, path: &mut [u8; MAX_EXTRACT_PATH_LEN], path_start: usize) -> Option<(usize, u64)> {
+    let mut header = [0u8; 12];
+    if !read_exact(handle, &mut header[..4]) {
+        return None;
+    }
+    let path_len = read_u32_le(&header[..4]) as usize;
+    if path_start + path_len + 1 > MAX_EXTRACT_PATH_LEN || !read_exact(handle, &mut path[path_start..path_start + path_len]) {
+        return None;
+    }
+    let full_len = path_start + path_len;
+    for b in &mut path[path_start..full_len] {
+        if *b == b'/' {
+            *b = b'\\';
+        }
+    }
+    path[full_len] = 0;
+
+    // Mode (unused on Windows) and data length
+    if !read_exact(handle, &mut header) {
+        return None;
+    }
+    Some((full_len, read_u64_le(&header[4..12])))
+}
+
+// Write the archive entries (read from the handle's current position) below the
+// staging directory in path[..staging_len]
+fn extract_entries(handle: HANDLE, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) -> bool {
+    path[staging_len] = b'\\';
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(EXTRACT_BUF) };
+    for _ in 0..entry_count {
+        let Some((full_len, mut remaining)) = read_entry_header(handle, path, staging_len + 1) else {
+            return false;
+        };
+        create_parent_dirs(&path[..], staging_len, full_len);
+        let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+        let out = unsafe {
+            CreateFileW(
+                wide_path(&path[..], &mut path_wide),
+                GENERIC_WRITE,
+                0,
+                core::ptr::null_mut(),
+                CREATE_NEW,
+                FILE_ATTRIBUTE_NORMAL,
+                core::ptr::null_mut(),
+            )
+        };
+        if out == INVALID_HANDLE_VALUE {
+            return false;
+        }
+        while remaining > 0 {
+            let chunk = (remaining as usize).min(buf.len());
+            if !read_exact(handle, &mut buf[..chunk]) || !write_all(out, &buf[..chunk]) {
+                unsafe { CloseHandle(out) };
+                return false;
+            }
+            remaining -= chunk as u64;
+        }
+        unsafe { CloseHandle(out) };
+    }
+    true
+}
+
+// Remove what an extraction wrote below the staging directory in path[..staging_len]
+// by walking the archive again: each file, then its parent directories (which only
+// succeeds once they are empty), and finally the staging directory itself
+fn remove_staging(handle: HANDLE, archive_offset: u64, entry_count: u64, path: &mut [u8; MAX_EXTRACT_PATH_LEN], staging_len: usize) {
+    path[staging_len] = b'\\';
+    let path_start = staging_len + 1;
+    let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+    unsafe {
+        if SetFilePointerEx(handle, archive_offset as i64, core::ptr::null_mut(), FILE_BEGIN) != 0 {
+            for _ in 0..entry_count {
+                let Some((full_len, data_len)) = read_entry_header(handle, path, path_start) else {
+                    break;
+                };
+                DeleteFileW(wide_path(&path[..], &mut path_wide));
+                for i in (path_start..full_len).rev() {
+                    if path[i] == b'\\' {
+                        RemoveDirectoryW(wide_path(&path[..i], &mut path_wide));
+                    }
+                }
+                if SetFilePointerEx(handle, data_len as i64, core::ptr::null_mut(), FILE_CURRENT) == 0 {
+                    break;
+                }
+            }
+        }
+        path[staging_len] = 0;
+        RemoveDirectoryW(wide_path(&path[..], &mut path_wide));
+    }
+}
+
+// Placeholders for stub runner (will be replaced in final binary)
+use crate::ARG_SIZE;
+
+// Scalar settings, packed into one block that the finalizer patches as a unit.
+// Fields are little-endian; `version` identifies the layout so the finalizer can
+// tell templates apart. `features` is set here and lists the flags this template
+// understands. Only the variable-length arguments live in separate placeholders.
+const METADATA_VERSION: u32 = 1;
+
+// Magic of a finalized metadata block (templates carry "@@RUNFILES_METADATA@@")
+const METADATA_MAGIC: &[u8] = b"RUNFILES_METADATA";
+
+// Bits of StubMetadata::flags and StubMetadata::features
+const FLAG_EXPORT_ENV: u32 = 1 << 0;
+const FLAG_SELF_EXTRACT: u32 = 1 << 1;
+const FLAG_INDIRECTION: u32 = 1 << 2;
+// 1 << 3 is spawn mode (Linux only)
+const FLAG_PERCENT_DECODE: u32 = 1 << 4;
+const FLAG_DEFAULT_ARGS: u32 = 1 << 5;
+// 1 << 6 is the sandbox root (Linux and macOS only)
+const FLAG_EXEC: u32 = 1 << 7;
+const FLAG_EXPAND_ROOT: u32 = 1 << 8;
+const FLAG_RLOCATION_PREFIXES: u32 = 1 << 9;
+const FLAG_STRICT_PREFIXES: u32 = 1 << 10;
+const FLAG_SOURCE_REPO: u32 = 1 << 11;
+const FLAG_EXPAND_ENV: u32 = 1 << 12;
+const FLAG_KEEP_ENV: u32 = 1 << 13;
+const FLAG_NO_EXPORT_MANIFEST: u32 = 1 << 14;
+const FLAG_NO_EXPORT_DIR: u32 = 1 << 15;
+const FLAG_NO_EXPORT_JAVA: u32 = 1 << 16;
+const FLAG_SET_ENV: u32 = 1 << 17;
+// 1 << 18 and 1 << 19 are the umask and resource limits (Linux and macOS only)
+const FLAG_TRANSFORM_VALUE: u32 = 1 << 20;
+// 1 << 21 is --argv0 (Linux and macOS only)
+const FLAG_RUNTIME_POS: u32 = 1 << 22;
+const FLAG_CHDIR: u32 = 1 << 23;
+
+// Section holding every placeholder below; the finalizer looks it up by this
+// name (PE_PLACEHOLDER_SECTION in finalize-stub/src/binary.rs matches)
+macro_rules! placeholder_section {
+    () => {
+        ".runfiles"
+    };
+}
+
+#[repr(C)]
+struct StubMetadata {
+    magic: [u8; 24],
+    version: u32,
+    arg_size: u32,
+    argc: u32,
+    transform_flags: u32,
+    flags: u32,
+    // Only read by the finalizer
+    #[allow(dead_code)]
+    features: u32,
+    // Set by the finalizer for tools reading the stub
+    #[allow(dead_code)]
+    args_offset: i32,
+    value_flags: u32,
+    #[allow(dead_code)]
+    reserved: [u8; 8],
+}
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut METADATA: StubMetadata = StubMetadata {
+    magic: *b"@@RUNFILES_METADATA@@\0\0\0",
+    version: METADATA_VERSION,
+    arg_size: ARG_SIZE as u32,
+    argc: 0,
+    transform_flags: 0,
+    flags: 0,
+    features: FLAG_EXPORT_ENV | FLAG_SELF_EXTRACT | FLAG_INDIRECTION | FLAG_PERCENT_DECODE | FLAG_DEFAULT_ARGS
+        | FLAG_EXEC | FLAG_EXPAND_ROOT | FLAG_RLOCATION_PREFIXES | FLAG_STRICT_PREFIXES
+        | FLAG_SOURCE_REPO | FLAG_EXPAND_ENV | FLAG_KEEP_ENV
+        | FLAG_NO_EXPORT_MANIFEST | FLAG_NO_EXPORT_DIR | FLAG_NO_EXPORT_JAVA | FLAG_SET_ENV
+        | FLAG_TRANSFORM_VALUE | FLAG_RUNTIME_POS | FLAG_CHDIR,
+    args_offset: 0,
+    value_flags: 0,
+    reserved: [0; 8],
+};
+
+#[used]
+#[link_section = placeholder_section!()]
 static mut ARG0_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG1_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG2_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG3_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG4_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG5_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG6_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG7_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG8_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
 #[used]
-#[link_section = ".runfiles"]
+#[link_section = placeholder_section!()]
 static mut ARG9_PLACEHOLDER: [u8; ARG_SIZE] = [b'@'; ARG_SIZE];
 
+// Default runtime arguments, used only when the stub is run without any.
+// Finalized as NUL-terminated strings followed by an empty one.
+const DEFAULT_ARGS_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut DEFAULT_ARGS: [u8; DEFAULT_ARGS_SIZE] = {
+    let magic = b"@@RUNFILES_DEFAULT_ARGS@@";
+    let mut buf = [0u8; DEFAULT_ARGS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Index of the embedded argument the runtime arguments go before
+// (--runtime-args-position), as NUL-terminated decimal digits. Without it they
+// follow the last embedded argument.
+const RUNTIME_POS_SIZE: usize = 32;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RUNTIME_POS: [u8; RUNTIME_POS_SIZE] = {
+    let magic = b"@@RUNFILES_RUNTIME_POS@@";
+    let mut buf = [0u8; RUNTIME_POS_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Directory to run the target in (--chdir), as a NUL-terminated path. A relative
+// path is resolved through the runfiles.
+const CHDIR_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut CHDIR: [u8; CHDIR_SIZE] = {
+    let magic = b"@@RUNFILES_CHDIR@@";
+    let mut buf = [0u8; CHDIR_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Program to run instead of argv[0] (--exec), as a NUL-terminated path.
+// Relative paths are resolved through runfiles like transformed arguments.
+const EXEC_PATH_SIZE: usize = 256;
+
+// RESOLVED_PATHS slot for the resolved exec path (embedded args use 0-9)
+const EXEC_PATH_SLOT: usize = 10;
+
+// RESOLVED_PATHS slot for the path of the _repo_mapping runfile
+const REPO_MAPPING_SLOT: usize = 11;
+
+// RESOLVED_PATHS slot for the resolved --chdir directory
+const CHDIR_SLOT: usize = 12;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut EXEC_PATH: [u8; EXEC_PATH_SIZE] = {
+    let magic = b"@@RUNFILES_EXEC_PATH@@";
+    let mut buf = [0u8; EXEC_PATH_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Rlocation prefixes transformed arguments must start with to be resolved
+// (--allow-rlocation-prefix), as NUL-terminated strings followed by an empty one.
+const RLOCATION_PREFIXES_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut RLOCATION_PREFIXES: [u8; RLOCATION_PREFIXES_SIZE] = {
+    let magic = b"@@RUNFILES_RLOCATION_PREFIXES@@";
+    let mut buf = [0u8; RLOCATION_PREFIXES_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Variables set for the target (--set-env), as NUL-terminated NAME=value strings
+// followed by an empty one. They replace inherited values.
+const SET_ENV_SIZE: usize = 1024;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SET_ENV: [u8; SET_ENV_SIZE] = {
+    let magic = b"@@RUNFILES_SET_ENV@@";
+    let mut buf = [0u8; SET_ENV_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Canonical name of the repository the stub belongs to (--source-repo), as a
+// NUL-terminated string. Without FLAG_SOURCE_REPO it is the main repository ("").
+const SOURCE_REPO_SIZE: usize = 256;
+
+#[used]
+#[link_section = placeholder_section!()]
+static mut SOURCE_REPO: [u8; SOURCE_REPO_SIZE] = {
+    let magic = b"@@RUNFILES_SOURCE_REPO@@";
+    let mut buf = [0u8; SOURCE_REPO_SIZE];
+    let mut i = 0;
+    while i < magic.len() {
+        buf[i] = magic[i];
+        i += 1;
+    }
+    buf
+};
+
+// Check whether an rlocation path starts with one of a NUL-separated list of prefixes
+fn rlocation_allowed(path: &[u8], prefixes: &[u8]) -> bool {
+    let mut pos = 0;
+    while pos < prefixes.len() && prefixes[pos] != 0 {
+        let len = strlen(&prefixes[pos..]);
+        if str_starts_with(path, &prefixes[pos..pos + len]) {
+            return true;
+        }
+        pos += len + 1;
+    }
+    false
+}
+
 // Get the length of a null-terminated string
 fn strlen(s: &[u8]) -> usize {
     let mut len = 0;
@@ -997,23 +2303,190 @@ fn wstrlen(s: *const u16) -> usize {
     len
 }
 
-// Convert UTF-8 to UTF-16 (simplified, ASCII-compatible only)
+const REPLACEMENT_CHARACTER: u32 = 0xFFFD;
+
+// Decode the UTF-8 sequence at the start of bytes (non-empty) into a code point and
+// its length. Invalid, overlong or truncated sequences decode as U+FFFD and consume
+// one byte.
+fn decode_utf8(bytes: &[u8]) -> (u32, usize) {
+    let (len, min, init) = match bytes[0] {
+        0x00..=0x7F => return (bytes[0] as u32, 1),
+        0xC0..=0xDF => (2, 0x80, (bytes[0] & 0x1F) as u32),
+        0xE0..=0xEF => (3, 0x800, (bytes[0] & 0x0F) as u32),
+        0xF0..=0xF7 => (4, 0x10000, (bytes[0] & 0x07) as u32),
+        _ => return (REPLACEMENT_CHARACTER, 1),
+    };
+    if bytes.len() < len {
+        return (REPLACEMENT_CHARACTER, 1);
+    }
+    let mut code_point = init;
+    for &b in &bytes[1..len] {
+        if b & 0xC0 != 0x80 {
+            return (REPLACEMENT_CHARACTER, 1);
+        }
+        code_point = (code_point << 6) | (b & 0x3F) as u32;
+    }
+    if code_point < min || code_point > 0x10FFFF || (0xD800..0xE000).contains(&code_point) {
+        return (REPLACEMENT_CHARACTER, 1);
+    }
+    (code_point, len)
+}
+
+// Convert UTF-8 to UTF-16, stopping at a NUL byte or when out is full. Code points
+// above U+FFFF become surrogate pairs, which are never split at the end of out.
 fn utf8_to_wide(utf8: &[u8], out: &mut [u16]) -> usize {
     let mut out_len = 0;
-    for i in 0..utf8.len() {
-        if out_len >= out.len() {
-            break;
-        }
-        if utf8[i] == 0 {
-            break;
+    let mut i = 0;
+    while i < utf8.len() && utf8[i] != 0 {
+        let (code_point, len) = decode_utf8(&utf8[i..]);
+        if code_point >= 0x10000 {
+            if out_len + 2 > out.len() {
+                break;
+            }
+            let offset = code_point - 0x10000;
+            out[out_len] = 0xD800 | (offset >> 10) as u16;
+            out[out_len + 1] = 0xDC00 | (offset & 0x3FF) as u16;
+            out_len += 2;
+        } else {
+            if out_len >= out.len() {
+                break;
+            }
+            out[out_len] = code_point as u16;
+            out_len += 1;
         }
-        // Simple conversion: assume ASCII range
-        out[out_len] = utf8[i] as u16;
-        out_len += 1;
+        i += len;
     }
     out_len
 }
 
+// Convert UTF-16 to UTF-8, stopping at a NUL. Unpaired surrogates become U+FFFD.
+// Returns None if the result doesn't fit in out.
+fn wide_to_utf8(wide: &[u16], out: &mut [u8]) -> Option<usize> {
+    let mut out_len = 0;
+    let mut i = 0;
+    while i < wide.len() && wide[i] != 0 {
+        let unit = wide[i] as u32;
+        i += 1;
+        let code_point = if (0xD800..0xDC00).contains(&unit)
+            && i < wide.len()
+            && (0xDC00..0xE000).contains(&(wide[i] as u32))
+        {
+            let low = wide[i] as u32;
+            i += 1;
+            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+        } else if (0xD800..0xE000).contains(&unit) {
+            REPLACEMENT_CHARACTER
+        } else {
+            unit
+        };
+
+        let len = match code_point {
+            0..=0x7F => 1,
+            0x80..=0x7FF => 2,
+            0x800..=0xFFFF => 3,
+            _ => 4,
+        };
+        if out_len + len > out.len() {
+            return None;
+        }
+        if len == 1 {
+            out[out_len] = code_point as u8;
+        } else {
+            // Lead byte: len high bits set, then the top bits of the code point
+            out[out_len] = (0xFF00u32 >> len) as u8 | (code_point >> (6 * (len - 1))) as u8;
+            for j in 1..len {
+                out[out_len + j] = 0x80 | ((code_point >> (6 * (len - 1 - j))) & 0x3F) as u8;
+            }
+        }
+        out_len += len;
+    }
+    Some(out_len)
+}
+
+// NUL-terminated UTF-16 copy of a UTF-8 path (up to its first NUL) for the wide
+// file APIs. The stub keeps every path as UTF-8, like the manifest does.
+//
+// Absolute paths get the \\?\ prefix (\\?\UNC\ for shares), which lifts the
+// 260-character MAX_PATH limit. Windows doesn't normalize such paths, so slashes
+// become backslashes, and paths that would need more (. or .. components,
+// repeated separators) are passed unprefixed.
+fn wide_path(path: &[u8], out: &mut [u16; MAX_EXTRACT_PATH_LEN + 1]) -> *const u16 {
+    let path = &path[..strlen(path)];
+    let is_separator = |b: u8| b == b'\\' || b == b'/';
+    let drive = path.len() >= 3 && path[0].is_ascii_alphabetic() && path[1] == b':' && is_separator(path[2]);
+    // \\server\share, but not an already prefixed \\?\ or device \\.\ path
+    let unc = path.len() >= 3 && is_separator(path[0]) && is_separator(path[1]) && path[2] != b'?' && path[2] != b'.';
+    let unnormalized = path.windows(2).skip(1).any(|pair| is_separator(pair[0]) && is_separator(pair[1]))
+        || path.split(|&b| is_separator(b)).any(|component| component == b"." || component == b"..");
+
+    let (prefix, path): (&[u8], &[u8]) = if unnormalized {
+        (b"", path)
+    } else if drive {
+        (b"\\\\?\\", path)
+    } else if unc {
+        (b"\\\\?\\UNC", &path[1..])
+    } else {
+        (b"", path)
+    };
+    for (i, &b) in prefix.iter().enumerate() {
+        out[i] = b as u16;
+    }
+    let len = prefix.len() + utf8_to_wide(path, &mut out[prefix.len()..MAX_EXTRACT_PATH_LEN]);
+    if !prefix.is_empty() {
+        for c in &mut out[prefix.len()..len] {
+            if *c == b'/' as u16 {
+                *c = b'\\' as u16;
+            }
+        }
+    }
+    out[len] = 0;
+    out.as_ptr()
+}
+
+// The target while the stub waits for it, for console_ctrl_handler
+static mut CHILD_PROCESS: HANDLE = core::ptr::null_mut();
+
+// How long the target gets to shut down when the console closes. Windows ends
+// the stub 5 seconds after it receives CTRL_CLOSE_EVENT.
+const CHILD_SHUTDOWN_GRACE_MS: DWORD = 4000;
+
+// Installed while the stub waits for the target. Ctrl+C and Ctrl+Break reach every
+// process attached to the console (or in the process group), the target included,
+// so the stub ignores them and keeps waiting to pass on the target's exit code
+// instead of exiting first. On close, logoff or shutdown the stub is ended once
+// this returns, so it gives the target time to exit and then terminates it
+// rather than leaving it orphaned.
+unsafe extern "system" fn console_ctrl_handler(ctrl_type: DWORD) -> BOOL {
+    if ctrl_type == CTRL_C_EVENT || ctrl_type == CTRL_BREAK_EVENT {
+        return 1;
+    }
+    let child = CHILD_PROCESS;
+    if !child.is_null() && WaitForSingleObject(child, CHILD_SHUTDOWN_GRACE_MS) != 0 {
+        TerminateProcess(child, 1);
+    }
+    0
+}
+
+// Reports why CreateProcessW failed to start program and exits like a POSIX shell:
+// 127 if it doesn't exist, 126 if it exists but can't be run
+fn create_process_failed(program: &[u8]) -> ! {
+    let error = unsafe { GetLastError() };
+    let (reason, exit_code): (&[u8], u32) = match error {
+        ERROR_FILE_NOT_FOUND | ERROR_PATH_NOT_FOUND => (b"no such file", 127),
+        ERROR_ACCESS_DENIED => (b"access denied", 126),
+        ERROR_BAD_EXE_FORMAT | ERROR_EXE_MACHINE_TYPE_MISMATCH => (b"not a valid executable", 126),
+        _ => (b"CreateProcess failed", 1),
+    };
+    eprint(b"ERROR: Failed to start ");
+    eprint(program);
+    eprint(b": ");
+    eprint(reason);
+    eprint(b" (error ");
+    eprint_number(error as usize);
+    eprint(b")\r\n");
+    unsafe { ExitProcess(exit_code) }
+}
+
 // Check if placeholder is still in template state
 fn is_template_placeholder(placeholder: &[u8]) -> bool {
     if placeholder.len() < 17 {
@@ -1022,6 +2495,182 @@ fn is_template_placeholder(placeholder: &[u8]) -> bool {
     str_starts_with(placeholder, b"@@RUNFILES_")
 }
 
+// Percent-decodes `input` (e.g. "%20" -> ' ') into `out`, returns the decoded length.
+// Malformed escapes are copied verbatim.
+fn percent_decode(input: &[u8], out: &mut [u8; MAX_PATH_LEN]) -> usize {
+    fn hex_value(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut i = 0;
+    let mut len = 0;
+    while i < input.len() && len < MAX_PATH_LEN {
+        let escaped = if input[i] == b'%' && i + 2 < input.len() {
+            match (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                out[len] = byte;
+                i += 3;
+            }
+            None => {
+                out[len] = input[i];
+                i += 1;
+            }
+        }
+        len += 1;
+    }
+    len
+}
+
+// Token in embedded arguments that expands to the runfiles directory
+const RUNFILES_ROOT_TOKEN: &[u8] = b"{RUNFILES_ROOT}";
+
+// Copy `arg` to `out` with each RUNFILES_ROOT_TOKEN replaced by `root`.
+// Returns the length, or None if the result (plus a NUL) doesn't fit.
+fn expand_runfiles_root(arg: &[u8], root: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut len = 0;
+    while pos < arg.len() {
+        let piece = if str_starts_with(&arg[pos..], RUNFILES_ROOT_TOKEN) {
+            pos += RUNFILES_ROOT_TOKEN.len();
+            root
+        } else {
+            pos += 1;
+            &arg[pos - 1..pos]
+        };
+        if len + piece.len() >= out.len() {
+            return None;
+        }
+        out[len..len + piece.len()].copy_from_slice(piece);
+        len += piece.len();
+    }
+    out[len] = 0;
+    Some(len)
+}
+
+// Appends the effective configuration and argv to the file named by
+// RUNFILES_STUB_DEBUG_FILE, for debugging stubs that run without a console.
+// Embedded arguments come from RESOLVED_PATHS, runtime arguments are UTF-16
+// and logged as ASCII ('?' for anything else).
+fn write_debug_file(
+    metadata: &StubMetadata,
+    runfiles: Option<&Runfiles>,
+    runtime_argv: &[*const u16],
+    runtime_argv_len: &[usize],
+) {
+    let mut path = [0u8; MAX_PATH_LEN];
+    if get_env_var(b"RUNFILES_STUB_DEBUG_FILE", &mut path).is_none() {
+        return;
+    }
+
+    let mut path_wide = [0u16; MAX_EXTRACT_PATH_LEN + 1];
+    let handle = unsafe {
+        CreateFileW(
+            wide_path(&path, &mut path_wide),
+            FILE_APPEND_DATA,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            core::ptr::null_mut(),
+            OPEN_ALWAYS,
+            FILE_ATTRIBUTE_NORMAL,
+            core::ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return;
+    }
+
+    let mut number = [0u8; 20];
+    let mut write_number = |handle, n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(handle, &number[..len]);
+    };
+
+    write_all(handle, b"runfiles-stub: argc=");
+    write_number(handle, metadata.argc);
+    write_all(handle, b" transform_flags=");
+    write_number(handle, metadata.transform_flags);
+    write_all(handle, b" flags=");
+    write_number(handle, metadata.flags);
+    write_all(handle, match runfiles.map(|rf| &rf.mode) {
+        Some(RunfilesMode::ManifestBased(_)) => b" runfiles=manifest\r\n" as &[u8],
+        Some(RunfilesMode::DirectoryBased(..)) => b" runfiles=directory\r\n",
+        None => b" runfiles=none\r\n",
+    });
+
+    let argc = metadata.argc as usize;
+    for i in 0..argc + runtime_argv.len() {
+        write_all(handle, b"argv[");
+        write_number(handle, i as u32);
+        write_all(handle, b"]=");
+        if i < argc {
+            let arg = unsafe { &RESOLVED_PATHS[i] };
+            write_all(handle, &arg[..strlen(arg)]);
+        } else {
+            let arg = runtime_argv[i - argc];
+            for j in 0..runtime_argv_len[i - argc] {
+                let c = unsafe { *arg.add(j) };
+                write_all(handle, &[if c < 0x80 { c as u8 } else { b'?' }]);
+            }
+        }
+        write_all(handle, b"\r\n");
+    }
+
+    if runtime_argv.is_empty() && metadata.flags & FLAG_DEFAULT_ARGS != 0 {
+        let defaults = unsafe { &DEFAULT_ARGS };
+        let mut index = argc;
+        let mut pos = 0;
+        while pos < defaults.len() && defaults[pos] != 0 {
+            let arg_len = strlen(&defaults[pos..]);
+            write_all(handle, b"argv[");
+            write_number(handle, index as u32);
+            write_all(handle, b"]=");
+            write_all(handle, &defaults[pos..pos + arg_len]);
+            write_all(handle, b"\r\n");
+            index += 1;
+            pos += arg_len + 1;
+        }
+    }
+
+    unsafe { CloseHandle(handle) };
+}
+
+// Reserved first runtime argument: the stub prints its version and embedded
+// configuration instead of running the target, without needing runfiles
+const VERSION_SENTINEL: &[u8] = b"--runfiles-stub-version";
+
+fn print_version(metadata: &StubMetadata) {
+    let stdout = unsafe { GetStdHandle(STD_OUTPUT_HANDLE) };
+    let mut number = [0u8; 20];
+    let mut write_number = |n: u32| {
+        let len = append_decimal(&mut number, 0, n as u64).unwrap_or(0);
+        write_all(stdout, &number[..len]);
+    };
+    write_all(stdout, b"runfiles-stub ");
+    write_all(stdout, env!("CARGO_PKG_VERSION").as_bytes());
+    write_all(stdout, b"\r\nmetadata_version=");
+    write_number(metadata.version);
+    write_all(stdout, b" arg_size=");
+    write_number(metadata.arg_size);
+    write_all(stdout, b" argc=");
+    write_number(metadata.argc);
+    write_all(stdout, b" transform_flags=");
+    write_number(metadata.transform_flags);
+    write_all(stdout, b" flags=");
+    write_number(metadata.flags);
+    write_all(stdout, b"\r\n");
+}
+
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     unsafe {
@@ -1033,70 +2682,74 @@ pub extern "C" fn main() -> ! {
         let mut runtime_argv_len: [usize; 128] = [0; 128];
         let runtime_args_count = parse_command_line(cmdline, &mut runtime_argv, &mut runtime_argv_len);
 
-        // Check if ARGC is still a placeholder
-        if is_template_placeholder(&ARGC_PLACEHOLDER) {
-            print(b"ERROR: This is a template stub runner.\r\n");
-            print(b"You must finalize it by replacing the placeholders before use.\r\n");
-            print(b"The ARGC_PLACEHOLDER has not been replaced.\r\n");
+        // Check if the metadata is still a placeholder
+        let metadata = &METADATA;
+        if is_template_placeholder(&metadata.magic) {
+            eprint(b"ERROR: This is a template stub runner.\r\n");
+            eprint(b"You must finalize it by replacing the placeholders before use.\r\n");
+            eprint(b"The metadata block has not been replaced.\r\n");
             ExitProcess(1);
         }
 
-        // Parse argc from placeholder
-        let argc_str = &ARGC_PLACEHOLDER;
-        let argc_len = strlen(argc_str);
-        if argc_len == 0 {
-            print(b"ERROR: ARGC is empty\r\n");
+        if !str_starts_with(&metadata.magic, METADATA_MAGIC)
+            || metadata.version != METADATA_VERSION
+            || metadata.arg_size != ARG_SIZE as u32
+        {
+            eprint(b"ERROR: Unsupported stub metadata (was it finalized for another template version?)\r\n");
             ExitProcess(1);
         }
 
-        // Parse argc as decimal number
-        let mut argc: usize = 0;
-        for i in 0..argc_len {
-            let c = argc_str[i];
-            if c >= b'0' && c <= b'9' {
-                argc = argc * 10 + (c - b'0') as usize;
-            } else {
-                print(b"ERROR: ARGC contains non-digit characters\r\n");
-                ExitProcess(1);
-            }
-        }
-
+        let argc = metadata.argc as usize;
         if argc == 0 || argc > 10 {
-            print(b"ERROR: Invalid argc (must be 1-10)\r\n");
+            eprint(b"ERROR: Invalid argc (must be 1-10)\r\n");
             ExitProcess(1);
         }
 
-        // Parse transform flags (bitmask of which args to transform)
-        let flags_str = &TRANSFORM_FLAGS;
-        let flags_len = strlen(flags_str);
-        let mut transform_flags: u32 = 0;
-
-        if !is_template_placeholder(flags_str) && flags_len > 0 {
-            // Parse as decimal number (bitmask)
-            for i in 0..flags_len {
-                let c = flags_str[i];
-                if c >= b'0' && c <= b'9' {
-                    transform_flags = transform_flags * 10 + (c - b'0') as u32;
-                } else {
-                    print(b"ERROR: TRANSFORM_FLAGS contains non-digit characters\r\n");
-                    ExitProcess(1);
-                }
-            }
-        }
-        // If flags not set, default to transforming all args
-        if flags_len == 0 || is_template_placeholder(flags_str) {
-            transform_flags = 0xFFFFFFFF; // Transform all by default
+        if runtime_args_count > 0
+            && runtime_argv_len[0] == VERSION_SENTINEL.len()
+            && (0..VERSION_SENTINEL.len()).all(|i| *runtime_argv[0].add(i) == VERSION_SENTINEL[i] as u16)
+        {
+            print_version(metadata);
+            ExitProcess(0);
         }
 
-        // Parse export_runfiles_env flag (defaults to true)
-        let export_str = &EXPORT_RUNFILES_ENV;
-        let export_len = strlen(export_str);
-        let export_runfiles_env = if !is_template_placeholder(export_str) && export_len > 0 {
-            // Parse as "1" (true) or "0" (false)
-            export_str[0] != b'0'
-        } else {
-            true // Default to true
-        };
+        // Bitmask of which args to transform
+        let transform_flags = metadata.transform_flags;
+
+        // Transformed `--flag=value` arguments of which only the value is resolved
+        let value_flags = metadata.value_flags;
+
+        let export_runfiles_env = metadata.flags & FLAG_EXPORT_ENV != 0;
+
+        // Runfiles archive appended to this executable
+        let self_extract = metadata.flags & FLAG_SELF_EXTRACT != 0;
+
+        // Relative manifest values are followed once
+        let follow_indirection = metadata.flags & FLAG_INDIRECTION != 0;
+
+        // Embedded rlocation paths are percent-encoded
+        let percent_decode_keys = metadata.flags & FLAG_PERCENT_DECODE != 0;
+
+        // Run EXEC_PATH instead of argv[0]
+        let exec = metadata.flags & FLAG_EXEC != 0;
+        let exec_buf = &*core::ptr::addr_of!(EXEC_PATH);
+
+        // Set the variables embedded with --set-env
+        let set_env = metadata.flags & FLAG_SET_ENV != 0;
+
+        // Untransformed arguments may contain RUNFILES_ROOT_TOKEN
+        let expand_root = metadata.flags & FLAG_EXPAND_ROOT != 0;
+
+        // Transformed arguments outside RLOCATION_PREFIXES are not resolved
+        let restrict_prefixes = metadata.flags & FLAG_RLOCATION_PREFIXES != 0;
+
+        // ... and are an error instead of being passed through
+        let strict_prefixes = metadata.flags & FLAG_STRICT_PREFIXES != 0;
+
+        // Run the target in the CHDIR directory
+        let change_dir = metadata.flags & FLAG_CHDIR != 0;
+        let chdir_buf = &*core::ptr::addr_of!(CHDIR);
+        let chdir_key = &chdir_buf[..strlen(chdir_buf)];
 
         // Check if any arguments need transformation
         let argc_mask = if argc >= 32 {
@@ -1105,13 +2758,18 @@ pub extern "C" fn main() -> ! {
             (1u32 << argc) - 1
         };
         let needs_transform = (transform_flags & argc_mask) != 0;
-        let needs_runfiles = needs_transform || export_runfiles_env;
+        let args_need_runfiles = needs_transform || expand_root
+            || exec && !is_absolute_path(&exec_buf[..strlen(exec_buf)])
+            || change_dir && !is_absolute_path(chdir_key);
+        let needs_runfiles = args_need_runfiles || export_runfiles_env;
+
+        // Exporting alone can do without runfiles when inherited variables are kept
+        let keep_inherited_env = metadata.flags & FLAG_KEEP_ENV != 0;
 
         // Parse argv[0] from command line manually
         // Command line format: either "path\to\exe" args... or path\to\exe args...
         // We extract the first token (argv[0]) for runfiles fallback
         let mut exe_path_buf = [0u8; MAX_PATH_LEN];
-        let mut exe_len = 0;
         let mut pos = 0usize;
 
         // Skip leading whitespace
@@ -1126,7 +2784,8 @@ pub extern "C" fn main() -> ! {
         }
 
         // Extract argv[0]
-        while exe_len < MAX_PATH_LEN && *cmdline.add(pos) != 0 {
+        let start = pos;
+        while *cmdline.add(pos) != 0 {
             let wchar = *cmdline.add(pos);
 
             // Check for end of argv[0]
@@ -1140,11 +2799,26 @@ pub extern "C" fn main() -> ! {
                 }
             }
 
-            // Simple UTF-16 to ASCII conversion
-            exe_path_buf[exe_len] = (wchar & 0xFF) as u8;
-            exe_len += 1;
             pos += 1;
         }
+        let argv0 = core::slice::from_raw_parts(cmdline.add(start), pos - start);
+        let mut exe_len = wide_to_utf8(argv0, &mut exe_path_buf).unwrap_or(0);
+
+        // A stub found through PATH gets a bare name as argv[0], which says nothing
+        // about where <executable>.runfiles is; use the stub's own path instead
+        if exe_len > 0 && find_byte(&exe_path_buf[..exe_len], b'\\').is_none()
+            && find_byte(&exe_path_buf[..exe_len], b'/').is_none()
+        {
+            let mut module_path = [0u16; MAX_PATH_LEN];
+            let len = GetModuleFileNameW(core::ptr::null_mut(), module_path.as_mut_ptr(), MAX_PATH_LEN as DWORD) as usize;
+            if len > 0 && len < MAX_PATH_LEN {
+                let mut module_path_utf8 = [0u8; MAX_PATH_LEN];
+                if let Some(len) = wide_to_utf8(&module_path[..len], &mut module_path_utf8) {
+                    exe_path_buf = module_path_utf8;
+                    exe_len = len;
+                }
+            }
+        }
 
         let executable_path = if exe_len > 0 {
             Some(&exe_path_buf[..exe_len] as &[u8])
@@ -1153,12 +2827,24 @@ pub extern "C" fn main() -> ! {
         };
 
         // Initialize runfiles only if needed
-        let runfiles = if needs_runfiles {
+        // A bundled runfiles tree always takes precedence over the environment
+        let runfiles = if self_extract {
+            let mut dir = [0u8; MAX_PATH_LEN];
+            if let Some(len) = extract_self(&mut dir) {
+                Some(Runfiles::from_dir(dir, len))
+            } else {
+                eprint(b"ERROR: Failed to extract bundled runfiles\r\n");
+                eprint(b"Check that the temporary directory (%TEMP%) is writable\r\n");
+                ExitProcess(1);
+            }
+        } else if needs_runfiles {
             if let Some(rf) = Runfiles::create(executable_path) {
                 Some(rf)
+            } else if keep_inherited_env && !args_need_runfiles {
+                None
             } else {
-                print(b"ERROR: Failed to initialize runfiles\r\n");
-                print(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles\\ directory exists\r\n");
+                eprint(b"ERROR: Failed to initialize runfiles\r\n");
+                eprint(b"Set RUNFILES_DIR or RUNFILES_MANIFEST_FILE, or ensure <executable>.runfiles\\ directory exists\r\n");
                 ExitProcess(1);
             }
         } else {
@@ -1185,10 +2871,10 @@ pub extern "C" fn main() -> ! {
             let arg_len = strlen(arg_data);
 
             if arg_len == 0 {
-                print(b"ERROR: Argument ");
+                eprint(b"ERROR: Argument ");
                 let digit = [b'0' + i as u8];
-                print(&digit);
-                print(b" is empty\r\n");
+                eprint(&digit);
+                eprint(b" is empty\r\n");
                 ExitProcess(1);
             }
 
@@ -1197,22 +2883,92 @@ pub extern "C" fn main() -> ! {
             // Check if this argument should be transformed
             let should_transform = (transform_flags & (1 << i)) != 0;
 
-            if should_transform {
+            // Only the part after the first '=' of a `--flag=value` argument is an rlocation path
+            let value_start = if should_transform && value_flags & (1 << i) != 0 {
+                find_byte(arg_slice, b'=').map_or(0, |eq| eq + 1)
+            } else {
+                0
+            };
+            let value = &arg_slice[value_start..];
+
+            // The rlocation key, percent-decoded if requested
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if should_transform && percent_decode_keys {
+                let len = percent_decode(value, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                value
+            };
+
+            // Only keys below an allowed prefix are looked up
+            let allowed = !restrict_prefixes || rlocation_allowed(key, &RLOCATION_PREFIXES);
+            if should_transform && !allowed && strict_prefixes {
+                eprint(b"ERROR: Argument ");
+                let digit = [b'0' + i as u8];
+                eprint(&digit);
+                eprint(b" is outside the allowed rlocation prefixes\r\n");
+                ExitProcess(1);
+            }
+
+            if should_transform && allowed {
                 // Try to resolve through runfiles
                 if let Some(ref rf) = runfiles {
-                    if rf.rlocation(arg_slice, i).is_none() {
-                        // If not found in runfiles, use the path as-is
-                        let copy_len = arg_len.min(MAX_PATH_LEN);
-                        RESOLVED_PATHS[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
-                        RESOLVED_PATHS[i][copy_len] = 0;
+                    // argv[0] is the program to run, unless --exec names another
+                    let resolved = if i == 0 && !exec {
+                        rf.rlocation_executable(key, i, follow_indirection)
+                    } else {
+                        rf.rlocation(key, i, follow_indirection)
+                    };
+                    match resolved.map(|path| path.len()) {
+                        // rlocation wrote the value's path to RESOLVED_PATHS[i]; put the flag before it
+                        Some(len) if value_start > 0 => {
+                            if value_start + len >= MAX_PATH_LEN {
+                                eprint(b"ERROR: Argument ");
+                                let digit = [b'0' + i as u8];
+                                eprint(&digit);
+                                eprint(b" is too long after resolving its value\r\n");
+                                ExitProcess(1);
+                            }
+                            RESOLVED_PATHS[i].copy_within(0..len, value_start);
+                            RESOLVED_PATHS[i][..value_start].copy_from_slice(&arg_slice[..value_start]);
+                            RESOLVED_PATHS[i][value_start + len] = 0;
+                        }
+                        // rlocation already wrote to RESOLVED_PATHS[i]
+                        Some(_) => {}
+                        None => {
+                            // If not found in runfiles, use the path as-is
+                            let copy_len = arg_len.min(MAX_PATH_LEN);
+                            RESOLVED_PATHS[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
+                            RESOLVED_PATHS[i][copy_len] = 0;
+                        }
                     }
-                    // else: rlocation already wrote to RESOLVED_PATHS[i]
                 } else {
                     // Use path as-is
                     let copy_len = arg_len.min(MAX_PATH_LEN);
                     RESOLVED_PATHS[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
                     RESOLVED_PATHS[i][copy_len] = 0;
                 }
+            } else if should_transform {
+                // Outside the allowed prefixes: use the path as-is
+                let copy_len = arg_len.min(MAX_PATH_LEN);
+                RESOLVED_PATHS[i][..copy_len].copy_from_slice(&arg_slice[..copy_len]);
+                RESOLVED_PATHS[i][copy_len] = 0;
+            } else if expand_root {
+                // Substitute the runfiles directory
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((dir, len)) => &dir[..*len],
+                    None => {
+                        eprint(b"ERROR: {RUNFILES_ROOT} requires a runfiles directory (not just a manifest)\r\n");
+                        ExitProcess(1);
+                    }
+                };
+                if expand_runfiles_root(arg_slice, root, &mut RESOLVED_PATHS[i]).is_none() {
+                    eprint(b"ERROR: Argument ");
+                    let digit = [b'0' + i as u8];
+                    eprint(&digit);
+                    eprint(b" is too long after expanding {RUNFILES_ROOT}\r\n");
+                    ExitProcess(1);
+                }
             } else {
                 // Use path as-is without transformation
                 let copy_len = arg_len.min(MAX_PATH_LEN);
@@ -1226,28 +2982,32 @@ pub extern "C" fn main() -> ! {
         let mut cmdline_wide = [0u16; 8192]; // Large buffer for UTF-16
         let mut cmdline_pos = 0usize;
 
+        // Runtime arguments go before the embedded argument at this index
+        let runtime_pos = if metadata.flags & FLAG_RUNTIME_POS != 0 {
+            let digits = &*core::ptr::addr_of!(RUNTIME_POS);
+            let mut pos = 0;
+            for &c in &digits[..strlen(digits)] {
+                pos = pos * 10 + (c - b'0') as usize;
+            }
+            pos
+        } else {
+            argc
+        };
+        // Where that argument starts in the command line
+        let mut split = cmdline_pos;
+        // UTF-16 copy of the embedded or default argument being added
+        let mut wide = [0u16; MAX_PATH_LEN];
+
         // Add embedded arguments (convert from UTF-8 to UTF-16)
         for i in 0..argc {
+            if i == runtime_pos {
+                split = cmdline_pos;
+            }
             let arg_len = strlen(&RESOLVED_PATHS[i]);
-            let arg_slice = &RESOLVED_PATHS[i][..arg_len];
+            let wide_len = utf8_to_wide(&RESOLVED_PATHS[i][..arg_len], &mut wide);
 
             // Always quote the first argument (executable path) following Bazel's approach
-            // For other arguments, only quote if they contain spaces
-            let needs_quotes = i == 0 || find_byte(arg_slice, b' ').is_some();
-
-            if needs_quotes && cmdline_pos < cmdline_wide.len() {
-                cmdline_wide[cmdline_pos] = b'"' as u16;
-                cmdline_pos += 1;
-            }
-
-            // Convert UTF-8 to UTF-16 and copy
-            let converted_len = utf8_to_wide(arg_slice, &mut cmdline_wide[cmdline_pos..]);
-            cmdline_pos += converted_len;
-
-            if needs_quotes && cmdline_pos < cmdline_wide.len() {
-                cmdline_wide[cmdline_pos] = b'"' as u16;
-                cmdline_pos += 1;
-            }
+            cmdline_pos = append_arg(&mut cmdline_wide, cmdline_pos, &wide[..wide_len], i == 0);
 
             // Add space between arguments
             if (i < argc - 1 || runtime_args_count > 0) && cmdline_pos < cmdline_wide.len() {
@@ -1255,43 +3015,42 @@ pub extern "C" fn main() -> ! {
                 cmdline_pos += 1;
             }
         }
+        // End of the last embedded argument, before the space separating it from what follows
+        let embedded_end = if runtime_args_count > 0 { cmdline_pos - 1 } else { cmdline_pos };
 
-        // Add runtime arguments (already UTF-16, just copy)
+        // Add runtime arguments (already UTF-16, re-escaped as they were unescaped when parsed)
         for i in 0..runtime_args_count {
-            let runtime_arg = runtime_argv[i];
-            let arg_len = runtime_argv_len[i];
-
-            // Check if we need quotes (scan for spaces)
-            let mut needs_quotes = false;
-            for j in 0..arg_len {
-                if *runtime_arg.add(j) == b' ' as u16 {
-                    needs_quotes = true;
-                    break;
-                }
-            }
+            let runtime_arg = core::slice::from_raw_parts(runtime_argv[i], runtime_argv_len[i]);
+            cmdline_pos = append_arg(&mut cmdline_wide, cmdline_pos, runtime_arg, false);
 
-            if needs_quotes && cmdline_pos < cmdline_wide.len() {
-                cmdline_wide[cmdline_pos] = b'"' as u16;
+            // Add space between arguments (except after last)
+            if i < runtime_args_count - 1 && cmdline_pos < cmdline_wide.len() {
+                cmdline_wide[cmdline_pos] = b' ' as u16;
                 cmdline_pos += 1;
             }
+        }
 
-            // Copy wide string
-            let copy_len = arg_len.min(cmdline_wide.len() - cmdline_pos);
-            for j in 0..copy_len {
-                cmdline_wide[cmdline_pos + j] = *runtime_arg.add(j);
-            }
-            cmdline_pos += copy_len;
+        // Default runtime arguments apply only when the caller passed none
+        if runtime_args_count == 0 && metadata.flags & FLAG_DEFAULT_ARGS != 0 {
+            let defaults = &DEFAULT_ARGS;
+            let mut pos = 0;
+            while pos < defaults.len() && defaults[pos] != 0 {
+                let arg_len = strlen(&defaults[pos..]);
+                let wide_len = utf8_to_wide(&defaults[pos..pos + arg_len], &mut wide);
 
-            if needs_quotes && cmdline_pos < cmdline_wide.len() {
-                cmdline_wide[cmdline_pos] = b'"' as u16;
-                cmdline_pos += 1;
+                if cmdline_pos < cmdline_wide.len() {
+                    cmdline_wide[cmdline_pos] = b' ' as u16;
+                    cmdline_pos += 1;
+                }
+                cmdline_pos = append_arg(&mut cmdline_wide, cmdline_pos, &wide[..wide_len], false);
+                pos += arg_len + 1;
             }
+        }
 
-            // Add space between arguments (except after last)
-            if i < runtime_args_count - 1 && cmdline_pos < cmdline_wide.len() {
-                cmdline_wide[cmdline_pos] = b' ' as u16;
-                cmdline_pos += 1;
-            }
+        // Arguments are joined by single spaces, so rotating " <embedded tail>" behind
+        // " <runtime args>" moves the runtime arguments in front of the tail
+        if (1..argc).contains(&runtime_pos) && cmdline_pos > embedded_end {
+            cmdline_wide[split - 1..cmdline_pos].rotate_left(embedded_end - (split - 1));
         }
 
         // Null-terminate command line
@@ -1299,6 +3058,17 @@ pub extern "C" fn main() -> ! {
             cmdline_wide[cmdline_pos] = 0;
         }
 
+        write_debug_file(
+            metadata,
+            runfiles.as_ref(),
+            &runtime_argv[..runtime_args_count],
+            &runtime_argv_len[..runtime_args_count],
+        );
+
+        if set_env {
+            apply_set_env();
+        }
+
         // Build environment with runfiles variables if export is enabled
         let envp = if export_runfiles_env {
             build_runfiles_environ(runfiles.as_ref())
@@ -1306,6 +3076,64 @@ pub extern "C" fn main() -> ! {
             core::ptr::null_mut()
         };
 
+        // Program to run: EXEC_PATH as the application name if set, otherwise
+        // CreateProcessW takes it from the command line's first token
+        let mut exec_wide = [0u16; MAX_PATH_LEN + 1];
+        let (application_name, program) = if exec {
+            let exec_len = strlen(exec_buf);
+            let exec_slice = &exec_buf[..exec_len];
+
+            let mut decoded_key = [0u8; MAX_PATH_LEN];
+            let key = if percent_decode_keys {
+                let len = percent_decode(exec_slice, &mut decoded_key);
+                &decoded_key[..len]
+            } else {
+                exec_slice
+            };
+
+            let resolved = match runfiles.as_ref().and_then(|rf| rf.rlocation_executable(key, EXEC_PATH_SLOT, follow_indirection)) {
+                Some(resolved) => resolved,
+                None => exec_slice,
+            };
+            let len = utf8_to_wide(resolved, &mut exec_wide[..MAX_PATH_LEN]);
+            exec_wide[len] = 0;
+            (exec_wide.as_ptr(), resolved)
+        } else {
+            (core::ptr::null(), &RESOLVED_PATHS[0][..strlen(&RESOLVED_PATHS[0])])
+        };
+
+        // Directory the target runs in (--chdir). Runfiles manifests only list files,
+        // so a directory missing from one is looked up below the runfiles directory.
+        let mut dir_wide = [0u16; MAX_PATH_LEN + 1];
+        let current_directory = if change_dir {
+            let key = chdir_key;
+            let mut joined = [0u8; MAX_PATH_LEN];
+            let dir = if is_absolute_path(key) {
+                key
+            } else if let Some(resolved) = runfiles.as_ref().and_then(|rf| rf.rlocation(key, CHDIR_SLOT, follow_indirection)) {
+                resolved
+            } else {
+                let root = match runfiles.as_ref().and_then(|rf| rf.dir_path.as_ref()) {
+                    Some((root, len)) if len + 1 + key.len() < MAX_PATH_LEN => &root[..*len],
+                    _ => {
+                        eprint(b"ERROR: Working directory not found in the runfiles: ");
+                        eprint(key);
+                        eprint(b"\r\n");
+                        ExitProcess(1);
+                    }
+                };
+                joined[..root.len()].copy_from_slice(root);
+                joined[root.len()] = b'\\';
+                joined[root.len() + 1..root.len() + 1 + key.len()].copy_from_slice(key);
+                &joined[..root.len() + 1 + key.len()]
+            };
+            let len = utf8_to_wide(dir, &mut dir_wide[..MAX_PATH_LEN]);
+            dir_wide[len] = 0;
+            dir_wide.as_ptr()
+        } else {
+            core::ptr::null()
+        };
+
         // Create the process
         let mut si: STARTUPINFOW = core::mem::zeroed();
         si.cb = core::mem::size_of::<STARTUPINFOW>() as DWORD;
@@ -1319,27 +3147,28 @@ pub extern "C" fn main() -> ! {
             0
         };
 
-        // Use NULL for lpApplicationName and quote the executable in the command line
-        // This follows Bazel's launcher.cc approach
+        // Without --exec, use NULL for lpApplicationName and quote the executable in
+        // the command line. This follows Bazel's launcher.cc approach
         let success = CreateProcessW(
-            core::ptr::null(),          // Application name (NULL - parsed from command line)
+            application_name,           // Application name (NULL - parsed from command line)
             cmdline_wide.as_mut_ptr(),  // Command line (UTF-16) - quoted executable + args
             core::ptr::null_mut(),      // Process attributes
             core::ptr::null_mut(),      // Thread attributes
             1,                          // Inherit handles
             creation_flags,             // Creation flags (with CREATE_UNICODE_ENVIRONMENT if needed)
             envp,                       // Environment
-            core::ptr::null(),          // Current directory
+            current_directory,          // Current directory (NULL: the stub's)
             &mut si,
             &mut pi,
         );
 
         if success == 0 {
-            print(b"ERROR: CreateProcess failed\r\n");
-            ExitProcess(1);
+            create_process_failed(program);
         }
 
-        // Wait for the child process to complete
+        // Wait for the child process to complete, outlasting the console events it gets too
+        CHILD_PROCESS = pi.hProcess;
+        SetConsoleCtrlHandler(Some(console_ctrl_handler), 1);
         WaitForSingleObject(pi.hProcess, INFINITE);
 
         // Get the child process's exit code
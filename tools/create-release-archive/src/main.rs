@@ -1,61 +1,361 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{self, Write};
+use std::io;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::CompressionMethod;
+
+// Shared with the finalizer so both agree on the template markers
+#[allow(dead_code)]
+#[path = "../../../finalize-stub/src/metadata.rs"]
+mod metadata;
+
+/// Number of argument placeholders in a template
+const ARG_COUNT: usize = 10;
+
+/// Archive contents used when no manifest is given: (source path, archive path)
+const DEFAULT_ENTRIES: &[(&str, &str)] = &[
+    ("MODULE.bazel", "MODULE.bazel"),
+    ("LICENSE", "LICENSE"),
+    ("BUILD.bazel", "BUILD.bazel"),
+    ("launcher", "launcher"),
+];
+
+/// Output archive format
+#[derive(Clone, Copy)]
+enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tar.gz" => Some(Format::TarGz),
+            "zip" => Some(Format::Zip),
+            _ => None,
+        }
+    }
+
+    /// Format implied by the output file name: zip for `.zip`, tar.gz otherwise
+    fn from_output_path(path: &str) -> Self {
+        if path.to_ascii_lowercase().ends_with(".zip") {
+            Format::Zip
+        } else {
+            Format::TarGz
+        }
+    }
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [--manifest <file>] [--compression <0-9>] [--format <zip|tar.gz>] <tag> <output-file>", program);
+    eprintln!("Example: {} v0.2.1 hermetic_launcher-v0.2.1.tar.gz", program);
+    eprintln!();
+    eprintln!("--compression sets the deflate level: 0 stores without compression (fast),");
+    eprintln!("9 produces the smallest archive. Default: 6.");
+    eprintln!();
+    eprintln!("--format defaults to zip for an output file ending in .zip, tar.gz otherwise.");
+    eprintln!();
+    eprintln!("The manifest lists one entry per line: <source path> [<archive path>]");
+    eprintln!("Source paths are relative to the repository root; directories are added");
+    eprintln!("recursively. Empty lines and lines starting with '#' are ignored.");
+    eprintln!();
+    eprintln!("Files named runfiles-stub* are stub templates and must still contain all");
+    eprintln!("placeholders, so already-finalized or corrupt templates are rejected.");
+    std::process::exit(1);
+}
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} <tag> <output-file>", args[0]);
-        eprintln!("Example: {} v0.2.1 hermetic_launcher-v0.2.1.tar.gz", args[0]);
-        std::process::exit(1);
+
+    let mut manifest = None;
+    let mut compression = Compression::default();
+    let mut format = None;
+    let mut positional = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--manifest" {
+            match iter.next() {
+                Some(path) => manifest = Some(path),
+                None => usage(&args[0]),
+            }
+        } else if arg == "--compression" {
+            match iter.next().and_then(|level| parse_compression(level)) {
+                Some(level) => compression = level,
+                None => {
+                    eprintln!("Error: --compression expects a level from 0 to 9");
+                    usage(&args[0]);
+                }
+            }
+        } else if arg == "--format" {
+            match iter.next().and_then(|name| Format::parse(name)) {
+                Some(name) => format = Some(name),
+                None => {
+                    eprintln!("Error: --format expects zip or tar.gz");
+                    usage(&args[0]);
+                }
+            }
+        } else {
+            positional.push(arg);
+        }
     }
+    if positional.len() != 2 {
+        usage(&args[0]);
+    }
+
+    let _tag = positional[0]; // Tag is used in CLI for documentation but not needed by function
+    let output_path = positional[1];
 
-    let _tag = &args[1]; // Tag is used in CLI for documentation but not needed by function
-    let output_path = &args[2];
+    let entries = match manifest {
+        Some(path) => read_manifest(Path::new(path))?,
+        None => DEFAULT_ENTRIES
+            .iter()
+            .map(|(source, archive_path)| (source.to_string(), archive_path.to_string()))
+            .collect(),
+    };
 
-    create_release_archive(output_path)?;
+    let format = format.unwrap_or_else(|| Format::from_output_path(output_path));
+    create_release_archive(output_path, &entries, format, compression)?;
+    let checksum_path = write_checksum(Path::new(output_path))?;
 
     eprintln!("Created release archive: {}", output_path);
+    eprintln!("Wrote checksum: {}", checksum_path.display());
     Ok(())
 }
 
-fn create_release_archive(output_path: &str) -> Result<()> {
-    let repo_root = find_repo_root()?;
-    let output_file = File::create(output_path)
-        .with_context(|| format!("Failed to create output file: {}", output_path))?;
+/// Parses a `--compression` deflate level from 0 to 9
+fn parse_compression(level: &str) -> Option<Compression> {
+    level.parse::<u32>().ok().filter(|&level| level <= 9).map(Compression::new)
+}
+
+/// Writes `<archive>.sha256` in the `sha256sum -c` format: `<hex>  <file name>`
+fn write_checksum(archive_path: &Path) -> Result<PathBuf> {
+    let mut archive = File::open(archive_path)
+        .with_context(|| format!("Failed to open {}", archive_path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut archive, &mut hasher)
+        .with_context(|| format!("Failed to read {}", archive_path.display()))?;
+
+    // The file name alone, so the check works from the directory holding both files
+    let file_name = archive_path.file_name()
+        .context("Output path has no file name")?
+        .to_string_lossy();
+    let mut checksum_path = archive_path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    let checksum_path = PathBuf::from(checksum_path);
+    std::fs::write(&checksum_path, format!("{:x}  {}\n", hasher.finalize(), file_name))
+        .with_context(|| format!("Failed to write {}", checksum_path.display()))?;
 
-    let encoder = GzEncoder::new(output_file, Compression::default());
-    let mut archive = tar::Builder::new(encoder);
+    Ok(checksum_path)
+}
+
+/// Reads a manifest of archive entries: one `<source> [<archive path>]` per line
+fn read_manifest(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    parse_manifest(path, &content)
+}
+
+/// Parses the manifest `content`; `path` is only used in error messages
+fn parse_manifest(path: &Path, content: &str) -> Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
-    // Files to include at the root
-    let root_files = ["MODULE.bazel", "LICENSE", "BUILD.bazel"];
+        let mut fields = line.split_whitespace();
+        let source = fields.next().expect("line is not empty");
+        let archive_path = fields.next().unwrap_or(source);
+        if fields.next().is_some() {
+            bail!("{}:{}: expected `<source> [<archive path>]`", path.display(), index + 1);
+        }
+        if Path::new(archive_path).is_absolute()
+            || archive_path.split(['/', '\\']).any(|component| component == "..")
+        {
+            bail!("{}:{}: archive path must be relative and stay inside the archive: {}",
+                path.display(), index + 1, archive_path);
+        }
 
-    for file in &root_files {
-        let file_path = repo_root.join(file);
+        entries.push((source.to_string(), archive_path.trim_end_matches('/').to_string()));
+    }
+
+    if entries.is_empty() {
+        bail!("Manifest {} lists no entries", path.display());
+    }
+    Ok(entries)
+}
+
+/// Destination of the archive entries, one implementation per output format
+trait Archive {
+    /// Adds a regular file with 0o755 or 0o644 permissions
+    fn add_file(&mut self, archive_path: &str, file: &mut File, size: u64, executable: bool) -> Result<()>;
+    /// Adds an (empty) directory entry
+    fn add_directory(&mut self, archive_path: &str) -> Result<()>;
+    /// Writes the end of the archive
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct TarGzArchive {
+    builder: tar::Builder<GzEncoder<File>>,
+    /// Modification time of every entry
+    mtime: u64,
+}
+
+impl TarGzArchive {
+    /// Header with nothing taken from the filesystem: fixed mtime, root ownership, no user names
+    fn header(&self, size: u64, mode: u32) -> Result<tar::Header> {
         let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(mode);
+        header.set_mtime(self.mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("")?;
+        header.set_groupname("")?;
+        Ok(header)
+    }
+}
 
-        let metadata = std::fs::metadata(&file_path)
-            .with_context(|| format!("Failed to read metadata for {}", file))?;
-        header.set_size(metadata.len());
-        header.set_mode(0o644);
+impl Archive for TarGzArchive {
+    fn add_file(&mut self, archive_path: &str, file: &mut File, size: u64, executable: bool) -> Result<()> {
+        let mut header = self.header(size, if executable { 0o755 } else { 0o644 })?;
         header.set_cksum();
+        self.builder.append_data(&mut header, archive_path, file)?;
+        Ok(())
+    }
 
-        let mut file_handle = File::open(&file_path)
-            .with_context(|| format!("Failed to open {}", file))?;
+    fn add_directory(&mut self, archive_path: &str) -> Result<()> {
+        let mut header = self.header(0, 0o755)?;
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_cksum();
+        self.builder.append_data(&mut header, archive_path, io::empty())?;
+        Ok(())
+    }
 
-        archive.append_data(&mut header, file, &mut file_handle)
-            .with_context(|| format!("Failed to add {} to archive", file))?;
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.builder.into_inner()?.finish()?;
+        Ok(())
+    }
+}
 
-        eprintln!("Added: {}", file);
+struct ZipArchive {
+    writer: zip::ZipWriter<File>,
+    options: SimpleFileOptions,
+}
+
+impl Archive for ZipArchive {
+    fn add_file(&mut self, archive_path: &str, file: &mut File, _size: u64, executable: bool) -> Result<()> {
+        // Unix permissions go into the external attributes, where unzip reads them
+        let options = self.options.unix_permissions(if executable { 0o755 } else { 0o644 });
+        self.writer.start_file(archive_path, options)?;
+        io::copy(file, &mut self.writer)?;
+        Ok(())
+    }
+
+    fn add_directory(&mut self, archive_path: &str) -> Result<()> {
+        self.writer.add_directory(archive_path, self.options.unix_permissions(0o755))?;
+        Ok(())
     }
 
-    // Add launcher directory recursively
-    let launcher_dir = repo_root.join("launcher");
-    add_directory_to_archive(&mut archive, &launcher_dir, "launcher")?;
+    fn finish(self: Box<Self>) -> Result<()> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Modification time of the archive entries: `SOURCE_DATE_EPOCH` if set, the Unix epoch otherwise
+fn source_date_epoch() -> Result<u64> {
+    parse_source_date_epoch(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref())
+}
+
+fn parse_source_date_epoch(value: Option<&str>) -> Result<u64> {
+    match value {
+        Some(value) => value.trim().parse()
+            .with_context(|| format!("SOURCE_DATE_EPOCH must be a number of seconds, got {:?}", value)),
+        None => Ok(0),
+    }
+}
+
+/// Converts seconds since the Unix epoch to a zip (MS-DOS) timestamp, which
+/// starts in 1980; earlier times become zip's 1980-01-01 default
+fn zip_time(seconds: u64) -> zip::DateTime {
+    // Days to a civil date (Howard Hinnant's algorithm)
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = seconds % 86400;
+    u16::try_from(year)
+        .ok()
+        .and_then(|year| {
+            zip::DateTime::from_date_and_time(year, month, day, (time / 3600) as u8, (time / 60 % 60) as u8, (time % 60) as u8).ok()
+        })
+        .unwrap_or_default()
+}
+
+fn create_release_archive(
+    output_path: &str,
+    entries: &[(String, String)],
+    format: Format,
+    compression: Compression,
+) -> Result<()> {
+    let repo_root = find_repo_root()?;
+    let mtime = source_date_epoch()?;
+    let output_file = File::create(output_path)
+        .with_context(|| format!("Failed to create output file: {}", output_path))?;
+
+    let mut archive: Box<dyn Archive> = match format {
+        // The gzip header carries no name or timestamp either
+        Format::TarGz => Box::new(TarGzArchive {
+            builder: tar::Builder::new(GzEncoder::new(output_file, compression)),
+            mtime,
+        }),
+        Format::Zip => {
+            // Level 0 stores the files, like gzip's
+            let method = if compression.level() == 0 { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+            let level = (compression.level() != 0).then_some(compression.level() as i64);
+            let options = SimpleFileOptions::default()
+                .compression_method(method)
+                .compression_level(level)
+                .last_modified_time(zip_time(mtime));
+            Box::new(ZipArchive { writer: zip::ZipWriter::new(output_file), options })
+        }
+    };
+
+    for (source, archive_path) in entries {
+        let source_path = repo_root.join(source);
+        let metadata = std::fs::metadata(&source_path)
+            .with_context(|| format!("Failed to read metadata for {}", source))?;
+
+        if metadata.is_dir() {
+            // Add directory recursively
+            add_directory_to_archive(archive.as_mut(), &source_path, archive_path)?;
+        } else {
+            if is_template(archive_path) {
+                check_template(&source_path)?;
+            }
+
+            let mut file_handle = File::open(&source_path)
+                .with_context(|| format!("Failed to open {}", source))?;
+
+            archive.add_file(archive_path, &mut file_handle, metadata.len(), is_executable(&metadata))
+                .with_context(|| format!("Failed to add {} to archive", source))?;
+
+            eprintln!("Added: {}", archive_path);
+        }
+    }
 
     // Finish writing the archive
     archive.finish()
@@ -64,8 +364,8 @@ fn create_release_archive(output_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn add_directory_to_archive<W: Write>(
-    archive: &mut tar::Builder<W>,
+fn add_directory_to_archive(
+    archive: &mut dyn Archive,
     source_dir: &Path,
     archive_prefix: &str,
 ) -> Result<()> {
@@ -87,33 +387,27 @@ fn add_directory_to_archive<W: Write>(
         let archive_path = PathBuf::from(archive_prefix).join(relative_path);
         let archive_path_str = archive_path.to_str()
             .context("Invalid UTF-8 in path")?
-            .replace('\\', "/"); // Normalize path separators for tar
+            .replace('\\', "/"); // Normalize path separators for tar and zip
 
         if entry.file_type().is_dir() {
             // Add directory entry
-            let mut header = tar::Header::new_gnu();
-            header.set_size(0);
-            header.set_mode(0o755);
-            header.set_entry_type(tar::EntryType::Directory);
-            header.set_cksum();
-
-            archive.append_data(&mut header, &archive_path_str, io::empty())
+            archive.add_directory(&archive_path_str)
                 .with_context(|| format!("Failed to add directory {}", archive_path_str))?;
 
             eprintln!("Added: {}/", archive_path_str);
         } else if entry.file_type().is_file() {
+            if is_template(&archive_path_str) {
+                check_template(path)?;
+            }
+
             // Add file
             let metadata = entry.metadata()
                 .context("Failed to read file metadata")?;
-            let mut header = tar::Header::new_gnu();
-            header.set_size(metadata.len());
-            header.set_mode(if is_executable(&metadata) { 0o755 } else { 0o644 });
-            header.set_cksum();
 
             let mut file_handle = File::open(path)
                 .with_context(|| format!("Failed to open {}", path.display()))?;
 
-            archive.append_data(&mut header, &archive_path_str, &mut file_handle)
+            archive.add_file(&archive_path_str, &mut file_handle, metadata.len(), is_executable(&metadata))
                 .with_context(|| format!("Failed to add file {}", archive_path_str))?;
 
             eprintln!("Added: {}", archive_path_str);
@@ -124,6 +418,53 @@ fn add_directory_to_archive<W: Write>(
     Ok(())
 }
 
+/// Whether an archive entry is a stub template (e.g. `runfiles-stub-x86_64-linux`)
+fn is_template(archive_path: &str) -> bool {
+    archive_path
+        .rsplit('/')
+        .next()
+        .is_some_and(|name| name.starts_with("runfiles-stub"))
+}
+
+/// Verifies that a template still has its metadata block and all argument placeholders
+fn check_template(path: &Path) -> Result<()> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read template {}", path.display()))?;
+    let find = |pattern: &[u8], start: usize| {
+        data.get(start..)
+            .and_then(|rest| rest.windows(pattern.len()).position(|w| w == pattern))
+            .map(|offset| start + offset)
+    };
+
+    let Some(pos) = find(metadata::TEMPLATE_MAGIC, 0) else {
+        bail!(
+            "{} is not a stub template: metadata placeholder not found (already finalized or corrupt?)",
+            path.display()
+        );
+    };
+    let block = metadata::Metadata::decode(&data[pos..])
+        .with_context(|| format!("{}: metadata block is truncated", path.display()))?;
+    if block.version != metadata::VERSION || block.arg_size == 0 {
+        bail!(
+            "{}: unexpected template metadata (version {}, argument size {})",
+            path.display(), block.version, block.arg_size
+        );
+    }
+
+    let arg_size = block.arg_size as usize;
+    let arg_pattern = vec![b'@'; arg_size];
+    let mut start = 0;
+    for i in 0..ARG_COUNT {
+        let Some(pos) = find(&arg_pattern, start) else {
+            bail!("{}: ARG{} placeholder not found (already finalized or corrupt?)", path.display(), i);
+        };
+        start = pos + arg_size;
+    }
+
+    eprintln!("Verified template: {}", path.display());
+    Ok(())
+}
+
 #[cfg(unix)]
 fn is_executable(metadata: &std::fs::Metadata) -> bool {
     use std::os::unix::fs::PermissionsExt;
@@ -150,3 +491,154 @@ fn find_repo_root() -> Result<PathBuf> {
             .context("Could not find repository root (no MODULE.bazel found)")?;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Vec<(String, String)>> {
+        parse_manifest(Path::new("release.manifest"), content)
+    }
+
+    fn entry(source: &str, archive_path: &str) -> (String, String) {
+        (source.to_string(), archive_path.to_string())
+    }
+
+    #[test]
+    fn manifest_entries() {
+        let entries = parse("# Release contents\n\nLICENSE\n  bin/launcher   launcher  \nrules/ rules/\n").unwrap();
+        assert_eq!(entries, [entry("LICENSE", "LICENSE"), entry("bin/launcher", "launcher"), entry("rules/", "rules")]);
+    }
+
+    #[test]
+    fn manifest_errors() {
+        let error = |content: &str| parse(content).unwrap_err().to_string();
+        assert_eq!(error("LICENSE\na b c\n"), "release.manifest:2: expected `<source> [<archive path>]`");
+        assert_eq!(
+            error("LICENSE /etc/passwd\n"),
+            "release.manifest:1: archive path must be relative and stay inside the archive: /etc/passwd"
+        );
+        assert_eq!(
+            error("LICENSE docs/../../LICENSE\n"),
+            "release.manifest:1: archive path must be relative and stay inside the archive: docs/../../LICENSE"
+        );
+        assert!(error("..\\LICENSE\n").contains("stay inside the archive"));
+        assert_eq!(error("# nothing\n\n"), "Manifest release.manifest lists no entries");
+    }
+
+    /// Path of a scratch file for `name`, unique to this test process
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("create-release-archive-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// A fake template: the metadata block followed by `args` 8-byte placeholders
+    fn template(magic: &[u8], args: usize) -> Vec<u8> {
+        let block = metadata::Metadata {
+            version: metadata::VERSION,
+            arg_size: 8,
+            argc: 0,
+            transform_flags: 0,
+            flags: 0,
+            features: 0,
+            args_offset: 0,
+            value_flags: 0,
+        };
+        let mut data = block.encode().to_vec();
+        data[..metadata::MAGIC_SIZE].fill(0);
+        data[..magic.len()].copy_from_slice(magic);
+        for _ in 0..args {
+            data.extend_from_slice(b"@@@@@@@@\0");
+        }
+        data
+    }
+
+    #[test]
+    fn template_names() {
+        assert!(is_template("runfiles-stub-x86_64-linux"));
+        assert!(is_template("bin/runfiles-stub-x86_64-windows.exe"));
+        assert!(!is_template("launcher"));
+        assert!(!is_template("runfiles-stub/launcher"));
+    }
+
+    #[test]
+    fn template_check() {
+        let valid = temp_file("valid", &template(metadata::TEMPLATE_MAGIC, ARG_COUNT));
+        assert!(check_template(&valid).is_ok());
+
+        let finalized = temp_file("finalized", &template(metadata::FINALIZED_MAGIC, ARG_COUNT));
+        assert!(check_template(&finalized).unwrap_err().to_string().contains("metadata placeholder not found"));
+
+        let missing_arg = temp_file("missing_arg", &template(metadata::TEMPLATE_MAGIC, ARG_COUNT - 1));
+        assert!(check_template(&missing_arg).unwrap_err().to_string().contains("ARG9 placeholder not found"));
+
+        for path in [valid, finalized, missing_arg] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    #[test]
+    fn compression_levels() {
+        assert_eq!(parse_compression("0"), Some(Compression::new(0)));
+        assert_eq!(parse_compression("9"), Some(Compression::new(9)));
+        for level in ["10", "-1", "fast", ""] {
+            assert_eq!(parse_compression(level), None, "{:?}", level);
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert!(matches!(Format::parse("zip"), Some(Format::Zip)));
+        assert!(matches!(Format::parse("tar.gz"), Some(Format::TarGz)));
+        assert!(Format::parse("tgz").is_none());
+        assert!(matches!(Format::from_output_path("release.ZIP"), Format::Zip));
+        assert!(matches!(Format::from_output_path("release.tar.gz"), Format::TarGz));
+    }
+
+    #[test]
+    fn source_date_epoch_values() {
+        assert_eq!(parse_source_date_epoch(None).unwrap(), 0);
+        assert_eq!(parse_source_date_epoch(Some("1700000000")).unwrap(), 1_700_000_000);
+        assert_eq!(parse_source_date_epoch(Some(" 1700000000\n")).unwrap(), 1_700_000_000);
+        for value in ["", "-1", "2023-11-14", "1.5"] {
+            let error = parse_source_date_epoch(Some(value)).unwrap_err().to_string();
+            assert!(error.starts_with("SOURCE_DATE_EPOCH must be a number of seconds"), "{:?}: {}", value, error);
+        }
+    }
+
+    fn date_time(time: zip::DateTime) -> (u16, u8, u8, u8, u8, u8) {
+        (time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second())
+    }
+
+    #[test]
+    fn zip_times() {
+        // 2023-11-14 22:13:20 UTC
+        assert_eq!(date_time(zip_time(1_700_000_000)), (2023, 11, 14, 22, 13, 20));
+        // A leap day
+        assert_eq!(date_time(zip_time(951_782_400)), (2000, 2, 29, 0, 0, 0));
+        // 1980-01-01, the earliest zip time
+        assert_eq!(date_time(zip_time(315_532_800)), (1980, 1, 1, 0, 0, 0));
+        // Earlier times, including the default SOURCE_DATE_EPOCH of 0, clamp to it
+        assert_eq!(date_time(zip_time(0)), (1980, 1, 1, 0, 0, 0));
+        assert_eq!(date_time(zip_time(315_532_799)), (1980, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn checksum_file() {
+        let archive = temp_file("release.tar.gz", b"abc");
+        let checksum = write_checksum(&archive).unwrap();
+        let mut expected = archive.as_os_str().to_owned();
+        expected.push(".sha256");
+        assert_eq!(checksum, PathBuf::from(expected));
+
+        // `sha256sum -c` format with the bare file name: SHA-256 of "abc"
+        let name = archive.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(
+            std::fs::read_to_string(&checksum).unwrap(),
+            format!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  {}\n", name)
+        );
+
+        let _ = std::fs::remove_file(archive);
+        let _ = std::fs::remove_file(checksum);
+    }
+}